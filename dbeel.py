@@ -31,6 +31,11 @@ class DB:
     def create(c):
         return _db_request(type="create_collection", name=c)
 
+    @staticmethod
+    def alter(c, r):
+        return _db_request(type="alter_collection", name=c,
+                           replication_factor=r)
+
     @staticmethod
     def drop(c):
         return _db_request(type="drop_collection", name=c)
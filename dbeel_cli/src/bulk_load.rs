@@ -0,0 +1,392 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+    time::Duration,
+};
+
+use dbeel::{
+    shards::{hash_bytes, hash_string, ClusterMetadata},
+    storage_engine::{
+        sstable_builder::SSTableBuilder, Entry, EntryKind, EntryValue,
+    },
+};
+use dbeel_client::DbeelClient;
+use rmpv::{decode::read_value, encode::write_value, Value};
+use serde_json::Value as JsonValue;
+use time::OffsetDateTime;
+
+use crate::{json::json_to_msgpack, Result};
+
+/// The max size of the documents (keys and values, for every owner shard)
+/// sorted in memory before they are written as sstables.
+const MAX_RUN_SIZE: usize = 64 * 1024 * 1024;
+
+struct Shard {
+    hash: u32,
+    name: String,
+    node_name: String,
+}
+
+/// The shards of the cluster sorted by hash, the same ring the client and
+/// the shards use to find the owners of a key.
+fn hash_ring(metadata: &ClusterMetadata) -> Result<Vec<Shard>> {
+    let mut ring = Vec::new();
+    for node in &metadata.nodes {
+        for shard_id in &node.ids {
+            let name = format!("{}-{}", node.name, shard_id);
+            ring.push(Shard {
+                hash: hash_string(&name)?,
+                name,
+                node_name: node.name.clone(),
+            });
+        }
+    }
+    ring.sort_unstable_by_key(|s| s.hash);
+    Ok(ring)
+}
+
+/// The names of the shards owning a key, the primary shard and the replicas
+/// after it in the ring, each on a different node.
+fn owners(ring: &[Shard], hash: u32, replication_factor: u16) -> Vec<&str> {
+    let start = ring.iter().position(|s| s.hash >= hash).unwrap_or(0);
+    let mut nodes = HashSet::new();
+    let mut owners = Vec::new();
+    for i in 0..ring.len() {
+        let shard = &ring[(start + i) % ring.len()];
+        if nodes.insert(&shard.node_name) {
+            owners.push(shard.name.as_str());
+            if owners.len() >= replication_factor as usize {
+                break;
+            }
+        }
+    }
+    owners
+}
+
+fn take_field(document: &mut Value, name: &str) -> Option<Value> {
+    let items = match document {
+        Value::Map(items) => items,
+        _ => return None,
+    };
+    let index = items.iter().position(|(k, _)| k.as_str() == Some(name))?;
+    Some(items.swap_remove(index).1)
+}
+
+/// Reads documents of {"key": .., "value": ..} one at a time, from a JSON
+/// lines file, or from a file of msgpack maps written one after the other.
+struct DocumentReader<R> {
+    reader: R,
+    format: String,
+
+    /// The number of the last document read, for errors.
+    document: usize,
+}
+
+impl<R: BufRead> DocumentReader<R> {
+    fn new(reader: R, format: &str) -> Result<Self> {
+        if !matches!(format, "json" | "msgpack") {
+            return Err(format!("Unknown format: {format}").into());
+        }
+        Ok(Self {
+            reader,
+            format: format.to_string(),
+            document: 0,
+        })
+    }
+
+    fn read_document(&mut self) -> Result<Option<(Value, Value)>> {
+        self.document += 1;
+        let i = self.document;
+        let mut document = if self.format == "json" {
+            let mut line = String::new();
+            loop {
+                if self.reader.read_line(&mut line)? == 0 {
+                    return Ok(None);
+                }
+                if !line.trim().is_empty() {
+                    break;
+                }
+                line.clear();
+            }
+            let json: JsonValue = serde_json::from_str(&line)
+                .map_err(|e| format!("Document {i}: {e}"))?;
+            json_to_msgpack(json)
+        } else {
+            if self.reader.fill_buf()?.is_empty() {
+                return Ok(None);
+            }
+            read_value(&mut self.reader)
+                .map_err(|e| format!("Document {i}: {e}"))?
+        };
+
+        let key = take_field(&mut document, "key");
+        let value = take_field(&mut document, "value");
+        match (key, value) {
+            (Some(key), Some(value)) => Ok(Some((key, value))),
+            _ => Err(format!(
+                "Document {i}: expected \"key\" and \"value\" fields"
+            )
+            .into()),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for DocumentReader<R> {
+    type Item = Result<(Value, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_document().transpose()
+    }
+}
+
+/// Write a run of documents sorted by key, as an sstable at `index` for
+/// every shard owning any of them, adds the number of documents written to
+/// each shard to `written`.
+/// Runs are stamped a nanosecond apart, so that the value of a key in a later
+/// run (a later document) wins.
+async fn write_run<'a>(
+    output: &Path,
+    index: usize,
+    timestamp: OffsetDateTime,
+    run: BTreeMap<&'a str, BTreeMap<Vec<u8>, Vec<u8>>>,
+    written: &mut BTreeMap<&'a str, usize>,
+) -> Result<()> {
+    let timestamp = timestamp + Duration::from_nanos(index as u64);
+    for (shard, documents) in run {
+        let mut builder = SSTableBuilder::create(
+            &output.join(shard),
+            index,
+            documents.len(),
+        )?;
+        for (key, data) in documents {
+            let value = EntryValue {
+                data,
+                timestamp,
+                kind: EntryKind::Put,
+            };
+            builder.add(&Entry { key, value }).await?;
+        }
+        *written.entry(shard).or_default() += builder.finish().await?;
+    }
+    Ok(())
+}
+
+/// Partition the documents by their owner shards into `<output>/<shard>`,
+/// returns the number of documents written to each shard.
+/// Documents are buffered and sorted in memory in runs of about
+/// `max_run_size` bytes, every run is written as an sstable per shard, so
+/// the input can be larger than the memory (the sstables are merged by
+/// compactions after they are ingested).
+async fn write_sstables<'a>(
+    ring: &'a [Shard],
+    replication_factor: u16,
+    documents: impl Iterator<Item = Result<(Value, Value)>>,
+    output: &Path,
+    max_run_size: usize,
+) -> Result<BTreeMap<&'a str, usize>> {
+    let timestamp = OffsetDateTime::now_utc();
+    let mut written = BTreeMap::new();
+    let mut run: BTreeMap<&str, BTreeMap<Vec<u8>, Vec<u8>>> = BTreeMap::new();
+    let mut run_size = 0;
+    let mut run_index = 0;
+
+    for document in documents {
+        let (key, value) = document?;
+        let (mut key_buf, mut value_buf) = (Vec::new(), Vec::new());
+        write_value(&mut key_buf, &key)?;
+        write_value(&mut value_buf, &value)?;
+        for shard in owners(ring, hash_bytes(&key_buf)?, replication_factor) {
+            run_size += key_buf.len() + value_buf.len();
+            run.entry(shard)
+                .or_default()
+                .insert(key_buf.clone(), value_buf.clone());
+        }
+
+        if run_size >= max_run_size {
+            let full_run = std::mem::take(&mut run);
+            write_run(output, run_index, timestamp, full_run, &mut written)
+                .await?;
+            run_size = 0;
+            run_index += 1;
+        }
+    }
+
+    if !run.is_empty() {
+        write_run(output, run_index, timestamp, run, &mut written).await?;
+    }
+
+    Ok(written)
+}
+
+/// Write the sstables of a collection's documents into `<output>/<shard>`
+/// for every shard owning any of them (replicas included), to be linked into
+/// the collection by the ingest command, after copying the output directory
+/// to all nodes.
+/// The hash ring is read when building, so the cluster must not change
+/// until the sstables are ingested.
+pub async fn build_sstables(
+    client: &DbeelClient,
+    collection_name: &str,
+    path: &str,
+    output: &str,
+    format: &str,
+) -> Result<()> {
+    let output = Path::new(output);
+    if output.exists() && output.read_dir()?.next().is_some() {
+        return Err(format!(
+            "Output directory is not empty: {}",
+            output.display()
+        )
+        .into());
+    }
+
+    let metadata = client.get_cluster_metadata().await?;
+    let replication_factor = metadata
+        .collections
+        .get(collection_name)
+        .ok_or_else(|| format!("Collection not found: {collection_name}"))?
+        .replication_factor;
+    let ring = hash_ring(&metadata)?;
+
+    let documents =
+        DocumentReader::new(BufReader::new(File::open(path)?), format)?;
+    let written = write_sstables(
+        &ring,
+        replication_factor,
+        documents,
+        output,
+        MAX_RUN_SIZE,
+    )
+    .await?;
+    for (shard, count) in written {
+        println!("{shard}: {count} documents");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, io::Cursor};
+
+    use dbeel::{
+        messages::NodeMetadata,
+        storage_engine::{
+            inspect::{inspect_sstable, list_collection_files},
+            DATA_FILE_EXT,
+        },
+    };
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn test_ring(nodes: usize, shards_per_node: u16) -> Vec<Shard> {
+        let metadata = ClusterMetadata {
+            nodes: (0..nodes)
+                .map(|i| NodeMetadata {
+                    name: format!("node{i}"),
+                    ip: "127.0.0.1".to_string(),
+                    remote_shard_base_port: 0,
+                    ids: (0..shards_per_node).collect(),
+                    gossip_port: 0,
+                    db_port: 0,
+                    incarnation: 0,
+                })
+                .collect(),
+            collections: HashMap::new(),
+            dead_nodes: HashMap::new(),
+            dropped_collections: HashMap::new(),
+        };
+        hash_ring(&metadata).unwrap()
+    }
+
+    fn node_of<'a>(ring: &'a [Shard], name: &str) -> &'a str {
+        &ring.iter().find(|s| s.name == name).unwrap().node_name
+    }
+
+    #[test]
+    fn owners_on_different_nodes() {
+        let ring = test_ring(3, 2);
+
+        for hash in [0, ring[2].hash, ring[2].hash + 1, u32::MAX] {
+            let owners = owners(&ring, hash, 2);
+            assert_eq!(owners.len(), 2);
+
+            // The primary owner is the first shard at or after the hash.
+            let primary =
+                ring.iter().find(|s| s.hash >= hash).unwrap_or(&ring[0]);
+            assert_eq!(owners[0], primary.name);
+            assert_ne!(node_of(&ring, owners[0]), node_of(&ring, owners[1]));
+        }
+
+        // Can't have more replicas than nodes.
+        assert_eq!(owners(&ring, 0, 5).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn build_sstables_in_runs() -> Result<()> {
+        let output_dir = tempdir()?;
+        let output = output_dir.path();
+
+        let input = (0..100)
+            .map(|i| format!("{{\"key\": {i}, \"value\": \"v{i}\"}}\n"))
+            .chain(std::iter::once("{\"key\": 5, \"value\": \"new\"}\n".into()))
+            .collect::<String>();
+        let documents = DocumentReader::new(Cursor::new(input), "json")?;
+
+        let ring = test_ring(2, 2);
+        let written = write_sstables(&ring, 2, documents, output, 256).await?;
+
+        // Every document is written to a shard on each of the 2 nodes, in
+        // many runs.
+        assert_eq!(written.values().sum::<usize>(), 2 * 101);
+        let mut values_of_key_5 = Vec::new();
+        for shard in written.keys() {
+            let dir = output.join(shard);
+            let runs = list_collection_files(&dir)?
+                .into_iter()
+                .filter(|file| file.extension == DATA_FILE_EXT)
+                .map(|file| file.index)
+                .collect::<Vec<_>>();
+            assert!(runs.len() > 1);
+
+            for index in runs {
+                let sstable = inspect_sstable(&dir, index)?;
+                assert!(sstable.corruptions.is_empty());
+                for inspected in sstable.entries {
+                    if inspected.entry.key == [5] {
+                        values_of_key_5.push(inspected.entry.value);
+                    }
+                }
+            }
+        }
+
+        // The last document of a key wins.
+        let newest = values_of_key_5
+            .iter()
+            .max_by_key(|value| value.timestamp)
+            .unwrap();
+        let mut expected = Vec::new();
+        write_value(&mut expected, &Value::from("new"))?;
+        assert_eq!(newest.data, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_documents_errors() {
+        let input = "{\"key\": 1, \"value\": 1}\n\n{\"key\": 2}\n";
+        let mut documents =
+            DocumentReader::new(Cursor::new(input), "json").unwrap();
+        assert!(documents.next().unwrap().is_ok());
+        let error = documents.next().unwrap().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Document 2: expected \"key\" and \"value\" fields"
+        );
+
+        assert!(DocumentReader::new(Cursor::new(""), "csv").is_err());
+    }
+}
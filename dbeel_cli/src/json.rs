@@ -0,0 +1,101 @@
+use rmpv::Value;
+use serde_json::{Map, Number, Value as JsonValue};
+
+pub fn json_to_msgpack(json: JsonValue) -> Value {
+    match json {
+        JsonValue::Null => Value::Nil,
+        JsonValue::Bool(b) => Value::Boolean(b),
+        JsonValue::Number(n) => {
+            if let Some(u) = n.as_u64() {
+                Value::from(u)
+            } else if let Some(i) = n.as_i64() {
+                Value::from(i)
+            } else {
+                Value::F64(n.as_f64().unwrap_or(f64::NAN))
+            }
+        }
+        JsonValue::String(s) => Value::String(s.into()),
+        JsonValue::Array(items) => {
+            Value::Array(items.into_iter().map(json_to_msgpack).collect())
+        }
+        JsonValue::Object(map) => Value::Map(
+            map.into_iter()
+                .map(|(k, v)| (Value::String(k.into()), json_to_msgpack(v)))
+                .collect(),
+        ),
+    }
+}
+
+fn bytes_to_json(bytes: &[u8]) -> JsonValue {
+    JsonValue::Array(bytes.iter().map(|b| JsonValue::from(*b)).collect())
+}
+
+fn float_to_json(f: f64) -> JsonValue {
+    Number::from_f64(f).map_or(JsonValue::Null, JsonValue::Number)
+}
+
+/// JSON object keys must be strings, other msgpack keys are written as JSON.
+fn map_key_to_string(key: Value) -> String {
+    match key {
+        Value::String(s) => String::from_utf8_lossy(s.as_bytes()).into_owned(),
+        key => msgpack_to_json(key).to_string(),
+    }
+}
+
+pub fn msgpack_to_json(value: Value) -> JsonValue {
+    match value {
+        Value::Nil => JsonValue::Null,
+        Value::Boolean(b) => JsonValue::Bool(b),
+        Value::Integer(i) => i
+            .as_u64()
+            .map(JsonValue::from)
+            .or_else(|| i.as_i64().map(JsonValue::from))
+            .unwrap_or(JsonValue::Null),
+        Value::F32(f) => float_to_json(f.into()),
+        Value::F64(f) => float_to_json(f),
+        Value::String(s) => {
+            JsonValue::String(String::from_utf8_lossy(s.as_bytes()).into())
+        }
+        Value::Binary(bytes) | Value::Ext(_, bytes) => bytes_to_json(&bytes),
+        Value::Array(items) => {
+            JsonValue::Array(items.into_iter().map(msgpack_to_json).collect())
+        }
+        Value::Map(items) => JsonValue::Object(
+            items
+                .into_iter()
+                .map(|(k, v)| (map_key_to_string(k), msgpack_to_json(v)))
+                .collect::<Map<_, _>>(),
+        ),
+    }
+}
+
+/// Split a command line into arguments, each argument is either a JSON value
+/// or a bare word (parsed as a string).
+pub fn parse_arguments(line: &str) -> Vec<JsonValue> {
+    let mut arguments = Vec::new();
+    let mut rest = line.trim_start();
+
+    while !rest.is_empty() {
+        let mut stream =
+            serde_json::Deserializer::from_str(rest).into_iter::<JsonValue>();
+        let parsed = match stream.next() {
+            Some(Ok(value)) => {
+                let length = stream.byte_offset();
+                let ends_at_word_boundary = length == rest.len()
+                    || rest[length..].starts_with(char::is_whitespace);
+                ends_at_word_boundary.then_some((value, length))
+            }
+            _ => None,
+        };
+
+        let (argument, length) = parsed.unwrap_or_else(|| {
+            let length = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            (JsonValue::String(rest[..length].to_string()), length)
+        });
+
+        arguments.push(argument);
+        rest = rest[length..].trim_start();
+    }
+
+    arguments
+}
@@ -0,0 +1,395 @@
+mod bulk_load;
+mod json;
+
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+};
+
+use bulk_load::build_sstables;
+use clap::Parser;
+use dbeel_client::{tls_config_from_ca_cert, DbeelClient};
+use futures::StreamExt;
+use json::{json_to_msgpack, msgpack_to_json, parse_arguments};
+use serde_json::{json, Value as JsonValue};
+use tokio::io::{stdin, AsyncBufReadExt};
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+/// Manage a dbeel cluster, starts a REPL when no command is given.
+struct Args {
+    #[clap(
+        short,
+        long,
+        help = "Server hostname / ip.",
+        default_value = "127.0.0.1"
+    )]
+    ip: String,
+
+    #[clap(short, long, help = "Server port.", default_value = "10000")]
+    port: u16,
+
+    #[clap(short, long, help = "Username, when the server has auth enabled.")]
+    username: Option<String>,
+
+    #[clap(long, help = "Password, when the server has auth enabled.")]
+    password: Option<String>,
+
+    #[clap(
+        long,
+        help = "Connect over TLS, trusting the CA certificate (PEM) at this \
+                path."
+    )]
+    tls_ca_cert: Option<String>,
+
+    #[clap(help = "A command to run, run 'help' to list all commands.")]
+    command: Vec<String>,
+}
+
+const PROMPT: &str = "dbeel> ";
+const SCAN_PAGE_SIZE: u64 = 1000;
+const DEFAULT_SCAN_LIMIT: usize = 20;
+
+const HELP: &str = "\
+Commands:
+  collections                           List all collections.
+  create <collection> [replication]     Create a collection.
+  drop <collection>                     Drop a collection.
+  truncate <collection>                 Delete all documents of a collection.
+  get <collection> <key>                Get a document.
+  set <collection> <key> <document>     Set a document.
+  delete <collection> <key>             Delete a document.
+  scan <collection> [limit]             Print documents of a collection.
+  count <collection>                    Print the approximate number of
+                                        documents in a collection.
+  status                                Print the cluster and node status.
+  stats [collection]                    Print the stats of all shards, or of
+                                        a collection.
+  flush <collection>                    Flush the memtables of a collection.
+  compact <collection>                  Compact the sstables of a collection.
+  export <collection> <file>            Export a collection to a JSON lines
+                                        file of {\"key\": .., \"value\": ..}.
+  import <collection> <file>            Import a JSON lines file (same format
+                                        as export) to a collection.
+  build-sstables <collection> <file> <dir> [json|msgpack]
+                                        Build sstables of the documents of a
+                                        JSON lines (same format as export) or
+                                        msgpack file, for every shard in dir.
+  ingest <collection> <dir>             Ingest the sstables built into dir,
+                                        dir must be readable by all nodes.
+  help                                  Print this message.
+  exit                                  Exit the REPL.
+
+Keys and documents are JSON, bare words are parsed as strings.";
+
+fn print_json(value: &JsonValue) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+fn argument_as_str<'a>(
+    arguments: &'a [JsonValue],
+    index: usize,
+    name: &str,
+) -> Result<&'a str> {
+    arguments
+        .get(index)
+        .and_then(JsonValue::as_str)
+        .ok_or_else(|| format!("Missing argument: <{name}>").into())
+}
+
+fn argument<'a>(
+    arguments: &'a [JsonValue],
+    index: usize,
+    name: &str,
+) -> Result<&'a JsonValue> {
+    arguments
+        .get(index)
+        .ok_or_else(|| format!("Missing argument: <{name}>").into())
+}
+
+async fn export(
+    client: &DbeelClient,
+    collection_name: &str,
+    path: &str,
+) -> Result<()> {
+    let collection = client.collection(collection_name).await?;
+    let mut documents = collection.dump().await?;
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    let mut exported = 0;
+    while let Some(document) = documents.next().await {
+        let (key, value) = document?;
+        let line = json!({
+            "key": msgpack_to_json(key),
+            "value": msgpack_to_json(value),
+        });
+        writeln!(writer, "{line}")?;
+        exported += 1;
+    }
+    writer.flush()?;
+
+    println!("Exported {exported} documents");
+    Ok(())
+}
+
+async fn import(
+    client: &DbeelClient,
+    collection_name: &str,
+    path: &str,
+) -> Result<()> {
+    let collection = client.collection(collection_name).await?;
+
+    let mut imported = 0;
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let mut document: JsonValue = serde_json::from_str(&line)
+            .map_err(|e| format!("Line {}: {e}", i + 1))?;
+        let key = document.get_mut("key").map(JsonValue::take);
+        let value = document.get_mut("value").map(JsonValue::take);
+        let (key, value) = match (key, value) {
+            (Some(key), Some(value)) => (key, value),
+            _ => {
+                return Err(format!(
+                    "Line {}: expected \"key\" and \"value\" fields",
+                    i + 1
+                )
+                .into());
+            }
+        };
+
+        collection
+            .set(json_to_msgpack(key), json_to_msgpack(value))
+            .await?;
+        imported += 1;
+    }
+
+    println!("Imported {imported} documents");
+    Ok(())
+}
+
+/// Run a single command, returns false when the REPL should exit.
+async fn run_command(client: &DbeelClient, line: &str) -> Result<bool> {
+    let arguments = parse_arguments(line);
+    let command = match arguments.first() {
+        Some(command) => command.as_str().unwrap_or_default(),
+        None => return Ok(true),
+    };
+
+    match command {
+        "collections" => {
+            for summary in client.list_collections().await? {
+                println!(
+                    "{} (replication factor: {}, documents: ~{}, disk bytes: \
+                     {}, created at: {})",
+                    summary.name,
+                    summary.metadata.replication_factor,
+                    summary.approximate_documents,
+                    summary.disk_bytes,
+                    summary.metadata.created_at
+                );
+            }
+        }
+        "create" => {
+            let name = argument_as_str(&arguments, 1, "collection")?;
+            let replication_factor = match arguments.get(2) {
+                Some(n) => n
+                    .as_u64()
+                    .and_then(|n| u16::try_from(n).ok())
+                    .ok_or("Replication factor must be a number")?,
+                None => 1,
+            };
+            client
+                .create_collection_with_replication(name, replication_factor)
+                .await?;
+        }
+        "drop" => {
+            let name = argument_as_str(&arguments, 1, "collection")?;
+            client.collection(name).await?.drop().await?;
+        }
+        "truncate" => {
+            let name = argument_as_str(&arguments, 1, "collection")?;
+            client.collection(name).await?.truncate().await?;
+        }
+        "get" => {
+            let name = argument_as_str(&arguments, 1, "collection")?;
+            let key = argument(&arguments, 2, "key")?;
+            let value = client
+                .collection(name)
+                .await?
+                .get(json_to_msgpack(key.clone()))
+                .await?;
+            print_json(&msgpack_to_json(value))?;
+        }
+        "set" => {
+            let name = argument_as_str(&arguments, 1, "collection")?;
+            let key = argument(&arguments, 2, "key")?;
+            let document = argument(&arguments, 3, "document")?;
+            client
+                .collection(name)
+                .await?
+                .set(
+                    json_to_msgpack(key.clone()),
+                    json_to_msgpack(document.clone()),
+                )
+                .await?;
+        }
+        "delete" => {
+            let name = argument_as_str(&arguments, 1, "collection")?;
+            let key = argument(&arguments, 2, "key")?;
+            client
+                .collection(name)
+                .await?
+                .delete(json_to_msgpack(key.clone()))
+                .await?;
+        }
+        "scan" => {
+            let name = argument_as_str(&arguments, 1, "collection")?;
+            let limit = match arguments.get(2) {
+                Some(n) => n.as_u64().ok_or("Limit must be a number")? as usize,
+                None => DEFAULT_SCAN_LIMIT,
+            };
+            // Pages are fetched only while documents are printed.
+            let page_size = SCAN_PAGE_SIZE.min(limit as u64).max(1);
+            let mut documents = client
+                .collection(name)
+                .await?
+                .scan(page_size)
+                .await?
+                .take(limit);
+            while let Some(document) = documents.next().await {
+                let (key, value) = document?;
+                println!(
+                    "{}: {}",
+                    msgpack_to_json(key),
+                    msgpack_to_json(value)
+                );
+            }
+        }
+        "count" => {
+            let name = argument_as_str(&arguments, 1, "collection")?;
+            println!("~{}", client.collection(name).await?.count().await?);
+        }
+        "status" => {
+            let metadata = client.get_cluster_metadata().await?;
+            let status = client.node_status().await?;
+            print_json(&json!({
+                "nodes": serde_json::to_value(metadata.nodes)?,
+                "node_status": serde_json::to_value(status)?,
+            }))?;
+        }
+        "stats" => match arguments.get(1).and_then(JsonValue::as_str) {
+            Some(name) => {
+                let stats = client.collection(name).await?.stats().await?;
+                print_json(&serde_json::to_value(stats)?)?;
+            }
+            None => {
+                print_json(&serde_json::to_value(
+                    client.shard_stats().await?,
+                )?)?;
+            }
+        },
+        "flush" => {
+            let name = argument_as_str(&arguments, 1, "collection")?;
+            client.collection(name).await?.flush().await?;
+        }
+        "compact" => {
+            let name = argument_as_str(&arguments, 1, "collection")?;
+            client.collection(name).await?.compact().await?;
+        }
+        "export" => {
+            let name = argument_as_str(&arguments, 1, "collection")?;
+            let path = argument_as_str(&arguments, 2, "file")?;
+            export(client, name, path).await?;
+        }
+        "import" => {
+            let name = argument_as_str(&arguments, 1, "collection")?;
+            let path = argument_as_str(&arguments, 2, "file")?;
+            import(client, name, path).await?;
+        }
+        "build-sstables" => {
+            let name = argument_as_str(&arguments, 1, "collection")?;
+            let path = argument_as_str(&arguments, 2, "file")?;
+            let output = argument_as_str(&arguments, 3, "dir")?;
+            let format = match arguments.get(4) {
+                Some(format) => format.as_str().ok_or("Unknown format")?,
+                None => "json",
+            };
+            build_sstables(client, name, path, output, format).await?;
+        }
+        "ingest" => {
+            let name = argument_as_str(&arguments, 1, "collection")?;
+            let dir = argument_as_str(&arguments, 2, "dir")?;
+            let ingested = client.collection(name).await?.ingest(dir).await?;
+            println!("Ingested {ingested} sstables");
+        }
+        "help" => println!("{HELP}"),
+        "exit" | "quit" => return Ok(false),
+        _ => return Err(format!("Unknown command: {command}").into()),
+    }
+
+    Ok(true)
+}
+
+async fn run_repl(client: &DbeelClient) -> Result<()> {
+    let mut lines = tokio::io::BufReader::new(stdin()).lines();
+    loop {
+        print!("{PROMPT}");
+        std::io::stdout().flush()?;
+
+        let line = match lines.next_line().await? {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+
+        match run_command(client, &line).await {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(e) => eprintln!("Error: {e}"),
+        }
+    }
+}
+
+async fn run(args: Args) -> Result<()> {
+    let tls_config = match &args.tls_ca_cert {
+        Some(path) => Some(tls_config_from_ca_cert(path)?),
+        None => None,
+    };
+    let credentials = match (&args.username, &args.password) {
+        (Some(username), Some(password)) => {
+            Some((username.as_str(), password.as_str()))
+        }
+        (None, None) => None,
+        _ => return Err("Both username and password are required".into()),
+    };
+
+    let seed_nodes = [(args.ip.clone(), args.port)];
+    let client =
+        DbeelClient::from_seed_nodes_ex(&seed_nodes, credentials, tls_config)
+            .await?;
+
+    if args.command.is_empty() {
+        run_repl(&client).await
+    } else {
+        run_command(&client, &args.command.join(" "))
+            .await
+            .map(|_| ())
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+
+    if let Err(e) = run(args).await {
+        eprintln!("Error: {e}");
+        std::process::exit(1);
+    }
+}
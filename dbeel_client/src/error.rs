@@ -34,6 +34,18 @@ pub enum Error {
     #[error("Failed to connect to a shard: {0}")]
     ConnectToShard(tokio::io::Error),
 
+    /// Failed to read TLS certificates.
+    #[error("Failed to read TLS certificates: {0}")]
+    ReadCertificates(std::io::Error),
+
+    /// Failed to create a TLS config.
+    #[error("Failed to create a TLS config: {0}")]
+    Tls(rustls::Error),
+
+    /// Failed to do a TLS handshake with a shard.
+    #[error("Failed to do a TLS handshake with a shard: {0}")]
+    TlsHandshake(std::io::Error),
+
     /// Failed to communicate with a shard.
     #[error("Failed to communicate with a shard: {0}")]
     CommunicateWithShard(std::io::Error),
@@ -73,6 +85,11 @@ pub enum Error {
     #[error("Failed to set timeout on a socket: {0}")]
     SetTimeout(glommio::GlommioError<()>),
 
+    /// Got a response that is missing a field, or has a field of an
+    /// unexpected type.
+    #[error("Got an unexpected response, bad field: {0}")]
+    UnexpectedResponse(String),
+
     /// Server error.
     #[error("Server error ({0}): {1}")]
     ServerErr(String, String),
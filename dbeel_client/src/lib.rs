@@ -1,7 +1,11 @@
 pub mod error;
 
+pub use rustls;
+
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet, VecDeque},
+    fs::File,
+    io::BufReader,
     net::{SocketAddr, ToSocketAddrs},
     sync::Arc,
     time::Duration,
@@ -9,14 +13,25 @@ use std::{
 
 use async_rwlock::RwLock;
 use dbeel::{
-    shards::{hash_bytes, hash_string, ClusterMetadata, CollectionMetadata},
+    auth::Permission,
+    metrics::{
+        merge_collection_summaries, CollectionShardStats, CollectionSummary,
+        NodeStatus, ShardStats,
+    },
+    shards::{
+        hash_bytes, hash_string, ClusterMetadata, CollectionMetadata,
+        ConflictResolution,
+    },
     tasks::db_server::{ResponseError, ResponseType},
 };
 use error::VecError;
+use futures::stream::{self, select_all, Stream, StreamExt, TryStreamExt};
 use rmp_serde::from_slice;
 use rmpv::{
     decode::read_value, encode::write_value, Integer, Utf8String, Value,
 };
+use rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+use serde::{Deserialize, Serialize};
 
 use crate::error::{Error, Result};
 
@@ -27,20 +42,37 @@ use tokio::{
     time::timeout,
 };
 
+#[cfg(feature = "tokio")]
+use tokio_rustls::TlsConnector;
+
 #[cfg(feature = "glommio")]
 use glommio::net::TcpStream;
 
 #[cfg(feature = "glommio")]
 use futures_lite::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+#[cfg(feature = "glommio")]
+use futures_rustls::TlsConnector;
+
 const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
 const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
 const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
 
+trait ConnectionStream: AsyncRead + AsyncWrite + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Unpin> ConnectionStream for T {}
+
+/// A connection kept open to stream responses from a shard.
+#[cfg(feature = "tokio")]
+type Connection = Box<dyn ConnectionStream + Send>;
+
+#[cfg(feature = "glommio")]
+type Connection = Box<dyn ConnectionStream>;
+
 #[derive(Debug, Clone)]
 struct Shard {
     hash: u32,
     address: SocketAddr,
+    name: String,
     node_name: String,
 }
 
@@ -51,6 +83,14 @@ pub struct DbeelClient {
     connect_timeout: Duration,
     read_timeout: Duration,
     write_timeout: Duration,
+
+    /// The encoded auth request sent before every request, when the client
+    /// was created with credentials.
+    auth_request: Option<Vec<u8>>,
+
+    /// Connect to shards over TLS when set, the shard certificates must be
+    /// valid for the shard IP addresses.
+    tls_config: Option<Arc<ClientConfig>>,
 }
 
 #[derive(Debug, Clone)]
@@ -70,6 +110,286 @@ fn to_utf8string<S: Into<Utf8String>>(
     Ok(utf8)
 }
 
+/// Create a TLS config that trusts the PEM certificates in the given file
+/// (e.g. the CA the servers' certificates are signed with, or the
+/// self-signed certificate itself).
+pub fn tls_config_from_ca_cert(path: &str) -> Result<Arc<ClientConfig>> {
+    let file = File::open(path).map_err(Error::ReadCertificates)?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(Error::ReadCertificates)?;
+
+    let mut roots = RootCertStore::empty();
+    for cert in certs {
+        roots.add(&Certificate(cert)).map_err(Error::Tls)?;
+    }
+
+    Ok(Arc::new(
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    ))
+}
+
+/// A write to a collection, streamed from the change log of a shard.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    /// The name of the shard the change was streamed from.
+    pub shard: String,
+
+    /// The position of the change in the change log of the shard.
+    pub sequence: u64,
+
+    pub key: Value,
+
+    /// None when the key was deleted.
+    pub value: Option<Value>,
+
+    /// The time of the write, in nanoseconds since the unix epoch.
+    pub timestamp: u64,
+}
+
+/// The position to resume streaming changes from, in the change log of each
+/// shard, only new changes are streamed from shards without a position.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeCursor(HashMap<String, u64>);
+
+impl ChangeCursor {
+    /// Move the cursor past a change, call after a change is handled to
+    /// resume from the next change.
+    pub fn advance(&mut self, change: &Change) {
+        self.0.insert(change.shard.clone(), change.sequence + 1);
+    }
+}
+
+fn decode_changes(shard: &str, response: &[u8]) -> Result<Vec<Change>> {
+    let response = read_value(&mut &response[..])?;
+    let bad_response = || Error::UnexpectedResponse("changes".to_string());
+
+    response["changes"]
+        .as_array()
+        .ok_or_else(bad_response)?
+        .iter()
+        .map(|change| match change.as_array().map(Vec::as_slice) {
+            Some([sequence, timestamp, key, value @ ..])
+                if value.len() <= 1 =>
+            {
+                Ok(Change {
+                    shard: shard.to_string(),
+                    sequence: sequence.as_u64().ok_or_else(bad_response)?,
+                    key: key.clone(),
+                    value: value.first().cloned(),
+                    timestamp: timestamp.as_u64().ok_or_else(bad_response)?,
+                })
+            }
+            _ => Err(bad_response()),
+        })
+        .collect()
+}
+
+/// A write to a watched key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchEvent {
+    pub key: Value,
+
+    /// None when the key was deleted.
+    pub value: Option<Value>,
+
+    /// The time of the write, in nanoseconds since the unix epoch.
+    pub timestamp: u64,
+}
+
+fn decode_watch_events(response: &[u8]) -> Result<Vec<WatchEvent>> {
+    let response = read_value(&mut &response[..])?;
+    let bad_response = || Error::UnexpectedResponse("events".to_string());
+
+    response["events"]
+        .as_array()
+        .ok_or_else(bad_response)?
+        .iter()
+        .map(|event| match event.as_array().map(Vec::as_slice) {
+            Some([timestamp, key, value @ ..]) if value.len() <= 1 => {
+                Ok(WatchEvent {
+                    key: key.clone(),
+                    value: value.first().cloned(),
+                    timestamp: timestamp.as_u64().ok_or_else(bad_response)?,
+                })
+            }
+            _ => Err(bad_response()),
+        })
+        .collect()
+}
+
+/// Decode a batch of dumped documents, None at the end of the dump.
+fn decode_documents(response: &[u8]) -> Result<Option<Vec<(Value, Value)>>> {
+    let response = read_value(&mut &response[..])?;
+    if response["done"].as_bool() == Some(true) {
+        return Ok(None);
+    }
+
+    let bad_response = || Error::UnexpectedResponse("documents".to_string());
+    response["documents"]
+        .as_array()
+        .ok_or_else(bad_response)?
+        .iter()
+        .map(|document| match document.as_array().map(Vec::as_slice) {
+            Some([key, value]) => Ok((key.clone(), value.clone())),
+            _ => Err(bad_response()),
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(Some)
+}
+
+/// The values of a key in a collection with siblings, see
+/// `Collection::get_siblings()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Siblings {
+    /// The values of all concurrent writes to the key.
+    pub values: Vec<Value>,
+
+    /// The causal context of the values, pass it to the next write to the
+    /// key to replace them.
+    pub context: Vec<u8>,
+}
+
+fn decode_siblings(response: &Value) -> Result<Siblings> {
+    let bad_response = || Error::UnexpectedResponse("siblings".to_string());
+
+    Ok(Siblings {
+        values: response["values"]
+            .as_array()
+            .ok_or_else(bad_response)?
+            .clone(),
+        context: response["context"]
+            .as_slice()
+            .ok_or_else(bad_response)?
+            .to_vec(),
+    })
+}
+
+/// A condition on the current value of a key, see `TransactionOperation`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    Exists,
+    NotExists,
+    Equals(Value),
+}
+
+/// An operation of a transaction, see `Collection::transaction()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransactionOperation {
+    op: &'static str,
+    key: Value,
+    value: Option<Value>,
+    condition: Option<Condition>,
+}
+
+impl TransactionOperation {
+    #[must_use]
+    pub fn set(key: Value, value: Value) -> Self {
+        Self {
+            op: "set",
+            key,
+            value: Some(value),
+            condition: None,
+        }
+    }
+
+    #[must_use]
+    pub fn delete(key: Value) -> Self {
+        Self {
+            op: "delete",
+            key,
+            value: None,
+            condition: None,
+        }
+    }
+
+    /// Only check a condition on a key, without writing to it.
+    #[must_use]
+    pub fn check(key: Value, condition: Condition) -> Self {
+        Self {
+            op: "check",
+            key,
+            value: None,
+            condition: Some(condition),
+        }
+    }
+
+    /// Fail the whole transaction when the condition doesn't pass.
+    #[must_use]
+    pub fn when(mut self, condition: Condition) -> Self {
+        self.condition = Some(condition);
+        self
+    }
+
+    fn encode(self) -> Vec<(Value, Value)> {
+        let mut items = vec![
+            (Value::String("op".into()), Value::String(self.op.into())),
+            (Value::String("key".into()), self.key),
+        ];
+        if let Some(value) = self.value {
+            items.push((Value::String("value".into()), value));
+        }
+        match self.condition {
+            Some(Condition::Exists) => items.push((
+                Value::String("condition".into()),
+                Value::String("exists".into()),
+            )),
+            Some(Condition::NotExists) => items.push((
+                Value::String("condition".into()),
+                Value::String("not_exists".into()),
+            )),
+            Some(Condition::Equals(expected)) => {
+                items.push((
+                    Value::String("condition".into()),
+                    Value::String("equals".into()),
+                ));
+                items.push((Value::String("expected".into()), expected));
+            }
+            None => {}
+        }
+        items
+    }
+}
+
+/// Stream the items of the batches pushed on a long-lived connection to a
+/// shard (see DbeelClient::open_stream()), the stream ends after the first
+/// error, or when decoding a batch returns None (the shard ended the
+/// stream).
+fn batch_stream<T, F>(
+    connection: Connection,
+    read_timeout: Duration,
+    decode: F,
+) -> impl Stream<Item = Result<T>>
+where
+    F: Fn(&[u8]) -> Result<Option<Vec<T>>>,
+{
+    stream::unfold(
+        Some((connection, decode, VecDeque::new())),
+        move |state| async move {
+            let (mut connection, decode, mut items) = state?;
+            loop {
+                if let Some(item) = items.pop_front() {
+                    return Some((Ok(item), Some((connection, decode, items))));
+                }
+
+                // An empty batch is a heartbeat.
+                let batch =
+                    DbeelClient::read_response(&mut connection, read_timeout)
+                        .await
+                        .and_then(DbeelClient::response_to_result)
+                        .and_then(|response| decode(&response));
+                match batch {
+                    Ok(Some(batch)) => items.extend(batch),
+                    Ok(None) => return None,
+                    Err(e) => return Some((Err(e), None)),
+                }
+            }
+        },
+    )
+}
+
 fn hash_key(key: &Value) -> Result<u32> {
     let mut buf: Vec<u8> = Vec::new();
     write_value(&mut buf, key)?;
@@ -86,6 +406,50 @@ impl DbeelClient {
     where
         A: ToSocketAddrs,
     {
+        Self::from_seed_nodes_ex(addresses, None, None).await
+    }
+
+    /// Create a client that authenticates with a (username, password) on
+    /// every request, required when the server runs with auth enabled.
+    pub async fn from_seed_nodes_with_credentials<A>(
+        addresses: &[A],
+        credentials: Option<(&str, &str)>,
+    ) -> Result<Self>
+    where
+        A: ToSocketAddrs,
+    {
+        Self::from_seed_nodes_ex(addresses, credentials, None).await
+    }
+
+    /// Create a client with optional credentials, that connects over TLS
+    /// when given a TLS config (see tls_config_from_ca_cert()).
+    pub async fn from_seed_nodes_ex<A>(
+        addresses: &[A],
+        credentials: Option<(&str, &str)>,
+        tls_config: Option<Arc<ClientConfig>>,
+    ) -> Result<Self>
+    where
+        A: ToSocketAddrs,
+    {
+        let auth_request = if let Some((username, password)) = credentials {
+            let request = Value::Map(vec![
+                (Value::String("type".into()), Value::String("auth".into())),
+                (
+                    Value::String("username".into()),
+                    Value::String(username.into()),
+                ),
+                (
+                    Value::String("password".into()),
+                    Value::String(password.into()),
+                ),
+            ]);
+            let mut encoded: Vec<u8> = Vec::new();
+            write_value(&mut encoded, &request)?;
+            Some(encoded)
+        } else {
+            None
+        };
+
         let mut seed_addresses = vec![];
         for address in addresses {
             match address.to_socket_addrs() {
@@ -100,6 +464,8 @@ impl DbeelClient {
             connect_timeout: DEFAULT_CONNECT_TIMEOUT,
             read_timeout: DEFAULT_READ_TIMEOUT,
             write_timeout: DEFAULT_WRITE_TIMEOUT,
+            auth_request,
+            tls_config,
         };
 
         this.sync_hash_ring().await?;
@@ -108,21 +474,7 @@ impl DbeelClient {
     }
 
     async fn sync_hash_ring(&self) -> Result<()> {
-        let request = Value::Map(vec![(
-            Value::String("type".into()),
-            Value::String("get_cluster_metadata".into()),
-        )]);
-
-        let buf = Self::send_request_ex(
-            &self.seed_shards,
-            request,
-            self.connect_timeout,
-            self.read_timeout,
-            self.write_timeout,
-        )
-        .await?;
-
-        let metadata: ClusterMetadata = from_slice(&buf)?;
+        let metadata = self.get_cluster_metadata().await?;
 
         let mut hash_ring = Vec::new();
         for node in metadata.nodes {
@@ -139,6 +491,7 @@ impl DbeelClient {
                 hash_ring.push(Shard {
                     hash,
                     address,
+                    name: shard_name,
                     node_name: node.name.clone(),
                 });
             }
@@ -230,10 +583,142 @@ impl DbeelClient {
             .map_err(|_| Error::CommunicateWithShardTimeout)?
     }
 
+    fn is_error_response(response: &[u8]) -> bool {
+        response.last() == Some(ResponseType::Err.into()).as_ref()
+    }
+
+    /// Strip the response type, converting error responses to errors.
+    fn response_to_result(mut response: Vec<u8>) -> Result<Vec<u8>> {
+        let is_error = Self::is_error_response(&response);
+        response.pop();
+        if is_error {
+            let err: ResponseError = from_slice(&response)?;
+            return Err(Error::ServerErr(err.name, err.message));
+        }
+        Ok(response)
+    }
+
+    #[cfg(feature = "tokio")]
+    async fn read_response(
+        connection: &mut Connection,
+        read_timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        timeout(read_timeout, Self::stream_read_buffer(connection))
+            .await
+            .map_err(|_| Error::CommunicateWithShardTimeout)?
+    }
+
+    #[cfg(feature = "glommio")]
+    async fn read_response(
+        connection: &mut Connection,
+        _read_timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        // The read timeout is set on the socket.
+        Self::stream_read_buffer(connection).await
+    }
+
+    #[cfg(feature = "tokio")]
+    async fn connect(&self, address: &SocketAddr) -> Result<Connection> {
+        let stream = timeout(self.connect_timeout, TcpStream::connect(address))
+            .await
+            .map_err(|_| Error::CommunicateWithShardTimeout)?
+            .map_err(Error::ConnectToShard)?;
+
+        match &self.tls_config {
+            Some(tls_config) => {
+                let connect = TlsConnector::from(tls_config.clone())
+                    .connect(ServerName::IpAddress(address.ip()), stream);
+                let stream = timeout(self.connect_timeout, connect)
+                    .await
+                    .map_err(|_| Error::CommunicateWithShardTimeout)?
+                    .map_err(Error::TlsHandshake)?;
+                Ok(Box::new(stream))
+            }
+            None => Ok(Box::new(stream)),
+        }
+    }
+
+    #[cfg(feature = "glommio")]
+    async fn connect(&self, address: &SocketAddr) -> Result<Connection> {
+        let stream = TcpStream::connect_timeout(address, self.connect_timeout)
+            .await
+            .map_err(Error::ConnectToShard)?;
+
+        stream
+            .set_read_timeout(Some(self.read_timeout))
+            .map_err(Error::SetTimeout)?;
+        stream
+            .set_write_timeout(Some(self.write_timeout))
+            .map_err(Error::SetTimeout)?;
+
+        match &self.tls_config {
+            Some(tls_config) => {
+                let stream = TlsConnector::from(tls_config.clone())
+                    .connect(ServerName::IpAddress(address.ip()), stream)
+                    .await
+                    .map_err(Error::TlsHandshake)?;
+                Ok(Box::new(stream))
+            }
+            None => Ok(Box::new(stream)),
+        }
+    }
+
+    /// Send a long-lived request (e.g. "subscribe") to a shard, returns the
+    /// connection the shard pushes batches on, see batch_stream().
+    async fn open_stream(
+        &self,
+        address: &SocketAddr,
+        request: &Value,
+    ) -> Result<Connection> {
+        let mut encoded: Vec<u8> = Vec::new();
+        write_value(&mut encoded, request)?;
+
+        let mut connection = self.connect(address).await?;
+        if let Some(auth_request) = &self.auth_request {
+            Self::stream_write_buffer(&mut connection, auth_request).await?;
+            Self::response_to_result(
+                Self::read_response(&mut connection, self.read_timeout).await?,
+            )?;
+        }
+        Self::stream_write_buffer(&mut connection, &encoded).await?;
+
+        // Wait for the request to be acknowledged with an empty batch.
+        Self::response_to_result(
+            Self::read_response(&mut connection, self.read_timeout).await?,
+        )?;
+
+        Ok(connection)
+    }
+
+    #[cfg(feature = "glommio")]
+    async fn send_buffer_to_stream(
+        stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+        data: &[u8],
+        auth_data: Option<&[u8]>,
+    ) -> Result<Vec<u8>> {
+        if let Some(auth_data) = auth_data {
+            Self::stream_write_buffer(stream, auth_data).await?;
+            let auth_response = Self::stream_read_buffer(stream).await?;
+            if Self::is_error_response(&auth_response) {
+                let _ = stream.close().await;
+                return Ok(auth_response);
+            }
+        }
+
+        Self::stream_write_buffer(stream, data).await?;
+        let response_result = Self::stream_read_buffer(stream).await?;
+
+        let _ = stream.close().await;
+
+        Ok(response_result)
+    }
+
     #[cfg(feature = "glommio")]
     async fn send_buffer_to_address(
         address: &SocketAddr,
         data: &[u8],
+        auth_data: Option<&[u8]>,
+        tls_config: Option<&Arc<ClientConfig>>,
         connect_timeout: Duration,
         read_timeout: Duration,
         write_timeout: Duration,
@@ -249,18 +734,47 @@ impl DbeelClient {
             .set_write_timeout(Some(write_timeout))
             .map_err(Error::SetTimeout)?;
 
-        Self::stream_write_buffer(&mut stream, data).await?;
-        let response_result = Self::stream_read_buffer(&mut stream).await?;
+        if let Some(tls_config) = tls_config {
+            let mut stream = TlsConnector::from(tls_config.clone())
+                .connect(ServerName::IpAddress(address.ip()), stream)
+                .await
+                .map_err(Error::TlsHandshake)?;
+            Self::send_buffer_to_stream(&mut stream, data, auth_data).await
+        } else {
+            Self::send_buffer_to_stream(&mut stream, data, auth_data).await
+        }
+    }
 
-        let _ = stream.close().await;
+    #[cfg(feature = "tokio")]
+    async fn send_buffer_to_stream(
+        stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+        data: &[u8],
+        auth_data: Option<&[u8]>,
+        read_timeout: Duration,
+        write_timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        if let Some(auth_data) = auth_data {
+            let auth_response = Self::send_buffer(
+                stream,
+                auth_data,
+                read_timeout,
+                write_timeout,
+            )
+            .await?;
+            if Self::is_error_response(&auth_response) {
+                return Ok(auth_response);
+            }
+        }
 
-        Ok(response_result)
+        Self::send_buffer(stream, data, read_timeout, write_timeout).await
     }
 
     #[cfg(feature = "tokio")]
     async fn send_buffer_to_address(
         address: &SocketAddr,
         data: &[u8],
+        auth_data: Option<&[u8]>,
+        tls_config: Option<&Arc<ClientConfig>>,
         connect_timeout: Duration,
         read_timeout: Duration,
         write_timeout: Duration,
@@ -269,7 +783,32 @@ impl DbeelClient {
             .await
             .map_err(|_| Error::CommunicateWithShardTimeout)?
             .map_err(Error::ConnectToShard)?;
-        Self::send_buffer(&mut stream, data, read_timeout, write_timeout).await
+
+        if let Some(tls_config) = tls_config {
+            let connect = TlsConnector::from(tls_config.clone())
+                .connect(ServerName::IpAddress(address.ip()), stream);
+            let mut stream = timeout(connect_timeout, connect)
+                .await
+                .map_err(|_| Error::CommunicateWithShardTimeout)?
+                .map_err(Error::TlsHandshake)?;
+            Self::send_buffer_to_stream(
+                &mut stream,
+                data,
+                auth_data,
+                read_timeout,
+                write_timeout,
+            )
+            .await
+        } else {
+            Self::send_buffer_to_stream(
+                &mut stream,
+                data,
+                auth_data,
+                read_timeout,
+                write_timeout,
+            )
+            .await
+        }
     }
 
     async fn send_request(
@@ -280,6 +819,8 @@ impl DbeelClient {
         Self::send_request_ex(
             addresses,
             request,
+            self.auth_request.as_deref(),
+            self.tls_config.as_ref(),
             self.connect_timeout,
             self.read_timeout,
             self.write_timeout,
@@ -290,6 +831,8 @@ impl DbeelClient {
     async fn send_request_ex(
         addresses: &[SocketAddr],
         request: Value,
+        auth_data: Option<&[u8]>,
+        tls_config: Option<&Arc<ClientConfig>>,
         connect_timeout: Duration,
         read_timeout: Duration,
         write_timeout: Duration,
@@ -306,6 +849,8 @@ impl DbeelClient {
             let response_result = Self::send_buffer_to_address(
                 address,
                 &data_encoded,
+                auth_data,
+                tls_config,
                 connect_timeout,
                 read_timeout,
                 write_timeout,
@@ -313,8 +858,7 @@ impl DbeelClient {
             .await;
             match response_result {
                 Ok(mut response_encoded)
-                    if response_encoded.last()
-                        != Some(ResponseType::Err.into()).as_ref() =>
+                    if !Self::is_error_response(&response_encoded) =>
                 {
                     response_encoded.pop().unwrap();
                     return Ok(response_encoded);
@@ -416,10 +960,16 @@ impl DbeelClient {
         }
     }
 
-    pub async fn create_collection_with_replication(
+    /// Create a collection with a conflict resolution, with
+    /// `ConflictResolution::Siblings` concurrent writes to a key are all kept
+    /// (see `Collection::get_siblings()`), with `Counter`, `Set` and `Map`
+    /// values are CRDTs (see `Collection::increment()`, `Collection::add()`
+    /// and `Collection::put_field()`).
+    pub async fn create_collection_ex(
         &self,
         name: &str,
         replication_factor: u16,
+        conflict_resolution: ConflictResolution,
     ) -> Result<Collection> {
         let request = Value::Map(vec![
             (
@@ -431,78 +981,362 @@ impl DbeelClient {
                 Value::String("replication_factor".into()),
                 Value::Integer(replication_factor.into()),
             ),
+            (
+                Value::String("conflict_resolution".into()),
+                Value::String(conflict_resolution.as_str().into()),
+            ),
         ]);
-        self.send_request(&self.seed_shards, request).await?;
+        let response = self.send_request(&self.seed_shards, request).await?;
 
         Ok(Collection {
             client: self.clone(),
             name: name.into(),
-            metadata: CollectionMetadata { replication_factor },
+            metadata: from_slice(&response)?,
         })
     }
 
+    pub async fn create_collection_with_replication(
+        &self,
+        name: &str,
+        replication_factor: u16,
+    ) -> Result<Collection> {
+        self.create_collection_ex(
+            name,
+            replication_factor,
+            ConflictResolution::LastWriteWins,
+        )
+        .await
+    }
+
     pub async fn create_collection(&self, name: &str) -> Result<Collection> {
         self.create_collection_with_replication(name, 1).await
     }
 
-    pub(crate) async fn drop_collection<S: Into<Utf8String>>(
+    /// Create a user with permissions on collections (a collection name of
+    /// "*" means all collections), requires admin permission on "*".
+    pub async fn create_user(
         &self,
-        name: S,
+        username: &str,
+        password: &str,
+        permissions: &[(&str, Permission)],
     ) -> Result<()> {
-        let name = to_utf8string(name)?;
         let request = Value::Map(vec![
             (
                 Value::String("type".into()),
-                Value::String("drop_collection".into()),
+                Value::String("create_user".into()),
+            ),
+            (
+                Value::String("username".into()),
+                Value::String(username.into()),
+            ),
+            (
+                Value::String("password".into()),
+                Value::String(password.into()),
+            ),
+            (
+                Value::String("permissions".into()),
+                Value::Map(
+                    permissions
+                        .iter()
+                        .map(|(collection, permission)| {
+                            (
+                                Value::String((*collection).into()),
+                                Value::String(permission.as_str().into()),
+                            )
+                        })
+                        .collect(),
+                ),
             ),
-            (Value::String("name".into()), Value::String(name.clone())),
         ]);
         self.send_request(&self.seed_shards, request).await?;
         Ok(())
     }
-}
-
-pub enum Consistency {
-    Fixed(u16),
-    Quorum,
-    All,
-}
 
-impl Consistency {
-    fn to_int(&self, replication_factor: u16) -> Integer {
-        match self {
-            Consistency::Fixed(x) => *x,
-            Consistency::Quorum => replication_factor / 2 + 1,
-            Consistency::All => replication_factor,
-        }
-        .into()
+    pub async fn drop_user(&self, username: &str) -> Result<()> {
+        let request = Value::Map(vec![
+            (
+                Value::String("type".into()),
+                Value::String("drop_user".into()),
+            ),
+            (
+                Value::String("username".into()),
+                Value::String(username.into()),
+            ),
+        ]);
+        self.send_request(&self.seed_shards, request).await?;
+        Ok(())
     }
-}
 
-impl Collection {
-    pub async fn get_consistent(
+    pub async fn grant(
         &self,
-        key: Value,
-        consistency: Consistency,
-    ) -> Result<Value> {
-        let hash = hash_key(&key)?;
+        username: &str,
+        collection: &str,
+        permission: Permission,
+    ) -> Result<()> {
         let request = Value::Map(vec![
-            (Value::String("type".into()), Value::String("get".into())),
-            (Value::String("key".into()), key),
-            (Value::String("hash".into()), hash.into()),
+            (Value::String("type".into()), Value::String("grant".into())),
+            (
+                Value::String("username".into()),
+                Value::String(username.into()),
+            ),
             (
                 Value::String("collection".into()),
-                Value::String(self.name.clone()),
+                Value::String(collection.into()),
             ),
             (
-                Value::String("consistency".into()),
-                Value::Integer(
-                    consistency.to_int(self.metadata.replication_factor),
-                ),
+                Value::String("permission".into()),
+                Value::String(permission.as_str().into()),
             ),
         ]);
-        let response_buffer = self
-            .client
+        self.send_request(&self.seed_shards, request).await?;
+        Ok(())
+    }
+
+    pub async fn revoke(&self, username: &str, collection: &str) -> Result<()> {
+        let request = Value::Map(vec![
+            (Value::String("type".into()), Value::String("revoke".into())),
+            (
+                Value::String("username".into()),
+                Value::String(username.into()),
+            ),
+            (
+                Value::String("collection".into()),
+                Value::String(collection.into()),
+            ),
+        ]);
+        self.send_request(&self.seed_shards, request).await?;
+        Ok(())
+    }
+
+    pub async fn get_cluster_metadata(&self) -> Result<ClusterMetadata> {
+        let request = Value::Map(vec![(
+            Value::String("type".into()),
+            Value::String("get_cluster_metadata".into()),
+        )]);
+        let response = self.send_request(&self.seed_shards, request).await?;
+        Ok(from_slice(&response)?)
+    }
+
+    /// The status of the first seed node that responds, requires admin
+    /// permission on "*".
+    pub async fn node_status(&self) -> Result<NodeStatus> {
+        let request = Value::Map(vec![(
+            Value::String("type".into()),
+            Value::String("node_status".into()),
+        )]);
+        let response = self.send_request(&self.seed_shards, request).await?;
+        Ok(from_slice(&response)?)
+    }
+
+    /// The addresses of the shards of each node.
+    async fn node_addresses(&self) -> HashMap<String, Vec<SocketAddr>> {
+        let mut node_addresses: HashMap<String, Vec<SocketAddr>> =
+            HashMap::new();
+        for shard in self.hash_ring.read().await.iter() {
+            node_addresses
+                .entry(shard.node_name.clone())
+                .or_default()
+                .push(shard.address);
+        }
+        node_addresses
+    }
+
+    /// List the collections the user can read, with their approximate number
+    /// of documents and size on disk summed up across all nodes.
+    pub async fn list_collections(&self) -> Result<Vec<CollectionSummary>> {
+        let node_addresses = self.node_addresses().await;
+        let request = Value::Map(vec![(
+            Value::String("type".into()),
+            Value::String("list_collections".into()),
+        )]);
+
+        // Each node responds with the summaries of all of its shards.
+        let mut summaries = Vec::new();
+        for addresses in node_addresses.values() {
+            let response =
+                self.send_request(addresses, request.clone()).await?;
+            merge_collection_summaries(&mut summaries, from_slice(&response)?);
+        }
+
+        // Every document is stored in replication factor nodes.
+        let nodes = node_addresses.len().max(1) as u64;
+        for summary in &mut summaries {
+            let replicas =
+                u64::from(summary.metadata.replication_factor).clamp(1, nodes);
+            summary.approximate_documents /= replicas;
+        }
+
+        summaries.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        Ok(summaries)
+    }
+
+    /// The stats of all shards of the first seed node that responds, requires
+    /// admin permission on "*".
+    pub async fn shard_stats(&self) -> Result<Vec<ShardStats>> {
+        let request = Value::Map(vec![(
+            Value::String("type".into()),
+            Value::String("shard_stats".into()),
+        )]);
+        let response = self.send_request(&self.seed_shards, request).await?;
+        Ok(from_slice(&response)?)
+    }
+
+    /// Send a request on a collection (e.g. "flush") to the first seed node
+    /// that responds.
+    async fn send_collection_admin_request(
+        &self,
+        request_type: &str,
+        name: Utf8String,
+    ) -> Result<Vec<u8>> {
+        let request = Value::Map(vec![
+            (
+                Value::String("type".into()),
+                Value::String(request_type.into()),
+            ),
+            (Value::String("name".into()), Value::String(name)),
+        ]);
+        self.send_request(&self.seed_shards, request).await
+    }
+
+    /// Scan a page of the documents in the primary range of a shard, returns
+    /// the documents and the key to continue the scan from.
+    async fn scan_shard(
+        &self,
+        address: SocketAddr,
+        collection: Utf8String,
+        after: Option<Value>,
+        limit: u64,
+    ) -> Result<(Vec<(Value, Value)>, Option<Value>)> {
+        let mut request = vec![
+            (Value::String("type".into()), Value::String("scan".into())),
+            (
+                Value::String("collection".into()),
+                Value::String(collection),
+            ),
+            (Value::String("limit".into()), Value::Integer(limit.into())),
+        ];
+        if let Some(after) = after {
+            request.push((Value::String("after".into()), after));
+        }
+        let response =
+            self.send_request(&[address], Value::Map(request)).await?;
+        let response = read_value(&mut &response[..])?;
+
+        let documents = response["documents"]
+            .as_array()
+            .ok_or_else(|| Error::UnexpectedResponse("documents".to_string()))?
+            .iter()
+            .map(|document| match document.as_array().map(Vec::as_slice) {
+                Some([key, value]) => Ok((key.clone(), value.clone())),
+                _ => Err(Error::UnexpectedResponse("documents".to_string())),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let next = match &response["next"] {
+            Value::Nil => None,
+            next => Some(next.clone()),
+        };
+
+        Ok((documents, next))
+    }
+
+    /// Alter the given fields of a collection's metadata, the rest are kept.
+    pub(crate) async fn alter_collection<S: Into<Utf8String>>(
+        &self,
+        name: S,
+        fields: Vec<(&str, Value)>,
+    ) -> Result<CollectionMetadata> {
+        let name = to_utf8string(name)?;
+        let mut items = vec![
+            (
+                Value::String("type".into()),
+                Value::String("alter_collection".into()),
+            ),
+            (Value::String("name".into()), Value::String(name)),
+        ];
+        items.extend(
+            fields
+                .into_iter()
+                .map(|(field, value)| (Value::String(field.into()), value)),
+        );
+        let request = Value::Map(items);
+        let response = self.send_request(&self.seed_shards, request).await?;
+        Ok(from_slice(&response)?)
+    }
+
+    pub(crate) async fn drop_collection<S: Into<Utf8String>>(
+        &self,
+        name: S,
+    ) -> Result<()> {
+        let name = to_utf8string(name)?;
+        let request = Value::Map(vec![
+            (
+                Value::String("type".into()),
+                Value::String("drop_collection".into()),
+            ),
+            (Value::String("name".into()), Value::String(name.clone())),
+        ]);
+        self.send_request(&self.seed_shards, request).await?;
+        Ok(())
+    }
+
+    pub(crate) async fn truncate_collection<S: Into<Utf8String>>(
+        &self,
+        name: S,
+    ) -> Result<()> {
+        let name = to_utf8string(name)?;
+        let request = Value::Map(vec![
+            (
+                Value::String("type".into()),
+                Value::String("truncate_collection".into()),
+            ),
+            (Value::String("name".into()), Value::String(name)),
+        ]);
+        self.send_request(&self.seed_shards, request).await?;
+        Ok(())
+    }
+}
+
+pub enum Consistency {
+    Fixed(u16),
+    Quorum,
+    All,
+}
+
+impl Consistency {
+    fn to_int(&self, replication_factor: u16) -> Integer {
+        match self {
+            Consistency::Fixed(x) => *x,
+            Consistency::Quorum => replication_factor / 2 + 1,
+            Consistency::All => replication_factor,
+        }
+        .into()
+    }
+}
+
+impl Collection {
+    pub async fn get_consistent(
+        &self,
+        key: Value,
+        consistency: Consistency,
+    ) -> Result<Value> {
+        let hash = hash_key(&key)?;
+        let request = Value::Map(vec![
+            (Value::String("type".into()), Value::String("get".into())),
+            (Value::String("key".into()), key),
+            (Value::String("hash".into()), hash.into()),
+            (
+                Value::String("collection".into()),
+                Value::String(self.name.clone()),
+            ),
+            (
+                Value::String("consistency".into()),
+                Value::Integer(
+                    consistency.to_int(self.metadata.replication_factor),
+                ),
+            ),
+        ]);
+        let response_buffer = self
+            .client
             .send_sharded_request(
                 hash,
                 request,
@@ -523,18 +1357,39 @@ impl Collection {
         self.get(Value::String(key.into())).await
     }
 
-    pub async fn set_consistent(
+    /// Get all values of a key in a collection with siblings, when there
+    /// were concurrent writes to the key, all of their values are returned.
+    /// Pass the returned context to the next write to the key to replace
+    /// the values.
+    pub async fn get_siblings_consistent(
         &self,
         key: Value,
-        value: Value,
+        consistency: Consistency,
+    ) -> Result<Siblings> {
+        decode_siblings(&self.get_consistent(key, consistency).await?)
+    }
+
+    pub async fn get_siblings(&self, key: Value) -> Result<Siblings> {
+        self.get_siblings_consistent(key, Consistency::Fixed(1))
+            .await
+    }
+
+    /// Send a write request of a key, with the request specific fields.
+    async fn write(
+        &self,
+        request_type: &str,
+        key: Value,
+        fields: Vec<(&str, Value)>,
         consistency: Consistency,
     ) -> Result<Value> {
         let hash = hash_key(&key)?;
-        let request = Value::Map(vec![
-            (Value::String("type".into()), Value::String("set".into())),
+        let mut items = vec![
+            (
+                Value::String("type".into()),
+                Value::String(request_type.into()),
+            ),
             (Value::String("key".into()), key),
             (Value::String("hash".into()), hash.into()),
-            (Value::String("value".into()), value),
             (
                 Value::String("collection".into()),
                 Value::String(self.name.clone()),
@@ -545,19 +1400,34 @@ impl Collection {
                     consistency.to_int(self.metadata.replication_factor),
                 ),
             ),
-        ]);
+        ];
+        items.extend(
+            fields
+                .into_iter()
+                .map(|(name, value)| (Value::String(name.into()), value)),
+        );
 
         let response_buffer = self
             .client
             .send_sharded_request(
                 hash,
-                request,
+                Value::Map(items),
                 self.metadata.replication_factor,
             )
             .await?;
         Ok(read_value(&mut &response_buffer[..])?)
     }
 
+    pub async fn set_consistent(
+        &self,
+        key: Value,
+        value: Value,
+        consistency: Consistency,
+    ) -> Result<Value> {
+        self.write("set", key, vec![("value", value)], consistency)
+            .await
+    }
+
     pub async fn set(&self, key: Value, value: Value) -> Result<Value> {
         self.set_consistent(key, value, Consistency::Fixed(1)).await
     }
@@ -570,16 +1440,259 @@ impl Collection {
         self.set(Value::String(key.into()), value).await
     }
 
+    /// Set a key in a collection with siblings, replacing the values of the
+    /// context (returned from `get_siblings()`), values written concurrently
+    /// are kept as siblings.
+    pub async fn set_with_context_consistent(
+        &self,
+        key: Value,
+        value: Value,
+        context: Vec<u8>,
+        consistency: Consistency,
+    ) -> Result<Value> {
+        self.write(
+            "set",
+            key,
+            vec![("value", value), ("context", Value::Binary(context))],
+            consistency,
+        )
+        .await
+    }
+
+    pub async fn set_with_context(
+        &self,
+        key: Value,
+        value: Value,
+        context: Vec<u8>,
+    ) -> Result<Value> {
+        self.set_with_context_consistent(
+            key,
+            value,
+            context,
+            Consistency::Fixed(1),
+        )
+        .await
+    }
+
     pub async fn delete_consistent(
         &self,
         key: Value,
         consistency: Consistency,
     ) -> Result<Value> {
-        let hash = hash_key(&key)?;
+        self.write("delete", key, vec![], consistency).await
+    }
+
+    /// Delete the values of the context (returned from `get_siblings()`) of
+    /// a key in a collection with siblings.
+    pub async fn delete_with_context_consistent(
+        &self,
+        key: Value,
+        context: Vec<u8>,
+        consistency: Consistency,
+    ) -> Result<Value> {
+        self.write(
+            "delete",
+            key,
+            vec![("context", Value::Binary(context))],
+            consistency,
+        )
+        .await
+    }
+
+    pub async fn delete_with_context(
+        &self,
+        key: Value,
+        context: Vec<u8>,
+    ) -> Result<Value> {
+        self.delete_with_context_consistent(key, context, Consistency::Fixed(1))
+            .await
+    }
+
+    pub async fn delete(&self, key: Value) -> Result<Value> {
+        self.delete_consistent(key, Consistency::Fixed(1)).await
+    }
+
+    pub async fn delete_from_str_key<S: Into<Utf8String>>(
+        &self,
+        key: S,
+    ) -> Result<Value> {
+        self.delete(Value::String(key.into())).await
+    }
+
+    /// Add to a counter in a collection with `ConflictResolution::Counter`,
+    /// negative amounts decrement it, concurrent increments are never lost.
+    pub async fn increment_consistent(
+        &self,
+        key: Value,
+        amount: i64,
+        consistency: Consistency,
+    ) -> Result<Value> {
+        self.write(
+            "increment",
+            key,
+            vec![("amount", Value::from(amount))],
+            consistency,
+        )
+        .await
+    }
+
+    pub async fn increment(&self, key: Value, amount: i64) -> Result<Value> {
+        self.increment_consistent(key, amount, Consistency::Fixed(1))
+            .await
+    }
+
+    pub async fn get_counter_consistent(
+        &self,
+        key: Value,
+        consistency: Consistency,
+    ) -> Result<i64> {
+        self.get_consistent(key, consistency)
+            .await?
+            .as_i64()
+            .ok_or_else(|| Error::UnexpectedResponse("counter".to_string()))
+    }
+
+    pub async fn get_counter(&self, key: Value) -> Result<i64> {
+        self.get_counter_consistent(key, Consistency::Fixed(1))
+            .await
+    }
+
+    /// Add an element to a set in a collection with
+    /// `ConflictResolution::Set`, an add wins over a concurrent remove.
+    pub async fn add_consistent(
+        &self,
+        key: Value,
+        element: Value,
+        consistency: Consistency,
+    ) -> Result<Value> {
+        self.write("add", key, vec![("value", element)], consistency)
+            .await
+    }
+
+    pub async fn add(&self, key: Value, element: Value) -> Result<Value> {
+        self.add_consistent(key, element, Consistency::Fixed(1))
+            .await
+    }
+
+    pub async fn remove_consistent(
+        &self,
+        key: Value,
+        element: Value,
+        consistency: Consistency,
+    ) -> Result<Value> {
+        self.write("remove", key, vec![("value", element)], consistency)
+            .await
+    }
+
+    pub async fn remove(&self, key: Value, element: Value) -> Result<Value> {
+        self.remove_consistent(key, element, Consistency::Fixed(1))
+            .await
+    }
+
+    pub async fn get_set_consistent(
+        &self,
+        key: Value,
+        consistency: Consistency,
+    ) -> Result<Vec<Value>> {
+        match self.get_consistent(key, consistency).await? {
+            Value::Array(elements) => Ok(elements),
+            _ => Err(Error::UnexpectedResponse("set".to_string())),
+        }
+    }
+
+    pub async fn get_set(&self, key: Value) -> Result<Vec<Value>> {
+        self.get_set_consistent(key, Consistency::Fixed(1)).await
+    }
+
+    /// Set a field of a map in a collection with `ConflictResolution::Map`,
+    /// concurrent writes to the same field are resolved by last write wins.
+    pub async fn put_field_consistent(
+        &self,
+        key: Value,
+        field: Value,
+        value: Value,
+        consistency: Consistency,
+    ) -> Result<Value> {
+        self.write(
+            "add",
+            key,
+            vec![("field", field), ("value", value)],
+            consistency,
+        )
+        .await
+    }
+
+    pub async fn put_field(
+        &self,
+        key: Value,
+        field: Value,
+        value: Value,
+    ) -> Result<Value> {
+        self.put_field_consistent(key, field, value, Consistency::Fixed(1))
+            .await
+    }
+
+    pub async fn remove_field_consistent(
+        &self,
+        key: Value,
+        field: Value,
+        consistency: Consistency,
+    ) -> Result<Value> {
+        self.write("remove", key, vec![("field", field)], consistency)
+            .await
+    }
+
+    pub async fn remove_field(
+        &self,
+        key: Value,
+        field: Value,
+    ) -> Result<Value> {
+        self.remove_field_consistent(key, field, Consistency::Fixed(1))
+            .await
+    }
+
+    pub async fn get_map_consistent(
+        &self,
+        key: Value,
+        consistency: Consistency,
+    ) -> Result<Vec<(Value, Value)>> {
+        match self.get_consistent(key, consistency).await? {
+            Value::Map(fields) => Ok(fields),
+            _ => Err(Error::UnexpectedResponse("map".to_string())),
+        }
+    }
+
+    pub async fn get_map(&self, key: Value) -> Result<Vec<(Value, Value)>> {
+        self.get_map_consistent(key, Consistency::Fixed(1)).await
+    }
+
+    /// Atomically apply the writes of all operations, only if the conditions
+    /// of all operations pass (checked on the coordinating replica).
+    /// All keys must be owned by the shard that owns the key of the first
+    /// operation.
+    pub async fn transaction_consistent(
+        &self,
+        operations: Vec<TransactionOperation>,
+        consistency: Consistency,
+    ) -> Result<()> {
+        let hash = match operations.first() {
+            Some(operation) => hash_key(&operation.key)?,
+            None => return Ok(()),
+        };
         let request = Value::Map(vec![
-            (Value::String("type".into()), Value::String("delete".into())),
-            (Value::String("key".into()), key),
-            (Value::String("hash".into()), hash.into()),
+            (
+                Value::String("type".into()),
+                Value::String("transaction".into()),
+            ),
+            (
+                Value::String("operations".into()),
+                Value::Array(
+                    operations
+                        .into_iter()
+                        .map(|operation| Value::Map(operation.encode()))
+                        .collect(),
+                ),
+            ),
             (
                 Value::String("collection".into()),
                 Value::String(self.name.clone()),
@@ -591,29 +1704,379 @@ impl Collection {
                 ),
             ),
         ]);
-        let response_buffer = self
-            .client
+
+        self.client
             .send_sharded_request(
                 hash,
                 request,
                 self.metadata.replication_factor,
             )
             .await?;
-        Ok(read_value(&mut &response_buffer[..])?)
+        Ok(())
     }
 
-    pub async fn delete(&self, key: Value) -> Result<Value> {
-        self.delete_consistent(key, Consistency::Fixed(1)).await
+    pub async fn transaction(
+        &self,
+        operations: Vec<TransactionOperation>,
+    ) -> Result<()> {
+        self.transaction_consistent(operations, Consistency::Fixed(1))
+            .await
     }
 
-    pub async fn delete_from_str_key<S: Into<Utf8String>>(
+    /// Apply the operation only if its condition passes, agreed on by a
+    /// quorum of the key's replicas using paxos, so it is linearizable with
+    /// all other `compare_and_set()` calls on the key (a lightweight
+    /// transaction).
+    /// Slower than a regular write, needs 4 round trips to the replicas.
+    pub async fn compare_and_set(
         &self,
-        key: S,
-    ) -> Result<Value> {
-        self.delete(Value::String(key.into())).await
+        operation: TransactionOperation,
+    ) -> Result<()> {
+        let hash = hash_key(&operation.key)?;
+        let mut items = operation.encode();
+        items.extend([
+            (Value::String("type".into()), Value::String("cas".into())),
+            (Value::String("hash".into()), hash.into()),
+            (
+                Value::String("collection".into()),
+                Value::String(self.name.clone()),
+            ),
+        ]);
+
+        self.client
+            .send_sharded_request(
+                hash,
+                Value::Map(items),
+                self.metadata.replication_factor,
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_replication_factor(
+        &mut self,
+        replication_factor: u16,
+    ) -> Result<()> {
+        self.metadata = self
+            .client
+            .alter_collection(
+                self.name.clone(),
+                vec![(
+                    "replication_factor",
+                    Value::Integer(replication_factor.into()),
+                )],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Set for how long tombstones of deleted documents are kept before
+    /// compaction may purge them, should be longer than a replica can be
+    /// down for.
+    pub async fn set_gc_grace_seconds(
+        &mut self,
+        gc_grace_seconds: u64,
+    ) -> Result<()> {
+        self.metadata = self
+            .client
+            .alter_collection(
+                self.name.clone(),
+                vec![(
+                    "gc_grace_seconds",
+                    Value::Integer(gc_grace_seconds.into()),
+                )],
+            )
+            .await?;
+        Ok(())
     }
 
     pub async fn drop(self) -> Result<()> {
         self.client.drop_collection(self.name).await
     }
+
+    /// Delete all documents of the collection, keeping the collection.
+    pub async fn truncate(&self) -> Result<()> {
+        self.client.truncate_collection(self.name.clone()).await
+    }
+
+    /// Stream all documents of the collection as (key, value) pairs, by
+    /// scanning the primary range of every shard (one shard after the other)
+    /// in pages of `page_size` documents.
+    /// A page is requested only once all documents of the previous page were
+    /// consumed, so reading only the first documents scans only a page.
+    pub async fn scan(
+        &self,
+        page_size: u64,
+    ) -> Result<impl Stream<Item = Result<(Value, Value)>>> {
+        let addresses = self
+            .client
+            .hash_ring
+            .read()
+            .await
+            .iter()
+            .map(|shard| shard.address)
+            .collect::<Vec<_>>();
+
+        let client = self.client.clone();
+        let name = self.name.clone();
+        Ok(Box::pin(stream::iter(addresses).flat_map(move |address| {
+            let (client, name) = (client.clone(), name.clone());
+
+            // The state is the key to continue the scan of the shard from,
+            // None when the scan of the shard is done.
+            stream::try_unfold(Some(None), move |after| {
+                let (client, name) = (client.clone(), name.clone());
+                async move {
+                    let after = match after {
+                        Some(after) => after,
+                        None => return Ok(None),
+                    };
+                    let (page, next) = client
+                        .scan_shard(address, name, after, page_size)
+                        .await?;
+                    Ok::<_, Error>(Some((
+                        stream::iter(page.into_iter().map(Ok::<_, Error>)),
+                        next.map(Some),
+                    )))
+                }
+            })
+            .try_flatten()
+        })))
+    }
+
+    /// Stream all documents of the collection as (key, value) pairs, every
+    /// shard streams the documents in its primary range in batches, so
+    /// documents are not read into memory all at once like in `scan()`.
+    /// Documents are ordered only within a shard, every shard streams the
+    /// documents it had when the dump started.
+    pub async fn dump(
+        &self,
+    ) -> Result<impl Stream<Item = Result<(Value, Value)>>> {
+        let request = Value::Map(vec![
+            (Value::String("type".into()), Value::String("dump".into())),
+            (
+                Value::String("collection".into()),
+                Value::String(self.name.clone()),
+            ),
+        ]);
+
+        let addresses = self
+            .client
+            .hash_ring
+            .read()
+            .await
+            .iter()
+            .map(|shard| shard.address)
+            .collect::<Vec<_>>();
+
+        let mut streams = Vec::with_capacity(addresses.len());
+        for address in addresses {
+            let connection =
+                self.client.open_stream(&address, &request).await?;
+            streams.push(Box::pin(batch_stream(
+                connection,
+                self.client.read_timeout,
+                decode_documents,
+            )));
+        }
+
+        Ok(select_all(streams))
+    }
+
+    /// Stream the changes (sets and deletes) to the collection from all
+    /// shards, starting at the cursor.
+    /// Changes are ordered only within a shard, and a change might be
+    /// streamed more than once (e.g. when data migrates between nodes).
+    /// Fails when the cursor expired because the changes after it were
+    /// dropped from the change log, a shard's stream ends after an error.
+    pub async fn subscribe(
+        &self,
+        cursor: &ChangeCursor,
+    ) -> Result<impl Stream<Item = Result<Change>>> {
+        let shards = self.client.hash_ring.read().await.clone();
+
+        let mut streams = Vec::with_capacity(shards.len());
+        for shard in shards {
+            let mut request = vec![
+                (
+                    Value::String("type".into()),
+                    Value::String("subscribe".into()),
+                ),
+                (
+                    Value::String("collection".into()),
+                    Value::String(self.name.clone()),
+                ),
+            ];
+            if let Some(sequence) = cursor.0.get(&shard.name) {
+                request.push((
+                    Value::String("cursor".into()),
+                    Value::from(*sequence),
+                ));
+            }
+
+            let connection = self
+                .client
+                .open_stream(&shard.address, &Value::Map(request))
+                .await?;
+            streams.push(Box::pin(batch_stream(
+                connection,
+                self.client.read_timeout,
+                move |response| decode_changes(&shard.name, response).map(Some),
+            )));
+        }
+
+        Ok(select_all(streams))
+    }
+
+    /// Get pushed the writes (sets and deletes) to a key.
+    /// The watch is on the shard owning the key, writes are missed when the
+    /// key moves to another shard (e.g. when a node is added).
+    pub async fn watch(
+        &self,
+        key: Value,
+    ) -> Result<impl Stream<Item = Result<WatchEvent>>> {
+        let hash = hash_key(&key)?;
+        let address = {
+            let ring = self.client.hash_ring.read().await;
+            let index = ring.iter().position(|s| s.hash >= hash).unwrap_or(0);
+            ring.get(index).ok_or(Error::NoAddresses)?.address
+        };
+
+        let request = Value::Map(vec![
+            (Value::String("type".into()), Value::String("watch".into())),
+            (
+                Value::String("collection".into()),
+                Value::String(self.name.clone()),
+            ),
+            (Value::String("key".into()), key),
+            (Value::String("hash".into()), hash.into()),
+        ]);
+        let connection = self.client.open_stream(&address, &request).await?;
+
+        Ok(Box::pin(batch_stream(
+            connection,
+            self.client.read_timeout,
+            |response| decode_watch_events(response).map(Some),
+        )))
+    }
+
+    /// Get pushed the writes (sets and deletes) to all string keys starting
+    /// with a prefix, from all shards.
+    pub async fn watch_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<impl Stream<Item = Result<WatchEvent>>> {
+        let request = Value::Map(vec![
+            (Value::String("type".into()), Value::String("watch".into())),
+            (
+                Value::String("collection".into()),
+                Value::String(self.name.clone()),
+            ),
+            (Value::String("prefix".into()), Value::String(prefix.into())),
+        ]);
+
+        let addresses = self
+            .client
+            .hash_ring
+            .read()
+            .await
+            .iter()
+            .map(|shard| shard.address)
+            .collect::<Vec<_>>();
+
+        let mut streams = Vec::with_capacity(addresses.len());
+        for address in addresses {
+            let connection =
+                self.client.open_stream(&address, &request).await?;
+            streams.push(Box::pin(batch_stream(
+                connection,
+                self.client.read_timeout,
+                |response| decode_watch_events(response).map(Some),
+            )));
+        }
+
+        Ok(select_all(streams))
+    }
+
+    /// The approximate number of documents in the collection, without
+    /// scanning it, each node counts the documents in the primary ranges of
+    /// its shards.
+    pub async fn count(&self) -> Result<u64> {
+        let request = Value::Map(vec![
+            (Value::String("type".into()), Value::String("count".into())),
+            (
+                Value::String("collection".into()),
+                Value::String(self.name.clone()),
+            ),
+        ]);
+
+        let mut count = 0;
+        for addresses in self.client.node_addresses().await.values() {
+            let response =
+                self.client.send_request(addresses, request.clone()).await?;
+            count +=
+                read_value(&mut &response[..])?.as_u64().ok_or_else(|| {
+                    Error::UnexpectedResponse("count".to_string())
+                })?;
+        }
+        Ok(count)
+    }
+
+    /// Link sstables built by `dbeel-cli build-sstables` into the collection,
+    /// every node reads the sstables of its shards from `<dir>/<shard name>`,
+    /// so `dir` must be reachable by all nodes (e.g. copied to them, or a
+    /// shared mount).
+    /// Returns the number of sstables ingested.
+    pub async fn ingest(&self, dir: &str) -> Result<u64> {
+        let request = Value::Map(vec![
+            (Value::String("type".into()), Value::String("ingest".into())),
+            (
+                Value::String("collection".into()),
+                Value::String(self.name.clone()),
+            ),
+            (Value::String("dir".into()), Value::String(dir.into())),
+        ]);
+
+        let mut ingested = 0;
+        for addresses in self.client.node_addresses().await.values() {
+            let response =
+                self.client.send_request(addresses, request.clone()).await?;
+            ingested +=
+                read_value(&mut &response[..])?.as_u64().ok_or_else(|| {
+                    Error::UnexpectedResponse("ingest".to_string())
+                })?;
+        }
+        Ok(ingested)
+    }
+
+    /// The stats of the collection in all shards of the first seed node that
+    /// responds.
+    pub async fn stats(&self) -> Result<Vec<CollectionShardStats>> {
+        let response = self
+            .client
+            .send_collection_admin_request(
+                "collection_stats",
+                self.name.clone(),
+            )
+            .await?;
+        Ok(from_slice(&response)?)
+    }
+
+    /// Flush the memtables of the collection in all shards of the first seed
+    /// node that responds.
+    pub async fn flush(&self) -> Result<()> {
+        self.client
+            .send_collection_admin_request("flush", self.name.clone())
+            .await?;
+        Ok(())
+    }
+
+    /// Compact all sstables of the collection in all shards of the first seed
+    /// node that responds.
+    pub async fn compact(&self) -> Result<()> {
+        self.client
+            .send_collection_admin_request("compact", self.name.clone())
+            .await?;
+        Ok(())
+    }
 }
@@ -0,0 +1,226 @@
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use dbeel::storage_engine::{
+    inspect::{
+        inspect_bloom, inspect_compaction_actions, inspect_range_tombstones,
+        inspect_sketch, inspect_sstable, inspect_wal, list_collection_files,
+        Corruption, InspectedEntry, InspectedFile,
+    },
+    EntryKind, BLOOM_FILE_EXT, COMPACT_ACTION_FILE_EXT, COMPACT_BLOOM_FILE_EXT,
+    COMPACT_DATA_FILE_EXT, COMPACT_INDEX_FILE_EXT,
+    COMPACT_RANGE_TOMBSTONES_FILE_EXT, COMPACT_SKETCH_FILE_EXT, DATA_FILE_EXT,
+    INDEX_FILE_EXT, MEMTABLE_FILE_EXT, RANGE_TOMBSTONES_FILE_EXT,
+    SKETCH_FILE_EXT,
+};
+use rmpv::decode::read_value;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+/// Inspect and validate the files of a collection directory, without running
+/// the database.
+struct Args {
+    #[clap(help = "The collection directory (<dir>/<collection>-<shard id>).")]
+    dir: PathBuf,
+
+    #[clap(short, long, help = "Print every entry, not only a summary.")]
+    entries: bool,
+}
+
+/// Documents are msgpack, print them as such when possible.
+fn format_msgpack(bytes: &[u8]) -> String {
+    match read_value(&mut &bytes[..]) {
+        Ok(value) => value.to_string(),
+        Err(_) => format!("{bytes:02x?}"),
+    }
+}
+
+fn print_entry(entry: &InspectedEntry) {
+    let value = match entry.entry.value.kind {
+        EntryKind::Merge => {
+            format!("<merge> {}", format_msgpack(&entry.entry.value.data))
+        }
+        EntryKind::Delete => "<tombstone>".to_string(),
+        EntryKind::RangeDelete => format!(
+            "<range tombstone> until {}",
+            format_msgpack(&entry.entry.value.data)
+        ),
+        EntryKind::Put => format_msgpack(&entry.entry.value.data),
+    };
+    println!(
+        "  offset: {}, key: {}, timestamp: {}, value: {}",
+        entry.offset,
+        format_msgpack(&entry.entry.key),
+        entry.entry.value.timestamp,
+        value
+    );
+}
+
+fn print_file(
+    path: &Path,
+    kind: &str,
+    file: InspectedFile,
+    print_entries: bool,
+    corruptions: &mut Vec<Corruption>,
+) {
+    println!("{}: {kind}, {} entries", path.display(), file.entries.len());
+
+    let timestamps = file.entries.iter().map(|e| e.entry.value.timestamp);
+    if let (Some(min), Some(max)) = (timestamps.clone().min(), timestamps.max())
+    {
+        println!("  timestamps: {min} - {max}");
+    }
+
+    if print_entries {
+        file.entries.iter().for_each(print_entry);
+    }
+
+    corruptions.extend(file.corruptions);
+}
+
+fn inspect_dir(args: &Args) -> dbeel::error::Result<Vec<Corruption>> {
+    let files = list_collection_files(&args.dir)?;
+    let mut corruptions = Vec::new();
+
+    for file in &files {
+        let result = match file.extension.as_str() {
+            MEMTABLE_FILE_EXT => inspect_wal(&file.path).map(|inspected| {
+                print_file(
+                    &file.path,
+                    "WAL",
+                    inspected,
+                    args.entries,
+                    &mut corruptions,
+                );
+            }),
+            DATA_FILE_EXT => {
+                inspect_sstable(&args.dir, file.index).map(|inspected| {
+                    print_file(
+                        &file.path,
+                        "sstable",
+                        inspected,
+                        args.entries,
+                        &mut corruptions,
+                    );
+                })
+            }
+            INDEX_FILE_EXT => {
+                // Inspected together with the data file.
+                if !files.iter().any(|f| {
+                    f.index == file.index && f.extension == DATA_FILE_EXT
+                }) {
+                    corruptions.push(Corruption {
+                        path: file.path.clone(),
+                        offset: 0,
+                        reason: "index file without a data file".to_string(),
+                    });
+                }
+                Ok(())
+            }
+            BLOOM_FILE_EXT => inspect_bloom(&file.path).map(|stats| {
+                println!(
+                    "{}: bloom filter, {} bits ({} set), {} hash functions, \
+                     estimated false positive rate: {:.6}",
+                    file.path.display(),
+                    stats.bits,
+                    stats.bits_set,
+                    stats.hash_functions,
+                    stats.estimated_false_positive_rate()
+                );
+            }),
+            RANGE_TOMBSTONES_FILE_EXT => inspect_range_tombstones(&file.path)
+                .map(|range_tombstones| {
+                    println!(
+                        "{}: {} range tombstones",
+                        file.path.display(),
+                        range_tombstones.len()
+                    );
+                    if args.entries {
+                        for range_tombstone in range_tombstones {
+                            println!(
+                                "  start: {}, end: {}, timestamp: {}",
+                                format_msgpack(&range_tombstone.start),
+                                format_msgpack(&range_tombstone.end),
+                                range_tombstone.timestamp
+                            );
+                        }
+                    }
+                }),
+            SKETCH_FILE_EXT => inspect_sketch(&file.path).map(|sketch| {
+                println!(
+                    "{}: keys sketch, ~{} distinct keys, ~{} deleted keys",
+                    file.path.display(),
+                    sketch.keys.estimate(),
+                    sketch.tombstones.estimate()
+                );
+            }),
+            COMPACT_ACTION_FILE_EXT => inspect_compaction_actions(&file.path)
+                .map(|(actions, action_corruptions)| {
+                    println!(
+                        "{}: pending compaction action, run on next open",
+                        file.path.display()
+                    );
+                    for action in actions {
+                        for (source, destination) in &action.renames {
+                            println!(
+                                "  rename: {} -> {}",
+                                source.display(),
+                                destination.display()
+                            );
+                        }
+                        for path in &action.deletes {
+                            println!("  delete: {}", path.display());
+                        }
+                    }
+                    corruptions.extend(action_corruptions);
+                }),
+            COMPACT_DATA_FILE_EXT
+            | COMPACT_INDEX_FILE_EXT
+            | COMPACT_BLOOM_FILE_EXT
+            | COMPACT_RANGE_TOMBSTONES_FILE_EXT
+            | COMPACT_SKETCH_FILE_EXT => {
+                println!(
+                    "{}: output of an unfinished compaction",
+                    file.path.display()
+                );
+                Ok(())
+            }
+            _ => {
+                println!("{}: unknown file type", file.path.display());
+                Ok(())
+            }
+        };
+
+        if let Err(e) = result {
+            corruptions.push(Corruption {
+                path: file.path.clone(),
+                offset: 0,
+                reason: format!("failed to read: {e}"),
+            });
+        }
+    }
+
+    Ok(corruptions)
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let corruptions = match inspect_dir(&args) {
+        Ok(corruptions) => corruptions,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", args.dir.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    if corruptions.is_empty() {
+        println!("No corruptions found");
+    } else {
+        println!("Found {} corruptions:", corruptions.len());
+        for corruption in &corruptions {
+            println!("  {corruption}");
+        }
+        std::process::exit(1);
+    }
+}
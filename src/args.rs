@@ -99,6 +99,14 @@ This port is for listening for gossip messages from \
     )]
     pub gossip_port: u16,
 
+    #[clap(
+        long,
+        help = "Metrics HTTP server port.
+Metrics of all shards are served in the prometheus format on /metrics.",
+        default_value = "40000"
+    )]
+    pub metrics_port: u16,
+
     #[clap(long, help = "Gossip number of nodes fanout.", default_value = "3")]
     pub gossip_fanout: usize,
 
@@ -110,6 +118,23 @@ retransmitting the event again.",
     )]
     pub gossip_max_seen_count: u8,
 
+    #[clap(
+        long,
+        help = "A secret shared by all nodes in the cluster, used to sign \
+gossip messages (HMAC-SHA256).
+Unsigned gossip messages or ones with an invalid signature are rejected."
+    )]
+    pub gossip_secret: Option<String>,
+
+    #[clap(
+        long,
+        help = "The interval at which to sync the full cluster metadata with \
+a random node (anti entropy), to converge on gossip events that were lost, in \
+milliseconds.",
+        default_value = "10000"
+    )]
+    pub anti_entropy_interval: u64,
+
     #[clap(
         long,
         help = "The interval at which to ping a node, in milliseconds.",
@@ -157,6 +182,15 @@ Note that on many systems, the effective resolution of sleep delays is 10 \
     )]
     pub sstable_bloom_min_size: u64,
 
+    #[clap(
+        long,
+        help = "Max number of changes to keep on disk for subscribers, per \
+collection in each shard.
+0 disables change data capture.",
+        default_value = "10000"
+    )]
+    pub change_log_capacity: usize,
+
     #[clap(
         long,
         help = "Foreground tasks shares (0 - 1000).",
@@ -170,6 +204,54 @@ Note that on many systems, the effective resolution of sleep delays is 10 \
         default_value = "250"
     )]
     pub background_tasks_shares: u16,
+
+    #[clap(
+        long,
+        help = "Require clients to authenticate before every request.
+Users are stored in the '__users' system collection.",
+        default_value = "false"
+    )]
+    pub auth: bool,
+
+    #[clap(
+        long,
+        help = "The name of the admin user that is created when auth is \
+enabled and the user doesn't exist yet.",
+        default_value = "root"
+    )]
+    pub root_username: String,
+
+    #[clap(
+        long,
+        help = "The password of the admin user that is created when auth is \
+enabled and the user doesn't exist yet, no user is created when not set."
+    )]
+    pub root_password: Option<String>,
+
+    #[clap(
+        long,
+        help = "Path to a PEM certificate chain, enables TLS on both the \
+client port and the remote shard port (requires --tls-key)."
+    )]
+    pub tls_cert: Option<String>,
+
+    #[clap(long, help = "Path to the PEM private key of --tls-cert.")]
+    pub tls_key: Option<String>,
+
+    #[clap(
+        long,
+        help = "Path to PEM CA certificates used to verify remote shards, \
+defaults to --tls-cert (for self-signed certificates)."
+    )]
+    pub tls_ca_cert: Option<String>,
+
+    #[clap(
+        long,
+        help = "Use mutual TLS between nodes, remote shards must present a \
+certificate signed by --tls-ca-cert.",
+        default_value = "false"
+    )]
+    pub tls_mutual: bool,
 }
 
 #[must_use]
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+use argon2::{
+    password_hash::{
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+    },
+    Argon2,
+};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+/// The system collection holding all users, key is the username.
+pub const USERS_COLLECTION: &str = "__users";
+
+/// Collections starting with this prefix are reserved for internal use.
+pub const SYSTEM_COLLECTION_PREFIX: &str = "__";
+
+/// A permission granted on this name applies to all collections.
+pub const ALL_COLLECTIONS: &str = "*";
+
+/// Each permission implies all the permissions before it.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub enum Permission {
+    Read,
+    Write,
+    Admin,
+}
+
+impl Permission {
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Write => "write",
+            Self::Admin => "admin",
+        }
+    }
+}
+
+impl TryFrom<&str> for Permission {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value {
+            "read" => Ok(Self::Read),
+            "write" => Ok(Self::Write),
+            "admin" => Ok(Self::Admin),
+            _ => Err(Error::BadFieldType("permission".to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    /// The password hashed with argon2, in the PHC string format.
+    pub password_hash: String,
+
+    /// Key is the collection name (or `ALL_COLLECTIONS`).
+    pub permissions: HashMap<String, Permission>,
+}
+
+impl User {
+    pub fn new(
+        password: &str,
+        permissions: HashMap<String, Permission>,
+    ) -> Result<Self> {
+        Ok(Self {
+            password_hash: hash_password(password)?,
+            permissions,
+        })
+    }
+
+    #[must_use]
+    pub fn verify_password(&self, password: &str) -> bool {
+        PasswordHash::new(&self.password_hash)
+            .and_then(|hash| {
+                Argon2::default().verify_password(password.as_bytes(), &hash)
+            })
+            .is_ok()
+    }
+
+    #[must_use]
+    pub fn has_permission(
+        &self,
+        collection: &str,
+        permission: Permission,
+    ) -> bool {
+        [collection, ALL_COLLECTIONS]
+            .iter()
+            .filter_map(|name| self.permissions.get(*name))
+            .any(|granted| *granted >= permission)
+    }
+}
+
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(Error::PasswordHashError)?
+        .to_string())
+}
+
+#[must_use]
+pub fn is_system_collection(name: &str) -> bool {
+    name.starts_with(SYSTEM_COLLECTION_PREFIX)
+}
+
+/// Check whether a user is allowed to access a collection, a user of None
+/// means authentication is disabled.
+/// System collections are never accessible directly by clients.
+pub fn check_permission(
+    user: Option<&User>,
+    collection: &str,
+    permission: Permission,
+) -> Result<()> {
+    if is_system_collection(collection) {
+        return Err(Error::PermissionDenied(collection.to_string()));
+    }
+
+    match user {
+        Some(user) if !user.has_permission(collection, permission) => {
+            Err(Error::PermissionDenied(collection.to_string()))
+        }
+        _ => Ok(()),
+    }
+}
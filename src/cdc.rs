@@ -0,0 +1,338 @@
+use std::{cell::Cell, rc::Rc};
+
+use async_channel::Sender;
+use bincode::Options;
+use glommio::sync::Semaphore;
+use rmpv::{decode::read_value_ref, ValueRef};
+use serde::{Deserialize, Serialize};
+use time::{Duration, OffsetDateTime};
+
+use crate::{
+    error::{Error, Result},
+    storage_engine::lsm_tree::LSMTree,
+    utils::{
+        bincode::bincode_options,
+        local_event::{LocalEvent, LocalEventListener},
+        timestamp_nanos,
+    },
+};
+
+/// A committed write to a collection.
+#[derive(Debug, Clone)]
+pub struct Change {
+    /// The position of the change in the change log, used as a cursor to
+    /// resume reading changes.
+    pub sequence: u64,
+    pub key: Vec<u8>,
+
+    /// None when the key was deleted.
+    pub value: Option<Vec<u8>>,
+    pub timestamp: OffsetDateTime,
+}
+
+/// A change as stored in the tree of the change log, keyed by its sequence.
+#[derive(Serialize, Deserialize)]
+struct StoredChange {
+    key: Vec<u8>,
+    value: Option<Vec<u8>>,
+    #[serde(with = "timestamp_nanos")]
+    timestamp: OffsetDateTime,
+}
+
+/// Sequences are stored big endian, so the tree keeps them in order.
+fn sequence_key(sequence: u64) -> Vec<u8> {
+    sequence.to_be_bytes().to_vec()
+}
+
+fn key_sequence(key: &[u8]) -> Result<u64> {
+    let bytes = key.try_into().map_err(|_| Error::InvalidChangeLogKey)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Entries of the tree are written with their sequence as the timestamp,
+/// which only grows, so a trim or a clear always deletes the entries written
+/// before it, regardless of the wall clock.
+fn sequence_timestamp(sequence: u64) -> OffsetDateTime {
+    OffsetDateTime::UNIX_EPOCH + Duration::nanoseconds(sequence as i64)
+}
+
+/// A bounded log of the changes to a collection (change data capture),
+/// persisted in its own tree next to the collection's tree, the oldest
+/// changes are dropped when the log is full.
+/// The live changes always have consecutive sequences, from the first
+/// sequence up to (not including) the next sequence.
+pub struct ChangeLog {
+    /// None when the log is disabled.
+    tree: Option<Rc<LSMTree>>,
+
+    /// Max number of changes to hold, 0 disables the log.
+    capacity: usize,
+
+    /// The sequence of the oldest change in the log.
+    first_sequence: Cell<u64>,
+
+    /// The sequence of the next change pushed to the log.
+    next_sequence: Cell<u64>,
+
+    /// Pushes are written one at a time, so a reader never sees a change
+    /// before all changes with smaller sequences.
+    write_semaphore: Semaphore,
+
+    /// Notified on every change pushed to the log.
+    change_event: LocalEvent,
+}
+
+impl ChangeLog {
+    /// Open the log persisted in the tree, None when the log is disabled.
+    pub async fn open(tree: Option<LSMTree>, capacity: usize) -> Result<Self> {
+        // An empty log starts from the current time, so cursors handed out
+        // before it was emptied (or disabled) are detected as expired.
+        let now = OffsetDateTime::now_utc().unix_timestamp_nanos() as u64;
+        let mut first = None;
+        let mut next = now;
+
+        let tree = match tree {
+            Some(tree) if capacity > 0 => {
+                let mut iter =
+                    tree.iter_sorted(None, Box::new(|_, _| true)).await?;
+                while let Some(entry) = iter.next().await? {
+                    let sequence = key_sequence(&entry.key)?;
+                    if first.is_none() && !entry.value.is_delete() {
+                        first = Some(sequence);
+                    }
+                    next = sequence + 1;
+                }
+                drop(iter);
+
+                // Without live changes, sequences don't have to continue
+                // from the deleted ones.
+                if first.is_none() {
+                    next = next.max(now);
+                }
+
+                let tree = Rc::new(tree);
+
+                // Opened with a smaller capacity than the log was written
+                // with, so a push never has more changes to drop than it
+                // adds.
+                let capacity_first = next.saturating_sub(capacity as u64);
+                if let Some(live_first) = first {
+                    if live_first < capacity_first {
+                        tree.clone()
+                            .delete_range_with_timestamp(
+                                sequence_key(live_first),
+                                sequence_key(capacity_first),
+                                sequence_timestamp(next),
+                            )
+                            .await?;
+                        first = Some(capacity_first);
+                    }
+                }
+                Some(tree)
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            tree,
+            capacity,
+            first_sequence: Cell::new(first.unwrap_or(next)),
+            next_sequence: Cell::new(next),
+            write_semaphore: Semaphore::new(1),
+            change_event: LocalEvent::new(),
+        })
+    }
+
+    /// The tree persisting the log, None when the log is disabled.
+    #[must_use]
+    pub fn tree(&self) -> Option<Rc<LSMTree>> {
+        self.tree.clone()
+    }
+
+    /// Append the changes of a write, dropping the oldest changes that no
+    /// longer fit in the log.
+    /// Each batch written to the tree holds at most as many entries as the
+    /// write, so a write that fit in the collection's tree also fits in the
+    /// log's tree.
+    pub async fn push(
+        &self,
+        changes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        timestamp: OffsetDateTime,
+    ) -> Result<()> {
+        let tree = match &self.tree {
+            Some(tree) if !changes.is_empty() => tree.clone(),
+            _ => return Ok(()),
+        };
+
+        let _permit = self.write_semaphore.acquire_permit(1).await?;
+
+        let first = self.first_sequence.get();
+        let start = self.next_sequence.get();
+        let next = start + changes.len() as u64;
+        let new_first = first.max(next.saturating_sub(self.capacity as u64));
+
+        // Changes that don't fit in the log are dropped without being
+        // written, their sequences are skipped.
+        let skipped = changes.len().saturating_sub(self.capacity);
+        let mut items = Vec::with_capacity(changes.len() - skipped);
+        for (sequence, (key, value)) in (start..).zip(changes).skip(skipped) {
+            let stored = StoredChange {
+                key,
+                value,
+                timestamp,
+            };
+            items.push((
+                sequence_key(sequence),
+                Some(bincode_options().serialize(&stored)?),
+            ));
+        }
+        tree.clone()
+            .set_batch_with_timestamp(items, sequence_timestamp(start))
+            .await?;
+        self.next_sequence.set(next);
+
+        // The log holds at most capacity changes before the push, so there
+        // are never more changes to drop than changes written.
+        let trimmed = (first..new_first.min(start))
+            .map(|sequence| (sequence_key(sequence), None))
+            .collect::<Vec<_>>();
+        tree.set_batch_with_timestamp(trimmed, sequence_timestamp(start))
+            .await?;
+
+        self.first_sequence.set(new_first);
+        self.change_event.notify();
+
+        Ok(())
+    }
+
+    /// Drop all changes, the cursors of readers expire.
+    pub async fn clear(&self) -> Result<()> {
+        let tree = match &self.tree {
+            Some(tree) => tree.clone(),
+            None => return Ok(()),
+        };
+
+        let _permit = self.write_semaphore.acquire_permit(1).await?;
+
+        let next = self.next_sequence.get();
+        tree.delete_range_with_timestamp(
+            sequence_key(self.first_sequence.get()),
+            sequence_key(next),
+            sequence_timestamp(next),
+        )
+        .await?;
+
+        // Skip a sequence, so even a reader that was at the end of the log
+        // has to start over.
+        self.first_sequence.set(next + 1);
+        self.next_sequence.set(next + 1);
+        self.change_event.notify();
+
+        Ok(())
+    }
+
+    /// Delete the files of the log.
+    pub fn purge(&self) -> Result<()> {
+        match &self.tree {
+            Some(tree) => tree.purge(),
+            None => Ok(()),
+        }
+    }
+
+    /// The cursor to read only changes that are pushed from now on.
+    #[must_use]
+    pub fn next_sequence(&self) -> u64 {
+        self.next_sequence.get()
+    }
+
+    /// Read up to `limit` changes, starting at the change with the `cursor`
+    /// sequence.
+    /// Fails when changes after the cursor were already dropped from the log.
+    pub async fn read(&self, cursor: u64, limit: usize) -> Result<Vec<Change>> {
+        let tree = self.tree.as_ref().ok_or(Error::ChangeLogDisabled)?;
+
+        if cursor < self.first_sequence.get() {
+            return Err(Error::ChangeCursorExpired(cursor));
+        }
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+
+        // The cursor is at least the first sequence, there's always a
+        // sequence before it.
+        let after = sequence_key(cursor - 1);
+        let entries = tree
+            .scan(
+                Some(after.as_slice()),
+                limit,
+                Box::new(|_, value| !value.is_delete()),
+            )
+            .await?;
+
+        let mut changes = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let stored: StoredChange =
+                bincode_options().deserialize(&entry.value.data)?;
+            changes.push(Change {
+                sequence: key_sequence(&entry.key)?,
+                key: stored.key,
+                value: stored.value,
+                timestamp: stored.timestamp,
+            });
+        }
+
+        // Dropped by a push while reading.
+        if cursor < self.first_sequence.get() {
+            return Err(Error::ChangeCursorExpired(cursor));
+        }
+
+        Ok(changes)
+    }
+
+    /// Wait for the next change pushed to the log.
+    #[must_use]
+    pub fn listen(&self) -> LocalEventListener {
+        self.change_event.listen()
+    }
+}
+
+/// What a watcher watches in a collection.
+#[derive(Debug, Clone)]
+pub enum WatchTarget {
+    /// An encoded key.
+    Key(Vec<u8>),
+
+    /// All string keys that start with the prefix.
+    Prefix(String),
+}
+
+impl WatchTarget {
+    #[must_use]
+    pub fn matches(&self, key: &[u8]) -> bool {
+        match self {
+            Self::Key(watched_key) => watched_key == key,
+            Self::Prefix(prefix) => match read_value_ref(&mut &key[..]) {
+                Ok(ValueRef::String(s)) => {
+                    s.as_str().is_some_and(|s| s.starts_with(prefix))
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+/// A write to a watched key, pushed to the watcher.
+#[derive(Debug, Clone)]
+pub struct WatchEvent {
+    pub key: Vec<u8>,
+
+    /// None when the key was deleted.
+    pub value: Option<Vec<u8>>,
+    pub timestamp: OffsetDateTime,
+}
+
+pub struct Watcher {
+    pub collection: String,
+    pub target: WatchTarget,
+    pub sender: Sender<WatchEvent>,
+}
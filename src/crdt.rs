@@ -0,0 +1,368 @@
+//! Conflict-free replicated value types, for values that are updated
+//! concurrently and can't lose updates (counters, sets of tags, ...).
+//!
+//! Like siblings, the coordinating shard applies an operation to the full
+//! value it holds for the key (under the collection's write lock), and the
+//! resulting value is what it replicates. Merging values is commutative,
+//! associative and idempotent, so values are merged in any order: in the
+//! memtable, in compaction and when resolving the values of multiple
+//! replicas.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use bincode::Options;
+use rmpv::{decode::read_value, Value};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{
+    error::Result,
+    siblings::{join, Dot, VersionVector},
+    storage_engine::{EntryKind, EntryValue},
+    utils::{bincode::bincode_options, timestamp_nanos},
+};
+
+pub trait Crdt: Default + Serialize + DeserializeOwned {
+    #[must_use]
+    fn merge(self, other: Self) -> Self;
+
+    /// The value returned to clients.
+    fn to_value(&self) -> Result<Value>;
+}
+
+pub fn decode<T: Crdt>(data: &[u8]) -> Result<T> {
+    Ok(bincode_options().deserialize(data)?)
+}
+
+pub fn encode<T: Crdt>(value: &T) -> Result<Vec<u8>> {
+    Ok(bincode_options().serialize(value)?)
+}
+
+/// The merge function of trees of collections holding `T` values.
+pub fn merge_entry_values<T: Crdt>(
+    a: &EntryValue,
+    b: &EntryValue,
+) -> Result<EntryValue> {
+    Ok(EntryValue {
+        data: encode(&decode::<T>(&a.data)?.merge(decode(&b.data)?))?,
+        timestamp: a.timestamp.max(b.timestamp),
+        kind: EntryKind::Put,
+    })
+}
+
+/// A PN-counter, the increments and decrements of each shard are counted
+/// separately, so they are merged by taking the max of each.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Counter {
+    increments: BTreeMap<u32, u64>,
+    decrements: BTreeMap<u32, u64>,
+}
+
+impl Counter {
+    pub fn increment(&mut self, amount: i64, node: u32) {
+        let counts = if amount < 0 {
+            &mut self.decrements
+        } else {
+            &mut self.increments
+        };
+        *counts.entry(node).or_insert(0) += amount.unsigned_abs();
+    }
+
+    #[must_use]
+    pub fn value(&self) -> i64 {
+        let increments = self.increments.values().sum::<u64>();
+        let decrements = self.decrements.values().sum::<u64>();
+        increments.wrapping_sub(decrements) as i64
+    }
+}
+
+fn merge_max(
+    mut counts: BTreeMap<u32, u64>,
+    other: BTreeMap<u32, u64>,
+) -> BTreeMap<u32, u64> {
+    for (node, count) in other {
+        let current = counts.entry(node).or_insert(0);
+        *current = (*current).max(count);
+    }
+    counts
+}
+
+impl Crdt for Counter {
+    fn merge(self, other: Self) -> Self {
+        Self {
+            increments: merge_max(self.increments, other.increments),
+            decrements: merge_max(self.decrements, other.decrements),
+        }
+    }
+
+    fn to_value(&self) -> Result<Value> {
+        Ok(Value::from(self.value()))
+    }
+}
+
+/// An observed-remove set (without tombstones): each add of an element is
+/// tagged with a dot, a remove only removes the adds it has seen, so an add
+/// concurrent to a remove wins.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Set {
+    /// All dots the set has seen, including the dots of removed elements.
+    context: VersionVector,
+
+    /// Encoded element to the dots of its adds.
+    elements: BTreeMap<Vec<u8>, BTreeSet<Dot>>,
+}
+
+impl Set {
+    pub fn add(&mut self, element: Vec<u8>, node: u32) {
+        let counter = self.context.entry(node).or_insert(0);
+        *counter += 1;
+        self.elements.insert(
+            element,
+            BTreeSet::from([Dot {
+                node,
+                counter: *counter,
+            }]),
+        );
+    }
+
+    pub fn remove(&mut self, element: &[u8]) {
+        self.elements.remove(element);
+    }
+
+    pub fn elements(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.elements.keys()
+    }
+}
+
+impl Crdt for Set {
+    /// An add is kept when both sets have it, or when the other set has
+    /// never seen it (otherwise it was removed).
+    fn merge(self, mut other: Self) -> Self {
+        let mut elements = BTreeMap::new();
+        for (element, dots) in self.elements {
+            let other_dots =
+                other.elements.remove(&element).unwrap_or_default();
+            let merged = dots
+                .iter()
+                .filter(|dot| {
+                    other_dots.contains(dot)
+                        || !dot.is_covered_by(&other.context)
+                })
+                .chain(
+                    other_dots
+                        .iter()
+                        .filter(|dot| !dot.is_covered_by(&self.context)),
+                )
+                .copied()
+                .collect::<BTreeSet<_>>();
+            if !merged.is_empty() {
+                elements.insert(element, merged);
+            }
+        }
+        for (element, dots) in other.elements {
+            let dots = dots
+                .into_iter()
+                .filter(|dot| !dot.is_covered_by(&self.context))
+                .collect::<BTreeSet<_>>();
+            if !dots.is_empty() {
+                elements.insert(element, dots);
+            }
+        }
+
+        let mut context = self.context;
+        join(&mut context, &other.context);
+
+        Self { context, elements }
+    }
+
+    fn to_value(&self) -> Result<Value> {
+        let mut elements = Vec::with_capacity(self.elements.len());
+        for element in self.elements() {
+            elements.push(read_value(&mut &element[..])?);
+        }
+        Ok(Value::Array(elements))
+    }
+}
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+struct Register {
+    #[serde(with = "timestamp_nanos")]
+    timestamp: OffsetDateTime,
+
+    /// None when the field was removed.
+    value: Option<Vec<u8>>,
+}
+
+/// A map of last-write-wins registers, each field is resolved by the
+/// timestamp of its last write (ties are broken by the value), removed
+/// fields are kept as tombstones.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Map {
+    /// Encoded field to its register.
+    fields: BTreeMap<Vec<u8>, Register>,
+}
+
+impl Map {
+    pub fn put(
+        &mut self,
+        field: Vec<u8>,
+        value: Option<Vec<u8>>,
+        timestamp: OffsetDateTime,
+    ) {
+        let register = Register { timestamp, value };
+        match self.fields.get_mut(&field) {
+            Some(current) => {
+                if register > *current {
+                    *current = register;
+                }
+            }
+            None => {
+                self.fields.insert(field, register);
+            }
+        }
+    }
+}
+
+impl Crdt for Map {
+    fn merge(mut self, other: Self) -> Self {
+        for (field, register) in other.fields {
+            self.put(field, register.value, register.timestamp);
+        }
+        self
+    }
+
+    fn to_value(&self) -> Result<Value> {
+        let mut fields = Vec::with_capacity(self.fields.len());
+        for (field, register) in &self.fields {
+            if let Some(value) = &register.value {
+                fields.push((
+                    read_value(&mut &field[..])?,
+                    read_value(&mut &value[..])?,
+                ));
+            }
+        }
+        Ok(Value::Map(fields))
+    }
+}
+
+/// An update to a CRDT value, all fields are msgpack encoded.
+#[derive(Debug, Clone)]
+pub enum Operation {
+    /// Add to a counter, negative amounts decrement it.
+    Increment(i64),
+    /// Add an element to a set.
+    Add(Vec<u8>),
+    /// Remove an element from a set.
+    Remove(Vec<u8>),
+    /// Set a field of a map.
+    Put(Vec<u8>, Vec<u8>),
+    /// Remove a field of a map.
+    RemoveField(Vec<u8>),
+}
+
+fn update<T: Crdt>(
+    current: Option<&[u8]>,
+    f: impl FnOnce(&mut T),
+) -> Result<Vec<u8>> {
+    let mut value = match current {
+        Some(data) => decode(data)?,
+        None => T::default(),
+    };
+    f(&mut value);
+    encode(&value)
+}
+
+impl Operation {
+    /// Apply the operation on the current value of a key (None when the key
+    /// doesn't exist yet), coordinated by the `node` shard.
+    pub fn apply(
+        self,
+        current: Option<&[u8]>,
+        node: u32,
+        timestamp: OffsetDateTime,
+    ) -> Result<Vec<u8>> {
+        match self {
+            Self::Increment(amount) => {
+                update(current, |counter: &mut Counter| {
+                    counter.increment(amount, node);
+                })
+            }
+            Self::Add(element) => {
+                update(current, |set: &mut Set| set.add(element, node))
+            }
+            Self::Remove(element) => {
+                update(current, |set: &mut Set| set.remove(&element))
+            }
+            Self::Put(field, value) => update(current, |map: &mut Map| {
+                map.put(field, Some(value), timestamp);
+            }),
+            Self::RemoveField(field) => update(current, |map: &mut Map| {
+                map.put(field, None, timestamp);
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const A: u32 = 1;
+    const B: u32 = 2;
+
+    #[test]
+    fn counter_merges_concurrent_increments() {
+        let mut a = Counter::default();
+        a.increment(5, A);
+        let mut b = a.clone();
+        a.increment(-2, A);
+        b.increment(10, B);
+
+        let merged = a.clone().merge(b.clone());
+        assert_eq!(merged.value(), 13);
+        assert_eq!(merged, b.merge(a.clone()));
+        assert_eq!(merged.clone().merge(a), merged);
+    }
+
+    #[test]
+    fn set_add_wins_over_concurrent_remove() {
+        let mut base = Set::default();
+        base.add(vec![1], A);
+        base.add(vec![2], A);
+
+        let mut a = base.clone();
+        a.remove(&[1]);
+        a.remove(&[2]);
+        let mut b = base.clone();
+        b.add(vec![1], B);
+
+        let merged = a.clone().merge(b.clone());
+        assert_eq!(merged.elements().collect::<Vec<_>>(), vec![&vec![1]]);
+        assert_eq!(merged, b.merge(a));
+
+        // A replica that missed the remove doesn't bring the element back.
+        assert_eq!(merged.clone().merge(base), merged);
+    }
+
+    #[test]
+    fn map_last_write_wins_per_field() {
+        let first = OffsetDateTime::now_utc();
+        let second = first + Duration::from_secs(1);
+
+        let mut a = Map::default();
+        a.put(vec![1], Some(vec![1]), first);
+        a.put(vec![2], Some(vec![2]), second);
+        let mut b = Map::default();
+        b.put(vec![1], Some(vec![3]), second);
+        b.put(vec![2], None, first);
+
+        let merged = a.clone().merge(b.clone());
+        assert_eq!(merged, b.merge(a));
+        assert_eq!(merged.fields[&vec![1]].value, Some(vec![3]));
+        assert_eq!(merged.fields[&vec![2]].value, Some(vec![2]));
+    }
+}
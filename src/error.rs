@@ -57,10 +57,76 @@ pub enum Error {
     CollectionNotFound(String),
     #[error("collection '{0}' already exists")]
     CollectionAlreadyExists(String),
+    #[error("collection '{0}' was dropped after it was created")]
+    CollectionDropped(String),
+    #[error("replication factor must be at least 1, got {0}")]
+    InvalidReplicationFactor(u16),
+    #[error("authentication required")]
+    Unauthenticated,
+    #[error("invalid username or password")]
+    InvalidCredentials,
+    #[error("permission denied on '{0}'")]
+    PermissionDenied(String),
+    #[error("user '{0}' not found")]
+    UserNotFound(String),
+    #[error("user '{0}' already exists")]
+    UserAlreadyExists(String),
+    #[error("hashing password failed: {0}")]
+    PasswordHashError(argon2::password_hash::Error),
+    #[error(transparent)]
+    TlsError(#[from] rustls::Error),
+    #[error("both a TLS certificate and a private key are required")]
+    TlsMissingCertOrKey,
+    #[error("no certificates found in '{0}'")]
+    TlsNoCertificates(String),
+    #[error("no private key found in '{0}'")]
+    TlsNoPrivateKey(String),
+    #[error("'{0}' is not a valid TLS server name")]
+    TlsInvalidServerName(String),
+    #[error("gossip message is unsigned or has an invalid signature")]
+    InvalidGossipSignature,
+    #[error("gossip message is older than the deduplication window")]
+    StaleGossipMessage,
     #[error("item too large")]
     ItemTooLarge,
+    #[error("batch of {0} entries doesn't fit in the memtable")]
+    BatchTooLarge(usize),
+    #[error("merge operand written to a tree without a merge operator")]
+    MergeOperatorNotRegistered,
+    #[error("sstable entries must be sorted by key, one entry per key")]
+    UnsortedSSTableEntries,
+    #[error("sstable format version {0} is newer than supported")]
+    UnsupportedSSTableFormatVersion(u32),
+    #[error("WAL record checksum mismatch")]
+    WalRecordChecksumMismatch,
+    #[error("WAL record has no header")]
+    WalRecordMissingHeader,
+    #[error("WAL format version {0} is newer than supported")]
+    UnsupportedWalFormatVersion(u32),
     #[error("key not found")]
     KeyNotFound,
+    #[error("change cursor {0} expired, changes after it were dropped")]
+    ChangeCursorExpired(u64),
+    #[error("change data capture is disabled")]
+    ChangeLogDisabled,
+    #[error("change log key is not a sequence")]
+    InvalidChangeLogKey,
+    #[error(
+        "watch closed, the client is too slow or the collection was dropped"
+    )]
+    WatchClosed,
+    #[error("transaction condition of operation {0} failed")]
+    TransactionConditionFailed(usize),
+    #[error("transaction keys are owned by more than one shard")]
+    TransactionSpansShards,
+    #[error("condition failed")]
+    ConditionFailed,
+    #[error("not enough replicas responded to reach a paxos quorum")]
+    PaxosQuorumNotReached,
+    #[error(
+        "'{0}' is not supported on collections with '{1}' conflict resolution"
+    )]
+    NotSupportedByConflictResolution(String, &'static str),
     #[error("msgpack decode failed")]
     MsgpackDecodeError(#[from] rmpv::decode::Error),
     #[error("msgpack encode failed")]
@@ -9,6 +9,8 @@ pub enum FlowEvent {
     DeadNodeRemoved,
     AliveNodeGossip,
     CollectionCreated,
+    CollectionAltered,
     DoneMigration,
     ItemSetFromShardMessage,
+    ClusterMetadataReconciled,
 }
@@ -1,40 +1,120 @@
 use bincode::Options;
+use hmac::{Hmac, Mac};
 use kinded::Kinded;
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use time::OffsetDateTime;
 
 use crate::{
-    error::Result, messages::NodeMetadata, utils::bincode::bincode_options,
+    auth::User,
+    error::{Error, Result},
+    messages::NodeMetadata,
+    shards::CollectionMetadata,
+    utils::{bincode::bincode_options, timestamp_nanos},
 };
 
+type HmacSha256 = Hmac<Sha256>;
+
+/// The size of the HMAC-SHA256 signature appended to signed messages.
+const SIGNATURE_SIZE: usize = 32;
+
 #[derive(Serialize, Deserialize, Debug, Clone, Kinded)]
 #[kinded(derive(Hash))]
 pub enum GossipEvent {
     Alive(NodeMetadata),
-    Dead(String),
-    CreateCollection(String, u16),
-    DropCollection(String),
+    /// Node name + the incarnation of the node that was seen dead.
+    Dead(String, u64),
+    CreateCollection(String, CollectionMetadata),
+    AlterCollection(String, CollectionMetadata),
+    /// Drop a collection created before the time.
+    DropCollection(String, OffsetDateTime),
+    /// Delete all data of a collection written up to the time, keeping its
+    /// metadata.
+    TruncateCollection(String, OffsetDateTime),
+    /// Username + the user (None when dropped) + the time of the change.
+    SetUser(String, Option<User>, OffsetDateTime),
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct GossipMessage {
+    /// The unique name of the shard that originated the message.
     pub source: String,
+
+    /// Monotonically increasing per source, (source, sequence) uniquely
+    /// identifies a message, which is used for deduplication.
+    pub sequence: u64,
+
+    /// When the message was created (by the clock of the source), signed
+    /// messages older than the deduplication window are rejected, as a
+    /// replay of them would no longer be recognized.
+    #[serde(with = "timestamp_nanos")]
+    pub timestamp: OffsetDateTime,
+
     pub event: GossipEvent,
 }
 
 impl GossipMessage {
     #[must_use]
-    pub fn new(source: String, event: GossipEvent) -> Self {
-        Self { source, event }
+    pub fn new(
+        source: String,
+        sequence: u64,
+        timestamp: OffsetDateTime,
+        event: GossipEvent,
+    ) -> Self {
+        Self {
+            source,
+            sequence,
+            timestamp,
+            event,
+        }
     }
 }
 
-pub fn deserialize_gossip_message(buf: &[u8]) -> Result<GossipMessage> {
-    Ok(
-        bincode_options()
-            .deserialize_from::<_, GossipMessage>(&mut &buf[..])?,
-    )
+fn create_mac(secret: &str) -> HmacSha256 {
+    HmacSha256::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any size")
 }
 
-pub fn serialize_gossip_message(message: &GossipMessage) -> Result<Vec<u8>> {
-    Ok(bincode_options().serialize(&message)?)
+/// When a secret is given, the message must end with a valid signature.
+pub fn deserialize_gossip_message(
+    buf: &[u8],
+    secret: Option<&str>,
+) -> Result<GossipMessage> {
+    let message_buf = match secret {
+        Some(secret) => {
+            if buf.len() < SIGNATURE_SIZE {
+                return Err(Error::InvalidGossipSignature);
+            }
+            let (message_buf, signature) =
+                buf.split_at(buf.len() - SIGNATURE_SIZE);
+
+            let mut mac = create_mac(secret);
+            mac.update(message_buf);
+            mac.verify_slice(signature)
+                .map_err(|_| Error::InvalidGossipSignature)?;
+
+            message_buf
+        }
+        None => buf,
+    };
+
+    Ok(bincode_options()
+        .deserialize_from::<_, GossipMessage>(&mut &message_buf[..])?)
+}
+
+/// When a secret is given, the message is signed by appending an HMAC of the
+/// serialized message.
+pub fn serialize_gossip_message(
+    message: &GossipMessage,
+    secret: Option<&str>,
+) -> Result<Vec<u8>> {
+    let mut buf = bincode_options().serialize(&message)?;
+
+    if let Some(secret) = secret {
+        let mut mac = create_mac(secret);
+        mac.update(&buf);
+        buf.extend_from_slice(&mac.finalize().into_bytes());
+    }
+
+    Ok(buf)
 }
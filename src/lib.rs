@@ -1,13 +1,21 @@
 pub mod args;
+pub mod auth;
+pub mod cdc;
+pub mod crdt;
 pub mod error;
 pub mod gossip;
 pub mod local_shard;
 pub mod messages;
+pub mod metrics;
+pub mod paxos;
 pub mod remote_shard_connection;
 pub mod run_shard;
 pub mod shards;
+pub mod siblings;
 pub mod storage_engine;
 pub mod tasks;
+pub mod tls;
+pub mod transaction;
 pub mod utils;
 
 #[cfg(feature = "flow-events")]
@@ -3,6 +3,7 @@ use dbeel::{
     error::{Error, Result},
     local_shard::LocalShardConnection,
     run_shard::{create_shard, run_shard},
+    shards::Incarnation,
 };
 use glommio::{enclose, CpuSet, LocalExecutorBuilder, Placement};
 use pretty_env_logger::formatted_timed_builder;
@@ -45,6 +46,8 @@ fn main() -> Result<()> {
         .map(LocalShardConnection::new)
         .collect::<Vec<_>>();
 
+    let incarnation = Incarnation::default();
+
     let handles = cpu_set
         .into_iter()
         .map(|x| x.cpu as u16)
@@ -53,9 +56,18 @@ fn main() -> Result<()> {
             LocalExecutorBuilder::new(Placement::Fixed(cpu as usize))
                 .name(format!("executor({cpu})").as_str())
                 .spawn(enclose!((local_connections.clone() => connections,
+                                incarnation.clone() => incarnation,
                                 args.clone() => args) move || async move {
-                    let shard = create_shard(args, cpu, connections);
-                    if let Err(e) = run_shard(shard, i == 0).await {
+                    let result = match create_shard(
+                        args,
+                        cpu,
+                        connections,
+                        incarnation,
+                    ) {
+                        Ok(shard) => run_shard(shard, i == 0).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = result {
                         error!("Failed to start shard {}: {}", cpu, e);
                     }
                 }))
@@ -3,27 +3,58 @@ use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
 
 use crate::{
+    auth::User,
     error::{Error, ErrorKind},
     gossip::GossipEvent,
+    metrics::{
+        CollectionShardStats, CollectionSummary, ShardStats, ShardStatus,
+    },
+    paxos::{Ballot, PaxosState, Promise, Proposal},
+    shards::{ClusterMetadata, CollectionMetadata},
     storage_engine::EntryValue,
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ShardEvent {
-    Gossip(GossipEvent),
-    Set(String, Vec<u8>, Vec<u8>, OffsetDateTime),
+    /// A gossip event + the timestamp of its gossip message, observed by the
+    /// clock of every shard.
+    Gossip(GossipEvent, OffsetDateTime),
+    /// A migrated entry (None deletes the key).
+    Set(String, Vec<u8>, Option<Vec<u8>>, OffsetDateTime),
+    /// The paxos state of a migrated key.
+    PaxosState(String, Vec<u8>, PaxosState),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ShardRequest {
     Ping,
+    /// Push our cluster metadata, pull the remote's (anti entropy).
+    SyncMetadata(ClusterMetadata),
     GetMetadata,
     GetCollections,
-    CreateCollection(String, u16),
-    DropCollection(String),
+    GetUsers,
+    GetStats,
+    GetStatus,
+    GetCollectionStats(String),
+    GetCollectionSummaries,
+    GetCollectionCount(String),
+    FlushCollection(String),
+    CompactCollection(String),
+    CreateCollection(String, CollectionMetadata),
+    AlterCollection(String, CollectionMetadata),
+    DropCollection(String, OffsetDateTime),
+    TruncateCollection(String, OffsetDateTime),
+    /// Collection name and the directory with the sstables of each shard.
+    Ingest(String, String),
     Set(String, Vec<u8>, Vec<u8>, OffsetDateTime),
     Delete(String, Vec<u8>, OffsetDateTime),
+    /// The writes of a transaction (None deletes a key), applied atomically
+    /// on a replica.
+    Transaction(String, Vec<(Vec<u8>, Option<Vec<u8>>)>, OffsetDateTime),
     Get(String, Vec<u8>),
+    PaxosPrepare(String, Vec<u8>, Ballot),
+    PaxosPropose(String, Vec<u8>, Proposal),
+    PaxosCommit(String, Vec<u8>, Proposal),
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
@@ -34,18 +65,37 @@ pub struct NodeMetadata {
     pub ids: Vec<u16>,
     pub gossip_port: u16,
     pub db_port: u16,
+    /// Bumped every time the node starts, or refutes a suspicion of its
+    /// death, used to resolve conflicting membership gossip events.
+    pub incarnation: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ShardResponse {
     Pong,
+    SyncMetadata(ClusterMetadata),
     GetMetadata(Vec<NodeMetadata>),
-    GetCollections(Vec<(String, u16)>),
+    GetCollections(Vec<(String, CollectionMetadata)>),
+    GetUsers(Vec<(String, Option<User>, OffsetDateTime)>),
+    GetStats(Box<ShardStats>),
+    GetStatus(ShardStatus),
+    GetCollectionStats(CollectionShardStats),
+    GetCollectionSummaries(Vec<CollectionSummary>),
+    GetCollectionCount(u64),
+    FlushCollection,
+    CompactCollection,
     CreateCollection,
+    AlterCollection,
     DropCollection,
+    TruncateCollection,
+    Ingest(u64),
     Set,
     Delete,
+    Transaction,
     Get(Option<EntryValue>),
+    PaxosPrepare(Promise),
+    PaxosPropose(bool),
+    PaxosCommit,
     Error(ErrorKind, String),
 }
 
@@ -0,0 +1,501 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt::{Display, Write},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::shards::CollectionMetadata;
+
+/// The upper bounds (in seconds) of the buckets of all duration histograms.
+pub const DURATION_BUCKETS: [f64; 14] = [
+    0.0001, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5,
+    1.0, 5.0, 30.0,
+];
+
+/// A histogram of durations, bucketed by DURATION_BUCKETS.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Histogram {
+    /// The count of observations in each bucket (not cumulative), the last
+    /// bucket is for observations larger than all bounds (+Inf).
+    pub buckets: Vec<u64>,
+
+    /// The sum of all observations, in seconds.
+    pub sum: f64,
+
+    /// The number of observations.
+    pub count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: vec![0; DURATION_BUCKETS.len() + 1],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let index = DURATION_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        self.buckets[index] += 1;
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RequestStats {
+    /// Number of requests that resulted in an error.
+    pub errors: u64,
+
+    /// The time it took to handle the requests (the count of requests is the
+    /// histogram count).
+    pub duration: Histogram,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CollectionStats {
+    pub memtable_entries: u64,
+
+    /// The size of the memtable as it is written in the WAL.
+    pub memtable_bytes: u64,
+
+    pub sstables: u64,
+    pub page_cache_hits: u64,
+    pub page_cache_misses: u64,
+    pub flush_duration: Histogram,
+    pub compaction_duration: Histogram,
+}
+
+/// A snapshot of the metrics of a shard, returned by ShardRequest::GetStats.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ShardStats {
+    pub shard_name: String,
+
+    /// Key is the request type.
+    pub requests: HashMap<String, RequestStats>,
+
+    /// Key is the collection name.
+    pub collections: HashMap<String, CollectionStats>,
+
+    pub gossip_packets_received: u64,
+    pub gossip_packets_sent: u64,
+    pub rejected_gossip_packets: u64,
+    pub running_migrations: u64,
+    pub migrated_entries: u64,
+    pub migration_deleted_entries: u64,
+}
+
+/// The stats of a collection in a single shard, returned by the
+/// collection_stats request.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CollectionShardStats {
+    pub shard_name: String,
+
+    /// The index and size (in bytes) of every sstable.
+    pub sstables: Vec<(usize, u64)>,
+
+    pub memtable_entries: u64,
+
+    /// The number of entries that trigger a flush when reached.
+    pub memtable_capacity: u64,
+
+    pub wal_bytes: u64,
+}
+
+/// A collection and its approximate size in a node, returned by the
+/// list_collections request (the client sums it up across all nodes).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CollectionSummary {
+    pub name: String,
+    pub metadata: CollectionMetadata,
+
+    /// Counts every replica of a document.
+    pub approximate_documents: u64,
+
+    pub disk_bytes: u64,
+}
+
+/// Sum up the sizes of the collections in `other` into the summaries of the
+/// same collections.
+pub fn merge_collection_summaries(
+    summaries: &mut Vec<CollectionSummary>,
+    other: Vec<CollectionSummary>,
+) {
+    for summary in other {
+        match summaries.iter_mut().find(|s| s.name == summary.name) {
+            Some(existing) => {
+                existing.approximate_documents += summary.approximate_documents;
+                existing.disk_bytes += summary.disk_bytes;
+            }
+            None => summaries.push(summary),
+        }
+    }
+}
+
+/// The placement of a shard on the consistent hash ring.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ShardStatus {
+    pub shard_name: String,
+
+    /// The position of the shard on the ring.
+    pub hash: u32,
+
+    /// The [start, end) hash ranges owned by the shard, wrapping around when
+    /// end < start, a range where start == end is the whole ring.
+    /// The first range is where the shard is the primary owner, the rest are
+    /// where it holds replicas (index is the replica index).
+    pub owned_ranges: Vec<(u32, u32)>,
+
+    pub running_migrations: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KnownNode {
+    pub name: String,
+    pub incarnation: u64,
+    pub alive: bool,
+}
+
+/// The status of a node, returned by the node_status request.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NodeStatus {
+    pub name: String,
+    pub incarnation: u64,
+    pub shards: Vec<ShardStatus>,
+
+    /// All other nodes this node knows of, both alive and dead.
+    pub nodes: Vec<KnownNode>,
+}
+
+/// The live metrics of a shard (collection metrics are kept in the trees).
+#[derive(Default)]
+pub struct ShardMetrics {
+    pub requests: RefCell<HashMap<String, RequestStats>>,
+    pub gossip_packets_received: Cell<u64>,
+    pub gossip_packets_sent: Cell<u64>,
+
+    /// Gossip packets that were rejected, either because they are malformed
+    /// or not signed with the gossip secret.
+    pub rejected_gossip_packets: Cell<u64>,
+
+    pub running_migrations: Cell<u64>,
+
+    /// Entries sent to other shards by migrations.
+    pub migrated_entries: Cell<u64>,
+
+    /// Entries deleted by migrations, as the shard no longer owns them.
+    pub migration_deleted_entries: Cell<u64>,
+}
+
+pub fn increment(counter: &Cell<u64>, amount: u64) {
+    counter.set(counter.get() + amount);
+}
+
+pub fn decrement(counter: &Cell<u64>, amount: u64) {
+    counter.set(counter.get().saturating_sub(amount));
+}
+
+impl ShardMetrics {
+    pub fn record_request(
+        &self,
+        request_type: &str,
+        duration: Duration,
+        is_error: bool,
+    ) {
+        let mut requests = self.requests.borrow_mut();
+        let stats = requests.entry(request_type.to_string()).or_default();
+        stats.duration.observe(duration);
+        if is_error {
+            stats.errors += 1;
+        }
+    }
+
+    #[must_use]
+    pub fn snapshot(
+        &self,
+        shard_name: String,
+        collections: HashMap<String, CollectionStats>,
+    ) -> ShardStats {
+        ShardStats {
+            shard_name,
+            requests: self.requests.borrow().clone(),
+            collections,
+            gossip_packets_received: self.gossip_packets_received.get(),
+            gossip_packets_sent: self.gossip_packets_sent.get(),
+            rejected_gossip_packets: self.rejected_gossip_packets.get(),
+            running_migrations: self.running_migrations.get(),
+            migrated_entries: self.migrated_entries.get(),
+            migration_deleted_entries: self.migration_deleted_entries.get(),
+        }
+    }
+}
+
+/// Writes metrics in the prometheus text exposition format.
+struct MetricsWriter {
+    output: String,
+}
+
+impl MetricsWriter {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.output, "# HELP {name} {help}");
+        let _ = writeln!(self.output, "# TYPE {name} {kind}");
+    }
+
+    fn sample(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        value: impl Display,
+    ) {
+        let labels = labels
+            .iter()
+            .map(|(key, value)| format!("{key}=\"{}\"", escape(value)))
+            .collect::<Vec<_>>()
+            .join(",");
+        let _ = writeln!(self.output, "{name}{{{labels}}} {value}");
+    }
+
+    fn histogram(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        histogram: &Histogram,
+    ) {
+        let bucket_name = format!("{name}_bucket");
+        let mut cumulative = 0;
+        for (i, count) in histogram.buckets.iter().enumerate() {
+            cumulative += count;
+            let bound = DURATION_BUCKETS
+                .get(i)
+                .map_or_else(|| "+Inf".to_string(), ToString::to_string);
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(("le", bound.as_str()));
+            self.sample(&bucket_name, &bucket_labels, cumulative);
+        }
+        self.sample(&format!("{name}_sum"), labels, histogram.sum);
+        self.sample(&format!("{name}_count"), labels, histogram.count);
+    }
+
+    fn shard_values(
+        &mut self,
+        name: &str,
+        kind: &str,
+        help: &str,
+        stats: &[ShardStats],
+        value_fn: impl Fn(&ShardStats) -> u64,
+    ) {
+        self.header(name, kind, help);
+        for shard in stats {
+            self.sample(name, &[("shard", &shard.shard_name)], value_fn(shard));
+        }
+    }
+
+    fn collection_values(
+        &mut self,
+        name: &str,
+        kind: &str,
+        help: &str,
+        stats: &[ShardStats],
+        value_fn: impl Fn(&CollectionStats) -> u64,
+    ) {
+        self.header(name, kind, help);
+        for shard in stats {
+            for (collection_name, collection) in &shard.collections {
+                self.sample(
+                    name,
+                    &[
+                        ("shard", &shard.shard_name),
+                        ("collection", collection_name),
+                    ],
+                    value_fn(collection),
+                );
+            }
+        }
+    }
+
+    fn collection_histograms(
+        &mut self,
+        name: &str,
+        help: &str,
+        stats: &[ShardStats],
+        histogram_fn: impl Fn(&CollectionStats) -> &Histogram,
+    ) {
+        self.header(name, "histogram", help);
+        for shard in stats {
+            for (collection_name, collection) in &shard.collections {
+                self.histogram(
+                    name,
+                    &[
+                        ("shard", &shard.shard_name),
+                        ("collection", collection_name),
+                    ],
+                    histogram_fn(collection),
+                );
+            }
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Render the stats of all shards in the prometheus text format.
+#[must_use]
+pub fn render_metrics(stats: &[ShardStats]) -> String {
+    let mut writer = MetricsWriter {
+        output: String::new(),
+    };
+
+    writer.header(
+        "dbeel_requests_total",
+        "counter",
+        "Number of client requests handled.",
+    );
+    for shard in stats {
+        for (request_type, request) in &shard.requests {
+            writer.sample(
+                "dbeel_requests_total",
+                &[("shard", &shard.shard_name), ("type", request_type)],
+                request.duration.count,
+            );
+        }
+    }
+
+    writer.header(
+        "dbeel_request_errors_total",
+        "counter",
+        "Number of client requests that resulted in an error.",
+    );
+    for shard in stats {
+        for (request_type, request) in &shard.requests {
+            writer.sample(
+                "dbeel_request_errors_total",
+                &[("shard", &shard.shard_name), ("type", request_type)],
+                request.errors,
+            );
+        }
+    }
+
+    writer.header(
+        "dbeel_request_duration_seconds",
+        "histogram",
+        "Time it took to handle client requests.",
+    );
+    for shard in stats {
+        for (request_type, request) in &shard.requests {
+            writer.histogram(
+                "dbeel_request_duration_seconds",
+                &[("shard", &shard.shard_name), ("type", request_type)],
+                &request.duration,
+            );
+        }
+    }
+
+    writer.collection_values(
+        "dbeel_memtable_entries",
+        "gauge",
+        "Number of entries in the active memtable.",
+        stats,
+        |c| c.memtable_entries,
+    );
+    writer.collection_values(
+        "dbeel_memtable_bytes",
+        "gauge",
+        "Size of the active memtable, as written in the WAL.",
+        stats,
+        |c| c.memtable_bytes,
+    );
+    writer.collection_values(
+        "dbeel_sstables",
+        "gauge",
+        "Number of sstables.",
+        stats,
+        |c| c.sstables,
+    );
+    writer.collection_values(
+        "dbeel_page_cache_hits_total",
+        "counter",
+        "Number of page reads served from the page cache.",
+        stats,
+        |c| c.page_cache_hits,
+    );
+    writer.collection_values(
+        "dbeel_page_cache_misses_total",
+        "counter",
+        "Number of page reads that missed the page cache.",
+        stats,
+        |c| c.page_cache_misses,
+    );
+    writer.collection_histograms(
+        "dbeel_flush_duration_seconds",
+        "Time it took to flush a memtable to an sstable.",
+        stats,
+        |c| &c.flush_duration,
+    );
+    writer.collection_histograms(
+        "dbeel_compaction_duration_seconds",
+        "Time it took to compact sstables.",
+        stats,
+        |c| &c.compaction_duration,
+    );
+
+    writer.shard_values(
+        "dbeel_gossip_packets_received_total",
+        "counter",
+        "Number of gossip packets received.",
+        stats,
+        |s| s.gossip_packets_received,
+    );
+    writer.shard_values(
+        "dbeel_gossip_packets_sent_total",
+        "counter",
+        "Number of gossip packets sent.",
+        stats,
+        |s| s.gossip_packets_sent,
+    );
+    writer.shard_values(
+        "dbeel_gossip_packets_rejected_total",
+        "counter",
+        "Number of gossip packets rejected for being malformed or having an \
+         invalid signature.",
+        stats,
+        |s| s.rejected_gossip_packets,
+    );
+    writer.shard_values(
+        "dbeel_running_migrations",
+        "gauge",
+        "Number of collection migrations currently running.",
+        stats,
+        |s| s.running_migrations,
+    );
+    writer.shard_values(
+        "dbeel_migrated_entries_total",
+        "counter",
+        "Number of entries sent to other shards by migrations.",
+        stats,
+        |s| s.migrated_entries,
+    );
+    writer.shard_values(
+        "dbeel_migration_deleted_entries_total",
+        "counter",
+        "Number of entries deleted by migrations.",
+        stats,
+        |s| s.migration_deleted_entries,
+    );
+
+    writer.output
+}
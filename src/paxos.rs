@@ -0,0 +1,398 @@
+//! Lightweight transactions: linearizable conditional writes to a single key,
+//! agreed on by the replicas of the key using Paxos (like Cassandra LWT).
+//!
+//! A round is:
+//!   1. Prepare / promise - a quorum of replicas promise to not accept
+//!      proposals with an older ballot, and respond with their value and the
+//!      proposal they accepted, if any.
+//!   2. An accepted proposal that was not committed is committed first, and
+//!      the round is restarted.
+//!   3. The condition is checked on the newest value of the quorum.
+//!   4. Propose / accept - a quorum of replicas accept the new value.
+//!   5. Commit - the value is written to the replicas.
+//!
+//! A round that is rejected by a newer ballot is retried after a random
+//! backoff.
+
+use std::{rc::Rc, time::Duration};
+
+use futures::future::try_join;
+use glommio::timer::sleep;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{
+    error::{Error, Result},
+    messages::{ShardRequest, ShardResponse},
+    response_to_empty_result, response_to_result,
+    shards::MyShard,
+    storage_engine::EntryValue,
+    transaction::Operation,
+};
+
+/// The max time to wait before retrying a round that was rejected.
+const MAX_BACKOFF: Duration = Duration::from_millis(50);
+
+/// The paxos state of a key that was not written for this long is dropped,
+/// and rounds with older ballots are rejected, so a round must finish (or be
+/// finished by a newer round) within it.
+pub const PAXOS_STATE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Identifies a round, newer rounds have greater ballots.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub struct Ballot {
+    pub timestamp: OffsetDateTime,
+
+    /// The hash of the coordinating shard, breaks ties between shards.
+    pub shard_hash: u32,
+}
+
+/// A value proposed in a round (None deletes the key).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Proposal {
+    pub ballot: Ballot,
+    pub value: Option<Vec<u8>>,
+}
+
+/// The paxos state of a key in a replica.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PaxosState {
+    /// The newest ballot the replica promised to not accept older ones of.
+    pub promised: Option<Ballot>,
+
+    /// The newest accepted proposal that was not committed yet.
+    pub accepted: Option<Proposal>,
+
+    /// The ballot of the newest committed proposal.
+    pub committed: Option<Ballot>,
+}
+
+impl PaxosState {
+    /// Merge the state of the key in another replica, a new replica of a key
+    /// must not forget the promises and proposals of the replica it replaces.
+    #[must_use]
+    pub fn merge(self, other: Self) -> Self {
+        let committed = self.committed.max(other.committed);
+        Self {
+            promised: self.promised.max(other.promised),
+            accepted: self
+                .accepted
+                .into_iter()
+                .chain(other.accepted)
+                .max_by_key(|p| p.ballot)
+                .filter(|p| committed < Some(p.ballot)),
+            committed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Promise {
+    Promised {
+        accepted: Option<Proposal>,
+        committed: Option<Ballot>,
+
+        /// The current value of the key in the replica.
+        value: Option<EntryValue>,
+    },
+
+    /// A newer ballot was already promised.
+    Rejected(Ballot),
+}
+
+/// The responses of a quorum of replicas to a prepare request.
+struct Prepared {
+    /// An accepted proposal, newer than any committed one.
+    in_progress: Option<Proposal>,
+
+    /// The newest value of the key.
+    value: Option<Vec<u8>>,
+}
+
+struct Replicas {
+    collection: String,
+    key: Vec<u8>,
+
+    /// Number of replicas that have to respond to a request, including the
+    /// coordinating shard.
+    quorum: usize,
+
+    /// Number of other nodes holding a replica of the key.
+    remote_nodes: usize,
+}
+
+impl Replicas {
+    /// Send a request to the local replica and the remote replicas, returns
+    /// the responses of a quorum of replicas.
+    async fn send<F, L, T>(
+        &self,
+        my_shard: &Rc<MyShard>,
+        local_future: L,
+        request: ShardRequest,
+        response_map_fn: F,
+    ) -> Result<Vec<T>>
+    where
+        F: Fn(ShardResponse) -> Result<T> + 'static,
+        L: std::future::Future<Output = Result<T>>,
+        T: 'static,
+    {
+        let remote_future = my_shard.clone().send_request_to_replicas(
+            request,
+            self.quorum - 1,
+            self.remote_nodes,
+            response_map_fn,
+        );
+        let (local, mut responses) =
+            try_join(local_future, remote_future).await?;
+
+        if responses.len() < self.quorum - 1 {
+            return Err(Error::PaxosQuorumNotReached);
+        }
+
+        responses.push(local);
+        Ok(responses)
+    }
+
+    /// Returns None when the ballot was rejected.
+    async fn prepare(
+        &self,
+        my_shard: &Rc<MyShard>,
+        ballot: Ballot,
+    ) -> Result<Option<Prepared>> {
+        let promises = self
+            .send(
+                my_shard,
+                my_shard.paxos_prepare(&self.collection, &self.key, ballot),
+                ShardRequest::PaxosPrepare(
+                    self.collection.clone(),
+                    self.key.clone(),
+                    ballot,
+                ),
+                |res| response_to_result!(res, ShardResponse::PaxosPrepare),
+            )
+            .await?;
+
+        let mut in_progress: Option<Proposal> = None;
+        let mut committed = None;
+        let mut value: Option<EntryValue> = None;
+        for promise in promises {
+            match promise {
+                Promise::Promised {
+                    accepted,
+                    committed: promise_committed,
+                    value: promise_value,
+                } => {
+                    committed = committed.max(promise_committed);
+                    in_progress = in_progress
+                        .into_iter()
+                        .chain(accepted)
+                        .max_by_key(|p| p.ballot);
+                    value = value
+                        .into_iter()
+                        .chain(promise_value)
+                        .max_by_key(|v| v.timestamp);
+                }
+                Promise::Rejected(promised) => {
+                    my_shard.clock.update(promised.timestamp);
+                    return Ok(None);
+                }
+            }
+        }
+
+        Ok(Some(Prepared {
+            // A proposal older than a committed one was already overridden.
+            in_progress: in_progress.filter(|p| committed < Some(p.ballot)),
+            value: value.and_then(EntryValue::into_data),
+        }))
+    }
+
+    /// Returns whether a quorum accepted the proposal.
+    async fn propose(
+        &self,
+        my_shard: &Rc<MyShard>,
+        proposal: &Proposal,
+    ) -> Result<bool> {
+        let accepts = self
+            .send(
+                my_shard,
+                my_shard.paxos_propose(
+                    &self.collection,
+                    &self.key,
+                    proposal.clone(),
+                ),
+                ShardRequest::PaxosPropose(
+                    self.collection.clone(),
+                    self.key.clone(),
+                    proposal.clone(),
+                ),
+                |res| response_to_result!(res, ShardResponse::PaxosPropose),
+            )
+            .await?;
+        Ok(accepts.into_iter().all(|accepted| accepted))
+    }
+
+    async fn commit(
+        &self,
+        my_shard: &Rc<MyShard>,
+        proposal: Proposal,
+    ) -> Result<()> {
+        self.send(
+            my_shard,
+            my_shard.paxos_commit(
+                &self.collection,
+                self.key.clone(),
+                proposal.clone(),
+            ),
+            ShardRequest::PaxosCommit(
+                self.collection.clone(),
+                self.key.clone(),
+                proposal,
+            ),
+            |res| response_to_empty_result!(res, ShardResponse::PaxosCommit),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+async fn backoff() {
+    let max = MAX_BACKOFF.as_micros() as u64;
+    sleep(Duration::from_micros(thread_rng().gen_range(0..max))).await;
+}
+
+/// Apply the write of the operation only if its condition passes, as a
+/// single linearizable step among all lightweight transactions on the key.
+/// An operation without a write only checks the condition.
+pub async fn compare_and_set(
+    my_shard: Rc<MyShard>,
+    collection: String,
+    operation: Operation,
+    replica_index: u16,
+) -> Result<()> {
+    let metadata = my_shard.get_collection(&collection)?.metadata;
+    metadata.conflict_resolution.check_supports("cas")?;
+
+    let replications = metadata.replication_factor;
+    let quorum = replications as usize / 2 + 1;
+    let remote_nodes = (replications - replica_index) as usize - 1;
+    if remote_nodes + 1 < quorum {
+        return Err(Error::PaxosQuorumNotReached);
+    }
+
+    let replicas = Replicas {
+        collection,
+        key: operation.key,
+        quorum,
+        remote_nodes,
+    };
+
+    loop {
+        let ballot = my_shard.next_paxos_ballot();
+
+        let prepared = match replicas.prepare(&my_shard, ballot).await? {
+            Some(prepared) => prepared,
+            None => {
+                backoff().await;
+                continue;
+            }
+        };
+
+        if let Some(in_progress) = prepared.in_progress {
+            // A previous round might have been accepted by a quorum, finish
+            // it before starting a new one.
+            let proposal = Proposal {
+                ballot,
+                value: in_progress.value,
+            };
+            if replicas.propose(&my_shard, &proposal).await? {
+                replicas.commit(&my_shard, proposal).await?;
+            } else {
+                backoff().await;
+            }
+            continue;
+        }
+
+        if let Some(condition) = &operation.condition {
+            if !condition.matches(prepared.value.as_deref()) {
+                return Err(Error::ConditionFailed);
+            }
+        }
+
+        let value = match &operation.write {
+            Some(write) => write.clone().into_value(),
+            None => return Ok(()),
+        };
+
+        let proposal = Proposal { ballot, value };
+        if !replicas.propose(&my_shard, &proposal).await? {
+            backoff().await;
+            continue;
+        }
+
+        return replicas.commit(&my_shard, proposal).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ballot(seconds: u64, shard_hash: u32) -> Ballot {
+        Ballot {
+            timestamp: OffsetDateTime::UNIX_EPOCH
+                + Duration::from_secs(seconds),
+            shard_hash,
+        }
+    }
+
+    fn proposal(seconds: u64, value: u8) -> Proposal {
+        Proposal {
+            ballot: ballot(seconds, 0),
+            value: Some(vec![value]),
+        }
+    }
+
+    #[test]
+    fn paxos_state_merge_keeps_newest() {
+        let a = PaxosState {
+            promised: Some(ballot(3, 0)),
+            accepted: Some(proposal(2, 1)),
+            committed: Some(ballot(1, 0)),
+        };
+        let b = PaxosState {
+            promised: Some(ballot(3, 1)),
+            accepted: None,
+            committed: None,
+        };
+
+        let merged = a.clone().merge(b.clone());
+        assert_eq!(merged.promised, Some(ballot(3, 1)));
+        assert_eq!(merged.accepted.map(|p| p.value), Some(Some(vec![1])));
+        assert_eq!(merged.committed, Some(ballot(1, 0)));
+
+        let merged = b.merge(a);
+        assert_eq!(merged.promised, Some(ballot(3, 1)));
+        assert_eq!(merged.accepted.map(|p| p.value), Some(Some(vec![1])));
+    }
+
+    #[test]
+    fn paxos_state_merge_drops_overridden_proposal() {
+        let a = PaxosState {
+            promised: Some(ballot(2, 0)),
+            accepted: Some(proposal(2, 1)),
+            committed: None,
+        };
+        let b = PaxosState {
+            promised: Some(ballot(4, 0)),
+            accepted: None,
+            committed: Some(ballot(4, 0)),
+        };
+
+        let merged = a.merge(b);
+        assert!(merged.accepted.is_none());
+        assert_eq!(merged.committed, Some(ballot(4, 0)));
+    }
+}
@@ -1,35 +1,53 @@
-use std::time::Duration;
+use std::{fmt, time::Duration};
 
 use bincode::Options;
 use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures_rustls::TlsConnector;
 use glommio::net::TcpStream;
+use time::OffsetDateTime;
 
 use crate::{
     args::Args,
+    auth::User,
     error::{Error, Result},
     messages::{NodeMetadata, ShardMessage, ShardRequest, ShardResponse},
     response_to_empty_result, response_to_result,
+    shards::{ClusterMetadata, CollectionMetadata},
+    tls::{server_name_from_address, RemoteShardStream, Tls},
     utils::bincode::bincode_options,
 };
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct RemoteShardConnection {
     // The address to use to connect to the remote shard.
     pub address: String,
     connect_timeout: Duration,
     write_timeout: Duration,
     read_timeout: Duration,
+    tls_connector: Option<TlsConnector>,
+}
+
+impl fmt::Debug for RemoteShardConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RemoteShardConnection")
+            .field("address", &self.address)
+            .field("tls", &self.tls_connector.is_some())
+            .finish()
+    }
 }
 
 impl RemoteShardConnection {
     #[must_use]
-    pub fn from_args(address: String, args: &Args) -> Self {
-        Self::new(
+    pub fn from_args(address: String, args: &Args, tls: Option<&Tls>) -> Self {
+        let mut connection = Self::new(
             address,
             Duration::from_millis(args.remote_shard_connect_timeout),
             Duration::from_millis(args.remote_shard_write_timeout),
             Duration::from_millis(args.remote_shard_read_timeout),
-        )
+        );
+        connection.tls_connector =
+            tls.map(|tls| tls.remote_shard_connector.clone());
+        connection
     }
 
     #[must_use]
@@ -44,16 +62,26 @@ impl RemoteShardConnection {
             connect_timeout,
             write_timeout,
             read_timeout,
+            tls_connector: None,
         }
     }
 
-    pub async fn connect(&self) -> Result<TcpStream> {
+    pub async fn connect(&self) -> Result<RemoteShardStream> {
         let stream =
             TcpStream::connect_timeout(&self.address, self.connect_timeout)
                 .await?;
         stream.set_write_timeout(Some(self.write_timeout))?;
         stream.set_read_timeout(Some(self.read_timeout))?;
-        Ok(stream)
+
+        Ok(match &self.tls_connector {
+            Some(connector) => {
+                let server_name = server_name_from_address(&self.address)?;
+                RemoteShardStream::Tls(Box::new(
+                    connector.connect(server_name, stream).await?,
+                ))
+            }
+            None => RemoteShardStream::Plain(stream),
+        })
     }
 
     pub async fn send_request(
@@ -78,6 +106,17 @@ impl RemoteShardConnection {
         )
     }
 
+    pub async fn sync_metadata(
+        &self,
+        metadata: ClusterMetadata,
+    ) -> Result<ClusterMetadata> {
+        response_to_result!(
+            self.send_request(ShardRequest::SyncMetadata(metadata))
+                .await?,
+            ShardResponse::SyncMetadata
+        )
+    }
+
     pub async fn get_metadata(&self) -> Result<Vec<NodeMetadata>> {
         response_to_result!(
             self.send_request(ShardRequest::GetMetadata).await?,
@@ -85,12 +124,23 @@ impl RemoteShardConnection {
         )
     }
 
-    pub async fn get_collections(&self) -> Result<Vec<(String, u16)>> {
+    pub async fn get_collections(
+        &self,
+    ) -> Result<Vec<(String, CollectionMetadata)>> {
         response_to_result!(
             self.send_request(ShardRequest::GetCollections).await?,
             ShardResponse::GetCollections
         )
     }
+
+    pub async fn get_users(
+        &self,
+    ) -> Result<Vec<(String, Option<User>, OffsetDateTime)>> {
+        response_to_result!(
+            self.send_request(ShardRequest::GetUsers).await?,
+            ShardResponse::GetUsers
+        )
+    }
 }
 
 pub async fn get_message_from_stream(
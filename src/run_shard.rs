@@ -1,32 +1,38 @@
 use crate::{
     args::Args,
+    auth::{Permission, User, ALL_COLLECTIONS, USERS_COLLECTION},
     error::{Error, Result},
     gossip::GossipEvent,
     local_shard::LocalShardConnection,
     messages::NodeMetadata,
     notify_flow_event,
     remote_shard_connection::RemoteShardConnection,
-    shards::{MyShard, Shard, ShardConnection},
+    shards::{
+        CollectionMetadata, Incarnation, MyShard, Shard, ShardConnection,
+    },
     storage_engine::page_cache::{PageCache, PAGE_SIZE},
     tasks::{
+        anti_entropy::spawn_anti_entropy_task,
         compaction::spawn_compaction_task, db_server::spawn_db_server_task,
         failure_detector::spawn_failure_detector_task,
         gossip_server::spawn_gossip_server_task,
         local_shard_server::spawn_local_shard_server_task,
+        metrics_server::spawn_metrics_server_task,
         remote_shard_server::spawn_remote_shard_server_task,
         stop_event_waiter::spawn_stop_event_waiter_task,
     },
 };
 use futures::future::try_join_all;
 use log::{error, info, trace};
-use std::rc::Rc;
+use std::{collections::HashMap, rc::Rc};
+use time::OffsetDateTime;
 
 #[cfg(feature = "flow-events")]
 use crate::flow_events::FlowEvent;
 
 async fn get_collections(
     seed_shards: &[RemoteShardConnection],
-) -> Option<Vec<(String, u16)>> {
+) -> Option<Vec<(String, CollectionMetadata)>> {
     for c in seed_shards {
         match c.get_collections().await {
             Ok(collections) => return Some(collections),
@@ -43,18 +49,30 @@ async fn discover_collections(
     my_shard: &MyShard,
     seed_shards: &[RemoteShardConnection],
 ) -> Result<()> {
+    my_shard.load_dropped_collections().await?;
+
+    // Collections (on disk or known by a seed node) that were dropped after
+    // they were created are skipped.
     for (collection, metadata) in my_shard.get_collections_from_disk().await? {
-        my_shard
-            .create_collection(collection, metadata.replication_factor)
-            .await?;
+        match my_shard
+            .create_collection_with_metadata(collection, metadata)
+            .await
+        {
+            Ok(()) | Err(Error::CollectionDropped(_)) => {}
+            Err(e) => return Err(e),
+        }
     }
 
     if let Some(collections) = get_collections(seed_shards).await {
-        for (collection, replication_factor) in collections {
+        for (collection, metadata) in collections {
             if !my_shard.collections.borrow().contains_key(&collection) {
-                my_shard
-                    .create_collection(collection, replication_factor)
-                    .await?;
+                match my_shard
+                    .create_collection_with_metadata(collection, metadata)
+                    .await
+                {
+                    Ok(()) | Err(Error::CollectionDropped(_)) => {}
+                    Err(e) => return Err(e),
+                }
             }
         }
     }
@@ -62,6 +80,64 @@ async fn discover_collections(
     Ok(())
 }
 
+async fn get_users(
+    seed_shards: &[RemoteShardConnection],
+) -> Option<Vec<(String, Option<User>, OffsetDateTime)>> {
+    for c in seed_shards {
+        match c.get_users().await {
+            Ok(users) => return Some(users),
+            Err(e) => {
+                error!("Failed to get users from '{}': {}", c.address, e);
+            }
+        }
+    }
+
+    None
+}
+
+async fn discover_users(
+    my_shard: &MyShard,
+    seed_shards: &[RemoteShardConnection],
+) -> Result<()> {
+    if !my_shard.args.auth {
+        return Ok(());
+    }
+
+    // Users are replicated to all shards, there is no need for the
+    // replication factor to be greater than 1.
+    if !my_shard.collections.borrow().contains_key(USERS_COLLECTION) {
+        my_shard
+            .create_collection(USERS_COLLECTION.to_string(), 1)
+            .await?;
+    }
+
+    if let Some(users) = get_users(seed_shards).await {
+        for (username, user, timestamp) in users {
+            my_shard.set_user(username, user, timestamp).await?;
+        }
+    }
+
+    if let Some(password) = &my_shard.args.root_password {
+        // The oldest possible change, so it never overrides a change made to
+        // the root user.
+        my_shard
+            .set_user(
+                my_shard.args.root_username.clone(),
+                Some(User::new(
+                    password,
+                    HashMap::from([(
+                        ALL_COLLECTIONS.to_string(),
+                        Permission::Admin,
+                    )]),
+                )?),
+                OffsetDateTime::UNIX_EPOCH,
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
 async fn get_nodes_metadata(
     seed_shards: &[RemoteShardConnection],
 ) -> Option<Vec<NodeMetadata>> {
@@ -118,12 +194,20 @@ pub async fn run_shard(
         .seed_nodes
         .iter()
         .map(|seed_node| {
-            RemoteShardConnection::from_args(seed_node.clone(), &my_shard.args)
+            RemoteShardConnection::from_args(
+                seed_node.clone(),
+                &my_shard.args,
+                my_shard.tls.as_ref(),
+            )
         })
         .collect::<Vec<_>>();
     discover_collections(&my_shard, remote_shard_connections).await?;
+    discover_users(&my_shard, remote_shard_connections).await?;
     discover_nodes(&my_shard, remote_shard_connections).await?;
 
+    // Open the paxos state left from a previous run, for it to be compacted.
+    my_shard.get_existing_paxos_tree().await?;
+
     // Tasks that all shards run.
     let mut tasks = vec![
         spawn_remote_shard_server_task(my_shard.clone()),
@@ -137,6 +221,8 @@ pub async fn run_shard(
     if is_node_managing {
         tasks.push(spawn_gossip_server_task(my_shard.clone()));
         tasks.push(spawn_failure_detector_task(my_shard.clone()));
+        tasks.push(spawn_anti_entropy_task(my_shard.clone()));
+        tasks.push(spawn_metrics_server_task(my_shard.clone()));
 
         // Notify all nodes that we are now alive.
         my_shard
@@ -158,7 +244,10 @@ pub async fn run_shard(
     if is_node_managing {
         // Notify all nodes that we are now dead.
         my_shard
-            .gossip(GossipEvent::Dead(my_shard.args.name.clone()))
+            .gossip(GossipEvent::Dead(
+                my_shard.args.name.clone(),
+                my_shard.incarnation.get(),
+            ))
             .await?;
     }
 
@@ -171,12 +260,13 @@ pub async fn run_shard(
     Ok(())
 }
 
-#[must_use]
+/// All shards of a node must be created with the same incarnation.
 pub fn create_shard(
     args: Args,
     id: u16,
     local_connections: Vec<LocalShardConnection>,
-) -> Rc<MyShard> {
+    incarnation: Incarnation,
+) -> Result<Rc<MyShard>> {
     let (receiver, stop_receiver, stop_sender) = local_connections
         .iter()
         .filter(|c| c.id == id)
@@ -201,13 +291,14 @@ pub fn create_shard(
     let cache_len = args.page_cache_size / PAGE_SIZE / shards.len();
     let cache = PageCache::new(cache_len, cache_len / 16);
 
-    Rc::new(MyShard::new(
+    Ok(Rc::new(MyShard::new(
         args,
         id,
         shards,
+        incarnation,
         cache,
         receiver,
         stop_receiver,
         stop_sender,
-    ))
+    )?))
 }
@@ -1,6 +1,15 @@
 use std::collections::HashSet;
-use std::time::Duration;
-use std::{cell::RefCell, collections::HashMap, path::PathBuf, rc::Rc};
+use std::time::{Duration, Instant};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
 use async_channel::{Receiver, Sender};
 use bincode::Options;
@@ -9,11 +18,12 @@ use futures::{
     stream::{FuturesUnordered, StreamExt},
     AsyncReadExt, AsyncWriteExt,
 };
-use glommio::io::{remove, StreamWriterBuilder};
+use glommio::io::{remove, rename, StreamWriterBuilder};
 use glommio::{
     io::{BufferedFile, StreamReaderBuilder},
     net::UdpSocket,
     spawn_local,
+    sync::{RwLock, Semaphore},
 };
 use itertools::Itertools;
 use log::{error, trace};
@@ -21,30 +31,44 @@ use murmur3::murmur3_32;
 use rand::seq::IteratorRandom;
 use rand::thread_rng;
 use regex::Regex;
-use serde::{Deserialize, Serialize};
+use rmpv::{decode::read_value, encode::write_value, Value};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use time::OffsetDateTime;
 
-use crate::gossip::{
-    serialize_gossip_message, GossipEvent, GossipEventKind, GossipMessage,
-};
+use crate::auth::{is_system_collection, User, USERS_COLLECTION};
+use crate::cdc::{ChangeLog, WatchEvent, WatchTarget, Watcher};
+use crate::crdt::{self, Counter, Crdt};
+use crate::gossip::{serialize_gossip_message, GossipEvent, GossipMessage};
 use crate::messages::{NodeMetadata, ShardRequest, ShardResponse};
-use crate::storage_engine::DEFAULT_TREE_CAPACITY;
+use crate::paxos::{Ballot, PaxosState, Promise, Proposal, PAXOS_STATE_TTL};
+use crate::siblings::{self, Siblings, VersionVector};
+use crate::storage_engine::{MergeFn, DEFAULT_TREE_CAPACITY};
 use crate::tasks::migration::{
     spawn_migration_actions_tasks, MigrationAction, RangeAndAction,
 };
-use crate::utils::local_event::LocalEvent;
 use crate::utils::{bincode::bincode_options, get_first_capture};
+use crate::utils::{hlc::HybridLogicalClock, local_event::LocalEvent};
 use crate::{
     args::Args,
     error::{Error, Result},
     local_shard::LocalShardConnection,
     messages::{ShardEvent, ShardMessage, ShardPacket},
+    metrics::{
+        increment, merge_collection_summaries, CollectionShardStats,
+        CollectionSummary, KnownNode, NodeStatus, ShardMetrics, ShardStats,
+        ShardStatus,
+    },
     remote_shard_connection::RemoteShardConnection,
+    response_to_result,
     storage_engine::{
         cached_file_reader::FileId,
         lsm_tree::LSMTree,
         page_cache::{PageCache, PartitionPageCache},
     },
+    tasks::compaction::compact_tree_fully,
+    tls::Tls,
+    transaction::Operation,
 };
 
 #[cfg(feature = "flow-events")]
@@ -64,10 +88,58 @@ macro_rules! notify_flow_event {
 const NEW_NODE_MIGARTION_DELAY: Option<Duration> =
     Some(Duration::from_millis(500));
 
-#[derive(Serialize, Deserialize)]
+/// Max number of events waiting to be sent to a watcher.
+const WATCHER_CAPACITY: usize = 1024;
+
+/// Nodes seen dead are forgotten after this long, well after the death (or
+/// its refutation) reached all nodes through gossip and anti entropy.
+const DEAD_NODE_EXPIRATION: Duration = Duration::from_secs(60 * 60);
+
+/// 10 days, like in Cassandra.
+pub const DEFAULT_GC_GRACE_SECONDS: u64 = 10 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClusterMetadata {
     pub nodes: Vec<NodeMetadata>,
-    pub collections: HashMap<String, u16>,
+    pub collections: HashMap<String, CollectionMetadata>,
+
+    /// The nodes that were seen dead, key is node unique name, value is the
+    /// incarnation of the node that was seen dead.
+    #[serde(default)]
+    pub dead_nodes: HashMap<String, u64>,
+
+    /// The collections that were dropped, key is the collection name, value
+    /// is the time of the drop.
+    #[serde(default)]
+    pub dropped_collections: HashMap<String, OffsetDateTime>,
+}
+
+/// The incarnation of a node (see `NodeMetadata::incarnation`), shared by all
+/// shards of the node, as any of them may refute a suspicion of its death.
+#[derive(Debug, Clone)]
+pub struct Incarnation(Arc<AtomicU64>);
+
+impl Default for Incarnation {
+    /// Starting from the current time makes sure a restarted node has a
+    /// greater incarnation than the ones seen before the restart.
+    fn default() -> Self {
+        let now = OffsetDateTime::now_utc().unix_timestamp_nanos() as u64;
+        Self(Arc::new(AtomicU64::new(now)))
+    }
+}
+
+impl Incarnation {
+    #[must_use]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Override a death seen at the given incarnation, returns false when it
+    /// was already overridden (possibly by another shard of the node).
+    pub fn refute(&self, dead_incarnation: u64) -> bool {
+        self.0.fetch_max(dead_incarnation + 1, Ordering::SeqCst)
+            <= dead_incarnation
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -92,6 +164,16 @@ pub struct Shard {
     connection: ShardConnection,
 }
 
+/// Whether two metadata of a node (of any incarnations) have the same
+/// addresses and shards.
+fn same_addresses(a: &NodeMetadata, b: &NodeMetadata) -> bool {
+    a.ip == b.ip
+        && a.remote_shard_base_port == b.remote_shard_base_port
+        && a.ids == b.ids
+        && a.gossip_port == b.gossip_port
+        && a.db_port == b.db_port
+}
+
 pub fn hash_string(s: &str) -> std::io::Result<u32> {
     murmur3_32(&mut std::io::Cursor::new(s), 0)
 }
@@ -100,7 +182,7 @@ pub fn hash_bytes(bytes: &[u8]) -> std::io::Result<u32> {
     murmur3_32(&mut std::io::Cursor::new(bytes), 0)
 }
 
-fn is_between(item: u32, start: u32, end: u32) -> bool {
+pub fn is_between(item: u32, start: u32, end: u32) -> bool {
     if end < start {
         item >= end || item < start
     } else {
@@ -125,12 +207,282 @@ impl Shard {
     }
 }
 
+/// How concurrent writes to a key are resolved, set when a collection is
+/// created and can't be altered.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+)]
+pub enum ConflictResolution {
+    /// The write with the greatest timestamp wins.
+    #[default]
+    LastWriteWins,
+
+    /// Concurrent writes are all kept, see the siblings module.
+    Siblings,
+
+    /// Values are CRDTs (see the crdt module) updated by `increment`
+    /// requests.
+    Counter,
+
+    /// Values are CRDTs updated by `add` / `remove` requests of elements.
+    Set,
+
+    /// Values are CRDTs updated by `add` / `remove` requests of fields.
+    Map,
+}
+
+impl ConflictResolution {
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::LastWriteWins => "last_write_wins",
+            Self::Siblings => "siblings",
+            Self::Counter => "counter",
+            Self::Set => "set",
+            Self::Map => "map",
+        }
+    }
+
+    /// The merge function of the collection's tree.
+    #[must_use]
+    pub fn merge_fn(self) -> Option<MergeFn> {
+        match self {
+            Self::LastWriteWins => None,
+            Self::Siblings => Some(siblings::merge_entry_values),
+            Self::Counter => Some(crdt::merge_entry_values::<Counter>),
+            Self::Set => Some(crdt::merge_entry_values::<crdt::Set>),
+            Self::Map => Some(crdt::merge_entry_values::<crdt::Map>),
+        }
+    }
+
+    /// Whether a write request type is supported on collections with this
+    /// conflict resolution.
+    #[must_use]
+    pub fn supports(self, request: &str) -> bool {
+        match self {
+            Self::LastWriteWins => {
+                matches!(
+                    request,
+                    "set" | "delete" | "transaction" | "cas" | "ingest"
+                )
+            }
+            Self::Siblings => matches!(request, "set" | "delete"),
+            Self::Counter => request == "increment",
+            Self::Set | Self::Map => matches!(request, "add" | "remove"),
+        }
+    }
+
+    pub fn check_supports(self, request: &str) -> Result<()> {
+        if self.supports(request) {
+            Ok(())
+        } else {
+            Err(Error::NotSupportedByConflictResolution(
+                request.to_string(),
+                self.as_str(),
+            ))
+        }
+    }
+
+    /// Decode a value stored in the collection's tree (None when deleted) to
+    /// the value returned to clients, None when the key was deleted.
+    pub fn to_value(self, data: Option<&[u8]>) -> Result<Option<Value>> {
+        let data = match data {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        Ok(match self {
+            Self::LastWriteWins => Some(read_value(&mut &data[..])?),
+            Self::Siblings => {
+                let siblings = Siblings::decode(data)?;
+                if siblings.values.is_empty() {
+                    return Ok(None);
+                }
+                Some(siblings.to_value()?)
+            }
+            Self::Counter => Some(crdt::decode::<Counter>(data)?.to_value()?),
+            Self::Set => Some(crdt::decode::<crdt::Set>(data)?.to_value()?),
+            Self::Map => Some(crdt::decode::<crdt::Map>(data)?.to_value()?),
+        })
+    }
+
+    /// Same as `to_value()`, msgpack encoded.
+    pub fn to_response(self, data: Option<&[u8]>) -> Result<Option<Vec<u8>>> {
+        if self == Self::LastWriteWins {
+            return Ok(data.map(<[u8]>::to_vec));
+        }
+
+        match self.to_value(data)? {
+            Some(value) => {
+                let mut buf = Vec::new();
+                write_value(&mut buf, &value)?;
+                Ok(Some(buf))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl TryFrom<&str> for ConflictResolution {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value {
+            "last_write_wins" => Ok(Self::LastWriteWins),
+            "siblings" => Ok(Self::Siblings),
+            "counter" => Ok(Self::Counter),
+            "set" => Ok(Self::Set),
+            "map" => Ok(Self::Map),
+            _ => Err(Error::BadFieldType("conflict_resolution".to_string())),
+        }
+    }
+}
+
 /// The metadata of a collection (saved to disk for each collection).
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CollectionMetadata {
     /// Number of nodes (replicas) that hold a copy for a specific key for
     /// tunable availability / consistency.
     pub replication_factor: u16,
+
+    /// The schema version, bumped on every alter of the collection, used to
+    /// resolve conflicting alter events (see `is_newer_than()`).
+    pub version: u64,
+
+    pub conflict_resolution: ConflictResolution,
+
+    /// Tombstones younger than this are never purged by compaction, so a
+    /// replica that missed a delete (down for less than this) can't bring
+    /// the deleted value back on migration or read repair.
+    pub gc_grace_seconds: u64,
+
+    /// The time the collection was created, kept on alter.
+    pub created_at: OffsetDateTime,
+
+    /// The time of the last truncate of the collection, values written up
+    /// to it are dropped, even when they reach a replica after the truncate.
+    pub truncated_at: Option<OffsetDateTime>,
+
+    /// The time of the last alter (the creation time until altered), breaks
+    /// ties between concurrent alters of the same version.
+    pub altered_at: OffsetDateTime,
+}
+
+impl Default for CollectionMetadata {
+    fn default() -> Self {
+        Self {
+            replication_factor: 0,
+            version: 0,
+            conflict_resolution: ConflictResolution::default(),
+            gc_grace_seconds: 0,
+            created_at: OffsetDateTime::UNIX_EPOCH,
+            truncated_at: None,
+            altered_at: OffsetDateTime::UNIX_EPOCH,
+        }
+    }
+}
+
+impl CollectionMetadata {
+    #[must_use]
+    pub fn new(replication_factor: u16) -> Self {
+        let created_at = OffsetDateTime::now_utc();
+        Self {
+            replication_factor,
+            version: 0,
+            conflict_resolution: ConflictResolution::LastWriteWins,
+            gc_grace_seconds: DEFAULT_GC_GRACE_SECONDS,
+            created_at,
+            truncated_at: None,
+            altered_at: created_at,
+        }
+    }
+
+    /// Whether this metadata should override the other metadata, ties on the
+    /// version are broken by the time of the alter, so all nodes converge on
+    /// the same metadata.
+    #[must_use]
+    pub fn is_newer_than(&self, other: &Self) -> bool {
+        (self.version, self.altered_at) > (other.version, other.altered_at)
+    }
+
+    /// Tombstones older than the returned time can be purged, None when the
+    /// grace period is too long to purge any.
+    #[must_use]
+    pub fn gc_before(&self) -> Option<OffsetDateTime> {
+        let grace = i64::try_from(self.gc_grace_seconds).ok()?;
+        OffsetDateTime::now_utc().checked_sub(time::Duration::seconds(grace))
+    }
+}
+
+/// Read the next field of a metadata file, the default when the file ends
+/// before it.
+fn read_metadata_field<T: DeserializeOwned>(
+    cursor: &mut std::io::Cursor<&[u8]>,
+    default: T,
+) -> Option<T> {
+    if cursor.position() == cursor.get_ref().len() as u64 {
+        return Some(default);
+    }
+    bincode_options().deserialize_from(cursor).ok()
+}
+
+/// Read a metadata file saved before some of the fields were added to the
+/// metadata. Fields are only ever appended, so the file holds the first
+/// fields, the rest get their defaults.
+fn deserialize_legacy_collection_metadata(
+    buf: &[u8],
+) -> Option<CollectionMetadata> {
+    let mut cursor = std::io::Cursor::new(buf);
+    let metadata = CollectionMetadata {
+        replication_factor: bincode_options()
+            .deserialize_from(&mut cursor)
+            .ok()?,
+        version: read_metadata_field(&mut cursor, 0)?,
+        conflict_resolution: read_metadata_field(
+            &mut cursor,
+            ConflictResolution::LastWriteWins,
+        )?,
+        gc_grace_seconds: read_metadata_field(
+            &mut cursor,
+            DEFAULT_GC_GRACE_SECONDS,
+        )?,
+        created_at: read_metadata_field(
+            &mut cursor,
+            OffsetDateTime::UNIX_EPOCH,
+        )?,
+        truncated_at: read_metadata_field(&mut cursor, None)?,
+        altered_at: read_metadata_field(
+            &mut cursor,
+            OffsetDateTime::UNIX_EPOCH,
+        )?,
+    };
+
+    // Saved by a newer version, with fields this version doesn't know.
+    if cursor.position() != buf.len() as u64 {
+        return None;
+    }
+
+    Some(metadata)
+}
+
+/// Deserialize a collection metadata file, bincode is not self-describing,
+/// so missing fields of a legacy file can't be defaulted by serde, the
+/// legacy metadata is read when the metadata fails to read.
+fn deserialize_collection_metadata(buf: &[u8]) -> Result<CollectionMetadata> {
+    match bincode_options().deserialize(buf) {
+        Ok(metadata) => Ok(metadata),
+        Err(e) => {
+            deserialize_legacy_collection_metadata(buf).ok_or_else(|| e.into())
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -140,13 +492,28 @@ pub struct Collection {
 
     /// The metadata of a collection.
     pub metadata: CollectionMetadata,
+
+    /// The latest writes to keys in the primary range of the shard, streamed
+    /// to subscribers, persisted next to the tree.
+    pub changes: Rc<ChangeLog>,
+
+    /// Writes hold a read lock, transactions hold the write lock, so no write
+    /// interleaves between checking the conditions of a transaction and
+    /// applying its writes.
+    pub write_lock: Rc<RwLock<()>>,
 }
 
 impl Collection {
-    fn new(tree: LSMTree, metadata: CollectionMetadata) -> Self {
+    fn new(
+        tree: LSMTree,
+        metadata: CollectionMetadata,
+        changes: ChangeLog,
+    ) -> Self {
         Self {
             tree: Rc::new(tree),
             metadata,
+            changes: Rc::new(changes),
+            write_lock: Rc::new(RwLock::new(())),
         }
     }
 }
@@ -168,13 +535,29 @@ pub struct MyShard {
 
     /// The consistent hash ring (shards sorted by hash).
     /// Starts with the first hash that has a greater hash than our shard.
-    shards: RefCell<Vec<Shard>>,
+    pub shards: RefCell<Vec<Shard>>,
 
     /// All known nodes other than this node, key is node unique name.
     pub nodes: RefCell<HashMap<String, NodeMetadata>>,
 
-    /// Holds the counts of gossip requests.
-    pub gossip_requests: RefCell<HashMap<(String, GossipEventKind), u8>>,
+    /// The incarnation of this node.
+    pub incarnation: Incarnation,
+
+    /// The incarnations of nodes that were seen dead and when they were seen
+    /// dead, key is node unique name, used to ignore stale alive events of
+    /// dead nodes.
+    pub dead_nodes: RefCell<HashMap<String, (u64, Instant)>>,
+
+    /// The sequence number of the next gossip message originating from this
+    /// shard.
+    gossip_sequence: Cell<u64>,
+
+    /// Holds the counts of gossip requests and when they were first seen, key
+    /// is (source, sequence).
+    pub gossip_requests: RefCell<HashMap<(String, u64), (u8, Instant)>>,
+
+    /// Counters and histograms exposed as metrics.
+    pub metrics: ShardMetrics,
 
     /// Collections to the lsm tree on disk.
     pub collections: RefCell<HashMap<String, Collection>>,
@@ -182,9 +565,44 @@ pub struct MyShard {
     /// Used for notfying any insertions / removals from |collections|.
     pub collections_change_event: LocalEvent,
 
+    /// The time of the last drop of each dropped collection (saved to disk),
+    /// so a collection created before it, known by a node that missed the
+    /// drop, is never created again.
+    dropped_collections: RefCell<HashMap<String, OffsetDateTime>>,
+
+    /// Watchers of keys from long-lived client connections, key is watcher
+    /// id.
+    watchers: RefCell<HashMap<u64, Watcher>>,
+
+    /// The id of the next registered watcher.
+    next_watcher_id: Cell<u64>,
+
+    /// The paxos state of keys written in lightweight transactions, opened
+    /// on first use (or on startup, when it exists).
+    /// Not a collection, as the state is local to the shard, it is migrated
+    /// together with the keys of the collections, and it expires after
+    /// `PAXOS_STATE_TTL`.
+    paxos_tree: RefCell<Option<Rc<LSMTree>>>,
+
+    /// Paxos requests read and then write the paxos state, run them one at
+    /// a time.
+    paxos_semaphore: Semaphore,
+
+    /// Stamps writes coordinated by the shard, and paxos ballots.
+    pub clock: HybridLogicalClock,
+
     /// The shard's page cache.
     cache: Rc<RefCell<PageCache<FileId>>>,
 
+    /// TLS configuration, None when TLS is disabled.
+    pub tls: Option<Tls>,
+
+    /// Credentials that were already verified, key is username, value is the
+    /// password hash of the user + a digest of the verified password.
+    /// Password hashing is slow by design, and it would block the shard on
+    /// every new connection.
+    verified_credentials: RefCell<HashMap<String, (String, Vec<u8>)>>,
+
     /// The packet receiver from other local shards.
     pub local_shards_packet_receiver: Receiver<ShardPacket>,
 
@@ -200,18 +618,24 @@ pub struct MyShard {
 }
 
 impl MyShard {
-    #[must_use]
     pub fn new(
         args: Args,
         id: u16,
         shards: Vec<Shard>,
+        incarnation: Incarnation,
         cache: PageCache<FileId>,
         local_shards_packet_receiver: Receiver<ShardPacket>,
         stop_receiver: Receiver<()>,
         stop_sender: Sender<()>,
-    ) -> Self {
+    ) -> Result<Self> {
+        let tls = Tls::from_args(&args)?;
         let shard_name = format!("{}-{}", args.name, id);
         let hash = hash_string(&shard_name).unwrap();
+
+        // Starting from the current time makes sure a restarted shard has a
+        // greater sequence than the ones seen before the restart.
+        let now = OffsetDateTime::now_utc().unix_timestamp_nanos() as u64;
+
         let this = Self {
             args,
             id,
@@ -219,10 +643,22 @@ impl MyShard {
             hash,
             shards: RefCell::new(shards),
             nodes: RefCell::new(HashMap::new()),
+            incarnation,
+            dead_nodes: RefCell::new(HashMap::new()),
+            gossip_sequence: Cell::new(now),
             gossip_requests: RefCell::new(HashMap::new()),
+            metrics: ShardMetrics::default(),
             collections: RefCell::new(HashMap::new()),
             collections_change_event: LocalEvent::new(),
+            dropped_collections: RefCell::new(HashMap::new()),
+            watchers: RefCell::new(HashMap::new()),
+            next_watcher_id: Cell::new(0),
+            paxos_tree: RefCell::new(None),
+            paxos_semaphore: Semaphore::new(1),
+            clock: HybridLogicalClock::default(),
             cache: Rc::new(RefCell::new(cache)),
+            tls,
+            verified_credentials: RefCell::new(HashMap::new()),
             local_shards_packet_receiver,
             stop_receiver,
             stop_sender,
@@ -232,7 +668,7 @@ impl MyShard {
 
         this.sort_consistent_hash_ring();
 
-        this
+        Ok(this)
     }
 
     pub async fn stop(&self) -> Result<()> {
@@ -248,6 +684,698 @@ impl MyShard {
             .cloned()
     }
 
+    #[must_use]
+    pub fn get_stats(&self) -> ShardStats {
+        let collections = self
+            .collections
+            .borrow()
+            .iter()
+            .map(|(name, collection)| (name.clone(), collection.tree.stats()))
+            .collect();
+        self.metrics.snapshot(self.shard_name.clone(), collections)
+    }
+
+    /// Collect the stats of all shards in the node.
+    pub async fn get_node_stats(&self) -> Result<Vec<ShardStats>> {
+        let mut stats = self
+            .send_request_to_local_shards(ShardRequest::GetStats, |res| {
+                response_to_result!(res, ShardResponse::GetStats).map(|s| *s)
+            })
+            .await?;
+        stats.push(self.get_stats());
+        stats.sort_unstable_by(|a, b| a.shard_name.cmp(&b.shard_name));
+        Ok(stats)
+    }
+
+    pub fn get_collection_stats(
+        &self,
+        name: &str,
+    ) -> Result<CollectionShardStats> {
+        let tree = self.get_collection_tree(name)?;
+        let stats = tree.stats();
+        Ok(CollectionShardStats {
+            shard_name: self.shard_name.clone(),
+            sstables: tree.sstable_indices_and_sizes(),
+            memtable_entries: stats.memtable_entries,
+            memtable_capacity: tree.memtable_capacity() as u64,
+            wal_bytes: stats.memtable_bytes,
+        })
+    }
+
+    /// Collect the stats of a collection in all shards in the node.
+    pub async fn get_collection_node_stats(
+        &self,
+        name: &str,
+    ) -> Result<Vec<CollectionShardStats>> {
+        let mut stats = self
+            .send_request_to_local_shards(
+                ShardRequest::GetCollectionStats(name.to_string()),
+                |res| {
+                    response_to_result!(res, ShardResponse::GetCollectionStats)
+                },
+            )
+            .await?;
+        stats.push(self.get_collection_stats(name)?);
+        stats.sort_unstable_by(|a, b| a.shard_name.cmp(&b.shard_name));
+        Ok(stats)
+    }
+
+    /// The summaries of all user collections in this shard.
+    pub async fn get_collection_summaries(
+        &self,
+    ) -> Result<Vec<CollectionSummary>> {
+        let collections = self
+            .collections
+            .borrow()
+            .iter()
+            .filter(|(name, _)| !is_system_collection(name))
+            .map(|(name, collection)| (name.clone(), collection.clone()))
+            .collect::<Vec<_>>();
+
+        let mut summaries = Vec::with_capacity(collections.len());
+        for (name, collection) in collections {
+            summaries.push(CollectionSummary {
+                name,
+                metadata: collection.metadata,
+                approximate_documents: collection
+                    .tree
+                    .approximate_live_keys()
+                    .await?,
+                disk_bytes: collection.tree.disk_size()?,
+            });
+        }
+        Ok(summaries)
+    }
+
+    /// The approximate number of documents of a collection in the primary
+    /// range of this shard, so replicas are not counted. Keys are spread
+    /// evenly on the ring, so the share of the primary range is proportional
+    /// to its size out of all ranges the shard holds.
+    pub async fn get_collection_primary_count(
+        &self,
+        name: &str,
+    ) -> Result<u64> {
+        let collection = self.get_collection(name)?;
+        let live_keys = collection.tree.approximate_live_keys().await?;
+
+        let range_size = |(start, end): (u32, u32)| -> u128 {
+            if start == end {
+                1 << 32
+            } else {
+                end.wrapping_sub(start).into()
+            }
+        };
+        let ranges =
+            self.owned_ranges(collection.metadata.replication_factor.into());
+        let total_size: u128 = ranges.iter().copied().map(range_size).sum();
+        let primary_size = range_size(ranges[0]);
+
+        Ok((u128::from(live_keys) * primary_size / total_size) as u64)
+    }
+
+    /// Sum up the primary counts of a collection in all shards in the node.
+    pub async fn get_collection_node_count(&self, name: &str) -> Result<u64> {
+        let counts = self
+            .send_request_to_local_shards(
+                ShardRequest::GetCollectionCount(name.to_string()),
+                |res| {
+                    response_to_result!(res, ShardResponse::GetCollectionCount)
+                },
+            )
+            .await?;
+        Ok(self.get_collection_primary_count(name).await?
+            + counts.iter().sum::<u64>())
+    }
+
+    /// Collect the summaries of all user collections in all shards in the
+    /// node, summed up per collection.
+    pub async fn get_node_collection_summaries(
+        &self,
+    ) -> Result<Vec<CollectionSummary>> {
+        let shard_summaries = self
+            .send_request_to_local_shards(
+                ShardRequest::GetCollectionSummaries,
+                |res| {
+                    response_to_result!(
+                        res,
+                        ShardResponse::GetCollectionSummaries
+                    )
+                },
+            )
+            .await?;
+
+        let mut summaries = self.get_collection_summaries().await?;
+        for other in shard_summaries {
+            merge_collection_summaries(&mut summaries, other);
+        }
+        summaries.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        Ok(summaries)
+    }
+
+    #[must_use]
+    pub fn get_status(&self) -> ShardStatus {
+        let max_replication_factor = self
+            .collections
+            .borrow()
+            .values()
+            .map(|c| c.metadata.replication_factor)
+            .max()
+            .unwrap_or(1);
+
+        ShardStatus {
+            shard_name: self.shard_name.clone(),
+            hash: self.hash,
+            owned_ranges: self.owned_ranges(max_replication_factor.into()),
+            running_migrations: self.metrics.running_migrations.get(),
+        }
+    }
+
+    /// Collect the status of all shards in the node, together with the
+    /// liveness of all known nodes.
+    pub async fn get_node_status(&self) -> Result<NodeStatus> {
+        let mut shards = self
+            .send_request_to_local_shards(ShardRequest::GetStatus, |res| {
+                response_to_result!(res, ShardResponse::GetStatus)
+            })
+            .await?;
+        shards.push(self.get_status());
+        shards.sort_unstable_by(|a, b| a.shard_name.cmp(&b.shard_name));
+
+        let mut nodes = self
+            .nodes
+            .borrow()
+            .values()
+            .map(|node| KnownNode {
+                name: node.name.clone(),
+                incarnation: node.incarnation,
+                alive: true,
+            })
+            .chain(self.dead_nodes.borrow().iter().map(
+                |(name, (incarnation, _))| KnownNode {
+                    name: name.clone(),
+                    incarnation: *incarnation,
+                    alive: false,
+                },
+            ))
+            .collect::<Vec<_>>();
+        nodes.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(NodeStatus {
+            name: self.args.name.clone(),
+            incarnation: self.incarnation.get(),
+            shards,
+            nodes,
+        })
+    }
+
+    /// Flush the memtable of a collection to a new sstable.
+    pub async fn flush_collection(&self, name: &str) -> Result<()> {
+        self.get_collection_tree(name)?.flush().await
+    }
+
+    /// Compact all sstables of a collection into a single sstable.
+    pub async fn compact_collection(&self, name: &str) -> Result<()> {
+        let collection = self.get_collection(name)?;
+        compact_tree_fully(&collection.tree, collection.metadata.gc_before())
+            .await
+    }
+
+    /// Write to a collection (a None value deletes the key), and append
+    /// the write to the collection's change log + notify its watchers when
+    /// the key is in the primary range of the shard.
+    pub async fn write_to_collection(
+        &self,
+        name: &str,
+        collection: &Collection,
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+        timestamp: OffsetDateTime,
+    ) -> Result<()> {
+        self.write_batch_to_collection(
+            name,
+            collection,
+            vec![(key, value)],
+            timestamp,
+        )
+        .await
+    }
+
+    /// Same as `write_to_collection`, for multiple keys written atomically.
+    pub async fn write_batch_to_collection(
+        &self,
+        name: &str,
+        collection: &Collection,
+        items: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        timestamp: OffsetDateTime,
+    ) -> Result<()> {
+        let _guard = collection.write_lock.read().await?;
+        let collection = self.get_locked_collection(name, collection)?;
+        self.apply_writes(name, &collection, items, timestamp).await
+    }
+
+    /// Get a collection again after taking its write lock, a writer that
+    /// waited on the lock must not use the clone it had before waiting, as
+    /// the collection could have been altered or dropped.
+    fn get_locked_collection(
+        &self,
+        name: &str,
+        locked: &Collection,
+    ) -> Result<Collection> {
+        let collection = self.get_collection(name)?;
+
+        // Dropped (and maybe created again) while waiting for the lock.
+        if !Rc::ptr_eq(&collection.write_lock, &locked.write_lock) {
+            return Err(Error::CollectionNotFound(name.to_string()));
+        }
+
+        Ok(collection)
+    }
+
+    async fn apply_writes(
+        &self,
+        name: &str,
+        collection: &Collection,
+        items: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        timestamp: OffsetDateTime,
+    ) -> Result<()> {
+        self.clock.update(timestamp);
+
+        let mut changes = Vec::new();
+        for (key, value) in &items {
+            if self.owns_key(hash_bytes(key)?, 0)? {
+                changes.push((
+                    key.clone(),
+                    collection
+                        .metadata
+                        .conflict_resolution
+                        .to_response(value.as_deref())?,
+                ));
+            }
+        }
+
+        collection
+            .tree
+            .clone()
+            .set_batch_with_timestamp(items, timestamp)
+            .await?;
+
+        for (key, value) in &changes {
+            self.notify_watchers(name, key, value, timestamp);
+        }
+        collection.changes.push(changes, timestamp).await
+    }
+
+    /// Check the conditions of all operations, and only if all of them pass,
+    /// apply all writes atomically.
+    /// Conditions are checked against the local copy of the keys only.
+    /// Returns the applied writes, to replicate to the other replicas.
+    pub async fn run_transaction(
+        &self,
+        name: &str,
+        collection: &Collection,
+        operations: Vec<Operation>,
+        timestamp: OffsetDateTime,
+    ) -> Result<Vec<(Vec<u8>, Option<Vec<u8>>)>> {
+        collection
+            .metadata
+            .conflict_resolution
+            .check_supports("transaction")?;
+
+        let _guard = collection.write_lock.write().await?;
+        let collection = &self.get_locked_collection(name, collection)?;
+
+        for (i, operation) in operations.iter().enumerate() {
+            if let Some(condition) = &operation.condition {
+                let value = collection.tree.get(&operation.key).await?;
+                if !condition.matches(value.as_deref()) {
+                    return Err(Error::TransactionConditionFailed(i));
+                }
+            }
+        }
+
+        let items = operations
+            .into_iter()
+            .filter_map(|operation| {
+                operation
+                    .write
+                    .map(|write| (operation.key, write.into_value()))
+            })
+            .collect::<Vec<_>>();
+        self.apply_writes(name, collection, items.clone(), timestamp)
+            .await?;
+
+        Ok(items)
+    }
+
+    /// Write to a key of a collection with siblings, replacing the siblings
+    /// in the client's causal context (a None value only deletes them).
+    /// A delete without a context deletes all siblings known to the shard,
+    /// a set without a context replaces nothing.
+    /// Holds the write lock, so every write coordinated by the shard gets a
+    /// unique dot.
+    /// Returns the full value of the key, to replicate to the other replicas.
+    pub async fn write_siblings(
+        &self,
+        name: &str,
+        collection: &Collection,
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+        context: Option<VersionVector>,
+        timestamp: OffsetDateTime,
+    ) -> Result<Vec<u8>> {
+        let _guard = collection.write_lock.write().await?;
+        let collection = &self.get_locked_collection(name, collection)?;
+
+        let current = match collection.tree.get(&key).await? {
+            Some(data) => Siblings::decode(&data)?,
+            None => Siblings::default(),
+        };
+        let context = match (context, &value) {
+            (Some(context), _) => context,
+            (None, None) => current.context.clone(),
+            (None, Some(_)) => VersionVector::new(),
+        };
+        let data = current.update(&context, value, self.hash).encode()?;
+
+        self.apply_writes(
+            name,
+            collection,
+            vec![(key, Some(data.clone()))],
+            timestamp,
+        )
+        .await?;
+
+        Ok(data)
+    }
+
+    /// Apply an operation to the CRDT value of a key.
+    /// Holds the write lock, so every operation coordinated by the shard is
+    /// applied on top of the previous one.
+    /// Returns the full value of the key, to replicate to the other replicas.
+    pub async fn update_crdt(
+        &self,
+        name: &str,
+        collection: &Collection,
+        key: Vec<u8>,
+        operation: crdt::Operation,
+        timestamp: OffsetDateTime,
+    ) -> Result<Vec<u8>> {
+        let _guard = collection.write_lock.write().await?;
+        let collection = &self.get_locked_collection(name, collection)?;
+
+        let current = collection.tree.get(&key).await?;
+        let data = operation.apply(current.as_deref(), self.hash, timestamp)?;
+
+        self.apply_writes(
+            name,
+            collection,
+            vec![(key, Some(data.clone()))],
+            timestamp,
+        )
+        .await?;
+
+        Ok(data)
+    }
+
+    #[must_use]
+    pub fn next_paxos_ballot(&self) -> Ballot {
+        Ballot {
+            timestamp: self.clock.now(),
+            shard_hash: self.hash,
+        }
+    }
+
+    fn get_paxos_dir(&self) -> PathBuf {
+        // Named so it's not discovered as a collection on startup.
+        let mut dir = PathBuf::from(self.args.dir.clone());
+        dir.push(format!("{}.paxos", self.id));
+        dir
+    }
+
+    /// Paxos state written before this time has expired.
+    #[must_use]
+    pub fn paxos_expired_before(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc() - PAXOS_STATE_TTL
+    }
+
+    /// The paxos tree if it was opened, expired state is dropped when read
+    /// and when compacted.
+    #[must_use]
+    pub fn get_open_paxos_tree(&self) -> Option<Rc<LSMTree>> {
+        let tree = self.paxos_tree.borrow().clone()?;
+        tree.truncate(self.paxos_expired_before());
+        Some(tree)
+    }
+
+    /// Must be called while holding a permit of the paxos semaphore.
+    async fn get_paxos_tree(&self) -> Result<Rc<LSMTree>> {
+        if let Some(tree) = self.get_open_paxos_tree() {
+            return Ok(tree);
+        }
+
+        let tree = Rc::new(
+            self.open_lsm_tree(self.get_paxos_dir(), "paxos", None)
+                .await?,
+        );
+        tree.truncate(self.paxos_expired_before());
+        self.paxos_tree.replace(Some(tree.clone()));
+
+        // For the compaction task to pick up the tree.
+        self.collections_change_event.notify();
+
+        Ok(tree)
+    }
+
+    /// The paxos tree, None when no paxos state was ever written.
+    pub async fn get_existing_paxos_tree(&self) -> Result<Option<Rc<LSMTree>>> {
+        if self.paxos_tree.borrow().is_none() && !self.get_paxos_dir().exists()
+        {
+            return Ok(None);
+        }
+
+        let _permit = self.paxos_semaphore.acquire_permit(1).await?;
+        Ok(Some(self.get_paxos_tree().await?))
+    }
+
+    fn get_paxos_key(collection: &str, key: &[u8]) -> Result<Vec<u8>> {
+        Ok(bincode_options().serialize(&(collection, key))?)
+    }
+
+    async fn get_paxos_state(
+        &self,
+        tree: &LSMTree,
+        paxos_key: &Vec<u8>,
+    ) -> Result<PaxosState> {
+        Ok(match tree.get(paxos_key).await? {
+            Some(value) => bincode_options().deserialize(&value)?,
+            None => PaxosState::default(),
+        })
+    }
+
+    /// Phase 1 of paxos on a replica, promise to not accept proposals older
+    /// than the ballot.
+    pub async fn paxos_prepare(
+        &self,
+        collection: &str,
+        key: &[u8],
+        ballot: Ballot,
+    ) -> Result<Promise> {
+        self.clock.update(ballot.timestamp);
+
+        // The state of the key may have already expired, the proposer must
+        // retry with a newer ballot.
+        if ballot.timestamp < self.paxos_expired_before() {
+            return Ok(Promise::Rejected(self.next_paxos_ballot()));
+        }
+
+        let tree = self.get_collection_tree(collection)?;
+        let _permit = self.paxos_semaphore.acquire_permit(1).await?;
+        let paxos_tree = self.get_paxos_tree().await?;
+        let paxos_key = Self::get_paxos_key(collection, key)?;
+
+        let mut state = self.get_paxos_state(&paxos_tree, &paxos_key).await?;
+        if let Some(promised) = state.promised {
+            if promised >= ballot {
+                return Ok(Promise::Rejected(promised));
+            }
+        }
+
+        state.promised = Some(ballot);
+        paxos_tree
+            .set(paxos_key, bincode_options().serialize(&state)?)
+            .await?;
+
+        Ok(Promise::Promised {
+            accepted: state.accepted,
+            committed: state.committed,
+            value: tree.get_entry(&key.to_vec()).await?,
+        })
+    }
+
+    /// Phase 2 of paxos on a replica, accept the proposal unless a newer
+    /// ballot was promised.
+    pub async fn paxos_propose(
+        &self,
+        collection: &str,
+        key: &[u8],
+        proposal: Proposal,
+    ) -> Result<bool> {
+        if proposal.ballot.timestamp < self.paxos_expired_before() {
+            return Ok(false);
+        }
+
+        let _permit = self.paxos_semaphore.acquire_permit(1).await?;
+        let paxos_tree = self.get_paxos_tree().await?;
+        let paxos_key = Self::get_paxos_key(collection, key)?;
+
+        let mut state = self.get_paxos_state(&paxos_tree, &paxos_key).await?;
+        if let Some(promised) = state.promised {
+            if promised > proposal.ballot {
+                return Ok(false);
+            }
+        }
+
+        state.promised = Some(proposal.ballot);
+        state.accepted = Some(proposal);
+        paxos_tree
+            .set(paxos_key, bincode_options().serialize(&state)?)
+            .await?;
+
+        Ok(true)
+    }
+
+    /// Write an agreed on proposal, timestamped by its ballot.
+    pub async fn paxos_commit(
+        &self,
+        collection: &str,
+        key: Vec<u8>,
+        proposal: Proposal,
+    ) -> Result<()> {
+        let existing_collection = self.get_collection(collection)?;
+        let _permit = self.paxos_semaphore.acquire_permit(1).await?;
+        let paxos_tree = self.get_paxos_tree().await?;
+        let paxos_key = Self::get_paxos_key(collection, &key)?;
+
+        self.write_to_collection(
+            collection,
+            &existing_collection,
+            key,
+            proposal.value,
+            proposal.ballot.timestamp,
+        )
+        .await?;
+
+        let mut state = self.get_paxos_state(&paxos_tree, &paxos_key).await?;
+        state.committed = state.committed.max(Some(proposal.ballot));
+        if state
+            .accepted
+            .as_ref()
+            .is_some_and(|accepted| accepted.ballot <= proposal.ballot)
+        {
+            state.accepted = None;
+        }
+        paxos_tree
+            .set(paxos_key, bincode_options().serialize(&state)?)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Merge the paxos state of a key migrated from another shard.
+    async fn merge_paxos_state(
+        &self,
+        collection: &str,
+        key: &[u8],
+        state: PaxosState,
+    ) -> Result<()> {
+        let _permit = self.paxos_semaphore.acquire_permit(1).await?;
+        let paxos_tree = self.get_paxos_tree().await?;
+        let paxos_key = Self::get_paxos_key(collection, key)?;
+
+        let current = self.get_paxos_state(&paxos_tree, &paxos_key).await?;
+        paxos_tree
+            .set(
+                paxos_key,
+                bincode_options().serialize(&current.merge(state))?,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Remove the paxos state of all keys of a dropped collection, so it is
+    /// not applied to a new collection with the same name.
+    async fn drop_paxos_state(&self, collection: &str) -> Result<()> {
+        if self.paxos_tree.borrow().is_none() && !self.get_paxos_dir().exists()
+        {
+            return Ok(());
+        }
+
+        let _permit = self.paxos_semaphore.acquire_permit(1).await?;
+        let paxos_tree = self.get_paxos_tree().await?;
+        let prefix = bincode_options().serialize(collection)?;
+
+        // All paxos keys of the collection start with the prefix, and as the
+        // name is UTF-8, the prefix never ends with a 0xff byte.
+        let mut end = prefix.clone();
+        *end.last_mut().unwrap() += 1;
+        paxos_tree.delete_range(prefix, end).await
+    }
+
+    /// Register a watcher, returns its id and the receiver of its events.
+    pub fn add_watcher(
+        &self,
+        collection: String,
+        target: WatchTarget,
+    ) -> (u64, Receiver<WatchEvent>) {
+        let (sender, receiver) = async_channel::bounded(WATCHER_CAPACITY);
+        let id = self.next_watcher_id.get();
+        self.next_watcher_id.set(id + 1);
+        self.watchers.borrow_mut().insert(
+            id,
+            Watcher {
+                collection,
+                target,
+                sender,
+            },
+        );
+        (id, receiver)
+    }
+
+    pub fn remove_watcher(&self, id: u64) {
+        self.watchers.borrow_mut().remove(&id);
+    }
+
+    /// Push an event to all watchers of a key, watchers that are too slow to
+    /// receive their events are removed, which closes their channel.
+    fn notify_watchers(
+        &self,
+        collection: &str,
+        key: &[u8],
+        value: &Option<Vec<u8>>,
+        timestamp: OffsetDateTime,
+    ) {
+        let mut watchers = self.watchers.borrow_mut();
+        if watchers.is_empty() {
+            return;
+        }
+
+        watchers.retain(|_, watcher| {
+            if watcher.collection != collection || !watcher.target.matches(key)
+            {
+                return true;
+            }
+
+            watcher
+                .sender
+                .try_send(WatchEvent {
+                    key: key.to_vec(),
+                    value: value.clone(),
+                    timestamp,
+                })
+                .is_ok()
+        });
+    }
+
     pub fn get_collection_tree(&self, name: &str) -> Result<Rc<LSMTree>> {
         self.collections
             .borrow()
@@ -262,6 +1390,61 @@ impl MyShard {
         dir
     }
 
+    fn get_dropped_collection_path(&self, name: &str) -> PathBuf {
+        let mut dir = PathBuf::from(self.args.dir.clone());
+        dir.push(format!("{name}.dropped"));
+        dir
+    }
+
+    /// Load the times collections were dropped at, must be called before
+    /// creating the collections found on disk or known by other nodes.
+    pub async fn load_dropped_collections(&self) -> Result<()> {
+        if !std::fs::metadata(&self.args.dir)
+            .map(|m| m.is_dir())
+            .unwrap_or(false)
+        {
+            return Ok(());
+        }
+
+        let pattern = r#"(.*)\.dropped$"#.to_string();
+        let regex = Regex::new(pattern.as_str())
+            .map_err(|source| Error::RegexCreationError { source, pattern })?;
+        let names = std::fs::read_dir(&self.args.dir)?
+            .filter_map(std::result::Result::ok)
+            .filter_map(|entry| get_first_capture(&regex, &entry))
+            .collect::<Vec<_>>();
+
+        let mut buf = Vec::new();
+        for name in names {
+            let file =
+                BufferedFile::open(self.get_dropped_collection_path(&name))
+                    .await?;
+            let mut reader = StreamReaderBuilder::new(file).build();
+            buf.clear();
+            reader.read_to_end(&mut buf).await?;
+            reader.close().await?;
+
+            let dropped_at = bincode_options().deserialize(&buf)?;
+            self.dropped_collections
+                .borrow_mut()
+                .insert(name, dropped_at);
+        }
+
+        Ok(())
+    }
+
+    /// Whether a collection created at the time was dropped since.
+    fn is_collection_dropped(
+        &self,
+        name: &str,
+        created_at: OffsetDateTime,
+    ) -> bool {
+        self.dropped_collections
+            .borrow()
+            .get(name)
+            .is_some_and(|dropped_at| *dropped_at >= created_at)
+    }
+
     pub async fn get_collections_from_disk(
         &self,
     ) -> Result<Vec<(String, CollectionMetadata)>> {
@@ -282,23 +1465,20 @@ impl MyShard {
 
         let mut collections = Vec::with_capacity(names.len());
 
-        let metadata_size = bincode_options()
-            .serialized_size(&CollectionMetadata::default())?
-            as usize;
-
-        let mut buf = vec![0; metadata_size];
+        // The size of the metadata is not fixed (e.g. the truncate time is
+        // optional), read the whole file.
+        let mut buf = Vec::new();
         for name in names {
             match BufferedFile::open(self.get_collection_metadata_path(&name))
                 .await
             {
                 Ok(file) => {
                     let mut reader = StreamReaderBuilder::new(file).build();
-                    let read = reader.read(&mut buf).await?;
-                    assert_eq!(metadata_size, read);
+                    buf.clear();
+                    reader.read_to_end(&mut buf).await?;
                     reader.close().await?;
 
-                    let metadata =
-                        bincode_options().deserialize_from(&mut &buf[..])?;
+                    let metadata = deserialize_collection_metadata(&buf)?;
                     collections.push((name, metadata));
                 }
                 Err(e) => panic!(
@@ -316,7 +1496,45 @@ impl MyShard {
         dir
     }
 
-    async fn create_lsm_tree(&self, name: &str) -> Result<LSMTree> {
+    async fn create_lsm_tree(
+        &self,
+        name: &str,
+        merge_fn: Option<MergeFn>,
+    ) -> Result<LSMTree> {
+        self.open_lsm_tree(self.get_collection_dir(name), name, merge_fn)
+            .await
+    }
+
+    fn get_change_log_dir(&self, name: &str) -> PathBuf {
+        // Named so it's not discovered as a collection on startup.
+        let mut dir = self.get_collection_dir(name).into_os_string();
+        dir.push(".changes");
+        dir.into()
+    }
+
+    async fn open_change_log(&self, name: &str) -> Result<ChangeLog> {
+        let capacity = self.args.change_log_capacity;
+        let tree = if capacity > 0 {
+            Some(
+                self.open_lsm_tree(
+                    self.get_change_log_dir(name),
+                    &format!("{name}.changes"),
+                    None,
+                )
+                .await?,
+            )
+        } else {
+            None
+        };
+        ChangeLog::open(tree, capacity).await
+    }
+
+    async fn open_lsm_tree(
+        &self,
+        dir: PathBuf,
+        name: &str,
+        merge_fn: Option<MergeFn>,
+    ) -> Result<LSMTree> {
         let cache = self.cache.clone();
 
         let wal_sync_delay = if self.args.wal_sync {
@@ -326,58 +1544,375 @@ impl MyShard {
         };
 
         LSMTree::open_or_create_ex(
-            self.get_collection_dir(name),
+            dir,
             PartitionPageCache::new_named(name, cache)?,
             DEFAULT_TREE_CAPACITY,
             wal_sync_delay,
             self.args.sstable_bloom_min_size,
+            merge_fn,
+            None,
         )
         .await
     }
 
-    pub async fn create_collection(
+    /// The file is shared by all shards of a node, write to a temporary
+    /// file first, so the file is always replaced atomically.
+    async fn write_node_file(&self, path: PathBuf, buf: &[u8]) -> Result<()> {
+        let mut temp_path = path.clone().into_os_string();
+        temp_path.push(format!(".{}.tmp", self.id));
+
+        let mut writer =
+            StreamWriterBuilder::new(BufferedFile::create(&temp_path).await?)
+                .build();
+        writer.write_all(buf).await?;
+        writer.close().await?;
+
+        rename(temp_path, path).await?;
+
+        Ok(())
+    }
+
+    async fn write_collection_metadata(
+        &self,
+        name: &str,
+        metadata: &CollectionMetadata,
+    ) -> Result<()> {
+        self.write_node_file(
+            self.get_collection_metadata_path(name),
+            &bincode_options().serialize(metadata)?,
+        )
+        .await
+    }
+
+    pub async fn create_collection(
+        &self,
+        name: String,
+        replication_factor: u16,
+    ) -> Result<()> {
+        self.create_collection_with_metadata(
+            name,
+            CollectionMetadata::new(replication_factor),
+        )
+        .await
+    }
+
+    pub async fn create_collection_with_metadata(
+        &self,
+        name: String,
+        metadata: CollectionMetadata,
+    ) -> Result<()> {
+        if self.collections.borrow().contains_key(&name) {
+            return Err(Error::CollectionAlreadyExists(name));
+        }
+        if self.is_collection_dropped(&name, metadata.created_at) {
+            return Err(Error::CollectionDropped(name));
+        }
+        let tree = self
+            .create_lsm_tree(&name, metadata.conflict_resolution.merge_fn())
+            .await?;
+        if let Some(truncated_at) = metadata.truncated_at {
+            tree.truncate(truncated_at);
+        }
+        let changes = self.open_change_log(&name).await?;
+
+        if !self.get_collection_metadata_path(&name).exists() {
+            self.write_collection_metadata(&name, &metadata).await?;
+        }
+
+        self.collections
+            .borrow_mut()
+            .insert(name, Collection::new(tree, metadata, changes));
+        self.collections_change_event.notify();
+
+        notify_flow_event!(self, FlowEvent::CollectionCreated);
+
+        Ok(())
+    }
+
+    /// Apply new metadata to an existing collection, migrating data when the
+    /// replication factor changes.
+    /// Returns false when the metadata is not newer than the current one.
+    pub async fn alter_collection(
+        self: Rc<Self>,
+        name: String,
+        metadata: CollectionMetadata,
+    ) -> Result<bool> {
+        let current = self.get_collection(&name)?.metadata;
+        if !metadata.is_newer_than(&current) {
+            return Ok(false);
+        }
+
+        // The tree of the collection merges values by it, and truncates are
+        // not undone by an alter that didn't know of them.
+        let metadata = CollectionMetadata {
+            conflict_resolution: current.conflict_resolution,
+            truncated_at: current.truncated_at.max(metadata.truncated_at),
+            ..metadata
+        };
+
+        self.write_collection_metadata(&name, &metadata).await?;
+
+        let replication_factor = metadata.replication_factor;
+        if let Some(collection) = self.collections.borrow_mut().get_mut(&name) {
+            collection.metadata = metadata;
+        }
+
+        notify_flow_event!(self, FlowEvent::CollectionAltered);
+
+        if replication_factor != current.replication_factor {
+            self.migrate_data_on_replication_factor_change(
+                name,
+                current.replication_factor as usize,
+                replication_factor as usize,
+            );
+        }
+
+        Ok(true)
+    }
+
+    /// Drop a collection created before the time, the time of the drop is
+    /// kept, so the collection is never created again by a node that missed
+    /// the drop.
+    pub async fn drop_collection(
+        &self,
+        name: &str,
+        timestamp: OffsetDateTime,
+    ) -> Result<()> {
+        self.clock.update(timestamp);
+
+        if !self.is_collection_dropped(name, timestamp) {
+            self.write_node_file(
+                self.get_dropped_collection_path(name),
+                &bincode_options().serialize(&timestamp)?,
+            )
+            .await?;
+            self.dropped_collections
+                .borrow_mut()
+                .insert(name.to_string(), timestamp);
+        }
+
+        let created_at = self
+            .collections
+            .borrow()
+            .get(name)
+            .map(|c| c.metadata.created_at)
+            .ok_or_else(|| Error::CollectionNotFound(name.to_string()))?;
+        if created_at > timestamp {
+            // Created again after the drop.
+            return Ok(());
+        }
+
+        let _ = remove(self.get_collection_metadata_path(name)).await;
+
+        let collection = self
+            .collections
+            .borrow_mut()
+            .remove(name)
+            .ok_or_else(|| Error::CollectionNotFound(name.to_string()))?;
+        collection.tree.purge()?;
+        collection.changes.purge()?;
+        self.collections_change_event.notify();
+
+        // Closes the channels of the collection's watchers.
+        self.watchers
+            .borrow_mut()
+            .retain(|_, watcher| watcher.collection != name);
+
+        self.drop_paxos_state(name).await?;
+
+        Ok(())
+    }
+
+    /// Delete all values of a collection written up to the time, keeping
+    /// its metadata.
+    /// The time is saved in the metadata of the collection, so values
+    /// written before it are dropped even when they reach the shard later
+    /// (replicated, migrated or read repaired), and a truncate that was
+    /// already applied is never applied again over newer writes.
+    pub async fn truncate_collection(
+        &self,
+        name: &str,
+        timestamp: OffsetDateTime,
+    ) -> Result<()> {
+        self.clock.update(timestamp);
+
+        let collection = self.get_collection(name)?;
+        if collection
+            .metadata
+            .truncated_at
+            .is_some_and(|at| at >= timestamp)
+        {
+            return Ok(());
+        }
+
+        let metadata = CollectionMetadata {
+            truncated_at: Some(timestamp),
+            ..collection.metadata.clone()
+        };
+        self.write_collection_metadata(name, &metadata).await?;
+        collection.tree.truncate(timestamp);
+
+        // Readers of the change log must start over (their cursors are now
+        // expired), and the channels of the collection's watchers are
+        // closed, like when the collection is dropped.
+        if let Some(collection) = self.collections.borrow_mut().get_mut(name) {
+            collection.metadata = metadata;
+        }
+        collection.changes.clear().await?;
+        self.watchers
+            .borrow_mut()
+            .retain(|_, watcher| watcher.collection != name);
+
+        self.drop_paxos_state(name).await?;
+
+        // Free the space of the dropped values in the background, rewriting
+        // the whole collection takes time proportional to its size, and the
+        // dropped values are already filtered out by the truncate time.
+        let tree = collection.tree.clone();
+        let gc_before = collection.metadata.gc_before();
+        spawn_local(async move {
+            let result = match tree.flush().await {
+                Ok(()) => compact_tree_fully(&tree, gc_before).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                error!("Failed to compact truncated collection: {}", e);
+            }
+        })
+        .detach();
+
+        Ok(())
+    }
+
+    /// Link the sstables written for this shard (by
+    /// `dbeel-cli build-sstables`) in `<dir>/<shard name>` into the
+    /// collection's tree, returns the number of sstables ingested.
+    pub async fn ingest_collection(
         &self,
-        name: String,
-        replication_factor: u16,
-    ) -> Result<()> {
-        if self.collections.borrow().contains_key(&name) {
-            return Err(Error::CollectionAlreadyExists(name));
+        name: &str,
+        dir: &str,
+    ) -> Result<u64> {
+        let collection = self.get_collection(name)?;
+        collection
+            .metadata
+            .conflict_resolution
+            .check_supports("ingest")?;
+
+        let source_dir = Path::new(dir).join(&self.shard_name);
+        if !source_dir.is_dir() {
+            return Ok(0);
         }
-        let tree = self.create_lsm_tree(&name).await?;
-        let metadata = CollectionMetadata { replication_factor };
 
-        let path = self.get_collection_metadata_path(&name);
-        if !path.exists() {
-            let mut writer =
-                StreamWriterBuilder::new(BufferedFile::create(path).await?)
-                    .build();
-            let buf = bincode_options().serialize(&metadata)?;
-            writer.write_all(&buf).await?;
-            writer.close().await?;
+        let _guard = collection.write_lock.read().await?;
+        let collection = self.get_locked_collection(name, &collection)?;
+        Ok(collection.tree.ingest(&source_dir).await? as u64)
+    }
+
+    /// Ingest into all shards in the node, see `ingest_collection()`.
+    pub async fn ingest_node_collection(
+        &self,
+        name: &str,
+        dir: &str,
+    ) -> Result<u64> {
+        let counts = self
+            .send_request_to_local_shards(
+                ShardRequest::Ingest(name.to_string(), dir.to_string()),
+                |res| response_to_result!(res, ShardResponse::Ingest),
+            )
+            .await?;
+        Ok(self.ingest_collection(name, dir).await?
+            + counts.iter().sum::<u64>())
+    }
+
+    pub async fn get_user(&self, username: &str) -> Result<Option<User>> {
+        let tree = self.get_collection_tree(USERS_COLLECTION)?;
+        Ok(match tree.get(&username.as_bytes().to_vec()).await? {
+            Some(value) => Some(bincode_options().deserialize(&value)?),
+            None => None,
+        })
+    }
+
+    /// Get all users, including the dropped ones (as None), to be able to
+    /// resolve conflicts by the time of the change.
+    pub async fn get_users(
+        &self,
+    ) -> Result<Vec<(String, Option<User>, OffsetDateTime)>> {
+        let tree = self.get_collection_tree(USERS_COLLECTION)?;
+        let mut users = Vec::new();
+        let mut iter = tree.iter();
+        while let Some(entry) = iter.next().await? {
+            let username = String::from_utf8_lossy(&entry.key).to_string();
+            let user = match entry.value.as_data() {
+                Some(data) => Some(bincode_options().deserialize(data)?),
+                None => None,
+            };
+            users.push((username, user, entry.value.timestamp));
         }
+        Ok(users)
+    }
 
-        self.collections
-            .borrow_mut()
-            .insert(name, Collection::new(tree, metadata));
-        self.collections_change_event.notify();
+    /// Set a user (or drop it when None), the last change wins.
+    pub async fn set_user(
+        &self,
+        username: String,
+        user: Option<User>,
+        timestamp: OffsetDateTime,
+    ) -> Result<()> {
+        self.clock.update(timestamp);
 
-        notify_flow_event!(self, FlowEvent::CollectionCreated);
+        let tree = self.get_collection_tree(USERS_COLLECTION)?;
+        let key = username.as_bytes().to_vec();
+
+        if let Some(existing) = tree.get_entry(&key).await? {
+            if existing.timestamp >= timestamp {
+                return Ok(());
+            }
+        }
+
+        match user {
+            Some(user) => {
+                let value = bincode_options().serialize(&user)?;
+                tree.set_with_timestamp(key, value, timestamp).await?;
+            }
+            None => {
+                tree.delete_with_timestamp(key, timestamp).await?;
+            }
+        }
+        self.verified_credentials.borrow_mut().remove(&username);
 
         Ok(())
     }
 
-    pub async fn drop_collection(&self, name: &str) -> Result<()> {
-        let _ = remove(self.get_collection_metadata_path(name)).await;
+    pub async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<User> {
+        let user = self
+            .get_user(username)
+            .await?
+            .ok_or(Error::InvalidCredentials)?;
+
+        let digest = Sha256::digest(password.as_bytes()).to_vec();
+        let verified = self.verified_credentials.borrow().get(username).map_or(
+            false,
+            |(password_hash, verified_digest)| {
+                password_hash == &user.password_hash
+                    && verified_digest == &digest
+            },
+        );
 
-        self.collections
-            .borrow_mut()
-            .remove(name)
-            .ok_or_else(|| Error::CollectionNotFound(name.to_string()))?
-            .tree
-            .purge()?;
-        self.collections_change_event.notify();
+        if !verified {
+            if !user.verify_password(password) {
+                return Err(Error::InvalidCredentials);
+            }
+            self.verified_credentials.borrow_mut().insert(
+                username.to_string(),
+                (user.password_hash.clone(), digest),
+            );
+        }
 
-        Ok(())
+        Ok(user)
     }
 
     pub fn try_to_stop_local_shards(&self) {
@@ -548,15 +2083,19 @@ impl MyShard {
     ) -> Result<()> {
         // All events should be idempotent.
         match event {
-            ShardEvent::Gossip(event) => {
+            ShardEvent::Gossip(event, timestamp) => {
+                self.clock.update(timestamp);
                 self.handle_gossip_event(event).await?;
             }
             ShardEvent::Set(collection, key, value, timestamp) => {
-                self.handle_shard_set_message(
+                self.handle_migrated_set_message(
                     collection, key, value, timestamp,
                 )
                 .await?;
             }
+            ShardEvent::PaxosState(collection, key, state) => {
+                self.merge_paxos_state(&collection, &key, state).await?;
+            }
         };
 
         Ok(())
@@ -580,6 +2119,7 @@ impl MyShard {
             ids,
             gossip_port: self.args.gossip_port,
             db_port: self.args.port,
+            incarnation: self.incarnation.get(),
         }
     }
 
@@ -617,6 +2157,37 @@ impl MyShard {
         Ok(false)
     }
 
+    /// The hash ranges this shard owns, see `ShardStatus::owned_ranges`.
+    pub fn owned_ranges(&self, replication_factor: usize) -> Vec<(u32, u32)> {
+        let shards = self.shards.borrow();
+        if shards.len() < 2 {
+            return vec![(self.hash, self.hash)];
+        }
+
+        let mut ranges = vec![(shards[shards.len() - 1].hash, shards[0].hash)];
+        let mut nodes = HashSet::with_capacity(replication_factor);
+
+        // Same walk as in owns_key().
+        for i in (1..shards.len()).rev() {
+            if ranges.len() >= replication_factor {
+                break;
+            }
+
+            let shard = &shards[i];
+            let previous_shard = &shards[i - 1];
+            if shard.node_name == previous_shard.node_name
+                || nodes.contains(&shard.node_name)
+            {
+                continue;
+            }
+
+            nodes.insert(&shard.node_name);
+            ranges.push((previous_shard.hash, shard.hash));
+        }
+
+        ranges
+    }
+
     pub fn add_shards_of_nodes(&self, nodes: &[NodeMetadata]) {
         self.shards.borrow_mut().extend(
             nodes
@@ -644,7 +2215,9 @@ impl MyShard {
                         shard_name,
                         ShardConnection::Remote(
                             RemoteShardConnection::from_args(
-                                address, &self.args,
+                                address,
+                                &self.args,
+                                self.tls.as_ref(),
                             ),
                         ),
                     )
@@ -681,59 +2254,236 @@ impl MyShard {
     }
 
     pub fn get_cluster_metadata(&self) -> ClusterMetadata {
+        self.prune_dead_nodes();
         ClusterMetadata {
             nodes: self.get_nodes(),
             collections: self
                 .collections
                 .borrow()
                 .iter()
-                .map(|(k, v)| (k.clone(), v.metadata.replication_factor))
+                .map(|(k, v)| (k.clone(), v.metadata.clone()))
+                .collect(),
+            dead_nodes: self
+                .dead_nodes
+                .borrow()
+                .iter()
+                .map(|(name, (incarnation, _))| (name.clone(), *incarnation))
                 .collect(),
+            dropped_collections: self.dropped_collections.borrow().clone(),
+        }
+    }
+
+    /// Reconcile the cluster metadata of a remote node with ours, by handling
+    /// every difference as if it was a gossip event we have missed.
+    /// Must be called by a single shard of a node, as it broadcasts the missed
+    /// events to all other local shards.
+    pub async fn reconcile_cluster_metadata(
+        self: Rc<Self>,
+        metadata: ClusterMetadata,
+    ) -> Result<()> {
+        let mut events = Vec::new();
+
+        for node in metadata.nodes {
+            if node.name != self.args.name
+                && self.is_alive_event_newer(&node.name, node.incarnation)
+            {
+                events.push(GossipEvent::Alive(node));
+            }
+        }
+
+        // Deaths of nodes we know nothing about are not taken, or nodes would
+        // keep passing a death back and forth long after it expired.
+        for (node_name, incarnation) in metadata.dead_nodes {
+            let known = node_name == self.args.name
+                || self.nodes.borrow().contains_key(&node_name)
+                || self.dead_nodes.borrow().contains_key(&node_name);
+            if known && self.is_dead_event_newer(&node_name, incarnation) {
+                events.push(GossipEvent::Dead(node_name, incarnation));
+            }
+        }
+
+        for (name, dropped_at) in &metadata.dropped_collections {
+            if !self.is_collection_dropped(name, *dropped_at) {
+                events.push(GossipEvent::DropCollection(
+                    name.clone(),
+                    *dropped_at,
+                ));
+            }
+        }
+
+        for (name, collection_metadata) in metadata.collections {
+            // Dropped by us, or by the drop events above.
+            let created_at = collection_metadata.created_at;
+            if self.is_collection_dropped(&name, created_at)
+                || metadata
+                    .dropped_collections
+                    .get(&name)
+                    .is_some_and(|dropped_at| *dropped_at >= created_at)
+            {
+                continue;
+            }
+
+            let current = self
+                .collections
+                .borrow()
+                .get(&name)
+                .map(|c| c.metadata.clone());
+            let current = if let Some(current) = current {
+                current
+            } else {
+                let created = CollectionMetadata {
+                    version: 0,
+                    ..collection_metadata.clone()
+                };
+                events.push(GossipEvent::CreateCollection(
+                    name.clone(),
+                    created.clone(),
+                ));
+                created
+            };
+
+            if let Some(truncated_at) = collection_metadata.truncated_at {
+                if current.truncated_at.map_or(true, |at| at < truncated_at) {
+                    events.push(GossipEvent::TruncateCollection(
+                        name.clone(),
+                        truncated_at,
+                    ));
+                }
+            }
+
+            if collection_metadata.is_newer_than(&current) {
+                events.push(GossipEvent::AlterCollection(
+                    name,
+                    collection_metadata,
+                ));
+            }
         }
+
+        for event in events {
+            trace!("Anti entropy: {:?}", event);
+            self.broadcast_message_to_local_shards(&ShardMessage::Event(
+                ShardEvent::Gossip(event.clone(), self.clock.now()),
+            ))
+            .await?;
+            self.clone().handle_gossip_event(event).await?;
+        }
+
+        notify_flow_event!(self, FlowEvent::ClusterMetadataReconciled);
+
+        Ok(())
     }
 
     async fn handle_shard_request(
-        &self,
+        self: Rc<Self>,
         request: ShardRequest,
     ) -> Result<ShardResponse> {
         let response = match request {
             ShardRequest::Ping => ShardResponse::Pong,
+            ShardRequest::SyncMetadata(metadata) => {
+                // Respond with what we knew before the sync, the remote node
+                // reconciles the differences on its side.
+                let response =
+                    ShardResponse::SyncMetadata(self.get_cluster_metadata());
+                self.clone().reconcile_cluster_metadata(metadata).await?;
+                response
+            }
             ShardRequest::GetMetadata => {
                 ShardResponse::GetMetadata(self.get_nodes())
             }
+            ShardRequest::GetUsers => {
+                ShardResponse::GetUsers(self.get_users().await?)
+            }
             ShardRequest::GetCollections => ShardResponse::GetCollections(
                 self.collections
                     .borrow()
                     .iter()
-                    .map(|(n, c)| (n.clone(), c.metadata.replication_factor))
+                    .map(|(n, c)| (n.clone(), c.metadata.clone()))
                     .collect::<Vec<_>>(),
             ),
-            ShardRequest::CreateCollection(name, replication_factor) => {
-                self.create_collection(name, replication_factor).await?;
+            ShardRequest::GetStats => {
+                ShardResponse::GetStats(Box::new(self.get_stats()))
+            }
+            ShardRequest::GetStatus => {
+                ShardResponse::GetStatus(self.get_status())
+            }
+            ShardRequest::GetCollectionCount(name) => {
+                ShardResponse::GetCollectionCount(
+                    self.get_collection_primary_count(&name).await?,
+                )
+            }
+            ShardRequest::GetCollectionSummaries => {
+                ShardResponse::GetCollectionSummaries(
+                    self.get_collection_summaries().await?,
+                )
+            }
+            ShardRequest::GetCollectionStats(name) => {
+                ShardResponse::GetCollectionStats(
+                    self.get_collection_stats(&name)?,
+                )
+            }
+            ShardRequest::FlushCollection(name) => {
+                self.flush_collection(&name).await?;
+                ShardResponse::FlushCollection
+            }
+            ShardRequest::CompactCollection(name) => {
+                self.compact_collection(&name).await?;
+                ShardResponse::CompactCollection
+            }
+            ShardRequest::CreateCollection(name, metadata) => {
+                self.create_collection_with_metadata(name, metadata).await?;
                 ShardResponse::CreateCollection
             }
-            ShardRequest::DropCollection(name) => {
-                self.drop_collection(&name).await?;
+            ShardRequest::AlterCollection(name, metadata) => {
+                self.clone().alter_collection(name, metadata).await?;
+                ShardResponse::AlterCollection
+            }
+            ShardRequest::DropCollection(name, timestamp) => {
+                self.drop_collection(&name, timestamp).await?;
                 ShardResponse::DropCollection
             }
+            ShardRequest::TruncateCollection(name, timestamp) => {
+                self.truncate_collection(&name, timestamp).await?;
+                ShardResponse::TruncateCollection
+            }
+            ShardRequest::Ingest(name, dir) => ShardResponse::Ingest(
+                self.ingest_collection(&name, &dir).await?,
+            ),
             ShardRequest::Set(collection, key, value, timestamp) => {
                 self.handle_shard_set_message(
-                    collection, key, value, timestamp,
+                    collection,
+                    key,
+                    Some(value),
+                    timestamp,
                 )
                 .await?;
                 ShardResponse::Set
             }
             ShardRequest::Delete(collection, key, timestamp) => {
-                let existing_tree = self
-                    .collections
-                    .borrow()
-                    .get(&collection)
-                    .map(|c| c.tree.clone());
-                if let Some(tree) = existing_tree {
-                    tree.delete_with_timestamp(key, timestamp).await?;
+                let existing_collection =
+                    self.collections.borrow().get(&collection).cloned();
+                if let Some(existing_collection) = existing_collection {
+                    self.write_to_collection(
+                        &collection,
+                        &existing_collection,
+                        key,
+                        None,
+                        timestamp,
+                    )
+                    .await?;
                 };
                 ShardResponse::Delete
             }
+            ShardRequest::Transaction(collection, items, timestamp) => {
+                let existing_collection = self.get_collection(&collection)?;
+                self.write_batch_to_collection(
+                    &collection,
+                    &existing_collection,
+                    items,
+                    timestamp,
+                )
+                .await?;
+                ShardResponse::Transaction
+            }
             ShardRequest::Get(collection, key) => {
                 let existing_tree = self
                     .collections
@@ -747,25 +2497,67 @@ impl MyShard {
                 };
                 ShardResponse::Get(value)
             }
+            ShardRequest::PaxosPrepare(collection, key, ballot) => {
+                ShardResponse::PaxosPrepare(
+                    self.paxos_prepare(&collection, &key, ballot).await?,
+                )
+            }
+            ShardRequest::PaxosPropose(collection, key, proposal) => {
+                ShardResponse::PaxosPropose(
+                    self.paxos_propose(&collection, &key, proposal).await?,
+                )
+            }
+            ShardRequest::PaxosCommit(collection, key, proposal) => {
+                self.paxos_commit(&collection, key, proposal).await?;
+                ShardResponse::PaxosCommit
+            }
         };
 
         Ok(response)
     }
 
+    /// Unlike a replicated write, a migrated entry is not a new change, so
+    /// it's neither appended to the change log nor sent to watchers.
+    async fn handle_migrated_set_message(
+        &self,
+        collection: String,
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+        timestamp: OffsetDateTime,
+    ) -> Result<()> {
+        let existing_collection = self.get_collection(&collection)?;
+        let _guard = existing_collection.write_lock.read().await?;
+        let existing_collection =
+            self.get_locked_collection(&collection, &existing_collection)?;
+
+        self.clock.update(timestamp);
+        existing_collection
+            .tree
+            .clone()
+            .set_batch_with_timestamp(vec![(key, value)], timestamp)
+            .await?;
+
+        notify_flow_event!(self, FlowEvent::ItemSetFromShardMessage);
+
+        Ok(())
+    }
+
     async fn handle_shard_set_message(
         &self,
         collection: String,
         key: Vec<u8>,
-        value: Vec<u8>,
+        value: Option<Vec<u8>>,
         timestamp: OffsetDateTime,
     ) -> Result<()> {
-        let tree = self
-            .collections
-            .borrow()
-            .get(&collection)
-            .map(|c| c.tree.clone())
-            .ok_or_else(|| Error::CollectionNotFound(collection))?;
-        tree.set_with_timestamp(key, value, timestamp).await?;
+        let existing_collection = self.get_collection(&collection)?;
+        self.write_to_collection(
+            &collection,
+            &existing_collection,
+            key,
+            value,
+            timestamp,
+        )
+        .await?;
 
         notify_flow_event!(self, FlowEvent::ItemSetFromShardMessage);
 
@@ -789,14 +2581,26 @@ impl MyShard {
     }
 
     pub async fn gossip(&self, event: GossipEvent) -> Result<()> {
+        let timestamp = self.clock.now();
         self.broadcast_message_to_local_shards(&ShardMessage::Event(
-            ShardEvent::Gossip(event.clone()),
+            ShardEvent::Gossip(event.clone(), timestamp),
         ))
         .await?;
 
-        let message = GossipMessage::new(self.args.name.clone(), event);
-        self.gossip_buffer(&serialize_gossip_message(&message)?)
-            .await
+        let sequence = self.gossip_sequence.get();
+        self.gossip_sequence.set(sequence + 1);
+
+        let message = GossipMessage::new(
+            self.shard_name.clone(),
+            sequence,
+            timestamp,
+            event,
+        );
+        self.gossip_buffer(&serialize_gossip_message(
+            &message,
+            self.args.gossip_secret.as_deref(),
+        )?)
+        .await
     }
 
     pub async fn gossip_buffer(&self, message_buffer: &[u8]) -> Result<()> {
@@ -816,6 +2620,8 @@ impl MyShard {
             })
             .collect::<Result<Vec<_>>>()?;
 
+        increment(&self.metrics.gossip_packets_sent, addresses.len() as u64);
+
         let futures = addresses
             .into_iter()
             .zip(sockets.iter())
@@ -826,9 +2632,41 @@ impl MyShard {
         Ok(())
     }
 
-    pub async fn handle_dead_node(self: Rc<Self>, node_name: &str) {
+    /// Forget the nodes that were seen dead long ago.
+    fn prune_dead_nodes(&self) {
+        self.dead_nodes.borrow_mut().retain(|_, (_, seen_dead_at)| {
+            seen_dead_at.elapsed() < DEAD_NODE_EXPIRATION
+        });
+    }
+
+    /// Remove a dead node from the ring, returns false when the event is stale
+    /// (the node has refuted it with a greater incarnation).
+    pub async fn handle_dead_node(
+        self: Rc<Self>,
+        node_name: &str,
+        incarnation: u64,
+    ) -> bool {
+        if let Some(node) = self.nodes.borrow().get(node_name) {
+            if incarnation < node.incarnation {
+                return false;
+            }
+        }
+
+        self.prune_dead_nodes();
+        let now = Instant::now();
+        self.dead_nodes
+            .borrow_mut()
+            .entry(node_name.to_string())
+            .and_modify(|(i, seen_dead_at)| {
+                if incarnation > *i {
+                    *i = incarnation;
+                    *seen_dead_at = now;
+                }
+            })
+            .or_insert((incarnation, now));
+
         if self.nodes.borrow_mut().remove(node_name).is_none() {
-            return;
+            return true;
         }
 
         let (removed, kept): (Vec<_>, Vec<_>) = self
@@ -848,6 +2686,8 @@ impl MyShard {
         notify_flow_event!(self, FlowEvent::DeadNodeRemoved);
 
         self.migrate_data_on_node_removal(&removed).await;
+
+        true
     }
 
     async fn migrate_data_on_node_removal(
@@ -1071,6 +2911,76 @@ impl MyShard {
         );
     }
 
+    fn migrate_data_on_replication_factor_change(
+        self: Rc<Self>,
+        collection_name: String,
+        old_replication_factor: usize,
+        new_replication_factor: usize,
+    ) {
+        let mut actions = Vec::new();
+
+        {
+            let shards = self.shards.borrow();
+            if shards.len() < 2 {
+                return;
+            }
+
+            // The previous shard in the hash ring is the last one in the vector.
+            let previous_shard_hash = shards[shards.len() - 1].hash;
+
+            if new_replication_factor > old_replication_factor {
+                // Send the items this shard is the first owner of, to the shards
+                // that are now also replicas of these items.
+                let mut nodes = HashSet::with_capacity(new_replication_factor);
+                for shard in shards.iter() {
+                    if !nodes.insert(&shard.node_name) {
+                        continue;
+                    }
+
+                    if nodes.len() > old_replication_factor {
+                        actions.push(RangeAndAction::new(
+                            previous_shard_hash,
+                            self.hash,
+                            MigrationAction::SendToShard(
+                                shard.connection.clone(),
+                            ),
+                        ));
+                    }
+
+                    if nodes.len() == new_replication_factor {
+                        break;
+                    }
+                }
+            } else {
+                // Delete items of the shards this shard is no longer a replica
+                // of. It's ok to delete while migrating, because this shard is
+                // not the first owner of these items.
+                // The range of the first shard starts at the last shard, as
+                // the ring wraps around.
+                for i in 0..shards.len() {
+                    let previous = (i + shards.len() - 1) % shards.len();
+                    if self.is_owning_shard(i, old_replication_factor)
+                        && !self.is_owning_shard(i, new_replication_factor)
+                    {
+                        actions.push(RangeAndAction::new(
+                            shards[previous].hash,
+                            shards[i].hash,
+                            MigrationAction::Delete,
+                        ));
+                    }
+                }
+            }
+        }
+
+        if !actions.is_empty() {
+            spawn_migration_actions_tasks(
+                self,
+                vec![(collection_name, actions)],
+                None,
+            );
+        }
+    }
+
     fn get_last_owning_shard(
         shards: &[Shard],
         start_shard_hash: u32,
@@ -1136,11 +3046,39 @@ impl MyShard {
         // multiple times.
         let another_gossip_sent = match event {
             GossipEvent::Alive(node) if node.name != self.args.name => {
-                self.nodes
-                    .borrow_mut()
-                    .entry(node.name.clone())
-                    .or_insert_with(|| node.clone());
+                if !self.is_alive_event_newer(&node.name, node.incarnation) {
+                    return Ok(false);
+                }
+
+                self.dead_nodes.borrow_mut().remove(&node.name);
                 let node_name = node.name.clone();
+                let previous = self
+                    .nodes
+                    .borrow_mut()
+                    .insert(node_name.clone(), node.clone());
+
+                // The shards of the node that were already in the ring.
+                let previous_shards = match previous {
+                    Some(previous) if same_addresses(&previous, &node) => {
+                        // The node refuted a suspicion of its death (or
+                        // restarted the same before it was seen dead), its
+                        // shards never left the ring.
+                        return Ok(true);
+                    }
+                    Some(_) => {
+                        // Restarted before it was seen dead, with other
+                        // addresses or shards, so its shards are added again.
+                        let (previous_shards, kept): (Vec<_>, Vec<_>) = self
+                            .shards
+                            .replace(Vec::new())
+                            .into_iter()
+                            .partition(|shard| shard.node_name == node_name);
+                        self.shards.replace(kept);
+                        previous_shards
+                    }
+                    None => Vec::new(),
+                };
+
                 self.add_shards_of_nodes(&[node]);
                 trace!(
                     "After alive of {}: holding {} nodes and {} shards",
@@ -1155,37 +3093,91 @@ impl MyShard {
                     .shards
                     .borrow()
                     .iter()
-                    .filter(|s| s.node_name == node_name)
+                    .filter(|s| {
+                        s.node_name == node_name
+                            && !previous_shards.iter().any(|p| p.name == s.name)
+                    })
                     .cloned()
                     .collect::<Vec<_>>();
-                self.migrate_data_on_node_addition(&added);
+                let removed = previous_shards
+                    .into_iter()
+                    .filter(|p| {
+                        !self.shards.borrow().iter().any(|s| s.name == p.name)
+                    })
+                    .collect::<Vec<_>>();
+
+                if !removed.is_empty() {
+                    self.clone().migrate_data_on_node_removal(&removed).await;
+                }
+                if !added.is_empty() {
+                    self.migrate_data_on_node_addition(&added);
+                }
 
                 false
             }
-            GossipEvent::Dead(node_name) => {
+            GossipEvent::Dead(node_name, incarnation) => {
                 if node_name == self.args.name {
                     // Whoops, someone marked us as dead, even though we are alive
                     // and well.
-                    // Let's notify everyone that we are actually alive.
+                    // Let's notify everyone that we are actually alive, with an
+                    // incarnation that overrides the death event.
+                    if !self.incarnation.refute(incarnation) {
+                        // Already refuted.
+                        return Ok(false);
+                    }
                     self.gossip(GossipEvent::Alive(self.get_node_metadata()))
                         .await?;
                     true
                 } else {
-                    self.handle_dead_node(&node_name).await;
+                    if !self.handle_dead_node(&node_name, incarnation).await {
+                        return Ok(false);
+                    }
                     false
                 }
             }
-            GossipEvent::CreateCollection(name, replication_factor) => {
-                match self.create_collection(name, replication_factor).await {
-                    Ok(()) | Err(Error::CollectionAlreadyExists(_)) => {}
+            GossipEvent::CreateCollection(name, metadata) => {
+                match self.create_collection_with_metadata(name, metadata).await
+                {
+                    Ok(())
+                    | Err(
+                        Error::CollectionAlreadyExists(_)
+                        | Error::CollectionDropped(_),
+                    ) => {}
+                    Err(e) => {
+                        return Err(e);
+                    }
+                };
+                false
+            }
+            GossipEvent::AlterCollection(name, metadata) => {
+                match self.alter_collection(name, metadata).await {
+                    Ok(_) | Err(Error::CollectionNotFound(_)) => {}
+                    Err(e) => {
+                        return Err(e);
+                    }
+                };
+                false
+            }
+            GossipEvent::DropCollection(name, timestamp) => {
+                match self.drop_collection(&name, timestamp).await {
+                    Ok(()) | Err(Error::CollectionNotFound(_)) => {}
+                    Err(e) => {
+                        return Err(e);
+                    }
+                };
+                false
+            }
+            GossipEvent::TruncateCollection(name, timestamp) => {
+                match self.truncate_collection(&name, timestamp).await {
+                    Ok(()) | Err(Error::CollectionNotFound(_)) => {}
                     Err(e) => {
                         return Err(e);
                     }
                 };
                 false
             }
-            GossipEvent::DropCollection(name) => {
-                match self.drop_collection(&name).await {
+            GossipEvent::SetUser(username, user, timestamp) => {
+                match self.set_user(username, user, timestamp).await {
                     Ok(()) | Err(Error::CollectionNotFound(_)) => {}
                     Err(e) => {
                         return Err(e);
@@ -1199,6 +3191,40 @@ impl MyShard {
         Ok(!another_gossip_sent)
     }
 
+    /// Whether an alive event of a node should override what we currently
+    /// know about the node.
+    fn is_alive_event_newer(&self, node_name: &str, incarnation: u64) -> bool {
+        if let Some(node) = self.nodes.borrow().get(node_name) {
+            return incarnation > node.incarnation;
+        }
+
+        self.dead_nodes
+            .borrow()
+            .get(node_name)
+            .map_or(true, |(dead_incarnation, _)| {
+                incarnation > *dead_incarnation
+            })
+    }
+
+    /// Whether a dead event of a node holds anything we don't already know
+    /// about the node.
+    fn is_dead_event_newer(&self, node_name: &str, incarnation: u64) -> bool {
+        if node_name == self.args.name {
+            return incarnation >= self.incarnation.get();
+        }
+
+        if let Some(node) = self.nodes.borrow().get(node_name) {
+            return incarnation >= node.incarnation;
+        }
+
+        self.dead_nodes
+            .borrow()
+            .get(node_name)
+            .map_or(true, |(dead_incarnation, _)| {
+                incarnation > *dead_incarnation
+            })
+    }
+
     #[cfg(feature = "flow-events")]
     pub fn subscribe_to_flow_event(
         &self,
@@ -0,0 +1,222 @@
+//! Sibling values, for collections that can't tolerate last-write-wins:
+//! concurrent writes to a key are all kept (as siblings), until a client
+//! that has read them writes a value that resolves them (like Riak).
+//!
+//! Each sibling is tagged with a dot - the shard that coordinated its write
+//! and a counter, next to a version vector of all dots the key has seen
+//! (dotted version vectors). A write replaces only the siblings that are in
+//! its causal context (the version vector returned from a read), siblings
+//! written concurrently are kept.
+//!
+//! Only the coordinating shard creates dots of its own, from the full value
+//! it holds for the key, and that full value is what it replicates, so a
+//! value holding a dot always knows of all the older dots of the same shard.
+//! Merging values is commutative, associative and idempotent, so values
+//! can be merged in any order: in the memtable, in compaction and when
+//! resolving the values of multiple replicas.
+
+use std::collections::BTreeMap;
+
+use bincode::Options;
+use rmpv::{decode::read_value, Value};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Result,
+    storage_engine::{EntryKind, EntryValue},
+    utils::bincode::bincode_options,
+};
+
+/// Shard hash to the greatest counter of a dot seen from the shard.
+pub type VersionVector = BTreeMap<u32, u64>;
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub struct Dot {
+    /// The hash of the shard that coordinated the write.
+    pub node: u32,
+    pub counter: u64,
+}
+
+impl Dot {
+    pub fn is_covered_by(&self, context: &VersionVector) -> bool {
+        context
+            .get(&self.node)
+            .is_some_and(|counter| *counter >= self.counter)
+    }
+}
+
+/// The value stored for a key in a collection with siblings.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Siblings {
+    /// All dots the key has seen, including the dots of the siblings.
+    pub context: VersionVector,
+
+    /// The concurrently written values, sorted by their dots.
+    /// Empty when all values were deleted.
+    pub values: Vec<(Dot, Vec<u8>)>,
+}
+
+impl Siblings {
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        Ok(bincode_options().deserialize(data)?)
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(bincode_options().serialize(self)?)
+    }
+
+    /// Merge with the value of another replica, a sibling is kept when both
+    /// values have it, or when the other value has never seen it.
+    #[must_use]
+    pub fn merge(self, other: Self) -> Self {
+        let mut values = Vec::with_capacity(self.values.len());
+        for (dot, value) in &self.values {
+            if !dot.is_covered_by(&other.context)
+                || other.values.iter().any(|(d, _)| d == dot)
+            {
+                values.push((*dot, value.clone()));
+            }
+        }
+        for (dot, value) in other.values {
+            if !dot.is_covered_by(&self.context) {
+                values.push((dot, value));
+            }
+        }
+        values.sort_unstable_by_key(|(dot, _)| *dot);
+
+        let mut context = self.context;
+        join(&mut context, &other.context);
+
+        Self { context, values }
+    }
+
+    /// A write coordinated by the `node` shard, replacing the siblings in the
+    /// client's causal context, a None value deletes them.
+    #[must_use]
+    pub fn update(
+        mut self,
+        client_context: &VersionVector,
+        value: Option<Vec<u8>>,
+        node: u32,
+    ) -> Self {
+        self.values
+            .retain(|(dot, _)| !dot.is_covered_by(client_context));
+        join(&mut self.context, client_context);
+
+        if let Some(value) = value {
+            let counter = self.context.entry(node).or_insert(0);
+            *counter += 1;
+            self.values.push((
+                Dot {
+                    node,
+                    counter: *counter,
+                },
+                value,
+            ));
+        }
+
+        self
+    }
+
+    /// Encode as the response of a read: {"values": [...], "context": bin},
+    /// the context is passed back on the next write to resolve the values.
+    pub fn to_value(&self) -> Result<Value> {
+        let mut values = Vec::with_capacity(self.values.len());
+        for (_, value) in &self.values {
+            values.push(read_value(&mut &value[..])?);
+        }
+
+        Ok(Value::Map(vec![
+            (Value::String("values".into()), Value::Array(values)),
+            (
+                Value::String("context".into()),
+                Value::Binary(encode_context(&self.context)?),
+            ),
+        ]))
+    }
+}
+
+pub fn join(context: &mut VersionVector, other: &VersionVector) {
+    for (node, counter) in other {
+        let current = context.entry(*node).or_insert(0);
+        *current = (*current).max(*counter);
+    }
+}
+
+pub fn encode_context(context: &VersionVector) -> Result<Vec<u8>> {
+    Ok(bincode_options().serialize(context)?)
+}
+
+pub fn decode_context(data: &[u8]) -> Result<VersionVector> {
+    Ok(bincode_options().deserialize(data)?)
+}
+
+/// The merge function of trees of collections with siblings.
+pub fn merge_entry_values(
+    a: &EntryValue,
+    b: &EntryValue,
+) -> Result<EntryValue> {
+    Ok(EntryValue {
+        data: Siblings::decode(&a.data)?
+            .merge(Siblings::decode(&b.data)?)
+            .encode()?,
+        timestamp: a.timestamp.max(b.timestamp),
+        kind: EntryKind::Put,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: u32 = 1;
+    const B: u32 = 2;
+
+    fn values(siblings: &Siblings) -> Vec<Vec<u8>> {
+        siblings.values.iter().map(|(_, v)| v.clone()).collect()
+    }
+
+    #[test]
+    fn concurrent_writes_are_siblings() {
+        let empty = VersionVector::new();
+        let first = Siblings::default().update(&empty, Some(vec![1]), A);
+        let second = first.clone().update(&empty, Some(vec![2]), A);
+        assert_eq!(values(&second), vec![vec![1], vec![2]]);
+
+        // Resolve the siblings by writing with the context that saw both.
+        let resolved = second.clone().update(&second.context, Some(vec![3]), A);
+        assert_eq!(values(&resolved), vec![vec![3]]);
+    }
+
+    #[test]
+    fn merge_keeps_concurrent_values() {
+        let empty = VersionVector::new();
+        let base = Siblings::default().update(&empty, Some(vec![1]), A);
+
+        // Both replicas overwrite the base value concurrently.
+        let a = base.clone().update(&base.context, Some(vec![2]), A);
+        let b = base.clone().update(&base.context, Some(vec![3]), B);
+
+        let merged = a.clone().merge(b.clone());
+        assert_eq!(values(&merged), vec![vec![2], vec![3]]);
+        assert_eq!(merged, b.clone().merge(a.clone()));
+        assert_eq!(merged, merged.clone().merge(a));
+        assert_eq!(merged.clone().merge(base), merged);
+    }
+
+    #[test]
+    fn delete_removes_seen_values_only() {
+        let empty = VersionVector::new();
+        let first = Siblings::default().update(&empty, Some(vec![1]), A);
+        let seen = first.context.clone();
+        let second = first.update(&empty, Some(vec![2]), B);
+
+        let deleted = second.clone().update(&seen, None, A);
+        assert_eq!(values(&deleted), vec![vec![2]]);
+
+        // A replica that missed the delete doesn't bring the value back.
+        assert_eq!(values(&deleted.merge(second)), vec![vec![2]]);
+    }
+}
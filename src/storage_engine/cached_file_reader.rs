@@ -58,8 +58,10 @@ impl CachedFileReader {
                 output_buf[written..written + write_size]
                     .copy_from_slice(&page[start..end]);
                 written += write_size;
+                self.cache.record_lookup(true);
                 continue;
             }
+            self.cache.record_lookup(false);
 
             // Not found in cache, read from disk.
             let page = self.file.read_at_aligned(address, PAGE_SIZE).await?;
@@ -24,6 +24,7 @@ pub struct EntryWriter {
     data_written: usize,
     index_buf: [u8; PAGE_SIZE],
     index_written: usize,
+    header_written: bool,
 }
 
 impl EntryWriter {
@@ -65,13 +66,30 @@ impl EntryWriter {
             data_written: 0,
             index_buf: [0; PAGE_SIZE],
             index_written: 0,
+            header_written: false,
         }
     }
 
+    /// Write the header of the index file (the format version of the
+    /// sstable), before the first entry.
+    async fn write_header(&mut self) -> Result<()> {
+        if self.header_written {
+            return Ok(());
+        }
+        self.header_written = true;
+
+        let header_encoded =
+            bincode_options().serialize(&EntryOffset::header())?;
+        self.index_writer.write_all(&header_encoded).await?;
+        self.write_to_cache(header_encoded, false);
+        Ok(())
+    }
+
     pub async fn write(&mut self, entry: &Entry) -> Result<(usize, usize)> {
         if bincode_options().serialized_size(entry)? > u64::from(u32::MAX) {
             return Err(Error::ItemTooLarge);
         }
+        self.write_header().await?;
 
         let data_encoded = bincode_options().serialize(entry)?;
         let data_size = data_encoded.len();
@@ -138,6 +156,8 @@ impl EntryWriter {
     }
 
     pub async fn close(&mut self) -> Result<()> {
+        self.write_header().await?;
+
         let data_left = self.data_written % PAGE_SIZE;
         if data_left != 0 {
             self.page_cache.set(
@@ -0,0 +1,324 @@
+//! Read the files of a collection directory without opening an `LSMTree`,
+//! validating them along the way (used by the dbeel-inspect tool).
+
+use std::{
+    fmt::Display,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use bincode::Options;
+use bloomfilter::Bloom;
+use regex::Regex;
+
+use super::{
+    deserialize_entry, deserialize_wal_record,
+    lsm_tree::{get_file_path, CompactionAction},
+    page_cache::PAGE_SIZE,
+    read_index_header,
+    sketch::KeysSketch,
+    Entry, EntryOffset, RangeTombstone, DATA_FILE_EXT, INDEX_ENTRY_SIZE,
+    INDEX_FILE_EXT,
+};
+use crate::{error::Result, utils::bincode::bincode_options};
+
+/// A problem found in a file, at a byte offset.
+#[derive(Debug)]
+pub struct Corruption {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub reason: String,
+}
+
+impl Display for Corruption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}",
+            self.path.display(),
+            self.offset,
+            self.reason
+        )
+    }
+}
+
+/// An entry read from a file, offset is where the entry starts in the file
+/// (the start of its record in the WAL file, or in the data file of an
+/// sstable).
+#[derive(Debug)]
+pub struct InspectedEntry {
+    pub offset: u64,
+    pub entry: Entry,
+}
+
+#[derive(Debug, Default)]
+pub struct InspectedFile {
+    pub entries: Vec<InspectedEntry>,
+    pub corruptions: Vec<Corruption>,
+}
+
+impl InspectedFile {
+    fn corruption(&mut self, path: &Path, offset: u64, reason: String) {
+        self.corruptions.push(Corruption {
+            path: path.to_path_buf(),
+            offset,
+            reason,
+        });
+    }
+}
+
+#[derive(Debug)]
+pub struct BloomStats {
+    pub bits: u64,
+    pub bits_set: u64,
+    pub hash_functions: u32,
+}
+
+impl BloomStats {
+    /// The false positive rate, estimated from the ratio of bits set.
+    #[must_use]
+    pub fn estimated_false_positive_rate(&self) -> f64 {
+        if self.bits == 0 {
+            return 1.0;
+        }
+        (self.bits_set as f64 / self.bits as f64)
+            .powi(self.hash_functions as i32)
+    }
+}
+
+/// A file in a collection directory, named "{index}.{extension}".
+#[derive(Debug)]
+pub struct CollectionFile {
+    pub index: usize,
+    pub extension: String,
+    pub path: PathBuf,
+}
+
+/// List all files of a collection directory, sorted by index and extension.
+/// Files that are not named like collection files are ignored.
+pub fn list_collection_files(dir: &Path) -> Result<Vec<CollectionFile>> {
+    let pattern = Regex::new(r"^(\d+)\.(\w+)$").unwrap();
+
+    let mut files = std::fs::read_dir(dir)?
+        .filter_map(std::result::Result::ok)
+        .filter_map(|entry| {
+            let file_name = entry.file_name();
+            let captures = pattern.captures(file_name.to_str()?)?;
+            Some(CollectionFile {
+                index: captures[1].parse().ok()?,
+                extension: captures[2].to_string(),
+                path: entry.path(),
+            })
+        })
+        .collect::<Vec<_>>();
+    files.sort_unstable_by(|a, b| {
+        a.index
+            .cmp(&b.index)
+            .then_with(|| a.extension.cmp(&b.extension))
+    });
+
+    Ok(files)
+}
+
+/// Read all records of a memtable WAL file, each record starts at a page
+/// boundary.
+pub fn inspect_wal(path: &Path) -> Result<InspectedFile> {
+    let buf = std::fs::read(path)?;
+    let mut result = InspectedFile::default();
+
+    let mut cursor = Cursor::new(&buf[..]);
+    let mut allow_legacy = true;
+    while cursor.position() < buf.len() as u64 {
+        let offset = cursor.position();
+        let end = match deserialize_wal_record(&mut cursor, &mut allow_legacy) {
+            Ok(entries) => {
+                result.entries.extend(
+                    entries
+                        .into_iter()
+                        .map(|entry| InspectedEntry { offset, entry }),
+                );
+                cursor.position()
+            }
+            Err(e) => {
+                result.corruption(path, offset, format!("bad WAL record: {e}"));
+                offset
+            }
+        };
+
+        // Records are padded the same way as when written to the WAL.
+        cursor
+            .set_position(end + (PAGE_SIZE as u64) - end % (PAGE_SIZE as u64));
+    }
+
+    Ok(result)
+}
+
+/// Read all entries of an sstable using its index file, validating that the
+/// entries are contiguous in the data file and sorted by key.
+pub fn inspect_sstable(dir: &Path, index: usize) -> Result<InspectedFile> {
+    let data_path = get_file_path(dir, index, DATA_FILE_EXT);
+    let index_path = get_file_path(dir, index, INDEX_FILE_EXT);
+    let data = std::fs::read(&data_path)?;
+    let index_buf = std::fs::read(&index_path)?;
+
+    let mut result = InspectedFile::default();
+
+    let (format_version, header_size) = match read_index_header(&index_buf) {
+        Ok(header) => header,
+        Err(e) => {
+            result.corruption(&index_path, 0, format!("bad header: {e}"));
+            return Ok(result);
+        }
+    };
+    let index_entries = &index_buf[header_size as usize..];
+
+    let trailing_index_bytes = index_entries.len() % INDEX_ENTRY_SIZE;
+    if trailing_index_bytes != 0 {
+        result.corruption(
+            &index_path,
+            (index_buf.len() - trailing_index_bytes) as u64,
+            format!("{trailing_index_bytes} trailing bytes"),
+        );
+    }
+
+    let mut expected_offset = 0u64;
+    for (i, chunk) in index_entries.chunks_exact(INDEX_ENTRY_SIZE).enumerate() {
+        let index_offset = header_size + (i * INDEX_ENTRY_SIZE) as u64;
+        let entry_offset: EntryOffset =
+            match bincode_options().deserialize(chunk) {
+                Ok(entry_offset) => entry_offset,
+                Err(e) => {
+                    result.corruption(
+                        &index_path,
+                        index_offset,
+                        format!("bad index entry: {e}"),
+                    );
+                    continue;
+                }
+            };
+
+        let start = entry_offset.offset;
+        let end = start + u64::from(entry_offset.full_size);
+        if start != expected_offset {
+            result.corruption(
+                &index_path,
+                index_offset,
+                format!("points to offset {start}, expected {expected_offset}"),
+            );
+        }
+        expected_offset = end;
+
+        if end > data.len() as u64 {
+            result.corruption(
+                &index_path,
+                index_offset,
+                format!(
+                    "entry ends at {end}, after the end of the data file ({})",
+                    data.len()
+                ),
+            );
+            continue;
+        }
+
+        let entry = match deserialize_entry(
+            format_version,
+            &data[start as usize..end as usize],
+        ) {
+            Ok(entry) => entry,
+            Err(e) => {
+                result.corruption(&data_path, start, format!("bad entry: {e}"));
+                continue;
+            }
+        };
+
+        let key_size = bincode_options().serialized_size(&entry.key)?;
+        if key_size != u64::from(entry_offset.key_size) {
+            result.corruption(
+                &index_path,
+                index_offset,
+                format!(
+                    "key size is {}, but the entry key size is {key_size}",
+                    entry_offset.key_size
+                ),
+            );
+        }
+
+        if let Some(previous) = result.entries.last() {
+            if previous.entry.key >= entry.key {
+                result.corruption(
+                    &data_path,
+                    start,
+                    "key is not greater than the previous key".to_string(),
+                );
+            }
+        }
+
+        result.entries.push(InspectedEntry {
+            offset: start,
+            entry,
+        });
+    }
+
+    if data[(expected_offset as usize).min(data.len())..]
+        .iter()
+        .any(|b| *b != 0)
+    {
+        result.corruption(
+            &data_path,
+            expected_offset,
+            "data after the last indexed entry".to_string(),
+        );
+    }
+
+    Ok(result)
+}
+
+pub fn inspect_bloom(path: &Path) -> Result<BloomStats> {
+    let bloom: Bloom<Vec<u8>> =
+        bincode_options().deserialize(&std::fs::read(path)?)?;
+    Ok(BloomStats {
+        bits: bloom.number_of_bits(),
+        bits_set: bloom
+            .bitmap()
+            .iter()
+            .map(|b| u64::from(b.count_ones()))
+            .sum(),
+        hash_functions: bloom.number_of_hash_functions(),
+    })
+}
+
+pub fn inspect_range_tombstones(path: &Path) -> Result<Vec<RangeTombstone>> {
+    Ok(bincode_options().deserialize(&std::fs::read(path)?)?)
+}
+
+pub fn inspect_sketch(path: &Path) -> Result<KeysSketch> {
+    Ok(bincode_options().deserialize(&std::fs::read(path)?)?)
+}
+
+/// Read the actions of a compact action file, which are run when the tree is
+/// opened.
+pub fn inspect_compaction_actions(
+    path: &Path,
+) -> Result<(Vec<CompactionAction>, Vec<Corruption>)> {
+    let buf = std::fs::read(path)?;
+    let mut actions = Vec::new();
+    let mut corruptions = Vec::new();
+
+    let mut cursor = Cursor::new(&buf[..]);
+    while cursor.position() < buf.len() as u64 {
+        let offset = cursor.position();
+        match bincode_options().deserialize_from(&mut cursor) {
+            Ok(action) => actions.push(action),
+            Err(e) => {
+                corruptions.push(Corruption {
+                    path: path.to_path_buf(),
+                    offset,
+                    reason: format!("bad compaction action: {e}"),
+                });
+                break;
+            }
+        }
+    }
+
+    Ok((actions, corruptions))
+}
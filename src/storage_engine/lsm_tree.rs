@@ -2,12 +2,13 @@ use bincode::Options;
 use bloomfilter::Bloom;
 use futures::{try_join, AsyncReadExt, AsyncWriteExt};
 use glommio::{
-    enclose,
+    enclose, executor,
     io::{
-        remove, rename, DmaBuffer, DmaFile, DmaStreamReaderBuilder,
-        DmaStreamWriterBuilder, OpenOptions,
+        remove, rename, DmaBuffer, DmaFile, DmaStreamReader,
+        DmaStreamReaderBuilder, DmaStreamWriterBuilder, OpenOptions,
     },
     spawn_local,
+    sync::{Permit, Semaphore},
     timer::sleep,
 };
 use log::{error, trace};
@@ -19,24 +20,33 @@ use time::OffsetDateTime;
 use std::{
     cell::{Cell, RefCell},
     cmp::Ordering,
-    collections::BinaryHeap,
+    collections::{BinaryHeap, HashMap},
+    ops::Bound,
     path::{Path, PathBuf},
     rc::Rc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use super::{
     cached_file_reader::{CachedFileReader, FileId},
+    deserialize_entry, deserialize_entry_value, deserialize_wal_record,
     entry_writer::EntryWriter,
+    merge_values,
     page_cache::{PartitionPageCache, PAGE_SIZE},
-    Entry, EntryOffset, EntryValue, FileTypeKind, BLOOM_FILE_EXT,
+    read_index_header, serialize_wal_record,
+    sketch::{HyperLogLog, KeysSketch},
+    wal_record_size, Entry, EntryKind, EntryOffset, EntryValue, FileTypeKind,
+    MergeFn, MergeOperator, RangeTombstone, BLOOM_FILE_EXT,
     COMPACT_ACTION_FILE_EXT, COMPACT_BLOOM_FILE_EXT, COMPACT_DATA_FILE_EXT,
-    COMPACT_INDEX_FILE_EXT, DATA_FILE_EXT, DEFAULT_SSTABLE_BLOOM_MIN_SIZE,
+    COMPACT_INDEX_FILE_EXT, COMPACT_RANGE_TOMBSTONES_FILE_EXT,
+    COMPACT_SKETCH_FILE_EXT, DATA_FILE_EXT, DEFAULT_SSTABLE_BLOOM_MIN_SIZE,
     DEFAULT_TREE_CAPACITY, DMA_STREAM_NUMBER_OF_BUFFERS, INDEX_ENTRY_SIZE,
-    INDEX_FILE_EXT, INDEX_PADDING, MEMTABLE_FILE_EXT, TOMBSTONE,
+    INDEX_FILE_EXT, INDEX_PADDING, MEMTABLE_FILE_EXT,
+    RANGE_TOMBSTONES_FILE_EXT, SKETCH_FILE_EXT,
 };
 use crate::{
     error::{Error, Result},
+    metrics::{CollectionStats, Histogram},
     utils::{
         bincode::bincode_options,
         get_first_capture,
@@ -45,9 +55,69 @@ use crate::{
 };
 
 /// The acceptable error rate in the bloom filter value in (0, 1].
-const BLOOM_MAX_ALLOWED_ERROR: f64 = 0.01;
+pub(super) const BLOOM_MAX_ALLOWED_ERROR: f64 = 0.01;
 
-type MemTable = RedBlackTree<Vec<u8>, EntryValue>;
+struct MemTable {
+    entries: RedBlackTree<Vec<u8>, EntryValue>,
+    range_tombstones: Vec<RangeTombstone>,
+}
+
+impl MemTable {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            entries: RedBlackTree::with_capacity(capacity),
+            range_tombstones: Vec::new(),
+        }
+    }
+
+    /// Range tombstones take room like any other entry, so that they
+    /// trigger flushes too.
+    fn len(&self) -> usize {
+        self.entries.len() + self.range_tombstones.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.entries.capacity()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The entries of the keys from `from`, sorted by key.
+    fn entries_from(&self, from: Bound<&[u8]>) -> Vec<Entry> {
+        self.entries
+            .iter()
+            .skip_while(|(key, _)| is_before(key, from))
+            .map(|(key, value)| Entry {
+                key: key.clone(),
+                value: value.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Whether a key is before the start of a range.
+fn is_before(key: &[u8], from: Bound<&[u8]>) -> bool {
+    match from {
+        Bound::Included(start) => key < start,
+        Bound::Excluded(after) => key <= after,
+        Bound::Unbounded => false,
+    }
+}
+
+/// The newest timestamp of the range tombstones that cover a key, values of
+/// the key written up to it are deleted.
+fn deleted_at<'a>(
+    range_tombstones: impl IntoIterator<Item = &'a RangeTombstone>,
+    key: &[u8],
+) -> Option<OffsetDateTime> {
+    range_tombstones
+        .into_iter()
+        .filter(|range_tombstone| range_tombstone.covers(key))
+        .map(|range_tombstone| range_tombstone.timestamp)
+        .max()
+}
 
 #[derive(Eq, PartialEq)]
 struct CompactionItem {
@@ -70,27 +140,53 @@ impl PartialOrd for CompactionItem {
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct CompactionAction {
-    renames: Vec<(PathBuf, PathBuf)>,
-    deletes: Vec<PathBuf>,
+/// Reads the entries of an sstable being compacted, in order.
+struct CompactionReader {
+    data_reader: DmaStreamReader,
+    index_reader: DmaStreamReader,
+    format_version: u32,
+
+    /// The number of entries not read yet.
+    remaining: u64,
+
+    /// The key of the last entry read, to check the entries are sorted.
+    last_key: Option<Vec<u8>>,
+}
+
+/// Written before the renames / deletes that end a compaction, so they can be
+/// completed when the process crashes in the middle.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompactionAction {
+    pub renames: Vec<(PathBuf, PathBuf)>,
+    pub deletes: Vec<PathBuf>,
 }
 
 #[derive(Clone)]
 struct SSTable {
     index: usize,
+
+    /// The number of entries.
     size: u64,
+
+    /// The format of the entries, read from the header of the index file.
+    format_version: u32,
+
+    /// The offset of the first entry in the index file, after its header.
+    index_start: u64,
+
     data_file: Rc<DmaFile>,
     index_file: Rc<DmaFile>,
     bloom: Option<Rc<Bloom<Vec<u8>>>>,
+
+    /// Loaded fully into memory, as they are checked on every read.
+    range_tombstones: Rc<Vec<RangeTombstone>>,
+
+    /// None for sstables written before sketches were saved.
+    sketch: Option<Rc<KeysSketch>>,
 }
 
 impl SSTable {
-    async fn new_with_bloom_read(
-        dir: &Path,
-        index: usize,
-        size: u64,
-    ) -> Result<Self> {
+    async fn new_with_bloom_read(dir: &Path, index: usize) -> Result<Self> {
         let bloom_path = get_file_path(dir, index, BLOOM_FILE_EXT);
         let bloom = if bloom_path.exists() {
             let buf = read_file(&bloom_path).await?;
@@ -100,24 +196,50 @@ impl SSTable {
             None
         };
 
-        Self::new(dir, index, size, bloom).await
+        let range_tombstones_path =
+            get_file_path(dir, index, RANGE_TOMBSTONES_FILE_EXT);
+        let range_tombstones = if range_tombstones_path.exists() {
+            let buf = read_file(&range_tombstones_path).await?;
+            bincode_options().deserialize(&buf)?
+        } else {
+            Vec::new()
+        };
+
+        let sketch_path = get_file_path(dir, index, SKETCH_FILE_EXT);
+        let sketch = if sketch_path.exists() {
+            let buf = read_file(&sketch_path).await?;
+            Some(Rc::new(bincode_options().deserialize(&buf)?))
+        } else {
+            None
+        };
+
+        Self::new(dir, index, bloom, Rc::new(range_tombstones), sketch).await
     }
 
     async fn new(
         dir: &Path,
         index: usize,
-        size: u64,
         bloom: Option<Rc<Bloom<Vec<u8>>>>,
+        range_tombstones: Rc<Vec<RangeTombstone>>,
+        sketch: Option<Rc<KeysSketch>>,
     ) -> Result<Self> {
         let (data_path, index_path) = get_data_file_paths(dir, index);
         let data_file = Rc::new(DmaFile::open(&data_path).await?);
         let index_file = Rc::new(DmaFile::open(&index_path).await?);
+        let (format_version, index_start) =
+            read_sstable_format(&index_file).await?;
+        let size = (index_file.file_size().await? - index_start)
+            / INDEX_ENTRY_SIZE as u64;
         Ok(Self {
             index,
             size,
+            format_version,
+            index_start,
             data_file,
             index_file,
             bloom,
+            range_tombstones,
+            sketch,
         })
     }
 
@@ -145,6 +267,14 @@ pub struct AsyncIter<'a> {
     filter_fn: Box<IterFilterFn>,
     state: IterState,
 
+    /// The range tombstones of all memtables and sstables iterated over.
+    range_tombstones: Vec<RangeTombstone>,
+
+    /// The value folded from the entries iterated over so far of every key,
+    /// to fold the merge operands of newer memtables and sstables into.
+    /// Kept only when the tree has a merge operator.
+    folded: HashMap<Vec<u8>, EntryValue>,
+
     /// Optimization - read into this buffer when reading an entry from the
     /// index, instead of allocating a new buffer each time.
     index_buffer: [u8; INDEX_ENTRY_SIZE],
@@ -153,28 +283,40 @@ pub struct AsyncIter<'a> {
 impl<'a> AsyncIter<'a> {
     fn new(tree: &'a LSMTree, mut filter_fn: Box<IterFilterFn>) -> Self {
         let mut memtable: Vec<Entry> = Vec::new();
+        let mut range_tombstones = Vec::new();
         if let Some(tree) = tree.flush_memtable.borrow().as_ref() {
-            memtable.extend(tree.iter().filter(|(k, v)| filter_fn(k, v)).map(
-                |(k, v)| Entry {
-                    key: k.clone(),
-                    value: v.clone(),
-                },
-            ));
+            memtable.extend(
+                tree.entries.iter().filter(|(k, v)| filter_fn(k, v)).map(
+                    |(k, v)| Entry {
+                        key: k.clone(),
+                        value: v.clone(),
+                    },
+                ),
+            );
+            range_tombstones.extend(tree.range_tombstones.iter().cloned());
+        }
+        {
+            let active_memtable = tree.active_memtable.borrow();
+            memtable.extend(
+                active_memtable
+                    .entries
+                    .iter()
+                    .filter(|(k, v)| filter_fn(k, v))
+                    .map(|(k, v)| Entry {
+                        key: k.clone(),
+                        value: v.clone(),
+                    }),
+            );
+            range_tombstones
+                .extend(active_memtable.range_tombstones.iter().cloned());
         }
-        memtable.extend(
-            tree.active_memtable
-                .borrow()
-                .iter()
-                .filter(|(k, v)| filter_fn(k, v))
-                .map(|(k, v)| Entry {
-                    key: k.clone(),
-                    value: v.clone(),
-                }),
-        );
 
         // The sstable files we operate on will not be deleted until this
         // object is dropped.
         let sstables = tree.sstables.borrow().clone();
+        for sstable in sstables.iter() {
+            range_tombstones.extend(sstable.range_tombstones.iter().cloned());
+        }
 
         let state = if sstables.len() > 0 {
             IterState::UnreadSSTable(0)
@@ -188,11 +330,180 @@ impl<'a> AsyncIter<'a> {
             memtable: memtable.into_iter(),
             filter_fn: Box::new(filter_fn),
             state,
+            range_tombstones,
+            folded: HashMap::new(),
             index_buffer: [0; INDEX_ENTRY_SIZE],
         }
     }
 }
 
+/// Reads the entries of an sstable in key order, from any key.
+struct SSTableCursor {
+    data_file: CachedFileReader,
+    index_file: CachedFileReader,
+    format_version: u32,
+
+    /// The offset in the index file of the next entry to read.
+    index_offset: u64,
+
+    /// The offset in the index file after the last entry.
+    index_end: u64,
+}
+
+impl SSTableCursor {
+    /// A cursor at the first key from `from`, binary searched in the index.
+    async fn seek(
+        sstable: &SSTable,
+        page_cache: &Rc<PartitionPageCache<FileId>>,
+        from: Bound<&[u8]>,
+    ) -> Result<Self> {
+        let mut cursor = Self {
+            data_file: CachedFileReader::new(
+                (FileTypeKind::Data, sstable.index),
+                sstable.data_file.clone(),
+                page_cache.clone(),
+            ),
+            index_file: CachedFileReader::new(
+                (FileTypeKind::Index, sstable.index),
+                sstable.index_file.clone(),
+                page_cache.clone(),
+            ),
+            format_version: sstable.format_version,
+            index_offset: sstable.index_start,
+            index_end: sstable.index_start
+                + sstable.size * INDEX_ENTRY_SIZE as u64,
+        };
+
+        if !matches!(from, Bound::Unbounded) {
+            let (mut low, mut high) = (0, sstable.size);
+            while low < high {
+                let middle = low + (high - low) / 2;
+                let offset =
+                    sstable.index_start + middle * INDEX_ENTRY_SIZE as u64;
+                if is_before(&cursor.read_key(offset).await?, from) {
+                    low = middle + 1;
+                } else {
+                    high = middle;
+                }
+            }
+            cursor.index_offset += low * INDEX_ENTRY_SIZE as u64;
+        }
+
+        Ok(cursor)
+    }
+
+    async fn read_entry_offset(
+        &self,
+        index_offset: u64,
+    ) -> Result<EntryOffset> {
+        let mut index_buf = [0; INDEX_ENTRY_SIZE];
+        self.index_file
+            .read_at_into(index_offset, &mut index_buf)
+            .await?;
+        Ok(bincode_options().deserialize(&index_buf)?)
+    }
+
+    async fn read_key(&self, index_offset: u64) -> Result<Vec<u8>> {
+        let entry_offset = self.read_entry_offset(index_offset).await?;
+        Ok(bincode_options().deserialize(
+            &self
+                .data_file
+                .read_at(entry_offset.offset, entry_offset.key_size as usize)
+                .await?,
+        )?)
+    }
+
+    async fn next(&mut self) -> Result<Option<Entry>> {
+        if self.index_offset >= self.index_end {
+            return Ok(None);
+        }
+
+        let entry_offset = self.read_entry_offset(self.index_offset).await?;
+        let entry = deserialize_entry(
+            self.format_version,
+            &self
+                .data_file
+                .read_at(entry_offset.offset, entry_offset.full_size as usize)
+                .await?,
+        )?;
+        self.index_offset += INDEX_ENTRY_SIZE as u64;
+        Ok(Some(entry))
+    }
+}
+
+/// Iterates over the current values of the keys of a tree in key order, a
+/// single entry per key (tombstones included), folded from the values in all
+/// memtables and sstables like in a compaction.
+/// Unlike `AsyncIter`, it can start from any key.
+pub struct SortedIter<'a> {
+    tree: &'a LSMTree,
+
+    /// The sstable files we operate on will not be deleted until this object
+    /// is dropped.
+    _sstables: Rc<Vec<SSTable>>,
+
+    /// The sources of entries, their index in the heap is their position in
+    /// the sstables (oldest first) followed by the memtables.
+    cursors: Vec<SSTableCursor>,
+    memtables: Vec<std::vec::IntoIter<Entry>>,
+
+    /// The next entry of every source.
+    heap: BinaryHeap<CompactionItem>,
+
+    range_tombstones: Vec<RangeTombstone>,
+    filter_fn: Box<IterFilterFn>,
+}
+
+impl<'a> SortedIter<'a> {
+    async fn push_next(&mut self, index: usize) -> Result<()> {
+        let entry = match self.cursors.get_mut(index) {
+            Some(cursor) => cursor.next().await?,
+            None => self.memtables[index - self.cursors.len()].next(),
+        };
+        if let Some(entry) = entry {
+            self.heap.push(CompactionItem { entry, index });
+        }
+        Ok(())
+    }
+
+    pub async fn next(&mut self) -> Result<Option<Entry>> {
+        while let Some(current) = self.heap.pop() {
+            self.push_next(current.index).await?;
+
+            let key = current.entry.key;
+            let deleted_at = deleted_at(&self.range_tombstones, &key);
+            let mut folded = self
+                .tree
+                .apply_range_tombstones(current.entry.value, deleted_at);
+
+            // Values of a key are popped from the oldest to the newest.
+            while let Some(next) = self.heap.pop() {
+                if next.entry.key != key {
+                    self.heap.push(next);
+                    break;
+                }
+                self.push_next(next.index).await?;
+                if let Some(value) = self
+                    .tree
+                    .apply_range_tombstones(next.entry.value, deleted_at)
+                {
+                    folded = Some(self.tree.fold_value(folded, value)?);
+                }
+            }
+
+            let value = match folded {
+                Some(value) => self.tree.resolve_operand(value)?,
+                None => continue,
+            };
+            if (self.filter_fn)(&key, &value) {
+                return Ok(Some(Entry { key, value }));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
 enum NextEntryResult {
     Found(Option<Entry>),
     Continue,
@@ -201,9 +512,36 @@ enum NextEntryResult {
 impl<'a> AsyncIter<'a> {
     pub async fn next(&mut self) -> Result<Option<Entry>> {
         loop {
-            if let NextEntryResult::Found(result) = self.read_one().await? {
-                return Ok(result);
+            let mut entry = match self.read_one().await? {
+                NextEntryResult::Found(Some(entry)) => entry,
+                NextEntryResult::Found(None) => return Ok(None),
+                NextEntryResult::Continue => continue,
+            };
+
+            let deleted_at = deleted_at(&self.range_tombstones, &entry.key);
+            let value =
+                match self.tree.apply_range_tombstones(entry.value, deleted_at)
+                {
+                    Some(value) => value,
+                    None => {
+                        self.folded.remove(&entry.key);
+                        continue;
+                    }
+                };
+            if self.tree.merge_operator.is_none() {
+                entry.value = value;
+                return Ok(Some(entry));
             }
+
+            // Memtables and sstables are iterated from the oldest to the
+            // newest, so operands are folded into the older values of their
+            // key as they are walked.
+            let folded = self
+                .tree
+                .fold_value(self.folded.remove(&entry.key), value)?;
+            entry.value = self.tree.resolve_operand(folded.clone())?;
+            self.folded.insert(entry.key.clone(), folded);
+            return Ok(Some(entry));
         }
     }
 
@@ -215,14 +553,25 @@ impl<'a> AsyncIter<'a> {
                 let i = *i;
                 let sstable = &self.sstables[i];
 
+                // An sstable of a flushed memtable holding only range
+                // tombstones.
+                if sstable.size == 0 {
+                    self.state = if i == self.sstables.len() - 1 {
+                        IterState::Memtable
+                    } else {
+                        IterState::UnreadSSTable(i + 1)
+                    };
+                    return Ok(Continue);
+                }
+
                 let data_file = CachedFileReader::new(
                     (FileTypeKind::Data, sstable.index),
                     sstable.data_file.clone(),
                     self.tree.page_cache.clone(),
                 );
 
-                // let index_file = DmaFile::open(&sstable.index_path).await?;
-                let index_file_size = sstable.size * (INDEX_ENTRY_SIZE as u64);
+                let index_file_size = sstable.index_start
+                    + sstable.size * (INDEX_ENTRY_SIZE as u64);
                 let index_file = CachedFileReader::new(
                     (FileTypeKind::Index, sstable.index),
                     sstable.index_file.clone(),
@@ -233,7 +582,7 @@ impl<'a> AsyncIter<'a> {
                     i,
                     data_file,
                     index_file,
-                    0,
+                    sstable.index_start,
                     index_file_size,
                 );
                 Continue
@@ -252,7 +601,8 @@ impl<'a> AsyncIter<'a> {
                     .await?;
                 let entry_offset: EntryOffset =
                     bincode_options().deserialize(&self.index_buffer)?;
-                let entry: Entry = bincode_options().deserialize(
+                let entry = deserialize_entry(
+                    self.sstables[i].format_version,
                     &data_file
                         .read_at(
                             entry_offset.offset,
@@ -281,13 +631,16 @@ impl<'a> AsyncIter<'a> {
     }
 }
 
-fn get_file_path(dir: &Path, index: usize, ext: &str) -> PathBuf {
+pub(super) fn get_file_path(dir: &Path, index: usize, ext: &str) -> PathBuf {
     let mut path = dir.to_path_buf();
     path.push(format!("{index:0INDEX_PADDING$}.{ext}"));
     path
 }
 
-fn get_data_file_paths(dir: &Path, index: usize) -> (PathBuf, PathBuf) {
+pub(super) fn get_data_file_paths(
+    dir: &Path,
+    index: usize,
+) -> (PathBuf, PathBuf) {
     let data_path = get_file_path(dir, index, DATA_FILE_EXT);
     let index_path = get_file_path(dir, index, INDEX_FILE_EXT);
     (data_path, index_path)
@@ -320,6 +673,72 @@ async fn write_file(path: &Path, buf: &[u8]) -> Result<()> {
     Ok(())
 }
 
+/// Hard link a file, or copy it when the link fails (e.g. the source is on
+/// another filesystem), returns false when there is no source file.
+/// Runs on the blocking thread pool, so copying a large file doesn't stall
+/// the executor.
+async fn link_or_copy_file(
+    source: PathBuf,
+    destination: PathBuf,
+) -> Result<bool> {
+    Ok(executor()
+        .spawn_blocking(move || -> std::io::Result<bool> {
+            if !source.exists() {
+                return Ok(false);
+            }
+            if destination.exists() {
+                // Left by an ingest that crashed before writing its action.
+                std::fs::remove_file(&destination)?;
+            }
+            if std::fs::hard_link(&source, &destination).is_err() {
+                std::fs::copy(&source, &destination)?;
+            }
+            Ok(true)
+        })
+        .await?)
+}
+
+/// Apply a merge operand on top of the older value of its key, the result is
+/// a full value when the older value is, otherwise a combined operand.
+fn apply_operand(
+    merge_operator: Option<&dyn MergeOperator>,
+    older: &EntryValue,
+    operand: EntryValue,
+) -> Result<EntryValue> {
+    let merge_operator =
+        merge_operator.ok_or(Error::MergeOperatorNotRegistered)?;
+    let (data, kind) = match older.kind {
+        EntryKind::Put => (
+            merge_operator.full_merge(Some(&older.data), &operand.data)?,
+            EntryKind::Put,
+        ),
+        EntryKind::Delete | EntryKind::RangeDelete => (
+            merge_operator.full_merge(None, &operand.data)?,
+            EntryKind::Put,
+        ),
+        EntryKind::Merge => (
+            merge_operator.partial_merge(&older.data, &operand.data)?,
+            EntryKind::Merge,
+        ),
+    };
+    Ok(EntryValue {
+        data,
+        timestamp: operand.timestamp,
+        kind,
+    })
+}
+
+/// Written next to an sstable, only when it has range tombstones.
+async fn write_range_tombstones(
+    path: &Path,
+    range_tombstones: &[RangeTombstone],
+) -> Result<()> {
+    if range_tombstones.is_empty() {
+        return Ok(());
+    }
+    write_file(path, &bincode_options().serialize(range_tombstones)?).await
+}
+
 async fn read_file(path: &Path) -> Result<Vec<u8>> {
     let file = DmaFile::open(path).await?;
     let mut reader = DmaStreamReaderBuilder::new(file)
@@ -332,6 +751,12 @@ async fn read_file(path: &Path) -> Result<Vec<u8>> {
     Ok(buf)
 }
 
+/// Read the format version of an sstable and the size of the header of its
+/// index file.
+async fn read_sstable_format(index_file: &DmaFile) -> Result<(u32, u64)> {
+    read_index_header(&index_file.read_at_aligned(0, PAGE_SIZE).await?)
+}
+
 pub struct LSMTree {
     dir: PathBuf,
 
@@ -381,6 +806,27 @@ pub struct LSMTree {
 
     ///The minimum size of an sstable (in bytes) to calculate and store its bloom filter.
     sstable_bloom_min_size: u64,
+
+    /// The durations of all flushes since the tree was opened.
+    flush_durations: RefCell<Histogram>,
+
+    /// The durations of all compactions since the tree was opened.
+    compaction_durations: RefCell<Histogram>,
+
+    /// Allows a single compaction at a time, as the sstables to compact are
+    /// chosen by the sstables that exist when the compaction starts.
+    compaction_semaphore: Semaphore,
+
+    /// When set, all values of a key are merged, instead of keeping only the
+    /// newest one.
+    merge_fn: Option<MergeFn>,
+
+    /// Applies the merge operands written with `merge()`.
+    merge_operator: Option<Rc<dyn MergeOperator>>,
+
+    /// Values written up to this time are dropped on read and compaction,
+    /// kept by the owner of the tree (not persisted in the tree's files).
+    truncated_at: Cell<Option<OffsetDateTime>>,
 }
 
 impl LSMTree {
@@ -394,6 +840,8 @@ impl LSMTree {
             DEFAULT_TREE_CAPACITY,
             None,
             DEFAULT_SSTABLE_BLOOM_MIN_SIZE,
+            None,
+            None,
         )
         .await
     }
@@ -404,6 +852,8 @@ impl LSMTree {
         tree_capacity: usize,
         wal_sync_delay: Option<Duration>,
         sstable_bloom_min_size: u64,
+        merge_fn: Option<MergeFn>,
+        merge_operator: Option<Rc<dyn MergeOperator>>,
     ) -> Result<Self> {
         assert_eq!(
             bincode_options()
@@ -448,12 +898,7 @@ impl LSMTree {
 
             let mut sstables = Vec::with_capacity(indices.len());
             for index in indices {
-                let path = get_file_path(&dir, index, INDEX_FILE_EXT);
-                let size =
-                    std::fs::metadata(path)?.len() / INDEX_ENTRY_SIZE as u64;
-                sstables.push(
-                    SSTable::new_with_bloom_read(&dir, index, size).await?,
-                );
+                sstables.push(SSTable::new_with_bloom_read(&dir, index).await?);
             }
             sstables
         };
@@ -492,6 +937,8 @@ impl LSMTree {
                 let memtable = Self::read_memtable_from_wal_file(
                     &unflashed_file_path,
                     tree_capacity,
+                    merge_fn,
+                    merge_operator.as_deref(),
                 )
                 .await?;
                 let (data_file, index_file) = try_join!(
@@ -499,13 +946,22 @@ impl LSMTree {
                     DmaFile::create(&index_file_path)
                 )?;
                 Self::flush_memtable_to_disk(
-                    memtable.into_iter().collect(),
+                    memtable.entries.into_iter().collect(),
                     data_file,
                     index_file,
                     wal_file_index,
                     page_cache.clone(),
                 )
                 .await?;
+                write_range_tombstones(
+                    &get_file_path(
+                        &dir,
+                        wal_file_index,
+                        RANGE_TOMBSTONES_FILE_EXT,
+                    ),
+                    &memtable.range_tombstones,
+                )
+                .await?;
                 remove(&unflashed_file_path).await?;
                 wal_file_index
             }
@@ -521,9 +977,15 @@ impl LSMTree {
         let wal_offset = wal_file.file_size().await?;
 
         let active_memtable = if wal_path.exists() {
-            Self::read_memtable_from_wal_file(&wal_path, tree_capacity).await?
+            Self::read_memtable_from_wal_file(
+                &wal_path,
+                tree_capacity,
+                merge_fn,
+                merge_operator.as_deref(),
+            )
+            .await?
         } else {
-            RedBlackTree::with_capacity(tree_capacity)
+            MemTable::with_capacity(tree_capacity)
         };
 
         Ok(Self {
@@ -541,28 +1003,138 @@ impl LSMTree {
             wal_sync_delay,
             wal_sync_event: RefCell::new(None),
             sstable_bloom_min_size,
+            flush_durations: RefCell::new(Histogram::default()),
+            compaction_durations: RefCell::new(Histogram::default()),
+            compaction_semaphore: Semaphore::new(1),
+            merge_fn,
+            merge_operator,
+            truncated_at: Cell::new(None),
         })
     }
 
+    /// Drop all values written up to the time, including ones written later
+    /// with an older timestamp (e.g. by a replica that didn't know of the
+    /// truncate yet).
+    /// Compact the tree to free the space of the dropped values.
+    pub fn truncate(&self, at: OffsetDateTime) {
+        if self.truncated_at.get().map_or(true, |current| current < at) {
+            self.truncated_at.set(Some(at));
+        }
+    }
+
     pub fn purge(&self) -> Result<()> {
         trace!("Deleting tree in: {:?}", self.dir);
         Ok(std::fs::remove_dir_all(&self.dir)?)
     }
 
+    /// Merge the entries of a write with the values of their keys in a
+    /// memtable (or an earlier entry of the key in the write), when there is
+    /// a merge function or an entry is a merge operand.
+    /// Doesn't change the memtable, so a merge that fails leaves no part of
+    /// the write behind.
+    fn merge_with_memtable(
+        memtable: &MemTable,
+        entries: Vec<Entry>,
+        merge_fn: Option<MergeFn>,
+        merge_operator: Option<&dyn MergeOperator>,
+    ) -> Result<Vec<Entry>> {
+        let mut merged: Vec<Entry> = Vec::with_capacity(entries.len());
+        let mut merged_indices: HashMap<Vec<u8>, usize> = HashMap::new();
+        for entry in entries {
+            if entry.value.kind == EntryKind::RangeDelete {
+                merged.push(entry);
+                continue;
+            }
+
+            let existing = match merged_indices.get(&entry.key) {
+                Some(i) => Some(&merged[*i].value),
+                None => memtable.entries.get(&entry.key),
+            };
+            let value = match (merge_fn, existing) {
+                (Some(merge_fn), Some(existing)) => {
+                    merge_values(merge_fn, existing, &entry.value)?
+                }
+                (None, Some(existing))
+                    if entry.value.kind == EntryKind::Merge =>
+                {
+                    apply_operand(merge_operator, existing, entry.value)?
+                }
+                _ => entry.value,
+            };
+            merged_indices.insert(entry.key.clone(), merged.len());
+            merged.push(Entry {
+                key: entry.key,
+                value,
+            });
+        }
+        Ok(merged)
+    }
+
+    /// Set merged entries (see `merge_with_memtable()`) in a memtable,
+    /// returns the previous value of each entry.
+    fn set_in_memtable(
+        memtable: &mut MemTable,
+        entries: Vec<Entry>,
+    ) -> Result<Vec<Option<EntryValue>>> {
+        let mut previous = Vec::with_capacity(entries.len());
+        for entry in entries {
+            if entry.value.kind == EntryKind::RangeDelete {
+                memtable
+                    .range_tombstones
+                    .push(RangeTombstone::from_entry(entry));
+                previous.push(None);
+            } else {
+                previous.push(memtable.entries.set(entry.key, entry.value)?);
+            }
+        }
+        Ok(previous)
+    }
+
     async fn read_memtable_from_wal_file(
         wal_path: &Path,
         tree_capacity: usize,
+        merge_fn: Option<MergeFn>,
+        merge_operator: Option<&dyn MergeOperator>,
     ) -> Result<MemTable> {
         let wal_buf = read_file(wal_path).await?;
         let mut cursor = std::io::Cursor::new(&wal_buf[..]);
 
-        let mut memtable = RedBlackTree::with_capacity(tree_capacity);
+        let mut memtable = MemTable::with_capacity(tree_capacity);
+        let mut allow_legacy = true;
 
         while cursor.position() < wal_buf.len() as u64 {
-            if let Ok(entry) =
-                bincode_options().deserialize_from::<_, Entry>(&mut cursor)
-            {
-                memtable.set(entry.key, entry.value)?;
+            let start = cursor.position() as usize;
+            let page_end = (start + PAGE_SIZE).min(wal_buf.len());
+
+            // A hole left by a write that didn't finish before a crash.
+            if wal_buf[start..page_end].iter().all(|b| *b == 0) {
+                cursor.set_position(page_end as u64);
+                continue;
+            }
+
+            match deserialize_wal_record(&mut cursor, &mut allow_legacy) {
+                Ok(entries) => {
+                    let entries = Self::merge_with_memtable(
+                        &memtable,
+                        entries,
+                        merge_fn,
+                        merge_operator,
+                    )?;
+                    Self::set_in_memtable(&mut memtable, entries)?;
+                }
+                Err(e @ Error::UnsupportedWalFormatVersion(_)) => {
+                    return Err(e);
+                }
+                Err(e) => {
+                    // Only the last write before a crash can be partial.
+                    error!(
+                        "Skipping a partially written record at offset {} of \
+                         WAL file '{}': {}",
+                        start,
+                        wal_path.display(),
+                        e
+                    );
+                }
             }
             let pos = cursor.position();
             cursor.set_position(
@@ -597,17 +1169,153 @@ impl LSMTree {
             .collect()
     }
 
+    /// The approximate number of keys with a value in the tree: the distinct
+    /// keys (estimated by merging the HyperLogLog sketches of the keys of all
+    /// memtables and sstables), minus the keys of tombstones without a value
+    /// in a newer memtable or sstable (estimated the same, from sketches of
+    /// the keys of the tombstones), minus the keys deleted by range
+    /// tombstones (read from the deleted ranges).
+    /// Sstables are ordered by their index, so an ingested sstable is newer
+    /// than the sstables before it, even if its values are older.
+    pub async fn approximate_live_keys(&self) -> Result<u64> {
+        let sstables = self.sstables.borrow().clone();
+        let mut sketches = Vec::with_capacity(sstables.len() + 2);
+        let mut range_tombstones = Vec::new();
+
+        for memtable in [
+            Some(&*self.active_memtable.borrow()),
+            self.flush_memtable.borrow().as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            let mut sketch = KeysSketch::default();
+            for (key, value) in memtable.entries.iter() {
+                sketch.add(key, value)?;
+            }
+            sketches.push(Rc::new(sketch));
+            range_tombstones.extend(memtable.range_tombstones.iter().cloned());
+        }
+
+        // Sstables without a sketch are assumed to have only distinct keys.
+        let mut unsketched_entries = 0;
+        for sstable in sstables.iter().rev() {
+            match &sstable.sketch {
+                Some(sketch) => sketches.push(sketch.clone()),
+                None => unsketched_entries += sstable.size,
+            }
+            range_tombstones.extend(sstable.range_tombstones.iter().cloned());
+        }
+
+        // From the newest to the oldest, a tombstone deletes a key only when
+        // the key is not in any newer memtable or sstable, so a key deleted
+        // and set again, or deleted again, is not counted as deleted.
+        let mut newer_keys = HyperLogLog::default();
+        let mut deleted = 0;
+        for sketch in &sketches {
+            let mut keys = newer_keys.clone();
+            keys.merge(&sketch.tombstones);
+            deleted += keys.estimate().saturating_sub(newer_keys.estimate());
+            newer_keys.merge(&sketch.keys);
+        }
+        deleted += self.count_range_deleted_keys(range_tombstones).await?;
+
+        Ok(
+            (newer_keys.estimate() + unsketched_entries)
+                .saturating_sub(deleted),
+        )
+    }
+
+    /// The number of keys with a value (ignoring range tombstones) that is
+    /// deleted by range tombstones, read from the deleted ranges.
+    async fn count_range_deleted_keys(
+        &self,
+        mut range_tombstones: Vec<RangeTombstone>,
+    ) -> Result<u64> {
+        range_tombstones.sort_unstable_by(|a, b| a.start.cmp(&b.start));
+
+        let mut count = 0;
+
+        // Overlapping ranges are read once, the keys before it were read.
+        let mut read_until: Option<Vec<u8>> = None;
+        for range_tombstone in &range_tombstones {
+            let start = match &read_until {
+                Some(until) if *until > range_tombstone.start => until.clone(),
+                _ => range_tombstone.start.clone(),
+            };
+            if start >= range_tombstone.end {
+                continue;
+            }
+
+            let mut iter = self
+                .iter_sorted_ex(
+                    Bound::Included(&start),
+                    false,
+                    Box::new(|_, value| !value.is_delete()),
+                )
+                .await?;
+            while let Some(entry) = iter.next().await? {
+                if entry.key >= range_tombstone.end {
+                    break;
+                }
+                if deleted_at(&range_tombstones, &entry.key)
+                    .is_some_and(|at| entry.value.timestamp <= at)
+                {
+                    count += 1;
+                }
+            }
+
+            read_until = Some(range_tombstone.end.clone());
+        }
+
+        Ok(count)
+    }
+
+    /// The size in bytes of all files of the tree (sstables, blooms and WAL
+    /// files).
+    pub fn disk_size(&self) -> Result<u64> {
+        let mut size = 0;
+        for entry in std::fs::read_dir(&self.dir)? {
+            size += entry?.metadata()?.len();
+        }
+        Ok(size)
+    }
+
+    /// The number of entries in the memtable that triggers a flush.
+    pub fn memtable_capacity(&self) -> usize {
+        self.active_memtable.borrow().capacity()
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> CollectionStats {
+        CollectionStats {
+            memtable_entries: self.active_memtable.borrow().len() as u64,
+            memtable_bytes: self.wal_offset.get(),
+            sstables: self.sstables.borrow().len() as u64,
+            page_cache_hits: self.page_cache.hits(),
+            page_cache_misses: self.page_cache.misses(),
+            flush_duration: self.flush_durations.borrow().clone(),
+            compaction_duration: self.compaction_durations.borrow().clone(),
+        }
+    }
+
     fn active_memtable_full(&self) -> bool {
         self.active_memtable.borrow().capacity()
             == self.active_memtable.borrow().len()
     }
 
+    fn active_memtable_has_room(&self, entries: usize) -> bool {
+        self.active_memtable.borrow().len() + entries
+            <= self.active_memtable.borrow().capacity()
+    }
+
     async fn binary_search(
         key: &Vec<u8>,
         data_file: &CachedFileReader,
         index_file: &CachedFileReader,
         index_offset_start: u64,
         index_offset_length: u64,
+        format_version: u32,
     ) -> Result<Option<(Entry, u64)>> {
         let mut half = index_offset_length / 2;
         let mut high_index = index_offset_length - 1;
@@ -633,17 +1341,15 @@ impl LSMTree {
 
             match raw_key.cmp(key) {
                 Ordering::Equal => {
-                    let entry_value: EntryValue = bincode_options()
-                        .deserialize(
-                            &data_file
-                                .read_at(
-                                    current.offset
-                                        + u64::from(current.key_size),
-                                    (current.full_size - current.key_size)
-                                        as usize,
-                                )
-                                .await?,
-                        )?;
+                    let entry_value = deserialize_entry_value(
+                        format_version,
+                        &data_file
+                            .read_at(
+                                current.offset + u64::from(current.key_size),
+                                (current.full_size - current.key_size) as usize,
+                            )
+                            .await?,
+                    )?;
                     let entry = Entry {
                         key: raw_key,
                         value: entry_value,
@@ -669,90 +1375,258 @@ impl LSMTree {
         Ok(None)
     }
 
-    /// Get the value together with the metadata saved for a key.
-    /// If you only want the raw value, use get().
-    pub async fn get_entry(&self, key: &Vec<u8>) -> Result<Option<EntryValue>> {
-        // Query the active tree first.
-        if let Some(result) = self.active_memtable.borrow().get(key) {
-            return Ok(Some(result.clone()));
-        }
-
-        // Key not found in active tree, query the flushed tree.
-        if let Some(tree) = self.flush_memtable.borrow().as_ref() {
-            if let Some(result) = tree.get(key) {
-                return Ok(Some(result.clone()));
+    /// Add a value of a key to the result of a get (values are found from
+    /// the newest memtable / sstable to the oldest).
+    /// Without merging, a full value (or delete) is replaced only by a full
+    /// value with a newer timestamp, like in compaction, as the values of a
+    /// newer sstable are not always newer (e.g. ingested sstables).
+    fn add_found_value(
+        &self,
+        result: &mut Option<EntryValue>,
+        value: EntryValue,
+    ) -> Result<()> {
+        let value = match (self.merge_fn, result.take()) {
+            (Some(merge_fn), Some(newer)) => {
+                merge_values(merge_fn, &newer, &value)?
             }
-        }
-
-        // Key not found in memory, query all files from the newest to the
-        // oldest.
-        let sstables = self.sstables.borrow().clone();
-        for sstable in sstables.iter().rev() {
-            // Skip keys that are not in the sstable.
-            if let Some(bloom) = &sstable.bloom {
-                if !bloom.check(key) {
-                    continue;
+            (None, Some(operand)) if operand.kind == EntryKind::Merge => {
+                apply_operand(self.merge_operator.as_deref(), &value, operand)?
+            }
+            (None, Some(current)) => {
+                if value.kind != EntryKind::Merge
+                    && value.timestamp > current.timestamp
+                {
+                    value
+                } else {
+                    current
                 }
             }
+            (_, None) => value,
+        };
+        *result = Some(value);
+        Ok(())
+    }
 
-            let data_file = CachedFileReader::new(
-                (FileTypeKind::Data, sstable.index),
-                sstable.data_file.clone(),
-                self.page_cache.clone(),
-            );
-            let index_file = CachedFileReader::new(
-                (FileTypeKind::Index, sstable.index),
-                sstable.index_file.clone(),
-                self.page_cache.clone(),
-            );
-
-            if let Some((entry, _)) = Self::binary_search(
-                key,
-                &data_file,
-                &index_file,
-                0,
-                sstable.size,
-            )
-            .await?
-            {
-                return Ok(Some(entry.value));
+    /// Fold a value of a key into the value folded from its older values.
+    fn fold_value(
+        &self,
+        folded: Option<EntryValue>,
+        value: EntryValue,
+    ) -> Result<EntryValue> {
+        Ok(match (folded, self.merge_fn) {
+            (None, _) => value,
+            (Some(older), Some(merge_fn)) => {
+                merge_values(merge_fn, &older, &value)?
             }
-        }
-
-        Ok(None)
+            (Some(older), None) if value.kind == EntryKind::Merge => {
+                apply_operand(self.merge_operator.as_deref(), &older, value)?
+            }
+            (Some(_), None) => value,
+        })
     }
 
-    /// Get the raw value saved for a key.
+    /// Apply a merge operand that has no older value of its key under it.
+    fn resolve_operand(&self, value: EntryValue) -> Result<EntryValue> {
+        if value.kind != EntryKind::Merge {
+            return Ok(value);
+        }
+        apply_operand(
+            self.merge_operator.as_deref(),
+            &EntryValue::tombstone(Some(value.timestamp)),
+            value,
+        )
+    }
+
+    /// A value deleted by a range tombstone becomes a delete at the time of
+    /// the range tombstone, or is dropped when the tree merges values (there
+    /// is nothing to merge a delete with).
+    /// A value written before the tree was truncated is always dropped, the
+    /// truncate time is kept forever, so it needs no tombstone.
+    fn apply_range_tombstones(
+        &self,
+        value: EntryValue,
+        deleted_at: Option<OffsetDateTime>,
+    ) -> Option<EntryValue> {
+        if self
+            .truncated_at
+            .get()
+            .is_some_and(|at| value.timestamp <= at)
+        {
+            return None;
+        }
+
+        match deleted_at {
+            Some(at) if value.timestamp <= at => self
+                .merge_fn
+                .is_none()
+                .then(|| EntryValue::tombstone(Some(at))),
+            _ => Some(value),
+        }
+    }
+
+    /// The newest timestamp of the range tombstones of the whole tree that
+    /// cover a key.
+    fn range_deleted_at(
+        &self,
+        sstables: &[SSTable],
+        key: &[u8],
+    ) -> Option<OffsetDateTime> {
+        let active =
+            deleted_at(&self.active_memtable.borrow().range_tombstones, key);
+        let flushing = self
+            .flush_memtable
+            .borrow()
+            .as_ref()
+            .and_then(|tree| deleted_at(&tree.range_tombstones, key));
+        let on_disk = deleted_at(
+            sstables
+                .iter()
+                .flat_map(|sstable| sstable.range_tombstones.iter()),
+            key,
+        );
+        active.max(flushing).max(on_disk)
+    }
+
+    async fn get_from_sstable(
+        &self,
+        sstable: &SSTable,
+        key: &Vec<u8>,
+    ) -> Result<Option<EntryValue>> {
+        // An sstable of a flushed memtable holding only range tombstones.
+        if sstable.size == 0 {
+            return Ok(None);
+        }
+
+        // Skip keys that are not in the sstable.
+        if let Some(bloom) = &sstable.bloom {
+            if !bloom.check(key) {
+                return Ok(None);
+            }
+        }
+
+        let data_file = CachedFileReader::new(
+            (FileTypeKind::Data, sstable.index),
+            sstable.data_file.clone(),
+            self.page_cache.clone(),
+        );
+        let index_file = CachedFileReader::new(
+            (FileTypeKind::Index, sstable.index),
+            sstable.index_file.clone(),
+            self.page_cache.clone(),
+        );
+
+        Ok(Self::binary_search(
+            key,
+            &data_file,
+            &index_file,
+            sstable.index_start,
+            sstable.size,
+            sstable.format_version,
+        )
+        .await?
+        .map(|(entry, _)| entry.value))
+    }
+
+    /// Get the value together with the metadata saved for a key.
+    /// If you only want the raw value, use get().
+    pub async fn get_entry(&self, key: &Vec<u8>) -> Result<Option<EntryValue>> {
+        let mut result = None;
+
+        let sstables = self.sstables.borrow().clone();
+        let deleted_at = self.range_deleted_at(&sstables, key);
+
+        // Query the active tree first.
+        let value = self.active_memtable.borrow().entries.get(key).cloned();
+        if let Some(value) =
+            value.and_then(|v| self.apply_range_tombstones(v, deleted_at))
+        {
+            self.add_found_value(&mut result, value)?;
+        }
+
+        // Query the flushed tree.
+        let value = self
+            .flush_memtable
+            .borrow()
+            .as_ref()
+            .and_then(|tree| tree.entries.get(key).cloned());
+        if let Some(value) =
+            value.and_then(|v| self.apply_range_tombstones(v, deleted_at))
+        {
+            self.add_found_value(&mut result, value)?;
+        }
+
+        // Query all files from the newest to the oldest, all of them, as a
+        // value in an older file may have a newer timestamp.
+        for sstable in sstables.iter().rev() {
+            if let Some(value) = self.get_from_sstable(sstable, key).await? {
+                if let Some(value) =
+                    self.apply_range_tombstones(value, deleted_at)
+                {
+                    self.add_found_value(&mut result, value)?;
+                }
+            }
+        }
+
+        match result {
+            Some(value) => self.resolve_operand(value).map(Some),
+            // Only deleted by a range tombstone.
+            None if self.merge_fn.is_none() => {
+                Ok(deleted_at.map(|at| EntryValue::tombstone(Some(at))))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Get the raw value saved for a key.
     /// If you prefer to also get metadata of the value, use `get_entry`().
     pub async fn get(&self, key: &Vec<u8>) -> Result<Option<Vec<u8>>> {
-        Ok(self.get_entry(key).await?.map(|v| v.data))
+        Ok(self.get_entry(key).await?.and_then(EntryValue::into_data))
     }
 
-    async fn set_ex(
+    /// Write entries to the memtable and as a single record to the WAL, so
+    /// they are either all recovered on reopen, or none of them are.
+    async fn write_entries(
         self: Rc<Self>,
-        key: Vec<u8>,
-        value: Vec<u8>,
-        timestamp: Option<OffsetDateTime>,
-    ) -> Result<Option<EntryValue>> {
-        let value = EntryValue::new(value, timestamp);
-        let entry = Entry { key, value };
+        entries: Vec<Entry>,
+    ) -> Result<Vec<Option<EntryValue>>> {
+        if entries.len() > self.memtable_capacity() {
+            return Err(Error::BatchTooLarge(entries.len()));
+        }
 
-        let entry_size = bincode_options().serialized_size(&entry)? as usize;
-        let size_padded = entry_size + PAGE_SIZE - (entry_size % PAGE_SIZE);
+        let record_size = wal_record_size(&entries)?;
+        let size_padded = record_size + PAGE_SIZE - (record_size % PAGE_SIZE);
         let mut dma_buffer =
             self.wal_file.borrow().alloc_dma_buffer(size_padded);
-        bincode_options().serialize_into(dma_buffer.as_bytes_mut(), &entry)?;
+        serialize_wal_record(&entries, dma_buffer.as_bytes_mut())?;
 
         // Wait until the active tree has space to fill.
-        while self.active_memtable_full() {
+        while !self.active_memtable_has_room(entries.len()) {
+            if !self.active_memtable_full() {
+                // A batch that doesn't fit in a tree that is not full, flush
+                // it early, as no other write will trigger a flush.
+                spawn_local(enclose!((self.clone() => tree) async move {
+                    if let Err(e) = tree.flush().await {
+                        error!("Failed to flush memtable: {}", e);
+                    }
+                }))
+                .detach();
+            }
             self.flush_start_event.listen().await;
         }
 
-        // Write to memtable in memory.
-        let result = self
-            .active_memtable
-            .borrow_mut()
-            .set(entry.key, entry.value)?;
+        // Write to memtable in memory, all entries are merged before any of
+        // them is set, and without awaiting in between, so no other write
+        // changes the values they were merged with.
+        let results = {
+            let mut memtable = self.active_memtable.borrow_mut();
+            let entries = Self::merge_with_memtable(
+                &memtable,
+                entries,
+                self.merge_fn,
+                self.merge_operator.as_deref(),
+            )?;
+            Self::set_in_memtable(&mut memtable, entries)?
+        };
 
         if self.active_memtable_full() {
             // Capacity is full, flush memtable to disk in background.
@@ -767,7 +1641,17 @@ impl LSMTree {
         // Write to WAL for persistance.
         self.write_to_wal(dma_buffer).await?;
 
-        Ok(result)
+        Ok(results)
+    }
+
+    async fn write_entry(
+        self: Rc<Self>,
+        key: Vec<u8>,
+        value: EntryValue,
+    ) -> Result<Option<EntryValue>> {
+        let mut results =
+            self.write_entries(vec![Entry { key, value }]).await?;
+        Ok(results.pop().flatten())
     }
 
     pub async fn set(
@@ -775,7 +1659,7 @@ impl LSMTree {
         key: Vec<u8>,
         value: Vec<u8>,
     ) -> Result<Option<EntryValue>> {
-        self.set_ex(key, value, None).await
+        self.write_entry(key, EntryValue::new(value, None)).await
     }
 
     pub async fn set_with_timestamp(
@@ -784,14 +1668,77 @@ impl LSMTree {
         value: Vec<u8>,
         timestamp: OffsetDateTime,
     ) -> Result<Option<EntryValue>> {
-        self.set_ex(key, value, Some(timestamp)).await
+        self.write_entry(key, EntryValue::new(value, Some(timestamp)))
+            .await
+    }
+
+    /// Set multiple keys (a None value deletes a key) atomically, either all
+    /// of the writes survive a crash, or none of them do.
+    pub async fn set_batch_with_timestamp(
+        self: Rc<Self>,
+        items: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        timestamp: OffsetDateTime,
+    ) -> Result<()> {
+        if items.is_empty() {
+            return Ok(());
+        }
+
+        let entries = items
+            .into_iter()
+            .map(|(key, value)| Entry {
+                key,
+                value: match value {
+                    Some(value) => EntryValue::new(value, Some(timestamp)),
+                    None => EntryValue::tombstone(Some(timestamp)),
+                },
+            })
+            .collect();
+        self.write_entries(entries).await?;
+        Ok(())
+    }
+
+    async fn merge_ex(
+        self: Rc<Self>,
+        key: Vec<u8>,
+        operand: Vec<u8>,
+        timestamp: Option<OffsetDateTime>,
+    ) -> Result<()> {
+        if self.merge_operator.is_none() {
+            return Err(Error::MergeOperatorNotRegistered);
+        }
+
+        let value = EntryValue {
+            kind: EntryKind::Merge,
+            ..EntryValue::new(operand, timestamp)
+        };
+        self.write_entry(key, value).await?;
+        Ok(())
+    }
+
+    /// Write a merge operand of a key, applied on top of the value of the key
+    /// by the tree's merge operator, without reading the value.
+    pub async fn merge(
+        self: Rc<Self>,
+        key: Vec<u8>,
+        operand: Vec<u8>,
+    ) -> Result<()> {
+        self.merge_ex(key, operand, None).await
+    }
+
+    pub async fn merge_with_timestamp(
+        self: Rc<Self>,
+        key: Vec<u8>,
+        operand: Vec<u8>,
+        timestamp: OffsetDateTime,
+    ) -> Result<()> {
+        self.merge_ex(key, operand, Some(timestamp)).await
     }
 
     pub async fn delete(
         self: Rc<Self>,
         key: Vec<u8>,
     ) -> Result<Option<EntryValue>> {
-        self.set(key, TOMBSTONE).await
+        self.write_entry(key, EntryValue::tombstone(None)).await
     }
 
     pub async fn delete_with_timestamp(
@@ -799,7 +1746,43 @@ impl LSMTree {
         key: Vec<u8>,
         timestamp: OffsetDateTime,
     ) -> Result<Option<EntryValue>> {
-        self.set_with_timestamp(key, TOMBSTONE, timestamp).await
+        self.write_entry(key, EntryValue::tombstone(Some(timestamp)))
+            .await
+    }
+
+    async fn delete_range_ex(
+        self: Rc<Self>,
+        start: Vec<u8>,
+        end: Vec<u8>,
+        timestamp: Option<OffsetDateTime>,
+    ) -> Result<()> {
+        let range_tombstone = RangeTombstone {
+            start,
+            end,
+            timestamp: timestamp.unwrap_or_else(OffsetDateTime::now_utc),
+        };
+        self.write_entries(vec![range_tombstone.into_entry()])
+            .await?;
+        Ok(())
+    }
+
+    /// Delete all keys in [start, end) with a single range tombstone,
+    /// without reading or writing the keys.
+    pub async fn delete_range(
+        self: Rc<Self>,
+        start: Vec<u8>,
+        end: Vec<u8>,
+    ) -> Result<()> {
+        self.delete_range_ex(start, end, None).await
+    }
+
+    pub async fn delete_range_with_timestamp(
+        self: Rc<Self>,
+        start: Vec<u8>,
+        end: Vec<u8>,
+        timestamp: OffsetDateTime,
+    ) -> Result<()> {
+        self.delete_range_ex(start, end, Some(timestamp)).await
     }
 
     async fn write_to_wal(&self, buffer: DmaBuffer) -> Result<()> {
@@ -841,19 +1824,25 @@ impl LSMTree {
         self.flush_done_event.listen()
     }
 
-    pub async fn flush(&self) -> Result<()> {
-        // Wait until the previous flush is finished.
+    /// Wait until the flush in progress (if there is one) is finished.
+    pub async fn wait_for_flush(&self) {
         while self.flush_memtable.borrow().is_some() {
             self.get_flush_event_listener().await;
         }
+    }
+
+    pub async fn flush(&self) -> Result<()> {
+        // Wait until the previous flush is finished.
+        self.wait_for_flush().await;
 
         if self.active_memtable.borrow().is_empty() {
             return Ok(());
         }
 
-        let memtable_to_flush = RedBlackTree::with_capacity(
-            self.active_memtable.borrow().capacity(),
-        );
+        let start = Instant::now();
+
+        let memtable_to_flush =
+            MemTable::with_capacity(self.active_memtable.borrow().capacity());
         self.flush_memtable
             .replace(Some(self.active_memtable.replace(memtable_to_flush)));
 
@@ -879,15 +1868,23 @@ impl LSMTree {
             DmaFile::create(&index_filename)
         )?;
 
-        let vec = self
-            .flush_memtable
-            .borrow()
-            .as_ref()
-            .unwrap()
-            .iter()
-            .map(|(k, v)| (k.clone(), v.clone()))
-            .collect();
-        let items_written = Self::flush_memtable_to_disk(
+        let (vec, range_tombstones) = {
+            let flush_memtable = self.flush_memtable.borrow();
+            let memtable = flush_memtable.as_ref().unwrap();
+            (
+                memtable
+                    .entries
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect::<Vec<_>>(),
+                memtable.range_tombstones.clone(),
+            )
+        };
+        let mut sketch = KeysSketch::default();
+        for (key, value) in &vec {
+            sketch.add(key, value)?;
+        }
+        Self::flush_memtable_to_disk(
             vec,
             data_file,
             index_file,
@@ -895,6 +1892,24 @@ impl LSMTree {
             self.page_cache.clone(),
         )
         .await?;
+        write_range_tombstones(
+            &get_file_path(
+                &self.dir,
+                self.write_sstable_index.get(),
+                RANGE_TOMBSTONES_FILE_EXT,
+            ),
+            &range_tombstones,
+        )
+        .await?;
+        write_file(
+            &get_file_path(
+                &self.dir,
+                self.write_sstable_index.get(),
+                SKETCH_FILE_EXT,
+            ),
+            &bincode_options().serialize(&sketch)?,
+        )
+        .await?;
 
         self.flush_memtable.replace(None);
 
@@ -905,8 +1920,14 @@ impl LSMTree {
 
             let index = self.write_sstable_index.get();
             sstables.push(
-                SSTable::new(&self.dir, index, items_written as u64, None)
-                    .await?,
+                SSTable::new(
+                    &self.dir,
+                    index,
+                    None,
+                    Rc::new(range_tombstones),
+                    Some(Rc::new(sketch)),
+                )
+                .await?,
             );
 
             self.sstables.replace(Rc::new(sstables));
@@ -919,6 +1940,8 @@ impl LSMTree {
         old_wal_file.clone().close_rc().await?;
         old_wal_file.remove().await?;
 
+        self.flush_durations.borrow_mut().observe(start.elapsed());
+
         Ok(())
     }
 
@@ -945,25 +1968,57 @@ impl LSMTree {
         Ok(table_length)
     }
 
+    /// Hold the returned permit from choosing the sstables to compact until
+    /// the compaction is done.
+    pub async fn acquire_compaction_permit(&self) -> Result<Permit<'_>> {
+        Ok(self.compaction_semaphore.acquire_permit(1).await?)
+    }
+
     /// Compact all sstables in the given list of sstable files, write the result
     /// to the output file given.
+    /// Tombstones older than `gc_before` are purged, unless a memtable or an
+    /// sstable that is not compacted may have a value of their key, None
+    /// keeps all tombstones.
     pub async fn compact(
         &self,
         indices_to_compact: &[usize],
         output_index: usize,
-        keep_tombstones: bool,
+        gc_before: Option<OffsetDateTime>,
     ) -> Result<()> {
-        let sstable_paths: Vec<(PathBuf, PathBuf, PathBuf)> =
-            indices_to_compact
-                .iter()
-                .map(|i| {
-                    let (data_path, index_path) =
-                        get_data_file_paths(&self.dir, *i);
-                    let bloom_path =
-                        get_file_path(&self.dir, *i, BLOOM_FILE_EXT);
-                    (data_path, index_path, bloom_path)
-                })
-                .collect();
+        let start = Instant::now();
+
+        // Newer sstables too, values are resolved by timestamp, and the
+        // values of a newer sstable may be older (e.g. ingested sstables).
+        let other_sstables: Vec<SSTable> = self
+            .sstables
+            .borrow()
+            .iter()
+            .filter(|sstable| !indices_to_compact.contains(&sstable.index))
+            .cloned()
+            .collect();
+
+        let sstable_paths: Vec<[PathBuf; 5]> = indices_to_compact
+            .iter()
+            .map(|i| {
+                let (data_path, index_path) =
+                    get_data_file_paths(&self.dir, *i);
+                [
+                    data_path,
+                    index_path,
+                    get_file_path(&self.dir, *i, BLOOM_FILE_EXT),
+                    get_file_path(&self.dir, *i, RANGE_TOMBSTONES_FILE_EXT),
+                    get_file_path(&self.dir, *i, SKETCH_FILE_EXT),
+                ]
+            })
+            .collect();
+
+        let range_tombstones: Vec<RangeTombstone> = self
+            .sstables
+            .borrow()
+            .iter()
+            .filter(|sstable| indices_to_compact.contains(&sstable.index))
+            .flat_map(|sstable| sstable.range_tombstones.iter().cloned())
+            .collect();
 
         // No stable AsyncIterator yet...
         // If there was, itertools::kmerge would probably solve it all.
@@ -971,12 +2026,15 @@ impl LSMTree {
         let mut max_items_after_compaction = 0usize;
         let mut max_data_size_after_compaction = 0u64;
 
-        for (data_path, index_path, _) in &sstable_paths {
+        for [data_path, index_path, ..] in &sstable_paths {
             let (data_file, index_file) =
                 try_join!(DmaFile::open(data_path), DmaFile::open(index_path))?;
 
+            let (format_version, index_start) =
+                read_sstable_format(&index_file).await?;
             let index_file_size = index_file.file_size().await?;
-            let num_entries = index_file_size / INDEX_ENTRY_SIZE as u64;
+            let num_entries =
+                (index_file_size - index_start) / INDEX_ENTRY_SIZE as u64;
             max_items_after_compaction += num_entries as usize;
 
             max_data_size_after_compaction += data_file.file_size().await?;
@@ -985,11 +2043,20 @@ impl LSMTree {
                 .with_buffer_size(PAGE_SIZE)
                 .with_read_ahead(DMA_STREAM_NUMBER_OF_BUFFERS)
                 .build();
-            let index_reader = DmaStreamReaderBuilder::new(index_file)
+            let mut index_reader = DmaStreamReaderBuilder::new(index_file)
                 .with_buffer_size(PAGE_SIZE)
                 .with_read_ahead(DMA_STREAM_NUMBER_OF_BUFFERS)
                 .build();
-            sstable_readers.push((data_reader, index_reader));
+            index_reader
+                .read_exact(&mut vec![0; index_start as usize])
+                .await?;
+            sstable_readers.push(CompactionReader {
+                data_reader,
+                index_reader,
+                format_version,
+                remaining: num_entries,
+                last_key: None,
+            });
         }
 
         let (compact_data_path, compact_index_path, compact_bloom_path) =
@@ -1002,18 +2069,14 @@ impl LSMTree {
         let mut offset_bytes = vec![0; INDEX_ENTRY_SIZE];
         let mut heap = BinaryHeap::new();
 
-        for (index, (data_reader, index_reader)) in
-            sstable_readers.iter_mut().enumerate()
-        {
-            let entry_result = Self::read_next_entry(
-                data_reader,
-                index_reader,
+        for index in 0..sstable_readers.len() {
+            Self::push_next_compaction_item(
+                &mut heap,
+                &mut sstable_readers,
+                index,
                 &mut offset_bytes,
             )
-            .await;
-            if let Ok(entry) = entry_result {
-                heap.push(CompactionItem { entry, index });
-            }
+            .await?;
         }
 
         let mut entry_writer = EntryWriter::new_from_dma(
@@ -1033,36 +2096,68 @@ impl LSMTree {
                 None
             };
 
-        let mut items_written = 0;
+        let mut sketch = KeysSketch::default();
 
         while let Some(current) = heap.pop() {
-            let index = current.index;
+            Self::push_next_compaction_item(
+                &mut heap,
+                &mut sstable_readers,
+                current.index,
+                &mut offset_bytes,
+            )
+            .await?;
 
-            let mut should_write_current = true;
-            if let Some(next) = heap.peek() {
-                should_write_current &= next.entry.key != current.entry.key;
+            let key = current.entry.key;
+            let deleted_at = deleted_at(&range_tombstones, &key);
+            let mut folded =
+                self.apply_range_tombstones(current.entry.value, deleted_at);
+
+            // Fold all values of the key (from the oldest to the newest) into
+            // a single entry.
+            while let Some(next) = heap.pop() {
+                if next.entry.key != key {
+                    heap.push(next);
+                    break;
+                }
+                Self::push_next_compaction_item(
+                    &mut heap,
+                    &mut sstable_readers,
+                    next.index,
+                    &mut offset_bytes,
+                )
+                .await?;
+                if let Some(next_value) =
+                    self.apply_range_tombstones(next.entry.value, deleted_at)
+                {
+                    folded = Some(self.fold_value(folded, next_value)?);
+                }
             }
-            should_write_current &=
-                keep_tombstones || current.entry.value.data != TOMBSTONE;
 
-            if should_write_current {
-                if let Some(ref mut bloom) = maybe_bloom {
-                    bloom.set(&current.entry.key);
+            let mut value = match folded {
+                Some(value) => value,
+                None => continue,
+            };
+
+            // Operands are resolved and tombstones are purged only when
+            // there is no other value of the key left to apply them to.
+            let purgeable = value.kind == EntryKind::Merge
+                || (value.is_delete()
+                    && gc_before.is_some_and(|at| value.timestamp < at));
+            if purgeable
+                && !self.memtables_contain(&key)
+                && !self.any_sstable_contains(&other_sstables, &key).await?
+            {
+                value = self.resolve_operand(value)?;
+                if value.is_delete() {
+                    continue;
                 }
-                entry_writer.write(&current.entry).await?;
-                items_written += 1;
             }
 
-            let (data_reader, index_reader) = &mut sstable_readers[index];
-            let entry_result = Self::read_next_entry(
-                data_reader,
-                index_reader,
-                &mut offset_bytes,
-            )
-            .await;
-            if let Ok(entry) = entry_result {
-                heap.push(CompactionItem { entry, index });
+            if let Some(ref mut bloom) = maybe_bloom {
+                bloom.set(&key);
             }
+            sketch.add(&key, &value)?;
+            entry_writer.write(&Entry { key, value }).await?;
         }
 
         entry_writer.close().await?;
@@ -1075,23 +2170,60 @@ impl LSMTree {
             .await?;
         }
 
-        let mut files_to_delete = Vec::with_capacity(sstable_paths.len() * 3);
-        for (data_path, index_path, bloom_path) in sstable_paths {
-            files_to_delete.push(data_path);
-            files_to_delete.push(index_path);
-            files_to_delete.push(bloom_path);
-        }
+        // Like tombstones, range tombstones are needed until there are no
+        // other sstables or memtable values they delete.
+        let range_tombstones = if other_sstables.is_empty() {
+            range_tombstones
+                .into_iter()
+                .filter(|range_tombstone| match gc_before {
+                    Some(at) => {
+                        range_tombstone.timestamp >= at
+                            || self.memtables_contain_range(range_tombstone)
+                    }
+                    None => true,
+                })
+                .collect()
+        } else {
+            range_tombstones
+        };
+        let compact_range_tombstones_path = get_file_path(
+            &self.dir,
+            output_index,
+            COMPACT_RANGE_TOMBSTONES_FILE_EXT,
+        );
+        write_range_tombstones(
+            &compact_range_tombstones_path,
+            &range_tombstones,
+        )
+        .await?;
+
+        let compact_sketch_path =
+            get_file_path(&self.dir, output_index, COMPACT_SKETCH_FILE_EXT);
+        write_file(
+            &compact_sketch_path,
+            &bincode_options().serialize(&sketch)?,
+        )
+        .await?;
+
+        let files_to_delete =
+            sstable_paths.into_iter().flatten().collect::<Vec<_>>();
 
         let (output_data_path, output_index_path) =
             get_data_file_paths(&self.dir, output_index);
         let output_bloom_path =
             get_file_path(&self.dir, output_index, BLOOM_FILE_EXT);
+        let output_range_tombstones_path =
+            get_file_path(&self.dir, output_index, RANGE_TOMBSTONES_FILE_EXT);
+        let output_sketch_path =
+            get_file_path(&self.dir, output_index, SKETCH_FILE_EXT);
 
         let action = CompactionAction {
             renames: vec![
                 (compact_data_path, output_data_path),
                 (compact_index_path, output_index_path),
                 (compact_bloom_path, output_bloom_path),
+                (compact_range_tombstones_path, output_range_tombstones_path),
+                (compact_sketch_path, output_sketch_path),
             ],
             deletes: files_to_delete,
         };
@@ -1128,8 +2260,9 @@ impl LSMTree {
                 SSTable::new(
                     &self.dir,
                     output_index,
-                    items_written,
                     maybe_bloom.map(Rc::new),
+                    Rc::new(range_tombstones),
+                    Some(Rc::new(sketch)),
                 )
                 .await?,
             );
@@ -1152,47 +2285,304 @@ impl LSMTree {
 
         Self::remove_file_log_on_err(&compact_action_path).await;
 
+        self.compaction_durations
+            .borrow_mut()
+            .observe(start.elapsed());
+
         Ok(())
     }
 
-    async fn read_next_entry(
-        data_reader: &mut (impl AsyncReadExt + Unpin),
-        index_reader: &mut (impl AsyncReadExt + Unpin),
-        offset_bytes: &mut [u8],
-    ) -> Result<Entry> {
-        index_reader.read_exact(offset_bytes).await?;
-        let entry_offset: EntryOffset =
-            bincode_options().deserialize(offset_bytes)?;
-        let mut data_bytes = vec![0; entry_offset.full_size as usize];
-        data_reader.read_exact(&mut data_bytes).await?;
-        let entry: Entry = bincode_options().deserialize(&data_bytes)?;
-        Ok(entry)
-    }
+    /// Link the sstables in `source_dir` (written by `SSTableBuilder`) into
+    /// the tree, returns the number of sstables ingested.
+    /// The ingested sstables are the newest ones in the tree, but reads and
+    /// compactions resolve values by timestamp, so older ingested values
+    /// don't override newer writes.
+    /// Files are hard linked when possible (copied otherwise), and renamed
+    /// into place through a compaction action, so a crash in the middle never
+    /// leaves a partially ingested sstable.
+    pub async fn ingest(&self, source_dir: &Path) -> Result<usize> {
+        let pattern = create_file_path_regex(DATA_FILE_EXT)?;
+        let read_dir = source_dir.to_path_buf();
+        let mut source_indices = executor()
+            .spawn_blocking(move || -> std::io::Result<Vec<usize>> {
+                Ok(std::fs::read_dir(read_dir)?
+                    .filter_map(std::result::Result::ok)
+                    .filter_map(|entry| get_first_capture(&pattern, &entry))
+                    .filter_map(|n| n.parse::<usize>().ok())
+                    .collect())
+            })
+            .await?;
+        source_indices.sort_unstable();
 
-    async fn remove_file_log_on_err(file_path: &PathBuf) {
-        if let Err(e) = remove(file_path).await {
-            error!(
-                "Failed to remove file '{}', that is irrelevant after \
-                 compaction: {}",
-                file_path.display(),
-                e
-            );
+        if source_indices.is_empty() {
+            return Ok(0);
         }
-    }
 
-    pub fn iter(&self) -> AsyncIter {
-        AsyncIter::new(self, Box::new(|_, _| true))
-    }
+        let _permit = self.acquire_compaction_permit().await?;
+        self.wait_for_flush().await;
+
+        // Reserved with no await in between, a flush that starts while
+        // linking writes to the index after the ingested ones.
+        let first_index = self.write_sstable_index.get();
+        self.write_sstable_index
+            .set(first_index + 2 * source_indices.len());
+
+        let mut renames = Vec::new();
+        let mut indices = Vec::with_capacity(source_indices.len());
+        for (i, source_index) in source_indices.into_iter().enumerate() {
+            let index = first_index + 2 * i;
+            for (ext, staging_ext) in [
+                (DATA_FILE_EXT, COMPACT_DATA_FILE_EXT),
+                (INDEX_FILE_EXT, COMPACT_INDEX_FILE_EXT),
+                (BLOOM_FILE_EXT, COMPACT_BLOOM_FILE_EXT),
+                (RANGE_TOMBSTONES_FILE_EXT, COMPACT_RANGE_TOMBSTONES_FILE_EXT),
+                (SKETCH_FILE_EXT, COMPACT_SKETCH_FILE_EXT),
+            ] {
+                let source_path = get_file_path(source_dir, source_index, ext);
+                let staging_path = get_file_path(&self.dir, index, staging_ext);
+                if !link_or_copy_file(source_path, staging_path.clone()).await?
+                {
+                    continue;
+                }
+                renames
+                    .push((staging_path, get_file_path(&self.dir, index, ext)));
+            }
+            indices.push(index);
+        }
 
-    pub fn iter_filter(&self, filter_fn: Box<IterFilterFn>) -> AsyncIter {
-        AsyncIter::new(self, filter_fn)
-    }
-}
+        let action = CompactionAction {
+            renames,
+            deletes: Vec::new(),
+        };
+        let action_path =
+            get_file_path(&self.dir, first_index, COMPACT_ACTION_FILE_EXT);
+        write_file(&action_path, &bincode_options().serialize(&action)?)
+            .await?;
+        Self::run_compaction_action(&action).await?;
 
-#[cfg(test)]
-mod tests {
-    use std::{
-        pin::Pin,
+        let mut ingested = Vec::with_capacity(indices.len());
+        for index in indices {
+            ingested
+                .push(SSTable::new_with_bloom_read(&self.dir, index).await?);
+        }
+        let ingested_count = ingested.len();
+
+        // No await from reading the sstables until replacing them, so a
+        // flush that finished in the meantime is not lost.
+        {
+            let mut sstables: Vec<SSTable> =
+                self.sstables.borrow().iter().cloned().collect();
+            sstables.extend(ingested);
+            sstables.sort_unstable_by_key(|t| t.index);
+            self.sstables.replace(Rc::new(sstables));
+        }
+
+        Self::remove_file_log_on_err(&action_path).await;
+
+        Ok(ingested_count)
+    }
+
+    /// Whether the active or the flushing memtable has a value of the key,
+    /// written while compacting, or not yet flushed.
+    fn memtables_contain(&self, key: &Vec<u8>) -> bool {
+        self.active_memtable.borrow().entries.get(key).is_some()
+            || self
+                .flush_memtable
+                .borrow()
+                .as_ref()
+                .is_some_and(|memtable| memtable.entries.get(key).is_some())
+    }
+
+    /// Whether the active or the flushing memtable has a value of a key
+    /// deleted by the range tombstone.
+    fn memtables_contain_range(
+        &self,
+        range_tombstone: &RangeTombstone,
+    ) -> bool {
+        let contains = |memtable: &MemTable| {
+            memtable.entries.iter().any(|(key, value)| {
+                range_tombstone.covers(key)
+                    && value.timestamp <= range_tombstone.timestamp
+            })
+        };
+        contains(&self.active_memtable.borrow())
+            || self.flush_memtable.borrow().as_ref().is_some_and(contains)
+    }
+
+    async fn any_sstable_contains(
+        &self,
+        sstables: &[SSTable],
+        key: &Vec<u8>,
+    ) -> Result<bool> {
+        for sstable in sstables {
+            if self.get_from_sstable(sstable, key).await?.is_some() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Push the next entry of an sstable being compacted to the heap, if
+    /// there is one.
+    /// A corrupted or unsorted sstable fails the compaction, instead of
+    /// dropping (or reordering) the rest of its entries in the compacted
+    /// sstable.
+    async fn push_next_compaction_item(
+        heap: &mut BinaryHeap<CompactionItem>,
+        sstable_readers: &mut [CompactionReader],
+        index: usize,
+        offset_bytes: &mut [u8],
+    ) -> Result<()> {
+        let reader = &mut sstable_readers[index];
+        if reader.remaining == 0 {
+            return Ok(());
+        }
+        reader.remaining -= 1;
+
+        let entry = Self::read_next_entry(
+            &mut reader.data_reader,
+            &mut reader.index_reader,
+            reader.format_version,
+            offset_bytes,
+        )
+        .await?;
+        if reader
+            .last_key
+            .as_ref()
+            .is_some_and(|last_key| &entry.key <= last_key)
+        {
+            return Err(Error::UnsortedSSTableEntries);
+        }
+        reader.last_key = Some(entry.key.clone());
+
+        heap.push(CompactionItem { entry, index });
+        Ok(())
+    }
+
+    async fn read_next_entry(
+        data_reader: &mut (impl AsyncReadExt + Unpin),
+        index_reader: &mut (impl AsyncReadExt + Unpin),
+        format_version: u32,
+        offset_bytes: &mut [u8],
+    ) -> Result<Entry> {
+        index_reader.read_exact(offset_bytes).await?;
+        let entry_offset: EntryOffset =
+            bincode_options().deserialize(offset_bytes)?;
+        let mut data_bytes = vec![0; entry_offset.full_size as usize];
+        data_reader.read_exact(&mut data_bytes).await?;
+        deserialize_entry(format_version, &data_bytes)
+    }
+
+    async fn remove_file_log_on_err(file_path: &PathBuf) {
+        if let Err(e) = remove(file_path).await {
+            error!(
+                "Failed to remove file '{}', that is irrelevant after \
+                 compaction: {}",
+                file_path.display(),
+                e
+            );
+        }
+    }
+
+    pub fn iter(&self) -> AsyncIter {
+        AsyncIter::new(self, Box::new(|_, _| true))
+    }
+
+    pub fn iter_filter(&self, filter_fn: Box<IterFilterFn>) -> AsyncIter {
+        AsyncIter::new(self, filter_fn)
+    }
+
+    /// Iterate over the current values of the keys greater than `after`, in
+    /// key order, see `SortedIter`.
+    pub async fn iter_sorted(
+        &self,
+        after: Option<&[u8]>,
+        filter_fn: Box<IterFilterFn>,
+    ) -> Result<SortedIter> {
+        let from = match after {
+            Some(after) => Bound::Excluded(after),
+            None => Bound::Unbounded,
+        };
+        self.iter_sorted_ex(from, true, filter_fn).await
+    }
+
+    /// Like `iter_sorted()`, from any bound, and optionally ignoring range
+    /// tombstones (values deleted by them are iterated over as if they were
+    /// not deleted).
+    async fn iter_sorted_ex(
+        &self,
+        from: Bound<&[u8]>,
+        with_range_tombstones: bool,
+        filter_fn: Box<IterFilterFn>,
+    ) -> Result<SortedIter> {
+        // Taken together before any await, so no flush moves entries from a
+        // memtable to an sstable in between.
+        let sstables = self.sstables.borrow().clone();
+        let mut memtables = Vec::with_capacity(2);
+        let mut range_tombstones = Vec::new();
+        if let Some(memtable) = self.flush_memtable.borrow().as_ref() {
+            memtables.push(memtable.entries_from(from).into_iter());
+            range_tombstones.extend(memtable.range_tombstones.iter().cloned());
+        }
+        {
+            let active_memtable = self.active_memtable.borrow();
+            memtables.push(active_memtable.entries_from(from).into_iter());
+            range_tombstones
+                .extend(active_memtable.range_tombstones.iter().cloned());
+        }
+
+        let mut cursors = Vec::with_capacity(sstables.len());
+        for sstable in sstables.iter() {
+            range_tombstones.extend(sstable.range_tombstones.iter().cloned());
+            cursors.push(
+                SSTableCursor::seek(sstable, &self.page_cache, from).await?,
+            );
+        }
+        if !with_range_tombstones {
+            range_tombstones.clear();
+        }
+
+        let mut iter = SortedIter {
+            tree: self,
+            _sstables: sstables,
+            cursors,
+            memtables,
+            heap: BinaryHeap::new(),
+            range_tombstones,
+            filter_fn,
+        };
+        for index in 0..iter.cursors.len() + iter.memtables.len() {
+            iter.push_next(index).await?;
+        }
+        Ok(iter)
+    }
+
+    /// Get the current entries of the first `limit` keys (sorted) that are
+    /// greater than `after`, tombstones included.
+    /// Seeks to `after` in every sstable, so a page reads only about `limit`
+    /// keys of each sstable.
+    pub async fn scan(
+        &self,
+        after: Option<&[u8]>,
+        limit: usize,
+        filter_fn: Box<IterFilterFn>,
+    ) -> Result<Vec<Entry>> {
+        let mut iter = self.iter_sorted(after, filter_fn).await?;
+        let mut entries = Vec::with_capacity(limit);
+        while entries.len() < limit {
+            match iter.next().await? {
+                Some(entry) => entries.push(entry),
+                None => break,
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
         task::{Context, Poll},
     };
 
@@ -1201,7 +2591,17 @@ mod tests {
     use glommio::{LocalExecutorBuilder, Placement};
     use tempfile::tempdir;
 
-    use crate::storage_engine::page_cache::PageCache;
+    use crate::{
+        crdt::{self, Counter},
+        siblings::{self, Siblings, VersionVector},
+        storage_engine::{
+            inspect::{inspect_sstable, inspect_wal},
+            page_cache::PageCache,
+            sstable_builder::SSTableBuilder,
+            LEGACY_SSTABLE_FORMAT_VERSION, SSTABLE_FORMAT_VERSION,
+            WAL_FORMAT_VERSION, WAL_HEADER_SIZE, WAL_RECORD_MAGIC,
+        },
+    };
 
     use super::*;
 
@@ -1224,6 +2624,8 @@ mod tests {
             TEST_TREE_CAPACITY,
             None,
             DEFAULT_SSTABLE_BLOOM_MIN_SIZE,
+            None,
+            None,
         )
         .await
     }
@@ -1279,6 +2681,1052 @@ mod tests {
         run_with_glommio(_set_and_get_memtable)
     }
 
+    async fn _set_batch(dir: PathBuf, cache: GlobalCache) -> Result<()> {
+        let values: Vec<Vec<u8>> = (0..TEST_TREE_CAPACITY as u16 + 2)
+            .map(|n| n.to_le_bytes().to_vec())
+            .collect();
+        let (singles, batch) = values.split_at(TEST_TREE_CAPACITY - 2);
+
+        // New tree.
+        {
+            let tree = Rc::new(
+                test_lsm_tree(dir.clone(), partitioned_cache(&cache)).await?,
+            );
+            for v in singles {
+                tree.clone().set(v.clone(), v.clone()).await?;
+            }
+
+            // Doesn't fit in the memtable, flushed early.
+            let batch_items =
+                batch.iter().map(|v| (v.clone(), Some(v.clone())));
+            tree.clone()
+                .set_batch_with_timestamp(
+                    batch_items.collect(),
+                    OffsetDateTime::now_utc(),
+                )
+                .await?;
+            assert_eq!(tree.active_memtable.borrow().len(), batch.len());
+            while tree.flush_memtable.borrow().is_some() {
+                tree.get_flush_event_listener().await;
+            }
+
+            let too_large = values.iter().map(|v| (v.clone(), Some(v.clone())));
+            assert!(matches!(
+                tree.clone()
+                    .set_batch_with_timestamp(
+                        too_large.collect(),
+                        OffsetDateTime::now_utc(),
+                    )
+                    .await,
+                Err(Error::BatchTooLarge(_))
+            ));
+        }
+
+        // Reopening the tree.
+        {
+            let tree = test_lsm_tree(dir, partitioned_cache(&cache)).await?;
+            for v in &values {
+                assert_eq!(tree.get(v).await?, Some(v.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn set_batch() -> Result<()> {
+        run_with_glommio(_set_batch)
+    }
+
+    async fn _replay_legacy_wal(
+        dir: PathBuf,
+        cache: GlobalCache,
+    ) -> Result<()> {
+        // Records from before WAL records had a header, a single entry each
+        // (a delete was an empty value).
+        let timestamp = OffsetDateTime::now_utc().unix_timestamp_nanos();
+        let mut wal_buf = Vec::new();
+        let records: [(Vec<u8>, Vec<u8>); 3] =
+            [(vec![0], vec![10]), (vec![1], vec![11]), (vec![1], vec![])];
+        for (key, data) in records {
+            let mut record =
+                bincode_options().serialize(&(key, data, timestamp))?;
+            record.resize(PAGE_SIZE, 0);
+            wal_buf.extend(record);
+        }
+        std::fs::write(get_file_path(&dir, 0, MEMTABLE_FILE_EXT), wal_buf)?;
+
+        {
+            let tree = Rc::new(
+                test_lsm_tree(dir.clone(), partitioned_cache(&cache)).await?,
+            );
+            assert_eq!(tree.get(&vec![0]).await?, Some(vec![10]));
+            assert!(tree.get_entry(&vec![1]).await?.unwrap().is_delete());
+
+            // Appended after the legacy records.
+            tree.clone().set(vec![2], vec![12]).await?;
+        }
+
+        // Reopening the tree.
+        {
+            let tree = test_lsm_tree(dir, partitioned_cache(&cache)).await?;
+            assert_eq!(tree.get(&vec![0]).await?, Some(vec![10]));
+            assert!(tree.get_entry(&vec![1]).await?.unwrap().is_delete());
+            assert_eq!(tree.get(&vec![2]).await?, Some(vec![12]));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn replay_legacy_wal() -> Result<()> {
+        run_with_glommio(_replay_legacy_wal)
+    }
+
+    async fn _reject_newer_wal_format(
+        dir: PathBuf,
+        cache: GlobalCache,
+    ) -> Result<()> {
+        let mut record = bincode_options()
+            .serialize(&(WAL_RECORD_MAGIC, WAL_FORMAT_VERSION + 1))?;
+        record.resize(PAGE_SIZE, 0);
+        std::fs::write(get_file_path(&dir, 0, MEMTABLE_FILE_EXT), record)?;
+
+        assert!(matches!(
+            test_lsm_tree(dir, partitioned_cache(&cache)).await,
+            Err(Error::UnsupportedWalFormatVersion(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_newer_wal_format() -> Result<()> {
+        run_with_glommio(_reject_newer_wal_format)
+    }
+
+    /// Merges values as sets of bytes.
+    fn merge_byte_sets(a: &EntryValue, b: &EntryValue) -> Result<EntryValue> {
+        let mut data = [a.data.clone(), b.data.clone()].concat();
+        data.sort_unstable();
+        data.dedup();
+        Ok(EntryValue {
+            data,
+            timestamp: a.timestamp.max(b.timestamp),
+            kind: EntryKind::Put,
+        })
+    }
+
+    async fn _merge_values(dir: PathBuf, cache: GlobalCache) -> Result<()> {
+        let open = || {
+            LSMTree::open_or_create_ex(
+                dir.clone(),
+                partitioned_cache(&cache),
+                TEST_TREE_CAPACITY,
+                None,
+                DEFAULT_SSTABLE_BLOOM_MIN_SIZE,
+                Some(merge_byte_sets),
+                None,
+            )
+        };
+        let key = vec![0];
+
+        // New tree.
+        {
+            let tree = Rc::new(open().await?);
+            tree.clone().set(key.clone(), vec![1]).await?;
+            tree.clone().set(key.clone(), vec![2]).await?;
+            tree.clone().flush().await?;
+            tree.clone().set(key.clone(), vec![3]).await?;
+            tree.clone().flush().await?;
+            assert_eq!(tree.get(&key).await?, Some(vec![1, 2, 3]));
+
+            tree.compact(&[0, 2], 3, None).await?;
+            assert_eq!(*tree.sstable_indices_and_sizes(), vec![(3, 1)]);
+            assert_eq!(tree.get(&key).await?, Some(vec![1, 2, 3]));
+
+            tree.clone().set(key.clone(), vec![4]).await?;
+            tree.clone().set(key.clone(), vec![5]).await?;
+            assert_eq!(tree.get(&key).await?, Some(vec![1, 2, 3, 4, 5]));
+        }
+
+        // Reopening the tree, the WAL entries are merged on replay.
+        {
+            let tree = open().await?;
+            assert_eq!(tree.get(&key).await?, Some(vec![1, 2, 3, 4, 5]));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn merge_values() -> Result<()> {
+        run_with_glommio(_merge_values)
+    }
+
+    async fn _delete_merged_values(
+        dir: PathBuf,
+        cache: GlobalCache,
+    ) -> Result<()> {
+        let siblings_value = Siblings::default()
+            .update(&VersionVector::new(), Some(vec![1]), 1)
+            .encode()?;
+        let mut counter = Counter::default();
+        counter.increment(1, 1);
+        let counter_value = crdt::encode(&counter)?;
+
+        let trees: [(&str, MergeFn, Vec<u8>); 2] = [
+            ("siblings", siblings::merge_entry_values, siblings_value),
+            (
+                "counter",
+                crdt::merge_entry_values::<Counter>,
+                counter_value,
+            ),
+        ];
+        for (name, merge_fn, data) in trees {
+            let tree = Rc::new(
+                LSMTree::open_or_create_ex(
+                    dir.join(name),
+                    partitioned_cache(&cache),
+                    TEST_TREE_CAPACITY,
+                    None,
+                    DEFAULT_SSTABLE_BLOOM_MIN_SIZE,
+                    Some(merge_fn),
+                    None,
+                )
+                .await?,
+            );
+            let key = vec![0];
+            let start = OffsetDateTime::now_utc();
+            let at = |seconds| start + Duration::from_secs(seconds);
+
+            tree.clone()
+                .set_with_timestamp(key.clone(), data.clone(), at(0))
+                .await?;
+            tree.clone().flush().await?;
+
+            // Deleted in the memtable, on top of a value in the memtable and
+            // a value in an sstable.
+            tree.clone()
+                .set_with_timestamp(key.clone(), data.clone(), at(1))
+                .await?;
+            tree.clone()
+                .delete_with_timestamp(key.clone(), at(2))
+                .await?;
+            assert_eq!(tree.get(&key).await?, None);
+
+            tree.clone().flush().await?;
+            assert_eq!(tree.get(&key).await?, None);
+
+            tree.compact(&[0, 2], 3, None).await?;
+            assert_eq!(tree.get(&key).await?, None);
+
+            // A write after the delete doesn't bring back the deleted state.
+            tree.clone()
+                .set_with_timestamp(key.clone(), data.clone(), at(3))
+                .await?;
+            assert_eq!(tree.get(&key).await?, Some(data));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn delete_merged_values() -> Result<()> {
+        run_with_glommio(_delete_merged_values)
+    }
+
+    /// Like `merge_byte_sets()`, but fails to merge values holding a 0.
+    fn merge_nonzero_byte_sets(
+        a: &EntryValue,
+        b: &EntryValue,
+    ) -> Result<EntryValue> {
+        if a.data.contains(&0) || b.data.contains(&0) {
+            return Err(Error::UnsupportedField("0".to_string()));
+        }
+        merge_byte_sets(a, b)
+    }
+
+    async fn _failed_merge_writes_nothing(
+        dir: PathBuf,
+        cache: GlobalCache,
+    ) -> Result<()> {
+        let open = || {
+            LSMTree::open_or_create_ex(
+                dir.clone(),
+                partitioned_cache(&cache),
+                TEST_TREE_CAPACITY,
+                None,
+                DEFAULT_SSTABLE_BLOOM_MIN_SIZE,
+                Some(merge_nonzero_byte_sets),
+                None,
+            )
+        };
+
+        {
+            let tree = Rc::new(open().await?);
+            tree.clone().set(vec![1], vec![1]).await?;
+
+            // The second entry fails to merge, after the first one merged.
+            let batch =
+                vec![(vec![2], Some(vec![2])), (vec![1], Some(vec![0]))];
+            assert!(tree
+                .clone()
+                .set_batch_with_timestamp(batch, OffsetDateTime::now_utc())
+                .await
+                .is_err());
+            assert_eq!(tree.get(&vec![1]).await?, Some(vec![1]));
+            assert_eq!(tree.get(&vec![2]).await?, None);
+        }
+
+        // Reopening the tree, the batch was not written to the WAL.
+        {
+            let tree = open().await?;
+            assert_eq!(tree.get(&vec![1]).await?, Some(vec![1]));
+            assert_eq!(tree.get(&vec![2]).await?, None);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn failed_merge_writes_nothing() -> Result<()> {
+        run_with_glommio(_failed_merge_writes_nothing)
+    }
+
+    /// Operands are u64 (little endian) increments.
+    struct AddOperator;
+
+    impl MergeOperator for AddOperator {
+        fn full_merge(
+            &self,
+            existing: Option<&[u8]>,
+            operand: &[u8],
+        ) -> Result<Vec<u8>> {
+            self.partial_merge(existing.unwrap_or(&[0; 8]), operand)
+        }
+
+        fn partial_merge(&self, older: &[u8], newer: &[u8]) -> Result<Vec<u8>> {
+            let older = u64::from_le_bytes(older.try_into().unwrap());
+            let newer = u64::from_le_bytes(newer.try_into().unwrap());
+            Ok((older + newer).to_le_bytes().to_vec())
+        }
+    }
+
+    async fn _merge_operands(dir: PathBuf, cache: GlobalCache) -> Result<()> {
+        let open = || {
+            LSMTree::open_or_create_ex(
+                dir.clone(),
+                partitioned_cache(&cache),
+                TEST_TREE_CAPACITY,
+                None,
+                DEFAULT_SSTABLE_BLOOM_MIN_SIZE,
+                None,
+                Some(Rc::new(AddOperator)),
+            )
+        };
+        let n = |n: u64| n.to_le_bytes().to_vec();
+        let (base_key, new_key) = (vec![0], vec![1]);
+
+        // New tree.
+        {
+            let tree = Rc::new(open().await?);
+            tree.clone().set(base_key.clone(), n(10)).await?;
+            tree.clone().merge(base_key.clone(), n(1)).await?;
+            tree.clone().merge(new_key.clone(), n(3)).await?;
+            tree.clone().flush().await?;
+
+            // Only operands in the memtable, on top of values in sstables.
+            tree.clone().merge(base_key.clone(), n(5)).await?;
+            tree.clone().merge(new_key.clone(), n(4)).await?;
+            tree.clone().flush().await?;
+            assert_eq!(tree.get(&base_key).await?, Some(n(16)));
+            assert_eq!(tree.get(&new_key).await?, Some(n(7)));
+
+            // Every sstable's operands are folded into the values under them.
+            let mut iter = tree.iter();
+            let mut iterated = Vec::new();
+            while let Some(entry) = iter.next().await? {
+                assert_eq!(entry.value.kind, EntryKind::Put);
+                iterated.push((entry.key, entry.value.data));
+            }
+            assert_eq!(
+                iterated,
+                vec![
+                    (base_key.clone(), n(11)),
+                    (new_key.clone(), n(3)),
+                    (base_key.clone(), n(16)),
+                    (new_key.clone(), n(7)),
+                ]
+            );
+
+            // Operands without a value under them are kept as a single
+            // operand, until no older sstable has a value of their key.
+            tree.compact(&[2], 3, None).await?;
+            assert_eq!(tree.get(&new_key).await?, Some(n(7)));
+            tree.compact(&[0, 3], 5, Some(OffsetDateTime::now_utc()))
+                .await?;
+            assert_eq!(*tree.sstable_indices_and_sizes(), vec![(5, 2)]);
+            assert_eq!(tree.get(&base_key).await?, Some(n(16)));
+            assert_eq!(tree.get(&new_key).await?, Some(n(7)));
+
+            tree.clone().delete(base_key.clone()).await?;
+            tree.clone().merge(base_key.clone(), n(2)).await?;
+            tree.clone().merge(base_key.clone(), n(2)).await?;
+        }
+
+        // Reopening the tree, the WAL operands are folded on replay.
+        {
+            let tree = open().await?;
+            assert_eq!(tree.get(&base_key).await?, Some(n(4)));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn merge_operands() -> Result<()> {
+        run_with_glommio(_merge_operands)
+    }
+
+    async fn _delete_range(dir: PathBuf, cache: GlobalCache) -> Result<()> {
+        async fn keys(tree: &LSMTree) -> Result<Vec<Vec<u8>>> {
+            let mut keys = Vec::new();
+            let mut iter = tree.iter();
+            while let Some(entry) = iter.next().await? {
+                if !entry.value.is_delete() {
+                    keys.push(entry.key);
+                }
+            }
+            Ok(keys)
+        }
+
+        // New tree.
+        {
+            let tree = Rc::new(
+                test_lsm_tree(dir.clone(), partitioned_cache(&cache)).await?,
+            );
+            for i in 0..8 {
+                tree.clone().set(vec![i], vec![i]).await?;
+            }
+            tree.clone().flush().await?;
+            tree.clone().set(vec![3], vec![3]).await?;
+
+            tree.clone().delete_range(vec![2], vec![6]).await?;
+            tree.clone().set(vec![4], vec![40]).await?;
+            assert_eq!(tree.get(&vec![2]).await?, None);
+            assert_eq!(tree.get(&vec![3]).await?, None);
+            assert_eq!(tree.get(&vec![4]).await?, Some(vec![40]));
+            assert_eq!(tree.get(&vec![6]).await?, Some(vec![6]));
+            assert!(tree.get_entry(&vec![5]).await?.unwrap().is_delete());
+            // Sstables are iterated before the memtable.
+            assert_eq!(
+                keys(&tree).await?,
+                vec![vec![0], vec![1], vec![6], vec![7], vec![4]]
+            );
+            tree.clone().flush().await?;
+
+            // An sstable with only a range tombstone.
+            tree.clone().delete_range(vec![0], vec![1]).await?;
+            tree.clone().flush().await?;
+            assert_eq!(tree.get(&vec![0]).await?, None);
+            assert_eq!(tree.get(&vec![3]).await?, None);
+            assert_eq!(tree.get(&vec![4]).await?, Some(vec![40]));
+
+            tree.compact(&[0, 2], 3, None).await?;
+            assert_eq!(*tree.sstable_indices_and_sizes(), vec![(3, 8), (4, 0)]);
+            assert_eq!(tree.get(&vec![3]).await?, None);
+            assert_eq!(tree.get(&vec![4]).await?, Some(vec![40]));
+            assert_eq!(tree.get(&vec![6]).await?, Some(vec![6]));
+        }
+
+        // Reopening the tree, and compacting away the range tombstones.
+        {
+            let tree =
+                test_lsm_tree(dir.clone(), partitioned_cache(&cache)).await?;
+            assert_eq!(tree.get(&vec![0]).await?, None);
+            assert_eq!(tree.get(&vec![2]).await?, None);
+            let expected = vec![vec![1], vec![4], vec![6], vec![7]];
+            assert_eq!(keys(&tree).await?, expected);
+
+            tree.compact(&[3, 4], 5, Some(OffsetDateTime::now_utc()))
+                .await?;
+            assert_eq!(*tree.sstable_indices_and_sizes(), vec![(5, 4)]);
+            assert!(tree.get_entry(&vec![2]).await?.is_none());
+            assert_eq!(keys(&tree).await?, expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn delete_range() -> Result<()> {
+        run_with_glommio(_delete_range)
+    }
+
+    async fn _purge_tombstones(dir: PathBuf, cache: GlobalCache) -> Result<()> {
+        let tree =
+            Rc::new(test_lsm_tree(dir, partitioned_cache(&cache)).await?);
+        let now = OffsetDateTime::now_utc();
+        let old = now - Duration::from_secs(60);
+        let gc_before = Some(now - Duration::from_secs(30));
+
+        for key in [vec![0], vec![1]] {
+            tree.clone()
+                .set_with_timestamp(
+                    key.clone(),
+                    key,
+                    old - Duration::from_secs(1),
+                )
+                .await?;
+        }
+        tree.clone().flush().await?;
+        tree.clone().delete_with_timestamp(vec![0], old).await?;
+        tree.clone().delete(vec![1]).await?;
+        tree.clone().delete_with_timestamp(vec![2], old).await?;
+        tree.clone().flush().await?;
+
+        // The old tombstone of a key in an older sstable outside of the
+        // compaction is kept, like a tombstone in its grace period.
+        tree.compact(&[2], 3, gc_before).await?;
+        assert_eq!(*tree.sstable_indices_and_sizes(), vec![(0, 2), (3, 2)]);
+        assert_eq!(tree.get(&vec![0]).await?, None);
+        assert_eq!(tree.get(&vec![1]).await?, None);
+
+        tree.compact(&[0, 3], 5, gc_before).await?;
+        assert_eq!(*tree.sstable_indices_and_sizes(), vec![(5, 1)]);
+        assert!(tree.get_entry(&vec![0]).await?.is_none());
+        assert!(tree.get_entry(&vec![1]).await?.unwrap().is_delete());
+
+        // Old tombstones of keys with an even older value that arrived late,
+        // still in the memtable, are kept.
+        tree.clone().delete_with_timestamp(vec![3], old).await?;
+        tree.clone()
+            .delete_range_with_timestamp(vec![4], vec![5], old)
+            .await?;
+        tree.clone().flush().await?;
+        for key in [vec![3], vec![4]] {
+            tree.clone()
+                .set_with_timestamp(
+                    key.clone(),
+                    key,
+                    old - Duration::from_secs(1),
+                )
+                .await?;
+        }
+        let indices = tree
+            .sstable_indices_and_sizes()
+            .iter()
+            .map(|(i, _)| *i)
+            .collect::<Vec<_>>();
+        tree.compact(&indices, 7, gc_before).await?;
+        assert_eq!(tree.get(&vec![3]).await?, None);
+        assert_eq!(tree.get(&vec![4]).await?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn purge_tombstones() -> Result<()> {
+        run_with_glommio(_purge_tombstones)
+    }
+
+    async fn _truncate(dir: PathBuf, cache: GlobalCache) -> Result<()> {
+        let tree =
+            Rc::new(test_lsm_tree(dir, partitioned_cache(&cache)).await?);
+        let now = OffsetDateTime::now_utc();
+        let before = now - Duration::from_secs(1);
+        let after = now + Duration::from_secs(1);
+
+        for i in 0..4u8 {
+            tree.clone()
+                .set_with_timestamp(vec![i], vec![i], before)
+                .await?;
+        }
+        tree.clone().flush().await?;
+        tree.clone()
+            .set_with_timestamp(vec![0], vec![10], after)
+            .await?;
+        tree.clone()
+            .set_with_timestamp(vec![1], vec![11], before)
+            .await?;
+
+        tree.truncate(now);
+
+        // A late write from before the truncate.
+        tree.clone()
+            .set_with_timestamp(vec![2], vec![12], before)
+            .await?;
+
+        assert_eq!(tree.get(&vec![0]).await?, Some(vec![10]));
+        for i in 1..4u8 {
+            assert!(tree.get_entry(&vec![i]).await?.is_none());
+        }
+        let mut iter = tree.iter();
+        assert_eq!(iter.next().await?.map(|e| e.key), Some(vec![0]));
+        assert!(iter.next().await?.is_none());
+
+        tree.clone().flush().await?;
+        tree.compact(&[0, 2], 3, None).await?;
+        assert_eq!(*tree.sstable_indices_and_sizes(), vec![(3, 1)]);
+        assert_eq!(tree.get(&vec![0]).await?, Some(vec![10]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn truncate() -> Result<()> {
+        run_with_glommio(_truncate)
+    }
+
+    async fn _scan(dir: PathBuf, cache: GlobalCache) -> Result<()> {
+        let tree =
+            Rc::new(test_lsm_tree(dir, partitioned_cache(&cache)).await?);
+
+        for i in 0..10u8 {
+            tree.clone().set(vec![i], vec![i]).await?;
+        }
+        tree.clone().flush().await?;
+        tree.clone().set(vec![3], vec![13]).await?;
+        tree.clone().delete(vec![4]).await?;
+        tree.clone().flush().await?;
+        tree.clone().set(vec![5], vec![15]).await?;
+        tree.clone().set(vec![10], vec![10]).await?;
+
+        let mut entries = Vec::new();
+        let mut after = None;
+        loop {
+            let page = tree
+                .scan(after.as_deref(), 3, Box::new(|_, _| true))
+                .await?;
+            assert!(page.len() <= 3);
+            after = page.last().map(|entry| entry.key.clone());
+            let done = page.len() < 3;
+            entries.extend(page);
+            if done {
+                break;
+            }
+        }
+
+        assert_eq!(
+            entries.iter().map(|e| e.key[0]).collect::<Vec<_>>(),
+            (0..=10).collect::<Vec<_>>()
+        );
+        assert!(entries[4].value.is_delete());
+        assert_eq!(
+            entries
+                .iter()
+                .filter_map(|e| e.value.as_data().map(|d| d[0]))
+                .collect::<Vec<_>>(),
+            vec![0, 1, 2, 13, 15, 6, 7, 8, 9, 10]
+        );
+
+        // Starts after a key that is not in the tree.
+        let page = tree
+            .scan(Some(&[4, 0]), 2, Box::new(|key, _| key[0] % 2 == 0))
+            .await?;
+        assert_eq!(
+            page.iter().map(|e| e.key[0]).collect::<Vec<_>>(),
+            vec![6, 8]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan() -> Result<()> {
+        run_with_glommio(_scan)
+    }
+
+    async fn _approximate_live_keys(
+        dir: PathBuf,
+        cache: GlobalCache,
+    ) -> Result<()> {
+        let tree =
+            Rc::new(test_lsm_tree(dir, partitioned_cache(&cache)).await?);
+
+        for i in 0..20u8 {
+            tree.clone().set(vec![i], vec![i]).await?;
+        }
+        tree.clone().flush().await?;
+        assert_eq!(tree.approximate_live_keys().await?, 20);
+
+        // Overwrites and deletes, both in an sstable and in the memtable.
+        for i in 0..10u8 {
+            tree.clone().set(vec![i], vec![i + 1]).await?;
+        }
+        tree.clone().delete(vec![10]).await?;
+        tree.clone().flush().await?;
+        tree.clone().set(vec![11], vec![0]).await?;
+        tree.clone().delete(vec![12]).await?;
+        assert_eq!(tree.approximate_live_keys().await?, 18);
+
+        tree.compact(&[0, 2], 3, None).await?;
+        assert_eq!(tree.approximate_live_keys().await?, 18);
+
+        // Deleting a key again in other sstables doesn't count it again.
+        for _ in 0..3 {
+            tree.clone().delete(vec![10]).await?;
+            tree.clone().flush().await?;
+        }
+        assert_eq!(tree.approximate_live_keys().await?, 18);
+
+        Ok(())
+    }
+
+    #[test]
+    fn approximate_live_keys() -> Result<()> {
+        run_with_glommio(_approximate_live_keys)
+    }
+
+    async fn _approximate_live_keys_after_deletes(
+        dir: PathBuf,
+        cache: GlobalCache,
+    ) -> Result<()> {
+        let tree =
+            Rc::new(test_lsm_tree(dir, partitioned_cache(&cache)).await?);
+
+        // Deleted and set again, each in its own sstable.
+        tree.clone().set(vec![0], vec![0]).await?;
+        tree.clone().flush().await?;
+        tree.clone().delete(vec![0]).await?;
+        tree.clone().flush().await?;
+        assert_eq!(tree.approximate_live_keys().await?, 0);
+        tree.clone().set(vec![0], vec![1]).await?;
+        tree.clone().flush().await?;
+        assert_eq!(tree.approximate_live_keys().await?, 1);
+
+        for i in 1..10u8 {
+            tree.clone().set(vec![i], vec![i]).await?;
+        }
+        tree.clone().flush().await?;
+        assert_eq!(tree.approximate_live_keys().await?, 10);
+
+        // Deletes 2, 3 and 4, both in the memtable and in an sstable.
+        tree.clone().delete_range(vec![2], vec![5]).await?;
+        assert_eq!(tree.approximate_live_keys().await?, 7);
+        tree.clone().flush().await?;
+        assert_eq!(tree.approximate_live_keys().await?, 7);
+
+        // Overlapping ranges count a key once.
+        tree.clone().delete_range(vec![4], vec![7]).await?;
+        assert_eq!(tree.approximate_live_keys().await?, 5);
+
+        // Set after the range was deleted.
+        tree.clone().set(vec![3], vec![0]).await?;
+        assert_eq!(tree.approximate_live_keys().await?, 6);
+
+        Ok(())
+    }
+
+    #[test]
+    fn approximate_live_keys_after_deletes() -> Result<()> {
+        run_with_glommio(_approximate_live_keys_after_deletes)
+    }
+
+    async fn _ingest(dir: PathBuf, cache: GlobalCache) -> Result<()> {
+        let source_dir = dir.join("ingest");
+        let mut builder = SSTableBuilder::create(&source_dir, 0, 3)?;
+        let entries: Vec<Entry> = (0..3u8)
+            .map(|i| Entry {
+                key: vec![i],
+                value: EntryValue::new(vec![i + 10], None),
+            })
+            .collect();
+        for entry in &entries {
+            builder.add(entry).await?;
+        }
+        assert!(matches!(
+            builder.add(&entries[1]).await,
+            Err(Error::UnsortedSSTableEntries)
+        ));
+        assert_eq!(builder.finish().await?, 3);
+
+        {
+            let tree = Rc::new(
+                test_lsm_tree(dir.clone(), partitioned_cache(&cache)).await?,
+            );
+            // Written after the sstable was built.
+            tree.clone().set(vec![0], vec![100]).await?;
+            // Written before the sstable was built.
+            let before = entries[2].value.timestamp - Duration::from_secs(1);
+            tree.clone()
+                .set_with_timestamp(vec![2], vec![100], before)
+                .await?;
+            tree.clone().flush().await?;
+            tree.clone().set(vec![1], vec![100]).await?;
+
+            assert_eq!(tree.ingest(&source_dir).await?, 1);
+            assert_eq!(tree.write_sstable_index.get(), 4);
+
+            // The newest value wins, not the newest sstable.
+            assert_eq!(tree.get(&vec![0]).await?, Some(vec![100]));
+            assert_eq!(tree.get(&vec![1]).await?, Some(vec![100]));
+            assert_eq!(tree.get(&vec![2]).await?, Some(vec![12]));
+            assert_eq!(tree.approximate_live_keys().await?, 3);
+        }
+
+        // Reopening the tree, and compacting it resolves values the same.
+        {
+            let tree = test_lsm_tree(dir, partitioned_cache(&cache)).await?;
+            assert_eq!(tree.write_sstable_index.get(), 4);
+            assert_eq!(tree.get(&vec![0]).await?, Some(vec![100]));
+            assert_eq!(tree.get(&vec![2]).await?, Some(vec![12]));
+
+            tree.compact(&[0, 2], 3, None).await?;
+            assert_eq!(tree.get(&vec![0]).await?, Some(vec![100]));
+            assert_eq!(tree.get(&vec![2]).await?, Some(vec![12]));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest() -> Result<()> {
+        run_with_glommio(_ingest)
+    }
+
+    /// Write an sstable in the format from before entries had a kind, with
+    /// no header in the index file.
+    fn write_legacy_sstable(
+        dir: &Path,
+        index: usize,
+        entries: &[(Vec<u8>, Vec<u8>)],
+        timestamp: OffsetDateTime,
+    ) -> Result<()> {
+        let mut data_buf = Vec::new();
+        let mut index_buf = Vec::new();
+        for (key, data) in entries {
+            let encoded = bincode_options().serialize(&(
+                key,
+                data,
+                timestamp.unix_timestamp_nanos(),
+            ))?;
+            index_buf.extend(bincode_options().serialize(&EntryOffset {
+                offset: data_buf.len() as u64,
+                key_size: bincode_options().serialized_size(key)? as u32,
+                full_size: encoded.len() as u32,
+            })?);
+            data_buf.extend(encoded);
+        }
+
+        let (data_path, index_path) = get_data_file_paths(dir, index);
+        std::fs::write(data_path, data_buf)?;
+        std::fs::write(index_path, index_buf)?;
+        Ok(())
+    }
+
+    /// Build sstable 0 of 3 entries of the same size, returns the size of an
+    /// entry in the data file.
+    async fn build_same_size_sstable(dir: &Path) -> Result<usize> {
+        let timestamp = OffsetDateTime::now_utc();
+        let mut builder = SSTableBuilder::create(dir, 0, 3)?;
+        for i in 0..3u8 {
+            builder
+                .add(&Entry {
+                    key: vec![i],
+                    value: EntryValue::new(vec![i + 10], Some(timestamp)),
+                })
+                .await?;
+        }
+        builder.finish().await?;
+
+        let data_len =
+            std::fs::metadata(get_file_path(dir, 0, DATA_FILE_EXT))?.len();
+        Ok(data_len as usize / 3)
+    }
+
+    async fn _reject_corrupted_sstable(
+        dir: PathBuf,
+        cache: GlobalCache,
+    ) -> Result<()> {
+        build_same_size_sstable(&dir).await?;
+
+        // The most significant byte of the length of the first key.
+        let data_path = get_file_path(&dir, 0, DATA_FILE_EXT);
+        let mut data = std::fs::read(&data_path)?;
+        data[7] ^= 0xff;
+        std::fs::write(&data_path, data)?;
+
+        let inspected = inspect_sstable(&dir, 0)?;
+        assert_eq!(inspected.corruptions.len(), 1);
+        assert_eq!(inspected.corruptions[0].offset, 0);
+        assert_eq!(inspected.entries.len(), 2);
+
+        // The compaction fails, keeping the corrupted sstable, instead of
+        // writing a compacted sstable without the entry.
+        let tree = test_lsm_tree(dir, partitioned_cache(&cache)).await?;
+        assert!(matches!(
+            tree.compact(&[0], 1, None).await,
+            Err(Error::BincodeSerdeError(_))
+        ));
+        assert_eq!(*tree.sstable_indices_and_sizes(), vec![(0, 3)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_corrupted_sstable() -> Result<()> {
+        run_with_glommio(_reject_corrupted_sstable)
+    }
+
+    async fn _reject_unsorted_sstable(
+        dir: PathBuf,
+        cache: GlobalCache,
+    ) -> Result<()> {
+        let entry_size = build_same_size_sstable(&dir).await?;
+
+        // Swap the first 2 entries, the index still points to valid entries.
+        let data_path = get_file_path(&dir, 0, DATA_FILE_EXT);
+        let mut data = std::fs::read(&data_path)?;
+        let (first, rest) = data.split_at_mut(entry_size);
+        first.swap_with_slice(&mut rest[..entry_size]);
+        std::fs::write(&data_path, data)?;
+
+        let inspected = inspect_sstable(&dir, 0)?;
+        assert_eq!(inspected.corruptions.len(), 1);
+        assert_eq!(inspected.corruptions[0].offset, entry_size as u64);
+        assert_eq!(inspected.entries.len(), 3);
+
+        let tree = test_lsm_tree(dir, partitioned_cache(&cache)).await?;
+        assert!(matches!(
+            tree.compact(&[0], 1, None).await,
+            Err(Error::UnsortedSSTableEntries)
+        ));
+        assert_eq!(*tree.sstable_indices_and_sizes(), vec![(0, 3)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_unsorted_sstable() -> Result<()> {
+        run_with_glommio(_reject_unsorted_sstable)
+    }
+
+    async fn _reject_corrupted_wal_record(
+        dir: PathBuf,
+        cache: GlobalCache,
+    ) -> Result<()> {
+        let entries = vec![Entry {
+            key: vec![0],
+            value: EntryValue::new(vec![10], None),
+        }];
+        let mut record = vec![0; wal_record_size(&entries)?];
+        serialize_wal_record(&entries, &mut record)?;
+
+        // A flipped byte of the key (after the lengths of the entries and of
+        // the key) fails the checksum.
+        let mut corrupted = record.clone();
+        corrupted[WAL_HEADER_SIZE + 16] ^= 0xff;
+        assert!(matches!(
+            deserialize_wal_record(
+                &mut std::io::Cursor::new(&corrupted[..]),
+                &mut false
+            ),
+            Err(Error::WalRecordChecksumMismatch)
+        ));
+
+        // A flipped byte in the magic is not read as a legacy record, after
+        // a record with a header was read.
+        let mut corrupted_magic = record.clone();
+        corrupted_magic[0] ^= 0xff;
+        assert!(matches!(
+            deserialize_wal_record(
+                &mut std::io::Cursor::new(&corrupted_magic[..]),
+                &mut false
+            ),
+            Err(Error::WalRecordMissingHeader)
+        ));
+
+        // The corrupted record is reported, the record after it is read.
+        let mut wal_buf = corrupted;
+        wal_buf.resize(PAGE_SIZE, 0);
+        wal_buf.extend(&record);
+        wal_buf.resize(PAGE_SIZE * 2, 0);
+        let wal_path = get_file_path(&dir, 0, MEMTABLE_FILE_EXT);
+        std::fs::write(&wal_path, wal_buf)?;
+
+        let inspected = inspect_wal(&wal_path)?;
+        assert_eq!(inspected.corruptions.len(), 1);
+        assert_eq!(inspected.corruptions[0].offset, 0);
+        assert_eq!(inspected.entries.len(), 1);
+        assert_eq!(inspected.entries[0].offset, PAGE_SIZE as u64);
+
+        let tree = test_lsm_tree(dir, partitioned_cache(&cache)).await?;
+        assert_eq!(tree.get(&vec![0]).await?, Some(vec![10]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_corrupted_wal_record() -> Result<()> {
+        run_with_glommio(_reject_corrupted_wal_record)
+    }
+
+    async fn _open_legacy_sstable(
+        dir: PathBuf,
+        cache: GlobalCache,
+    ) -> Result<()> {
+        let entries: Vec<(Vec<u8>, Vec<u8>)> =
+            (0..3u8).map(|i| (vec![i], vec![i + 10])).collect();
+        let written_at = OffsetDateTime::now_utc();
+        write_legacy_sstable(&dir, 0, &entries, written_at)?;
+
+        // A delete was written as an empty value.
+        write_legacy_sstable(
+            &dir,
+            2,
+            &[(vec![2], Vec::new())],
+            written_at + Duration::from_secs(1),
+        )?;
+
+        let tree = Rc::new(
+            test_lsm_tree(dir.clone(), partitioned_cache(&cache)).await?,
+        );
+        assert_eq!(*tree.sstable_indices_and_sizes(), vec![(0, 3), (2, 1)]);
+        assert_eq!(
+            tree.sstables.borrow()[0].format_version,
+            LEGACY_SSTABLE_FORMAT_VERSION
+        );
+        assert_eq!(tree.get(&vec![1]).await?, Some(vec![11]));
+        assert!(tree.get_entry(&vec![2]).await?.unwrap().is_delete());
+
+        let mut iter = tree.iter();
+        let mut iterated = Vec::new();
+        while let Some(entry) = iter.next().await? {
+            iterated.push((entry.key, entry.value.kind));
+        }
+        assert_eq!(
+            iterated,
+            vec![
+                (vec![0], EntryKind::Put),
+                (vec![1], EntryKind::Put),
+                (vec![2], EntryKind::Put),
+                (vec![2], EntryKind::Delete),
+            ]
+        );
+
+        // Rewritten in the current format when compacted.
+        tree.clone().set(vec![1], vec![100]).await?;
+        tree.clone().flush().await?;
+        tree.compact(&[0, 2, 4], 5, None).await?;
+        assert_eq!(*tree.sstable_indices_and_sizes(), vec![(5, 3)]);
+        assert_eq!(
+            tree.sstables.borrow()[0].format_version,
+            SSTABLE_FORMAT_VERSION
+        );
+        assert_eq!(tree.get(&vec![0]).await?, Some(vec![10]));
+        assert_eq!(tree.get(&vec![1]).await?, Some(vec![100]));
+        assert!(tree.get_entry(&vec![2]).await?.unwrap().is_delete());
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_legacy_sstable() -> Result<()> {
+        run_with_glommio(_open_legacy_sstable)
+    }
+
     async fn _set_and_get_sstable(
         dir: PathBuf,
         cache: GlobalCache,
@@ -1431,7 +3879,8 @@ mod tests {
                 ]
             );
 
-            tree.compact(&[0, 2, 4], 5, false).await?;
+            tree.compact(&[0, 2, 4], 5, Some(OffsetDateTime::now_utc()))
+                .await?;
             validate_tree_after_compaction(&tree).await?;
         }
 
@@ -1,17 +1,23 @@
-use std::cmp::Ordering;
+use std::{cmp::Ordering, io::Cursor};
 
+use bincode::Options;
 use kinded::Kinded;
+use murmur3::murmur3_32;
 use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
 
-use crate::utils::timestamp_nanos;
+use crate::{
+    error::{Error, Result},
+    utils::{bincode::bincode_options, timestamp_nanos},
+};
 
 pub mod cached_file_reader;
 pub mod entry_writer;
+pub mod inspect;
 pub mod lsm_tree;
 pub mod page_cache;
-
-pub const TOMBSTONE: Vec<u8> = vec![];
+pub mod sketch;
+pub mod sstable_builder;
 
 const DMA_STREAM_NUMBER_OF_BUFFERS: usize = 16;
 
@@ -20,18 +26,49 @@ pub const DEFAULT_SSTABLE_BLOOM_MIN_SIZE: u64 = 1_048_576;
 
 const INDEX_PADDING: usize = 20; // Number of integers in max u64.
 
-const MEMTABLE_FILE_EXT: &str = "memtable";
-const DATA_FILE_EXT: &str = "data";
-const INDEX_FILE_EXT: &str = "index";
-const BLOOM_FILE_EXT: &str = "bloom";
-const COMPACT_DATA_FILE_EXT: &str = "compact_data";
-const COMPACT_INDEX_FILE_EXT: &str = "compact_index";
-const COMPACT_BLOOM_FILE_EXT: &str = "compact_bloom";
-const COMPACT_ACTION_FILE_EXT: &str = "compact_action";
+pub const MEMTABLE_FILE_EXT: &str = "memtable";
+pub const DATA_FILE_EXT: &str = "data";
+pub const INDEX_FILE_EXT: &str = "index";
+pub const BLOOM_FILE_EXT: &str = "bloom";
+pub const RANGE_TOMBSTONES_FILE_EXT: &str = "range_tombstones";
+pub const SKETCH_FILE_EXT: &str = "sketch";
+pub const COMPACT_DATA_FILE_EXT: &str = "compact_data";
+pub const COMPACT_INDEX_FILE_EXT: &str = "compact_index";
+pub const COMPACT_BLOOM_FILE_EXT: &str = "compact_bloom";
+pub const COMPACT_RANGE_TOMBSTONES_FILE_EXT: &str = "compact_range_tombstones";
+pub const COMPACT_SKETCH_FILE_EXT: &str = "compact_sketch";
+pub const COMPACT_ACTION_FILE_EXT: &str = "compact_action";
 
 /// An `EntryOffset` item size ater serialization with bincode.
 const INDEX_ENTRY_SIZE: usize = 16;
 
+/// The format of the entries of an sstable, written in the header of its
+/// index file.
+const SSTABLE_FORMAT_VERSION: u32 = 1;
+
+/// The format of sstables written before entries had a kind, their index
+/// files have no header.
+const LEGACY_SSTABLE_FORMAT_VERSION: u32 = 0;
+
+/// The offset of the `EntryOffset` heading an index file, which tells it
+/// apart from an index without a header, whose first entry is at offset 0.
+const INDEX_HEADER_OFFSET: u64 = u64::MAX;
+
+/// The size of the checksum at the end of a WAL record.
+const WAL_CHECKSUM_SIZE: usize = 4;
+
+/// Starts every WAL record, followed by the format version of the record, so
+/// they are told apart from records written before WAL records had a header
+/// (their first 8 bytes are the length of a key).
+const WAL_RECORD_MAGIC: u64 = u64::MAX;
+
+/// The format of the entries of a WAL record.
+const WAL_FORMAT_VERSION: u32 = 1;
+
+/// The size of the magic and the format version at the start of a WAL
+/// record.
+const WAL_HEADER_SIZE: usize = 12;
+
 #[derive(Kinded)]
 #[kinded(derive(Hash))]
 pub enum FileType {
@@ -49,11 +86,171 @@ struct EntryOffset {
     full_size: u32,
 }
 
+impl EntryOffset {
+    /// The first item of an index file, holding the format version of the
+    /// sstable.
+    fn header() -> Self {
+        Self {
+            offset: INDEX_HEADER_OFFSET,
+            key_size: SSTABLE_FORMAT_VERSION,
+            full_size: 0,
+        }
+    }
+}
+
+/// Read the format version of an sstable from the start of its index file,
+/// returns the version and the size of the header to skip before the index
+/// entries.
+fn read_index_header(index_start: &[u8]) -> Result<(u32, u64)> {
+    if index_start.len() < INDEX_ENTRY_SIZE {
+        return Ok((LEGACY_SSTABLE_FORMAT_VERSION, 0));
+    }
+    let first: EntryOffset =
+        bincode_options().deserialize(&index_start[..INDEX_ENTRY_SIZE])?;
+    if first.offset != INDEX_HEADER_OFFSET {
+        return Ok((LEGACY_SSTABLE_FORMAT_VERSION, 0));
+    }
+    if first.key_size > SSTABLE_FORMAT_VERSION {
+        return Err(Error::UnsupportedSSTableFormatVersion(first.key_size));
+    }
+    Ok((first.key_size, INDEX_ENTRY_SIZE as u64))
+}
+
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq,
+)]
+pub enum EntryKind {
+    /// The full value of the key.
+    #[default]
+    Put,
+
+    /// The key was deleted (the data is empty).
+    Delete,
+
+    /// All keys from the entry's key (inclusive) to the end key in the data
+    /// (exclusive) were deleted, see `RangeTombstone`.
+    RangeDelete,
+
+    /// A merge operand, applied on top of the older value of the key by the
+    /// tree's `MergeOperator`.
+    Merge,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct EntryValue {
     pub data: Vec<u8>,
     #[serde(with = "timestamp_nanos")]
     pub timestamp: OffsetDateTime,
+    pub kind: EntryKind,
+}
+
+/// An entry of an sstable written before entries had a kind.
+#[derive(Deserialize)]
+struct LegacyEntry {
+    key: Vec<u8>,
+    value: LegacyEntryValue,
+}
+
+#[derive(Deserialize)]
+struct LegacyEntryValue {
+    data: Vec<u8>,
+    #[serde(with = "timestamp_nanos")]
+    timestamp: OffsetDateTime,
+}
+
+impl From<LegacyEntryValue> for EntryValue {
+    /// A delete was written as an empty value.
+    fn from(value: LegacyEntryValue) -> Self {
+        let kind = if value.data.is_empty() {
+            EntryKind::Delete
+        } else {
+            EntryKind::Put
+        };
+        Self {
+            data: value.data,
+            timestamp: value.timestamp,
+            kind,
+        }
+    }
+}
+
+impl From<LegacyEntry> for Entry {
+    fn from(entry: LegacyEntry) -> Self {
+        Self {
+            key: entry.key,
+            value: entry.value.into(),
+        }
+    }
+}
+
+/// Deserialize an entry of an sstable in the given format version.
+fn deserialize_entry(format_version: u32, buf: &[u8]) -> Result<Entry> {
+    Ok(if format_version == LEGACY_SSTABLE_FORMAT_VERSION {
+        bincode_options().deserialize::<LegacyEntry>(buf)?.into()
+    } else {
+        bincode_options().deserialize(buf)?
+    })
+}
+
+/// Deserialize the value of an entry of an sstable in the given format
+/// version.
+fn deserialize_entry_value(
+    format_version: u32,
+    buf: &[u8],
+) -> Result<EntryValue> {
+    Ok(if format_version == LEGACY_SSTABLE_FORMAT_VERSION {
+        bincode_options()
+            .deserialize::<LegacyEntryValue>(buf)?
+            .into()
+    } else {
+        bincode_options().deserialize(buf)?
+    })
+}
+
+/// Merges two values of the same key into one, must be commutative,
+/// associative and idempotent, as values are merged in any order (on write
+/// to the memtable, on read and in compaction).
+pub type MergeFn = fn(&EntryValue, &EntryValue) -> Result<EntryValue>;
+
+/// Merge two values of a key with a tree's merge function, unless one of
+/// them is a delete, which has no data to merge: the newer one wins (the
+/// delete on a tie), so a delete replaces the merged state, and a write
+/// after it starts a new one.
+pub fn merge_values(
+    merge_fn: MergeFn,
+    a: &EntryValue,
+    b: &EntryValue,
+) -> Result<EntryValue> {
+    if !a.is_delete() && !b.is_delete() {
+        return merge_fn(a, b);
+    }
+    let newer = match a.timestamp.cmp(&b.timestamp) {
+        Ordering::Greater => a,
+        Ordering::Less => b,
+        Ordering::Equal if a.is_delete() => a,
+        Ordering::Equal => b,
+    };
+    Ok(newer.clone())
+}
+
+/// Applies merge operands (deltas, like an append or an increment) written
+/// with `LSMTree::merge()`, so a write doesn't have to read the old value.
+/// Operands are folded lazily on reads, and collapsed in compaction.
+/// Storage engine only: the server opens the trees of collections without a
+/// merge operator, so merge operands can't be written through its API.
+pub trait MergeOperator {
+    /// Apply an operand on top of the value of a key, None when the key
+    /// doesn't exist or was deleted.
+    fn full_merge(
+        &self,
+        existing: Option<&[u8]>,
+        operand: &[u8],
+    ) -> Result<Vec<u8>>;
+
+    /// Combine two consecutive operands into a single operand, equivalent to
+    /// applying the older one and then the newer one, used when the value
+    /// under them is not known yet.
+    fn partial_merge(&self, older: &[u8], newer: &[u8]) -> Result<Vec<u8>>;
 }
 
 impl EntryValue {
@@ -61,6 +258,68 @@ impl EntryValue {
         Self {
             data,
             timestamp: timestamp.unwrap_or_else(OffsetDateTime::now_utc),
+            kind: EntryKind::Put,
+        }
+    }
+
+    fn tombstone(timestamp: Option<OffsetDateTime>) -> Self {
+        Self {
+            kind: EntryKind::Delete,
+            ..Self::new(Vec::new(), timestamp)
+        }
+    }
+
+    #[must_use]
+    pub fn is_delete(&self) -> bool {
+        self.kind == EntryKind::Delete
+    }
+
+    /// The data of the value, None when the key was deleted.
+    #[must_use]
+    pub fn as_data(&self) -> Option<&[u8]> {
+        (!self.is_delete()).then_some(&self.data[..])
+    }
+
+    #[must_use]
+    pub fn into_data(self) -> Option<Vec<u8>> {
+        (!self.is_delete()).then_some(self.data)
+    }
+}
+
+/// A delete of all keys in [start, end), of values written up to the
+/// timestamp.
+/// Kept apart from the entries of keys (in memory, and in a file next to
+/// each sstable), as any key in the range could be looked up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RangeTombstone {
+    pub start: Vec<u8>,
+    pub end: Vec<u8>,
+    #[serde(with = "timestamp_nanos")]
+    pub timestamp: OffsetDateTime,
+}
+
+impl RangeTombstone {
+    #[must_use]
+    pub fn covers(&self, key: &[u8]) -> bool {
+        self.start.as_slice() <= key && key < self.end.as_slice()
+    }
+
+    fn from_entry(entry: Entry) -> Self {
+        Self {
+            start: entry.key,
+            end: entry.value.data,
+            timestamp: entry.value.timestamp,
+        }
+    }
+
+    fn into_entry(self) -> Entry {
+        Entry {
+            key: self.start,
+            value: EntryValue {
+                data: self.end,
+                timestamp: self.timestamp,
+                kind: EntryKind::RangeDelete,
+            },
         }
     }
 }
@@ -93,3 +352,72 @@ impl PartialEq for Entry {
 }
 
 impl Eq for Entry {}
+
+/// The size of a WAL record holding the entries (before padding).
+pub fn wal_record_size(entries: &[Entry]) -> Result<usize> {
+    Ok(WAL_HEADER_SIZE
+        + bincode_options().serialized_size(entries)? as usize
+        + WAL_CHECKSUM_SIZE)
+}
+
+/// Serialize the entries of a single write into a WAL record: a header (the
+/// WAL magic and format version), the entries, and a checksum of both.
+/// The buffer must be at least `wal_record_size()` long.
+pub fn serialize_wal_record(entries: &[Entry], buf: &mut [u8]) -> Result<()> {
+    bincode_options().serialize_into(&mut buf[..8], &WAL_RECORD_MAGIC)?;
+    bincode_options()
+        .serialize_into(&mut buf[8..WAL_HEADER_SIZE], &WAL_FORMAT_VERSION)?;
+
+    let end =
+        WAL_HEADER_SIZE + bincode_options().serialized_size(entries)? as usize;
+    bincode_options()
+        .serialize_into(&mut buf[WAL_HEADER_SIZE..end], entries)?;
+
+    let checksum = murmur3_32(&mut Cursor::new(&buf[..end]), 0)?.to_le_bytes();
+    buf[end..end + WAL_CHECKSUM_SIZE].copy_from_slice(&checksum);
+
+    Ok(())
+}
+
+/// Deserialize a WAL record written by `serialize_wal_record()`, fails when
+/// the record was only partially written, so a write of multiple entries is
+/// replayed all or nothing.
+/// A record without the WAL magic is read as a record from before records
+/// had a header (a single entry, with no checksum), as long as
+/// `allow_legacy` is set. It's cleared once a record with a header is read,
+/// as legacy records can only come before them in a WAL file.
+pub fn deserialize_wal_record(
+    cursor: &mut Cursor<&[u8]>,
+    allow_legacy: &mut bool,
+) -> Result<Vec<Entry>> {
+    let start = cursor.position();
+    let magic: u64 = bincode_options().deserialize_from(&mut *cursor)?;
+    if magic != WAL_RECORD_MAGIC {
+        if !*allow_legacy {
+            return Err(Error::WalRecordMissingHeader);
+        }
+        cursor.set_position(start);
+        let entry: LegacyEntry =
+            bincode_options().deserialize_from(&mut *cursor)?;
+        return Ok(vec![entry.into()]);
+    }
+
+    let version: u32 = bincode_options().deserialize_from(&mut *cursor)?;
+    if version > WAL_FORMAT_VERSION {
+        return Err(Error::UnsupportedWalFormatVersion(version));
+    }
+
+    let entries: Vec<Entry> =
+        bincode_options().deserialize_from(&mut *cursor)?;
+    let end = cursor.position() as usize;
+    let checksum: u32 = bincode_options().deserialize_from(&mut *cursor)?;
+
+    if murmur3_32(&mut Cursor::new(&cursor.get_ref()[start as usize..end]), 0)?
+        != checksum
+    {
+        return Err(Error::WalRecordChecksumMismatch);
+    }
+
+    *allow_legacy = false;
+    Ok(entries)
+}
@@ -1,6 +1,6 @@
 use core::hash::Hash;
 use std::{
-    cell::{RefCell, RefMut},
+    cell::{Cell, RefCell, RefMut},
     rc::Rc,
 };
 
@@ -27,11 +27,22 @@ pub fn align_down(address: u64) -> u64 {
 pub struct PartitionPageCache<K: Hash + Eq> {
     name_hash: u32,
     cache: Rc<RefCell<PageCache<K>>>,
+
+    /// Number of lookups of the partition that were found in the cache.
+    hits: Cell<u64>,
+
+    /// Number of lookups of the partition that were not found in the cache.
+    misses: Cell<u64>,
 }
 
 impl<K: Hash + Eq> PartitionPageCache<K> {
     pub fn new(name_hash: u32, cache: Rc<RefCell<PageCache<K>>>) -> Self {
-        Self { name_hash, cache }
+        Self {
+            name_hash,
+            cache,
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+        }
     }
 
     pub fn new_named(
@@ -54,6 +65,21 @@ impl<K: Hash + Eq> PartitionPageCache<K> {
             .copied()
     }
 
+    pub fn record_lookup(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.set(counter.get() + 1);
+    }
+
+    #[must_use]
+    pub fn hits(&self) -> u64 {
+        self.hits.get()
+    }
+
+    #[must_use]
+    pub fn misses(&self) -> u64 {
+        self.misses.get()
+    }
+
     #[must_use]
     pub fn borrow_mut(&self) -> RefMut<PageCache<K>> {
         self.cache.borrow_mut()
@@ -0,0 +1,139 @@
+//! A [HyperLogLog](http://algo.inria.fr/flajolet/Publications/FlFuGaMe07.pdf)
+//! sketch of the keys of an sstable, estimates the number of distinct keys
+//! across sstables by merging their sketches, so the number of live keys in a
+//! tree can be estimated without reading any sstable.
+
+use std::io::Cursor;
+
+use murmur3::murmur3_x64_128;
+use serde::{Deserialize, Serialize};
+
+use super::EntryValue;
+use crate::error::Result;
+
+/// Number of bits of the hash used to choose a register, 2^12 registers give
+/// a standard error of about 1.6%.
+const PRECISION: u32 = 12;
+const REGISTERS: usize = 1 << PRECISION;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HyperLogLog {
+    /// The max rank (position of the first set bit) seen in each register.
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self {
+            registers: vec![0; REGISTERS],
+        }
+    }
+}
+
+impl HyperLogLog {
+    pub fn insert(&mut self, item: &[u8]) -> Result<()> {
+        // Only the lower 64 bits, plenty for the number of keys of a tree.
+        let hash = murmur3_x64_128(&mut Cursor::new(item), 0)? as u64;
+        let index = (hash >> (64 - PRECISION)) as usize;
+
+        // The set bit limits the rank when the rest of the hash is zeroes.
+        let rest = (hash << PRECISION) | (1 << (PRECISION - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+
+        self.registers[index] = self.registers[index].max(rank);
+        Ok(())
+    }
+
+    /// Merge the items of another sketch into this one.
+    pub fn merge(&mut self, other: &Self) {
+        for (register, other) in self.registers.iter_mut().zip(&other.registers)
+        {
+            *register = (*register).max(*other);
+        }
+    }
+
+    /// The estimated number of distinct items inserted.
+    #[must_use]
+    pub fn estimate(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|&rank| 2f64.powi(-i32::from(rank)))
+            .sum();
+        let raw = alpha * m * m / sum;
+
+        // Small cardinalities are estimated better by linear counting.
+        let zeroes = self.registers.iter().filter(|&&rank| rank == 0).count();
+        let estimate = if raw <= 2.5 * m && zeroes > 0 {
+            m * (m / zeroes as f64).ln()
+        } else {
+            raw
+        };
+
+        estimate.round() as u64
+    }
+}
+
+/// Saved next to an sstable, see `LSMTree::approximate_live_keys()`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KeysSketch {
+    pub keys: HyperLogLog,
+
+    /// The keys of the tombstones in the sstable, a key deleted again in
+    /// other sstables is counted once.
+    pub tombstones: HyperLogLog,
+}
+
+impl KeysSketch {
+    pub fn add(&mut self, key: &[u8], value: &EntryValue) -> Result<()> {
+        if value.is_delete() {
+            self.tombstones.insert(key)?;
+        }
+        self.keys.insert(key)
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.keys.merge(&other.keys);
+        self.tombstones.merge(&other.tombstones);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(estimate: u64, expected: u64) {
+        let error = estimate.abs_diff(expected) as f64 / expected as f64;
+        assert!(error < 0.05, "estimate {estimate}, expected {expected}");
+    }
+
+    #[test]
+    fn estimate_distinct_items() -> Result<()> {
+        let mut hll = HyperLogLog::default();
+        assert_eq!(hll.estimate(), 0);
+
+        for expected in [100u64, 10_000, 200_000] {
+            for i in 0..expected {
+                hll.insert(&i.to_le_bytes())?;
+            }
+            assert_close(hll.estimate(), expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn merge_counts_shared_items_once() -> Result<()> {
+        let (mut a, mut b) = (HyperLogLog::default(), HyperLogLog::default());
+        for i in 0..20_000u64 {
+            a.insert(&i.to_le_bytes())?;
+        }
+        for i in 10_000..30_000u64 {
+            b.insert(&i.to_le_bytes())?;
+        }
+        a.merge(&b);
+        assert_close(a.estimate(), 30_000);
+        Ok(())
+    }
+}
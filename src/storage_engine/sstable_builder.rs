@@ -0,0 +1,116 @@
+//! Writes an sstable outside of a tree (no glommio executor required), to be
+//! linked into a tree later with `LSMTree::ingest()`.
+//! Used to bulk load data, instead of writing it key by key.
+
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use bincode::Options;
+use bloomfilter::Bloom;
+use futures::io::AllowStdIo;
+
+use super::{
+    entry_writer::EntryWriter,
+    lsm_tree::{get_data_file_paths, get_file_path, BLOOM_MAX_ALLOWED_ERROR},
+    page_cache::{PageCache, PartitionPageCache},
+    sketch::KeysSketch,
+    Entry, BLOOM_FILE_EXT, SKETCH_FILE_EXT,
+};
+use crate::{
+    error::{Error, Result},
+    utils::bincode::bincode_options,
+};
+
+/// The entry writer fills a page cache with the pages it writes, they are
+/// never read here, so the cache is kept small.
+const PAGE_CACHE_CAPACITY: usize = 64;
+
+pub struct SSTableBuilder {
+    dir: PathBuf,
+    index: usize,
+    entry_writer: EntryWriter,
+    bloom: Bloom<Vec<u8>>,
+    sketch: KeysSketch,
+    last_key: Option<Vec<u8>>,
+    items_written: usize,
+}
+
+impl SSTableBuilder {
+    /// Create the files of the sstable at `index` in `dir`, the bloom filter
+    /// is sized for the number of entries expected to be added.
+    pub fn create(
+        dir: &Path,
+        index: usize,
+        expected_items: usize,
+    ) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+
+        let (data_path, index_path) = get_data_file_paths(dir, index);
+        let data_writer =
+            Box::new(AllowStdIo::new(BufWriter::new(File::create(data_path)?)));
+        let index_writer = Box::new(AllowStdIo::new(BufWriter::new(
+            File::create(index_path)?,
+        )));
+
+        let cache = Rc::new(RefCell::new(PageCache::new(
+            PAGE_CACHE_CAPACITY,
+            PAGE_CACHE_CAPACITY,
+        )));
+        let page_cache = Rc::new(PartitionPageCache::new(0, cache));
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            index,
+            entry_writer: EntryWriter::new(
+                data_writer,
+                index_writer,
+                index,
+                page_cache,
+            ),
+            bloom: Bloom::new_for_fp_rate(
+                expected_items.max(1),
+                BLOOM_MAX_ALLOWED_ERROR,
+            ),
+            sketch: KeysSketch::default(),
+            last_key: None,
+            items_written: 0,
+        })
+    }
+
+    /// Entries must be added sorted by key, with a single entry per key.
+    pub async fn add(&mut self, entry: &Entry) -> Result<()> {
+        if matches!(&self.last_key, Some(last_key) if &entry.key <= last_key) {
+            return Err(Error::UnsortedSSTableEntries);
+        }
+
+        self.entry_writer.write(entry).await?;
+        self.bloom.set(&entry.key);
+        self.sketch.add(&entry.key, &entry.value)?;
+
+        self.last_key = Some(entry.key.clone());
+        self.items_written += 1;
+        Ok(())
+    }
+
+    /// Close the sstable and write its bloom filter and keys sketch, returns
+    /// the number of entries written.
+    pub async fn finish(mut self) -> Result<usize> {
+        self.entry_writer.close().await?;
+
+        std::fs::write(
+            get_file_path(&self.dir, self.index, BLOOM_FILE_EXT),
+            bincode_options().serialize(&self.bloom)?,
+        )?;
+        std::fs::write(
+            get_file_path(&self.dir, self.index, SKETCH_FILE_EXT),
+            bincode_options().serialize(&self.sketch)?,
+        )?;
+
+        Ok(self.items_written)
+    }
+}
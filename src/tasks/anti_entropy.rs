@@ -0,0 +1,95 @@
+use std::{rc::Rc, time::Duration};
+
+use glommio::{
+    executor, spawn_local_into, timer::sleep, Latency, Shares, Task,
+};
+use log::error;
+use rand::{seq::IteratorRandom, thread_rng};
+
+use crate::{
+    error::Result, remote_shard_connection::RemoteShardConnection,
+    shards::MyShard,
+};
+
+/// Push our cluster metadata to a random node, and pull its cluster metadata
+/// back, both sides reconcile the differences, so gossip events that were
+/// lost on the way eventually converge.
+async fn run_anti_entropy(my_shard: Rc<MyShard>) -> Result<()> {
+    let interval = Duration::from_millis(my_shard.args.anti_entropy_interval);
+
+    loop {
+        sleep(interval).await;
+
+        let mut rng = thread_rng();
+        let address = if let Some(address) = my_shard
+            .nodes
+            .borrow()
+            .values()
+            .filter(|node| !node.ids.is_empty())
+            .choose(&mut rng)
+            .map(|node| {
+                format!(
+                    "{}:{}",
+                    node.ip,
+                    node.ids
+                        .iter()
+                        .map(|id| node.remote_shard_base_port + id)
+                        .choose(&mut rng)
+                        .unwrap()
+                )
+            }) {
+            address
+        } else {
+            continue;
+        };
+
+        let connection = RemoteShardConnection::from_args(
+            address,
+            &my_shard.args,
+            my_shard.tls.as_ref(),
+        );
+
+        // Failing to reach a node is the failure detector's business.
+        let metadata = match connection
+            .sync_metadata(my_shard.get_cluster_metadata())
+            .await
+        {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                error!(
+                    "Failed to sync cluster metadata with '{}': {}",
+                    connection.address, e
+                );
+                continue;
+            }
+        };
+
+        if let Err(e) =
+            my_shard.clone().reconcile_cluster_metadata(metadata).await
+        {
+            error!(
+                "Failed to reconcile cluster metadata of '{}': {}",
+                connection.address, e
+            );
+        }
+    }
+}
+
+pub fn spawn_anti_entropy_task(my_shard: Rc<MyShard>) -> Task<Result<()>> {
+    let shares = my_shard.args.background_tasks_shares.into();
+    spawn_local_into(
+        async move {
+            let result = run_anti_entropy(my_shard).await;
+            if let Err(e) = &result {
+                error!("Error starting anti entropy: {}", e);
+            }
+            result
+        },
+        executor().create_task_queue(
+            Shares::Static(shares),
+            Latency::NotImportant,
+            "anti-entropy",
+        ),
+    )
+    .unwrap()
+}
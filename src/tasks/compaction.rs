@@ -4,6 +4,7 @@ use futures::future::{join_all, select, select_all, Either};
 use glommio::{executor, spawn_local_into, Latency, Shares, Task};
 use itertools::Itertools;
 use log::error;
+use time::OffsetDateTime;
 
 use crate::{
     error::Result, shards::MyShard, storage_engine::lsm_tree::LSMTree,
@@ -12,35 +13,84 @@ use crate::{
 
 const MIN_COMPACTION_FACTOR: usize = 2;
 
+/// What a compacted tree stores.
+enum TreeKind {
+    Collection(String),
+    ChangeLog,
+    Paxos,
+}
+
+/// The trees of the collections by name, their change logs, and the paxos
+/// tree.
 async fn get_trees_and_listeners(
     my_shard: &MyShard,
-) -> (Vec<Rc<LSMTree>>, Vec<LocalEventListener>) {
+) -> (Vec<(TreeKind, Rc<LSMTree>)>, Vec<LocalEventListener>) {
     while my_shard.collections.borrow().is_empty() {
         my_shard.collections_change_event.listen().await;
     }
 
-    let trees = my_shard
-        .collections
-        .borrow()
-        .values()
-        .map(|c| c.tree.clone())
-        .collect::<Vec<_>>();
+    let mut trees = Vec::new();
+    for (name, collection) in my_shard.collections.borrow().iter() {
+        trees.push((
+            TreeKind::Collection(name.clone()),
+            collection.tree.clone(),
+        ));
+        if let Some(tree) = collection.changes.tree() {
+            trees.push((TreeKind::ChangeLog, tree));
+        }
+    }
+    if let Some(tree) = my_shard.get_open_paxos_tree() {
+        trees.push((TreeKind::Paxos, tree));
+    }
     let listeners = trees
         .iter()
-        .map(|tree| tree.get_flush_event_listener())
+        .map(|(_, tree)| tree.get_flush_event_listener())
         .collect::<Vec<_>>();
     (trees, listeners)
 }
 
-async fn compact_tree(tree: Rc<LSMTree>, compaction_factor: usize) {
-    let indices_and_sizes = tree.sstable_indices_and_sizes();
+/// Looked up on every compaction, as the grace period of a collection can be
+/// altered.
+/// The tombstones of a change log are never replicated, and the tombstones
+/// of the paxos tree only delete state, which expires anyway.
+fn gc_before(my_shard: &MyShard, kind: &TreeKind) -> Option<OffsetDateTime> {
+    match kind {
+        TreeKind::Collection(name) => my_shard
+            .collections
+            .borrow()
+            .get(name)
+            .and_then(|c| c.metadata.gc_before()),
+        TreeKind::ChangeLog => Some(OffsetDateTime::now_utc()),
+        TreeKind::Paxos => Some(my_shard.paxos_expired_before()),
+    }
+}
 
-    let mut index_to_compact = indices_and_sizes
+/// Compaction outputs are written to odd indices, flushes to even indices.
+fn next_compaction_index(indices_and_sizes: &[(usize, u64)]) -> usize {
+    indices_and_sizes
         .iter()
         .map(|(i, _)| *i)
         .filter(|i| i % 2 != 0)
         .max()
-        .map_or(1, |i| i + 2);
+        .map_or(1, |i| i + 2)
+}
+
+async fn compact_tree(
+    tree: Rc<LSMTree>,
+    compaction_factor: usize,
+    gc_before: Option<OffsetDateTime>,
+) {
+    let _permit = match tree.acquire_compaction_permit().await {
+        Ok(permit) => permit,
+        Err(e) => {
+            error!("Failed to acquire compaction permit: {}", e);
+            return;
+        }
+    };
+
+    let indices_and_sizes = tree.sstable_indices_and_sizes();
+
+    let mut index_to_compact = next_compaction_index(&indices_and_sizes);
 
     let mut groups = indices_and_sizes
         .into_iter()
@@ -79,7 +129,7 @@ async fn compact_tree(tree: Rc<LSMTree>, compaction_factor: usize) {
             .extend(items);
     }
 
-    for (i, items) in optimized_groups.into_values().enumerate() {
+    for items in optimized_groups.into_values() {
         if items.len() < MIN_COMPACTION_FACTOR
             || items.len() < compaction_factor
         {
@@ -88,12 +138,10 @@ async fn compact_tree(tree: Rc<LSMTree>, compaction_factor: usize) {
 
         let indices = items.into_iter().map(|(i, _)| i).collect::<Vec<_>>();
 
-        // To avoid data resurrection, remove tombstones only on the final level.
-        let keep_tombstones = i > 0;
-
-        if let Err(e) = tree
-            .compact(&indices, index_to_compact, keep_tombstones)
-            .await
+        // The tree keeps tombstones that may still delete values in sstables
+        // not being compacted, to avoid data resurrection.
+        if let Err(e) =
+            tree.compact(&indices, index_to_compact, gc_before).await
         {
             error!("Failed to compact files: {}", e);
         }
@@ -101,6 +149,34 @@ async fn compact_tree(tree: Rc<LSMTree>, compaction_factor: usize) {
     }
 }
 
+/// Compact all sstables of a tree into a single sstable, regardless of the
+/// compaction factor.
+/// A single sstable is rewritten too, dropping its purgeable tombstones and
+/// truncated values.
+pub async fn compact_tree_fully(
+    tree: &LSMTree,
+    gc_before: Option<OffsetDateTime>,
+) -> Result<()> {
+    let _permit = tree.acquire_compaction_permit().await?;
+
+    let indices_and_sizes = tree.sstable_indices_and_sizes();
+    if indices_and_sizes.is_empty() {
+        return Ok(());
+    }
+
+    let indices = indices_and_sizes
+        .iter()
+        .map(|(i, _)| *i)
+        .collect::<Vec<_>>();
+
+    tree.compact(
+        &indices,
+        next_compaction_index(&indices_and_sizes),
+        gc_before,
+    )
+    .await
+}
+
 async fn run_compaction_loop(my_shard: Rc<MyShard>) {
     let compaction_factor = my_shard.args.compaction_factor;
     if compaction_factor < MIN_COMPACTION_FACTOR {
@@ -111,10 +187,13 @@ async fn run_compaction_loop(my_shard: Rc<MyShard>) {
 
     // Try to compact once, in case we return from a crash and want to compact
     // whatever files are currently saved.
-    let futures = trees
-        .iter()
-        .cloned()
-        .map(|tree| compact_tree(tree, compaction_factor));
+    let futures = trees.iter().map(|(kind, tree)| {
+        compact_tree(
+            tree.clone(),
+            compaction_factor,
+            gc_before(&my_shard, kind),
+        )
+    });
     join_all(futures).await;
 
     loop {
@@ -128,9 +207,18 @@ async fn run_compaction_loop(my_shard: Rc<MyShard>) {
                 (trees, listeners) = get_trees_and_listeners(&my_shard).await;
             }
             Either::Right((((), i, _), _)) => {
-                let tree = &trees[i];
+                let (kind, tree) = &trees[i];
                 listeners[i] = tree.get_flush_event_listener();
-                compact_tree(tree.clone(), compaction_factor).await;
+                if matches!(kind, TreeKind::Paxos) {
+                    // Drop the paxos state that expired since the last read.
+                    tree.truncate(my_shard.paxos_expired_before());
+                }
+                compact_tree(
+                    tree.clone(),
+                    compaction_factor,
+                    gc_before(&my_shard, kind),
+                )
+                .await;
             }
         };
     }
@@ -1,5 +1,11 @@
-use std::{cmp::min, rc::Rc, time::Duration};
+use std::{
+    cmp::min,
+    collections::HashMap,
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
+use async_channel::Receiver;
 use futures::{
     future::try_join, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
 };
@@ -10,7 +16,7 @@ use glommio::{
 use log::{error, trace};
 use rmp_serde::Serializer;
 use rmpv::{
-    decode::read_value_ref,
+    decode::{read_value, read_value_ref},
     encode::{write_value, write_value_ref},
     Value, ValueRef,
 };
@@ -19,17 +25,36 @@ use stupid_from_num::FromNum;
 use time::OffsetDateTime;
 
 use crate::{
+    auth::{check_permission, Permission, User, ALL_COLLECTIONS},
+    cdc::{Change, WatchEvent, WatchTarget},
+    crdt,
     error::{Error, Result},
     gossip::GossipEvent,
     messages::{ShardRequest, ShardResponse},
+    paxos::compare_and_set,
     response_to_empty_result, response_to_result,
-    shards::{hash_bytes, MyShard},
-    storage_engine::TOMBSTONE,
+    shards::{
+        hash_bytes, is_between, CollectionMetadata, ConflictResolution,
+        MyShard, DEFAULT_GC_GRACE_SECONDS,
+    },
+    siblings::{decode_context, VersionVector},
+    storage_engine::{merge_values, EntryValue},
+    tls,
+    transaction::{Condition, Operation},
     utils::timeout::timeout,
 };
 
 const DEFAULT_SET_TIMEOUT_MS: u64 = 15000;
 const DEFAULT_GET_TIMEOUT_MS: u64 = 15000;
+const DEFAULT_SCAN_LIMIT: u64 = 100;
+const MAX_SCAN_LIMIT: u64 = 10000;
+const SUBSCRIBE_BATCH_SIZE: usize = 1000;
+const DUMP_BATCH_SIZE: usize = 1000;
+
+/// When there is nothing to send on a long-lived connection (subscribe /
+/// watch), an empty batch is sent after this interval, so closed connections
+/// are detected and the client doesn't time out.
+const STREAM_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Serialize, Deserialize)]
 pub struct ResponseError {
@@ -95,6 +120,17 @@ fn extract_field_as_u16(map: &Value, field_name: &str) -> Result<u16> {
     }
 }
 
+/// The replication factor of a collection in a request, or the default
+/// when missing, a collection must have at least a single replica.
+fn extract_replication_factor(map: &Value, default: u16) -> Result<u16> {
+    let replication_factor =
+        extract_field_as_u16(map, "replication_factor").unwrap_or(default);
+    if replication_factor == 0 {
+        return Err(Error::InvalidReplicationFactor(replication_factor));
+    }
+    Ok(replication_factor)
+}
+
 fn extract_field_encoded(map: &Value, field_name: &str) -> Result<Vec<u8>> {
     let field = extract_field(map, field_name)?;
 
@@ -104,6 +140,32 @@ fn extract_field_encoded(map: &Value, field_name: &str) -> Result<Vec<u8>> {
     Ok(field_encoded)
 }
 
+/// Extract an optional map of collection name to permission.
+fn extract_field_as_permissions(
+    map: &Value,
+    field_name: &str,
+) -> Result<HashMap<String, Permission>> {
+    let field = &map[field_name];
+    if field.is_nil() {
+        return Ok(HashMap::new());
+    }
+
+    field
+        .as_map()
+        .ok_or_else(|| Error::BadFieldType(field_name.to_string()))?
+        .iter()
+        .map(|(collection, permission)| {
+            match (collection.as_str(), permission.as_str()) {
+                (Some(collection), Some(permission)) => Ok((
+                    collection.to_string(),
+                    Permission::try_from(permission)?,
+                )),
+                _ => Err(Error::BadFieldType(field_name.to_string())),
+            }
+        })
+        .collect()
+}
+
 /// Extract a field named "key", returns an error if the current shard doesn't own the key.
 fn extract_key(
     my_shard: &MyShard,
@@ -126,13 +188,671 @@ fn extract_key(
     Ok(key)
 }
 
+/// Extract an operation on a key from a map of "op" (set / delete / check),
+/// "value" and an optional "condition" (exists / not_exists / equals,
+/// compared to "expected").
+fn extract_operation(map: &Value, key: Vec<u8>) -> Result<Operation> {
+    let condition = match &map["condition"] {
+        Value::Nil => None,
+        condition => Some(Condition::parse(
+            condition
+                .as_str()
+                .ok_or_else(|| Error::BadFieldType("condition".to_string()))?,
+            extract_field_encoded(map, "expected").ok(),
+        )?),
+    };
+
+    Operation::parse(
+        &extract_field_as_str(map, "op")?,
+        key,
+        extract_field_encoded(map, "value").ok(),
+        condition,
+    )
+}
+
+/// Extract the "operations" of a transaction, an array of operations (see
+/// `extract_operation`) with a "key".
+/// Returns an error if the current shard doesn't own all the keys, the
+/// transaction is routed by the first key, so the client should only update
+/// its cluster metadata when the first key is not owned.
+fn extract_operations(
+    my_shard: &MyShard,
+    map: &Value,
+    replica_index: usize,
+) -> Result<Vec<Operation>> {
+    extract_field(map, "operations")?
+        .as_array()
+        .ok_or_else(|| Error::BadFieldType("operations".to_string()))?
+        .iter()
+        .enumerate()
+        .map(|(i, operation)| {
+            let key = extract_field_encoded(operation, "key")?;
+            if !my_shard.owns_key(hash_bytes(&key)?, replica_index)? {
+                return Err(if i == 0 {
+                    Error::KeyNotOwnedByShard
+                } else {
+                    Error::TransactionSpansShards
+                });
+            }
+            extract_operation(operation, key)
+        })
+        .collect()
+}
+
+/// Extract the optional causal "context" of a write to a collection with
+/// siblings (as returned from a get).
+fn extract_context(map: &Value) -> Result<Option<VersionVector>> {
+    match &map["context"] {
+        Value::Nil => Ok(None),
+        Value::Binary(context) => Ok(Some(decode_context(context)?)),
+        _ => Err(Error::BadFieldType("context".to_string())),
+    }
+}
+
+/// Extract the operation of an "increment" / "add" / "remove" request on a
+/// collection of CRDT values, "amount" is optional (defaults to 1), on sets
+/// the element is the "value", on maps a "field" is required.
+fn extract_crdt_operation(
+    map: &Value,
+    request: &str,
+    conflict_resolution: ConflictResolution,
+) -> Result<crdt::Operation> {
+    Ok(match (request, conflict_resolution) {
+        ("increment", _) => crdt::Operation::Increment(match &map["amount"] {
+            Value::Nil => 1,
+            amount => amount
+                .as_i64()
+                .ok_or_else(|| Error::BadFieldType("amount".to_string()))?,
+        }),
+        ("add", ConflictResolution::Map) => crdt::Operation::Put(
+            extract_field_encoded(map, "field")?,
+            extract_field_encoded(map, "value")?,
+        ),
+        ("add", _) => {
+            crdt::Operation::Add(extract_field_encoded(map, "value")?)
+        }
+        ("remove", ConflictResolution::Map) => {
+            crdt::Operation::RemoveField(extract_field_encoded(map, "field")?)
+        }
+        _ => crdt::Operation::Remove(extract_field_encoded(map, "value")?),
+    })
+}
+
+/// Run a transaction on the current shard, and only when all its conditions
+/// pass, replicate its writes.
+async fn transaction(
+    my_shard: Rc<MyShard>,
+    collection_name: String,
+    operations: Vec<Operation>,
+    replica_index: u16,
+    write_consistency: u16,
+    timestamp: OffsetDateTime,
+) -> Result<()> {
+    let collection = my_shard.get_collection(&collection_name)?;
+    let replications = collection.metadata.replication_factor;
+
+    let items = my_shard
+        .run_transaction(&collection_name, &collection, operations, timestamp)
+        .await?;
+
+    if replications > 1 && !items.is_empty() {
+        my_shard
+            .send_request_to_replicas(
+                ShardRequest::Transaction(collection_name, items, timestamp),
+                write_consistency as usize - 1,
+                (replications - replica_index) as usize - 1,
+                |res| {
+                    response_to_empty_result!(res, ShardResponse::Transaction)
+                },
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Write to a key of a collection with siblings on the current shard, and
+/// replicate the resulting value of the key (None deletes the siblings in
+/// the context).
+async fn write_siblings(
+    my_shard: Rc<MyShard>,
+    collection_name: String,
+    key: Vec<u8>,
+    value: Option<Vec<u8>>,
+    context: Option<VersionVector>,
+    replica_index: u16,
+    write_consistency: u16,
+) -> Result<()> {
+    let collection = my_shard.get_collection(&collection_name)?;
+    let timestamp = my_shard.clock.now();
+
+    let data = my_shard
+        .write_siblings(
+            &collection_name,
+            &collection,
+            key.clone(),
+            value,
+            context,
+            timestamp,
+        )
+        .await?;
+
+    replicate_full_value(
+        my_shard,
+        collection_name,
+        key,
+        data,
+        timestamp,
+        replica_index,
+        write_consistency,
+    )
+    .await
+}
+
+/// Apply an operation to the CRDT value of a key on the current shard, and
+/// replicate the resulting value of the key.
+async fn update_crdt(
+    my_shard: Rc<MyShard>,
+    collection_name: String,
+    key: Vec<u8>,
+    operation: crdt::Operation,
+    replica_index: u16,
+    write_consistency: u16,
+) -> Result<()> {
+    let collection = my_shard.get_collection(&collection_name)?;
+    let timestamp = my_shard.clock.now();
+
+    let data = my_shard
+        .update_crdt(
+            &collection_name,
+            &collection,
+            key.clone(),
+            operation,
+            timestamp,
+        )
+        .await?;
+
+    replicate_full_value(
+        my_shard,
+        collection_name,
+        key,
+        data,
+        timestamp,
+        replica_index,
+        write_consistency,
+    )
+    .await
+}
+
+/// Replicate the full value of a key, merged into the value of each replica
+/// by the merge function of the collection.
+async fn replicate_full_value(
+    my_shard: Rc<MyShard>,
+    collection_name: String,
+    key: Vec<u8>,
+    data: Vec<u8>,
+    timestamp: OffsetDateTime,
+    replica_index: u16,
+    write_consistency: u16,
+) -> Result<()> {
+    let replications = my_shard
+        .get_collection(&collection_name)?
+        .metadata
+        .replication_factor;
+
+    if replications > 1 {
+        my_shard
+            .send_request_to_replicas(
+                ShardRequest::Set(collection_name, key, data, timestamp),
+                write_consistency as usize - 1,
+                (replications - replica_index) as usize - 1,
+                |res| response_to_empty_result!(res, ShardResponse::Set),
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Resolve the values of a key read from its replicas into the response of
+/// a get.
+fn resolve_read(
+    conflict_resolution: ConflictResolution,
+    values: Vec<Option<EntryValue>>,
+) -> Result<Vec<u8>> {
+    let mut values = values.into_iter().flatten();
+    let value = match conflict_resolution.merge_fn() {
+        None => values.max_by_key(|v| v.timestamp),
+        Some(merge_fn) => match values.next() {
+            Some(first) => Some(values.try_fold(first, |merged, v| {
+                merge_values(merge_fn, &merged, &v)
+            })?),
+            None => None,
+        },
+    };
+    match value {
+        Some(value) => conflict_resolution.to_response(value.as_data())?,
+        None => None,
+    }
+    .ok_or(Error::KeyNotFound)
+}
+
+async fn set_user(
+    my_shard: &MyShard,
+    username: String,
+    user: Option<User>,
+) -> Result<()> {
+    let timestamp = my_shard.clock.now();
+    my_shard
+        .set_user(username.clone(), user.clone(), timestamp)
+        .await?;
+    my_shard
+        .gossip(GossipEvent::SetUser(username, user, timestamp))
+        .await
+}
+
+/// Scan a page of the documents in the primary range of the shard, the
+/// response is a map of "documents" (an array of [key, value]) and "next",
+/// the key to continue the scan from (nil when the scan is done).
+async fn scan(
+    my_shard: &MyShard,
+    collection_name: &str,
+    after: Option<Vec<u8>>,
+    limit: usize,
+) -> Result<Vec<u8>> {
+    let collection = my_shard.get_collection(collection_name)?;
+    let tree = collection.tree;
+
+    let (start, end) = my_shard.owned_ranges(1)[0];
+    let entries = tree
+        .scan(
+            after.as_deref(),
+            limit,
+            Box::new(move |key, _| {
+                hash_bytes(key)
+                    .map(|hash| start == end || is_between(hash, start, end))
+                    .unwrap_or(false)
+            }),
+        )
+        .await?;
+
+    let next = if entries.len() == limit {
+        match entries.last() {
+            Some(entry) => read_value(&mut &entry.key[..])?,
+            None => Value::Nil,
+        }
+    } else {
+        Value::Nil
+    };
+
+    let mut documents = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let value = match collection
+            .metadata
+            .conflict_resolution
+            .to_value(entry.value.as_data())?
+        {
+            Some(value) => value,
+            None => continue,
+        };
+        documents
+            .push(Value::Array(vec![read_value(&mut &entry.key[..])?, value]));
+    }
+
+    let response = Value::Map(vec![
+        (Value::String("documents".into()), Value::Array(documents)),
+        (Value::String("next".into()), next),
+    ]);
+    let mut buf: Vec<u8> = Vec::new();
+    write_value(&mut buf, &response)?;
+    Ok(buf)
+}
+
+/// Encode a batch of changes as {"changes": [[sequence, timestamp, key,
+/// value]...]}, a delete has no value, timestamps are in unix nanos.
+fn encode_changes(changes: Vec<Change>) -> Result<Vec<u8>> {
+    let mut encoded = Vec::with_capacity(changes.len());
+    for change in changes {
+        let mut items = vec![
+            Value::from(change.sequence),
+            Value::from(change.timestamp.unix_timestamp_nanos() as u64),
+            read_value(&mut &change.key[..])?,
+        ];
+        if let Some(value) = change.value {
+            items.push(read_value(&mut &value[..])?);
+        }
+        encoded.push(Value::Array(items));
+    }
+
+    let response = Value::Map(vec![(
+        Value::String("changes".into()),
+        Value::Array(encoded),
+    )]);
+    let mut buf: Vec<u8> = Vec::new();
+    write_value(&mut buf, &response)?;
+    Ok(buf)
+}
+
+/// Read the next batch of changes starting at the cursor, waiting for new
+/// changes when there are none, returns an empty batch on heartbeat.
+async fn next_changes(
+    my_shard: &MyShard,
+    collection_name: &str,
+    cursor: u64,
+) -> Result<Vec<Change>> {
+    // Get the change log every time, as the collection might get dropped.
+    let changes = my_shard.get_collection(collection_name)?.changes;
+
+    let listener = changes.listen();
+    let batch = changes.read(cursor, SUBSCRIBE_BATCH_SIZE).await?;
+    if !batch.is_empty() {
+        return Ok(batch);
+    }
+
+    let waited = timeout(STREAM_HEARTBEAT_INTERVAL, async {
+        listener.await;
+        Ok(())
+    })
+    .await;
+    if waited.is_err() {
+        return Ok(Vec::new());
+    }
+
+    changes.read(cursor, SUBSCRIBE_BATCH_SIZE).await
+}
+
+/// Extract the collection name and the cursor to start streaming changes
+/// from, without a cursor only new changes are streamed.
+async fn extract_subscription(
+    my_shard: &MyShard,
+    buffer: &[u8],
+    user: Option<&User>,
+) -> Result<(String, u64)> {
+    let map = read_value_ref(&mut &buffer[..])?.to_owned();
+    if map.as_map().is_none() {
+        return Err(Error::BadFieldType("document".to_string()));
+    }
+
+    let collection_name = extract_field_as_str(&map, "collection")?;
+    check_permission(user, &collection_name, Permission::Read)?;
+
+    let changes = my_shard.get_collection(&collection_name)?.changes;
+    let cursor = extract_field_as_u64(&map, "cursor")
+        .unwrap_or_else(|_| changes.next_sequence());
+
+    // Fail early when the cursor already expired.
+    changes.read(cursor, 0).await?;
+
+    Ok((collection_name, cursor))
+}
+
+/// Stream batches of changes to a subscribed client (see encode_changes()),
+/// until the client disconnects or an error is sent to the client.
+async fn handle_subscribe_request(
+    my_shard: &MyShard,
+    client: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    buffer: &[u8],
+    user: Option<&User>,
+) -> Result<()> {
+    let (collection_name, mut cursor) =
+        match extract_subscription(my_shard, buffer, user).await {
+            Ok(subscription) => subscription,
+            Err(e) => return send_response(client, Err(e)).await,
+        };
+
+    // Acknowledge the subscription, changes from now on are streamed.
+    send_response(client, Ok(Some(encode_changes(Vec::new())?))).await?;
+
+    loop {
+        let result = next_changes(my_shard, &collection_name, cursor)
+            .await
+            .and_then(|changes| {
+                if let Some(last) = changes.last() {
+                    cursor = last.sequence + 1;
+                }
+                encode_changes(changes)
+            });
+
+        let failed = result.is_err();
+        send_response(client, result.map(Some)).await?;
+        if failed {
+            return Ok(());
+        }
+    }
+}
+
+/// Encode a batch of watch events as {"events": [[timestamp, key, value]...]},
+/// a delete has no value, timestamps are in unix nanos.
+fn encode_watch_events(events: Vec<WatchEvent>) -> Result<Vec<u8>> {
+    let mut encoded = Vec::with_capacity(events.len());
+    for event in events {
+        let mut items = vec![
+            Value::from(event.timestamp.unix_timestamp_nanos() as u64),
+            read_value(&mut &event.key[..])?,
+        ];
+        if let Some(value) = event.value {
+            items.push(read_value(&mut &value[..])?);
+        }
+        encoded.push(Value::Array(items));
+    }
+
+    let response = Value::Map(vec![(
+        Value::String("events".into()),
+        Value::Array(encoded),
+    )]);
+    let mut buf: Vec<u8> = Vec::new();
+    write_value(&mut buf, &response)?;
+    Ok(buf)
+}
+
+/// Wait for the next events of a watcher, returns an empty batch on
+/// heartbeat.
+async fn next_watch_events(
+    receiver: &Receiver<WatchEvent>,
+) -> Result<Vec<WatchEvent>> {
+    let first = timeout(STREAM_HEARTBEAT_INTERVAL, async {
+        receiver.recv().await.map_err(|_| Error::WatchClosed)
+    })
+    .await;
+
+    let mut events = match first {
+        Ok(event) => vec![event],
+        Err(Error::Timeout) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    while let Ok(event) = receiver.try_recv() {
+        events.push(event);
+    }
+
+    Ok(events)
+}
+
+/// Extract the collection name and what to watch in it, either a "key" or
+/// a string key "prefix".
+fn extract_watch(
+    my_shard: &MyShard,
+    buffer: &[u8],
+    user: Option<&User>,
+) -> Result<(String, WatchTarget)> {
+    let map = read_value_ref(&mut &buffer[..])?.to_owned();
+    if map.as_map().is_none() {
+        return Err(Error::BadFieldType("document".to_string()));
+    }
+
+    let collection_name = extract_field_as_str(&map, "collection")?;
+    check_permission(user, &collection_name, Permission::Read)?;
+    my_shard.get_collection(&collection_name)?;
+
+    let target = if map["prefix"].is_nil() {
+        // Writes are notified by the shard owning the key.
+        WatchTarget::Key(extract_key(my_shard, &map, 0)?)
+    } else {
+        WatchTarget::Prefix(extract_field_as_str(&map, "prefix")?)
+    };
+
+    Ok((collection_name, target))
+}
+
+async fn stream_watch_events(
+    client: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    receiver: &Receiver<WatchEvent>,
+) -> Result<()> {
+    // Acknowledge the watch, writes from now on are notified.
+    send_response(client, Ok(Some(encode_watch_events(Vec::new())?))).await?;
+
+    loop {
+        let result = next_watch_events(receiver)
+            .await
+            .and_then(encode_watch_events);
+
+        let failed = result.is_err();
+        send_response(client, result.map(Some)).await?;
+        if failed {
+            return Ok(());
+        }
+    }
+}
+
+/// Push batches of watch events to a client (see encode_watch_events()),
+/// until the client disconnects or an error is sent to the client.
+async fn handle_watch_request(
+    my_shard: &MyShard,
+    client: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    buffer: &[u8],
+    user: Option<&User>,
+) -> Result<()> {
+    let (collection_name, target) = match extract_watch(my_shard, buffer, user)
+    {
+        Ok(watch) => watch,
+        Err(e) => return send_response(client, Err(e)).await,
+    };
+
+    let (id, receiver) = my_shard.add_watcher(collection_name, target);
+    let result = stream_watch_events(client, &receiver).await;
+    my_shard.remove_watcher(id);
+
+    result
+}
+
+/// Encode a batch of dumped documents as {"documents": [[key, value]...]},
+/// the end of the dump is marked by {"documents": [], "done": true}.
+fn encode_documents(documents: Vec<Value>, done: bool) -> Result<Vec<u8>> {
+    let mut items =
+        vec![(Value::String("documents".into()), Value::Array(documents))];
+    if done {
+        items.push((Value::String("done".into()), Value::Boolean(true)));
+    }
+    let mut buf: Vec<u8> = Vec::new();
+    write_value(&mut buf, &Value::Map(items))?;
+    Ok(buf)
+}
+
+/// Extract the name of the collection to dump.
+fn extract_dump(
+    my_shard: &MyShard,
+    buffer: &[u8],
+    user: Option<&User>,
+) -> Result<String> {
+    let map = read_value_ref(&mut &buffer[..])?.to_owned();
+    if map.as_map().is_none() {
+        return Err(Error::BadFieldType("document".to_string()));
+    }
+
+    let collection_name = extract_field_as_str(&map, "collection")?;
+    check_permission(user, &collection_name, Permission::Read)?;
+    my_shard.get_collection(&collection_name)?;
+
+    Ok(collection_name)
+}
+
+/// Send the documents in the primary range of the shard in batches, a batch
+/// is sent early (maybe empty) as a heartbeat when finding documents takes
+/// long (e.g. over many tombstones).
+async fn dump_documents(
+    my_shard: &MyShard,
+    client: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    collection_name: &str,
+) -> Result<()> {
+    let collection = my_shard.get_collection(collection_name)?;
+    let tree = collection.tree.clone();
+
+    let (start, end) = my_shard.owned_ranges(1)[0];
+    let mut iter = tree
+        .iter_sorted(
+            None,
+            Box::new(move |key, _| {
+                hash_bytes(key)
+                    .map(|hash| start == end || is_between(hash, start, end))
+                    .unwrap_or(false)
+            }),
+        )
+        .await?;
+
+    let mut documents = Vec::new();
+    let mut last_sent = Instant::now();
+    while let Some(entry) = iter.next().await? {
+        if let Some(value) = collection
+            .metadata
+            .conflict_resolution
+            .to_value(entry.value.as_data())?
+        {
+            documents.push(Value::Array(vec![
+                read_value(&mut &entry.key[..])?,
+                value,
+            ]));
+        }
+
+        if documents.len() >= DUMP_BATCH_SIZE
+            || last_sent.elapsed() >= STREAM_HEARTBEAT_INTERVAL
+        {
+            let batch =
+                encode_documents(std::mem::take(&mut documents), false)?;
+            send_response(client, Ok(Some(batch))).await?;
+            last_sent = Instant::now();
+        }
+    }
+
+    if !documents.is_empty() {
+        send_response(client, Ok(Some(encode_documents(documents, false)?)))
+            .await?;
+    }
+    send_response(client, Ok(Some(encode_documents(Vec::new(), true)?))).await
+}
+
+/// Stream all documents in the primary range of the shard to a client (see
+/// encode_documents()), until the end of the dump or an error is sent to the
+/// client.
+/// The documents are read from the memtables and sstables of the collection
+/// when the dump starts, merged in key order.
+async fn handle_dump_request(
+    my_shard: &MyShard,
+    client: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    buffer: &[u8],
+    user: Option<&User>,
+) -> Result<()> {
+    let collection_name = match extract_dump(my_shard, buffer, user) {
+        Ok(collection_name) => collection_name,
+        Err(e) => return send_response(client, Err(e)).await,
+    };
+
+    // Acknowledge the dump.
+    send_response(client, Ok(Some(encode_documents(Vec::new(), false)?)))
+        .await?;
+
+    if let Err(e) = dump_documents(my_shard, client, &collection_name).await {
+        send_response(client, Err(e)).await?;
+    }
+    Ok(())
+}
+
 async fn handle_request(
     my_shard: Rc<MyShard>,
     buffer: Vec<u8>,
+    user: Option<&User>,
 ) -> Result<Option<Vec<u8>>> {
     let msgpack_request = read_value_ref(&mut &buffer[..])?.to_owned();
     if let Some(map_vec) = msgpack_request.as_map() {
-        let timestamp = OffsetDateTime::now_utc();
+        let timestamp = my_shard.clock.now();
         let map = Value::Map(map_vec.clone());
         match map["type"].as_str() {
             Some("get_cluster_metadata") => {
@@ -144,23 +864,48 @@ async fn handle_request(
             }
             Some("create_collection") => {
                 let name = extract_field_as_str(&map, "name")?;
-                let replication_factor =
-                    extract_field_as_u16(&map, "replication_factor")
-                        .unwrap_or(my_shard.args.default_replication_factor);
+                check_permission(user, &name, Permission::Admin)?;
+                let replication_factor = extract_replication_factor(
+                    &map,
+                    my_shard.args.default_replication_factor,
+                )?;
+                let conflict_resolution =
+                    match extract_field_as_str(&map, "conflict_resolution") {
+                        Ok(name) => {
+                            ConflictResolution::try_from(name.as_str())?
+                        }
+                        Err(_) => ConflictResolution::default(),
+                    };
+                // After any drop of the collection the shard knows of.
+                let created_at = my_shard.clock.now();
+                let metadata = CollectionMetadata {
+                    conflict_resolution,
+                    gc_grace_seconds: extract_field_as_u64(
+                        &map,
+                        "gc_grace_seconds",
+                    )
+                    .unwrap_or(DEFAULT_GC_GRACE_SECONDS),
+                    created_at,
+                    altered_at: created_at,
+                    ..CollectionMetadata::new(replication_factor)
+                };
 
                 if my_shard.collections.borrow().contains_key(&name) {
                     return Err(Error::CollectionAlreadyExists(name));
                 }
 
                 my_shard
-                    .create_collection(name.clone(), replication_factor)
+                    .create_collection_with_metadata(
+                        name.clone(),
+                        metadata.clone(),
+                    )
                     .await?;
 
                 let _ = my_shard
                     .send_request_to_local_shards(
                         ShardRequest::CreateCollection(
                             name.clone(),
-                            replication_factor,
+                            metadata.clone(),
                         ),
                         |res| {
                             response_to_empty_result!(
@@ -174,12 +919,69 @@ async fn handle_request(
                 my_shard
                     .gossip(GossipEvent::CreateCollection(
                         name,
-                        replication_factor,
+                        metadata.clone(),
                     ))
                     .await?;
+
+                let mut buf: Vec<u8> = Vec::new();
+                metadata.serialize(&mut Serializer::new(&mut buf))?;
+                return Ok(Some(buf));
+            }
+            Some("alter_collection") => {
+                let name = extract_field_as_str(&map, "name")?;
+                check_permission(user, &name, Permission::Admin)?;
+                let current = my_shard.get_collection(&name)?.metadata;
+                let metadata = CollectionMetadata {
+                    replication_factor: extract_replication_factor(
+                        &map,
+                        current.replication_factor,
+                    )?,
+                    version: current.version + 1,
+                    conflict_resolution: current.conflict_resolution,
+                    gc_grace_seconds: extract_field_as_u64(
+                        &map,
+                        "gc_grace_seconds",
+                    )
+                    .unwrap_or(current.gc_grace_seconds),
+                    created_at: current.created_at,
+                    truncated_at: current.truncated_at,
+                    altered_at: my_shard.clock.now(),
+                };
+
+                my_shard
+                    .clone()
+                    .alter_collection(name.clone(), metadata.clone())
+                    .await?;
+
+                let _ = my_shard
+                    .send_request_to_local_shards(
+                        ShardRequest::AlterCollection(
+                            name.clone(),
+                            metadata.clone(),
+                        ),
+                        |res| {
+                            response_to_empty_result!(
+                                res,
+                                ShardResponse::AlterCollection
+                            )
+                        },
+                    )
+                    .await?;
+
+                my_shard
+                    .gossip(GossipEvent::AlterCollection(
+                        name,
+                        metadata.clone(),
+                    ))
+                    .await?;
+
+                let mut buf: Vec<u8> = Vec::new();
+                metadata.serialize(&mut Serializer::new(&mut buf))?;
+                return Ok(Some(buf));
             }
             Some("get_collection") => {
                 let name = extract_field_as_str(&map, "name")?;
+                check_permission(user, &name, Permission::Read)?;
                 let mut buf: Vec<u8> = Vec::new();
                 my_shard
                     .get_collection(&name)?
@@ -189,12 +991,14 @@ async fn handle_request(
             }
             Some("drop_collection") => {
                 let name = extract_field_as_str(&map, "name")?;
+                check_permission(user, &name, Permission::Admin)?;
 
-                my_shard.drop_collection(&name).await?;
+                let timestamp = my_shard.clock.now();
+                my_shard.drop_collection(&name, timestamp).await?;
 
                 let _ = my_shard
                     .send_request_to_local_shards(
-                        ShardRequest::DropCollection(name.clone()),
+                        ShardRequest::DropCollection(name.clone(), timestamp),
                         |res| {
                             response_to_empty_result!(
                                 res,
@@ -204,10 +1008,50 @@ async fn handle_request(
                     )
                     .await?;
 
-                my_shard.gossip(GossipEvent::DropCollection(name)).await?;
+                my_shard
+                    .gossip(GossipEvent::DropCollection(name, timestamp))
+                    .await?;
+            }
+            Some("truncate_collection") => {
+                let name = extract_field_as_str(&map, "name")?;
+                check_permission(user, &name, Permission::Admin)?;
+
+                let timestamp = my_shard.clock.now();
+                my_shard.truncate_collection(&name, timestamp).await?;
+
+                let _ = my_shard
+                    .send_request_to_local_shards(
+                        ShardRequest::TruncateCollection(
+                            name.clone(),
+                            timestamp,
+                        ),
+                        |res| {
+                            response_to_empty_result!(
+                                res,
+                                ShardResponse::TruncateCollection
+                            )
+                        },
+                    )
+                    .await?;
+
+                my_shard
+                    .gossip(GossipEvent::TruncateCollection(name, timestamp))
+                    .await?;
+            }
+            Some("ingest") => {
+                let collection_name = extract_field_as_str(&map, "collection")?;
+                check_permission(user, &collection_name, Permission::Admin)?;
+                let dir = extract_field_as_str(&map, "dir")?;
+                let ingested = my_shard
+                    .ingest_node_collection(&collection_name, &dir)
+                    .await?;
+                let mut buf: Vec<u8> = Vec::new();
+                write_value(&mut buf, &Value::Integer(ingested.into()))?;
+                return Ok(Some(buf));
             }
             Some("set") => {
                 let collection_name = extract_field_as_str(&map, "collection")?;
+                check_permission(user, &collection_name, Permission::Write)?;
                 let value = extract_field_encoded(&map, "value")?;
                 let write_timeout = Duration::from_millis(
                     extract_field_as_u64(&map, "timeout")
@@ -218,7 +1062,6 @@ async fn handle_request(
                 let key = extract_key(&my_shard, &map, replica_index.into())?;
 
                 let collection = my_shard.get_collection(&collection_name)?;
-                let tree = collection.tree;
                 let replications = collection.metadata.replication_factor;
 
                 let write_consistency = min(
@@ -227,16 +1070,39 @@ async fn handle_request(
                     replications,
                 );
 
-                if replications > 1 {
-                    let local_future = tree.set_with_timestamp(
+                collection
+                    .metadata
+                    .conflict_resolution
+                    .check_supports("set")?;
+
+                if collection.metadata.conflict_resolution
+                    == ConflictResolution::Siblings
+                {
+                    timeout(
+                        write_timeout,
+                        write_siblings(
+                            my_shard.clone(),
+                            collection_name,
+                            key,
+                            Some(value),
+                            extract_context(&map)?,
+                            replica_index,
+                            write_consistency,
+                        ),
+                    )
+                    .await?;
+                } else if replications > 1 {
+                    let local_future = my_shard.write_to_collection(
+                        &collection_name,
+                        &collection,
                         key.clone(),
-                        value.clone(),
+                        Some(value.clone()),
                         timestamp,
                     );
                     let remote_future =
                         my_shard.clone().send_request_to_replicas(
                             ShardRequest::Set(
-                                collection_name,
+                                collection_name.clone(),
                                 key,
                                 value,
                                 timestamp,
@@ -258,13 +1124,20 @@ async fn handle_request(
                 } else {
                     timeout(
                         write_timeout,
-                        tree.set_with_timestamp(key, value, timestamp),
+                        my_shard.write_to_collection(
+                            &collection_name,
+                            &collection,
+                            key,
+                            Some(value),
+                            timestamp,
+                        ),
                     )
                     .await?;
                 }
             }
             Some("delete") => {
                 let collection_name = extract_field_as_str(&map, "collection")?;
+                check_permission(user, &collection_name, Permission::Write)?;
                 let delete_timeout = Duration::from_millis(
                     extract_field_as_u64(&map, "timeout")
                         .unwrap_or(DEFAULT_SET_TIMEOUT_MS),
@@ -273,7 +1146,6 @@ async fn handle_request(
                     extract_field_as_u16(&map, "replica_index").unwrap_or(0);
 
                 let collection = my_shard.get_collection(&collection_name)?;
-                let tree = collection.tree;
                 let replications = collection.metadata.replication_factor;
                 let key = extract_key(&my_shard, &map, replica_index.into())?;
 
@@ -283,13 +1155,39 @@ async fn handle_request(
                     replications,
                 );
 
-                if replications > 1 {
-                    let local_future =
-                        tree.delete_with_timestamp(key.clone(), timestamp);
+                collection
+                    .metadata
+                    .conflict_resolution
+                    .check_supports("delete")?;
+
+                if collection.metadata.conflict_resolution
+                    == ConflictResolution::Siblings
+                {
+                    timeout(
+                        delete_timeout,
+                        write_siblings(
+                            my_shard.clone(),
+                            collection_name,
+                            key,
+                            None,
+                            extract_context(&map)?,
+                            replica_index,
+                            delete_consistency,
+                        ),
+                    )
+                    .await?;
+                } else if replications > 1 {
+                    let local_future = my_shard.write_to_collection(
+                        &collection_name,
+                        &collection,
+                        key.clone(),
+                        None,
+                        timestamp,
+                    );
                     let remote_future =
                         my_shard.clone().send_request_to_replicas(
                             ShardRequest::Delete(
-                                collection_name,
+                                collection_name.clone(),
                                 key,
                                 timestamp,
                             ),
@@ -310,13 +1208,116 @@ async fn handle_request(
                 } else {
                     timeout(
                         delete_timeout,
-                        tree.delete_with_timestamp(key, timestamp),
+                        my_shard.write_to_collection(
+                            &collection_name,
+                            &collection,
+                            key,
+                            None,
+                            timestamp,
+                        ),
                     )
                     .await?;
                 }
             }
+            Some(request @ ("increment" | "add" | "remove")) => {
+                let collection_name = extract_field_as_str(&map, "collection")?;
+                check_permission(user, &collection_name, Permission::Write)?;
+                let write_timeout = Duration::from_millis(
+                    extract_field_as_u64(&map, "timeout")
+                        .unwrap_or(DEFAULT_SET_TIMEOUT_MS),
+                );
+                let replica_index =
+                    extract_field_as_u16(&map, "replica_index").unwrap_or(0);
+                let key = extract_key(&my_shard, &map, replica_index.into())?;
+
+                let collection = my_shard.get_collection(&collection_name)?;
+                let conflict_resolution =
+                    collection.metadata.conflict_resolution;
+                conflict_resolution.check_supports(request)?;
+                let operation =
+                    extract_crdt_operation(&map, request, conflict_resolution)?;
+
+                let replications = collection.metadata.replication_factor;
+                let write_consistency = min(
+                    extract_field_as_u16(&map, "consistency")
+                        .unwrap_or(replications),
+                    replications,
+                );
+
+                timeout(
+                    write_timeout,
+                    update_crdt(
+                        my_shard.clone(),
+                        collection_name,
+                        key,
+                        operation,
+                        replica_index,
+                        write_consistency,
+                    ),
+                )
+                .await?;
+            }
+            Some("transaction") => {
+                let collection_name = extract_field_as_str(&map, "collection")?;
+                check_permission(user, &collection_name, Permission::Write)?;
+                let transaction_timeout = Duration::from_millis(
+                    extract_field_as_u64(&map, "timeout")
+                        .unwrap_or(DEFAULT_SET_TIMEOUT_MS),
+                );
+                let replica_index =
+                    extract_field_as_u16(&map, "replica_index").unwrap_or(0);
+                let operations =
+                    extract_operations(&my_shard, &map, replica_index.into())?;
+
+                let replications = my_shard
+                    .get_collection(&collection_name)?
+                    .metadata
+                    .replication_factor;
+                let write_consistency = min(
+                    extract_field_as_u16(&map, "consistency")
+                        .unwrap_or(replications),
+                    replications,
+                );
+
+                timeout(
+                    transaction_timeout,
+                    transaction(
+                        my_shard.clone(),
+                        collection_name,
+                        operations,
+                        replica_index,
+                        write_consistency,
+                        timestamp,
+                    ),
+                )
+                .await?;
+            }
+            Some("cas") => {
+                let collection_name = extract_field_as_str(&map, "collection")?;
+                check_permission(user, &collection_name, Permission::Write)?;
+                let cas_timeout = Duration::from_millis(
+                    extract_field_as_u64(&map, "timeout")
+                        .unwrap_or(DEFAULT_SET_TIMEOUT_MS),
+                );
+                let replica_index =
+                    extract_field_as_u16(&map, "replica_index").unwrap_or(0);
+                let key = extract_key(&my_shard, &map, replica_index.into())?;
+                let operation = extract_operation(&map, key)?;
+
+                timeout(
+                    cas_timeout,
+                    compare_and_set(
+                        my_shard.clone(),
+                        collection_name,
+                        operation,
+                        replica_index,
+                    ),
+                )
+                .await?;
+            }
             Some("get") => {
                 let collection_name = extract_field_as_str(&map, "collection")?;
+                check_permission(user, &collection_name, Permission::Read)?;
                 let read_timeout = Duration::from_millis(
                     extract_field_as_u64(&map, "timeout")
                         .unwrap_or(DEFAULT_GET_TIMEOUT_MS),
@@ -335,7 +1336,7 @@ async fn handle_request(
                     replications,
                 );
 
-                return if replications > 1 {
+                let values = if replications > 1 {
                     let local_future = tree.get_entry(&key);
                     let remote_future =
                         my_shard.clone().send_request_to_replicas(
@@ -351,22 +1352,173 @@ async fn handle_request(
                     .await?;
 
                     values.push(local_value);
-
-                    match values
-                        .into_iter()
-                        .flatten()
-                        .max_by_key(|v| v.timestamp)
-                        .map(|v| v.data)
-                    {
-                        Some(value) if value != TOMBSTONE => Ok(Some(value)),
-                        _ => Err(Error::KeyNotFound),
-                    }
+                    values
                 } else {
-                    match timeout(read_timeout, tree.get(&key)).await? {
-                        Some(value) if value != TOMBSTONE => Ok(Some(value)),
-                        _ => Err(Error::KeyNotFound),
-                    }
+                    vec![timeout(read_timeout, tree.get_entry(&key)).await?]
                 };
+
+                return resolve_read(
+                    collection.metadata.conflict_resolution,
+                    values,
+                )
+                .map(Some);
+            }
+            Some("node_status") => {
+                check_permission(user, ALL_COLLECTIONS, Permission::Admin)?;
+                let mut buf: Vec<u8> = Vec::new();
+                my_shard
+                    .get_node_status()
+                    .await?
+                    .serialize(&mut Serializer::new(&mut buf))?;
+                return Ok(Some(buf));
+            }
+            Some("shard_stats") => {
+                check_permission(user, ALL_COLLECTIONS, Permission::Admin)?;
+                let mut buf: Vec<u8> = Vec::new();
+                my_shard
+                    .get_node_stats()
+                    .await?
+                    .serialize(&mut Serializer::new(&mut buf))?;
+                return Ok(Some(buf));
+            }
+            Some("list_collections") => {
+                let summaries = my_shard
+                    .get_node_collection_summaries()
+                    .await?
+                    .into_iter()
+                    .filter(|summary| {
+                        check_permission(user, &summary.name, Permission::Read)
+                            .is_ok()
+                    })
+                    .collect::<Vec<_>>();
+                let mut buf: Vec<u8> = Vec::new();
+                summaries.serialize(&mut Serializer::new(&mut buf))?;
+                return Ok(Some(buf));
+            }
+            Some("collection_stats") => {
+                let name = extract_field_as_str(&map, "name")?;
+                check_permission(user, &name, Permission::Admin)?;
+                let mut buf: Vec<u8> = Vec::new();
+                my_shard
+                    .get_collection_node_stats(&name)
+                    .await?
+                    .serialize(&mut Serializer::new(&mut buf))?;
+                return Ok(Some(buf));
+            }
+            Some("flush") => {
+                let name = extract_field_as_str(&map, "name")?;
+                check_permission(user, &name, Permission::Admin)?;
+
+                my_shard.flush_collection(&name).await?;
+
+                let _ = my_shard
+                    .send_request_to_local_shards(
+                        ShardRequest::FlushCollection(name),
+                        |res| {
+                            response_to_empty_result!(
+                                res,
+                                ShardResponse::FlushCollection
+                            )
+                        },
+                    )
+                    .await?;
+            }
+            Some("compact") => {
+                let name = extract_field_as_str(&map, "name")?;
+                check_permission(user, &name, Permission::Admin)?;
+
+                my_shard.compact_collection(&name).await?;
+
+                let _ = my_shard
+                    .send_request_to_local_shards(
+                        ShardRequest::CompactCollection(name),
+                        |res| {
+                            response_to_empty_result!(
+                                res,
+                                ShardResponse::CompactCollection
+                            )
+                        },
+                    )
+                    .await?;
+            }
+            Some("count") => {
+                let collection_name = extract_field_as_str(&map, "collection")?;
+                check_permission(user, &collection_name, Permission::Read)?;
+                let count = my_shard
+                    .get_collection_node_count(&collection_name)
+                    .await?;
+                let mut buf: Vec<u8> = Vec::new();
+                write_value(&mut buf, &Value::Integer(count.into()))?;
+                return Ok(Some(buf));
+            }
+            Some("scan") => {
+                let collection_name = extract_field_as_str(&map, "collection")?;
+                check_permission(user, &collection_name, Permission::Read)?;
+                let limit = min(
+                    extract_field_as_u64(&map, "limit")
+                        .unwrap_or(DEFAULT_SCAN_LIMIT),
+                    MAX_SCAN_LIMIT,
+                );
+                if limit == 0 {
+                    return Err(Error::BadFieldType("limit".to_string()));
+                }
+                let after = extract_field_encoded(&map, "after").ok();
+
+                return Ok(Some(
+                    scan(&my_shard, &collection_name, after, limit as usize)
+                        .await?,
+                ));
+            }
+            Some("create_user") => {
+                check_permission(user, ALL_COLLECTIONS, Permission::Admin)?;
+                let username = extract_field_as_str(&map, "username")?;
+                let password = extract_field_as_str(&map, "password")?;
+                let permissions =
+                    extract_field_as_permissions(&map, "permissions")?;
+
+                if my_shard.get_user(&username).await?.is_some() {
+                    return Err(Error::UserAlreadyExists(username));
+                }
+
+                let new_user = User::new(&password, permissions)?;
+                set_user(&my_shard, username, Some(new_user)).await?;
+            }
+            Some("drop_user") => {
+                check_permission(user, ALL_COLLECTIONS, Permission::Admin)?;
+                let username = extract_field_as_str(&map, "username")?;
+
+                if my_shard.get_user(&username).await?.is_none() {
+                    return Err(Error::UserNotFound(username));
+                }
+
+                set_user(&my_shard, username, None).await?;
+            }
+            Some("grant") => {
+                check_permission(user, ALL_COLLECTIONS, Permission::Admin)?;
+                let username = extract_field_as_str(&map, "username")?;
+                let collection_name = extract_field_as_str(&map, "collection")?;
+                let permission = Permission::try_from(
+                    extract_field_as_str(&map, "permission")?.as_str(),
+                )?;
+
+                let mut granted_user = my_shard
+                    .get_user(&username)
+                    .await?
+                    .ok_or_else(|| Error::UserNotFound(username.clone()))?;
+                granted_user.permissions.insert(collection_name, permission);
+                set_user(&my_shard, username, Some(granted_user)).await?;
+            }
+            Some("revoke") => {
+                check_permission(user, ALL_COLLECTIONS, Permission::Admin)?;
+                let username = extract_field_as_str(&map, "username")?;
+                let collection_name = extract_field_as_str(&map, "collection")?;
+
+                let mut revoked_user = my_shard
+                    .get_user(&username)
+                    .await?
+                    .ok_or_else(|| Error::UserNotFound(username.clone()))?;
+                revoked_user.permissions.remove(&collection_name);
+                set_user(&my_shard, username, Some(revoked_user)).await?;
             }
             Some(name) => {
                 return Err(Error::UnsupportedField(name.to_string()));
@@ -392,17 +1544,141 @@ async fn send_buffer(
     Ok(())
 }
 
-async fn handle_client(
-    my_shard: Rc<MyShard>,
+/// Handle the auth handshake, the first request of a client when auth is
+/// enabled.
+async fn handle_auth_request(
+    my_shard: &MyShard,
+    buffer: Vec<u8>,
+) -> Result<User> {
+    let msgpack_request = read_value_ref(&mut &buffer[..])?.to_owned();
+    if msgpack_request.as_map().is_none() {
+        return Err(Error::BadFieldType("document".to_string()));
+    }
+    if msgpack_request["type"].as_str() != Some("auth") {
+        return Err(Error::Unauthenticated);
+    }
+
+    let username = extract_field_as_str(&msgpack_request, "username")?;
+    let password = extract_field_as_str(&msgpack_request, "password")?;
+    my_shard.authenticate(&username, &password).await
+}
+
+/// Peek at the type of a request, used as the label of request metrics.
+fn get_request_type(buffer: &[u8]) -> Option<String> {
+    match read_value_ref(&mut &buffer[..]).ok()? {
+        ValueRef::Map(items) => {
+            items.iter().find_map(|(key, value)| match (key, value) {
+                (ValueRef::String(key), ValueRef::String(value))
+                    if key.as_str() == Some("type") =>
+                {
+                    value.as_str().map(str::to_string)
+                }
+                _ => None,
+            })
+        }
+        _ => None,
+    }
+}
+
+async fn read_request(
     client: &mut (impl AsyncRead + AsyncWrite + Unpin),
-) -> Result<()> {
+) -> Result<Vec<u8>> {
     let mut size_buf = [0; 2];
     client.read_exact(&mut size_buf).await?;
     let size = u16::from_le_bytes(size_buf);
     let mut request_buf = vec![0; size as usize];
     client.read_exact(&mut request_buf).await?;
+    Ok(request_buf)
+}
+
+async fn handle_client(
+    my_shard: Rc<MyShard>,
+    client: &mut (impl AsyncRead + AsyncWrite + Unpin),
+) -> Result<()> {
+    let user = if my_shard.args.auth {
+        let request_buf = read_request(client).await?;
+        match handle_auth_request(&my_shard, request_buf).await {
+            Ok(user) => {
+                send_response(client, Ok(None)).await?;
+                Some(user)
+            }
+            Err(e) => {
+                send_response(client, Err(e)).await?;
+                client.close().await?;
+                return Ok(());
+            }
+        }
+    } else {
+        None
+    };
+
+    let request_buf = read_request(client).await?;
+    let request_type = get_request_type(&request_buf);
+
+    // Long-lived requests live for as long as the connection, so they're
+    // not recorded as request metrics.
+    let long_lived_result = match request_type.as_deref() {
+        Some("subscribe") => Some(
+            handle_subscribe_request(
+                &my_shard,
+                client,
+                &request_buf,
+                user.as_ref(),
+            )
+            .await,
+        ),
+        Some("watch") => Some(
+            handle_watch_request(
+                &my_shard,
+                client,
+                &request_buf,
+                user.as_ref(),
+            )
+            .await,
+        ),
+        Some("dump") => Some(
+            handle_dump_request(&my_shard, client, &request_buf, user.as_ref())
+                .await,
+        ),
+        _ => None,
+    };
+    if let Some(result) = long_lived_result {
+        result?;
+        client.close().await?;
+        return Ok(());
+    }
+
+    let start = Instant::now();
+    let result =
+        handle_request(my_shard.clone(), request_buf, user.as_ref()).await;
 
-    match handle_request(my_shard, request_buf).await {
+    if let Some(request_type) = request_type {
+        // Don't let clients create a metric for every unsupported type.
+        let unsupported = matches!(
+            &result,
+            Err(Error::UnsupportedField(field)) if field == &request_type
+        );
+        if !unsupported {
+            my_shard.metrics.record_request(
+                &request_type,
+                start.elapsed(),
+                result.is_err(),
+            );
+        }
+    }
+
+    send_response(client, result).await?;
+
+    client.close().await?;
+
+    Ok(())
+}
+
+async fn send_response(
+    client: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    result: Result<Option<Vec<u8>>>,
+) -> Result<()> {
+    match result {
         Ok(None) => {
             let mut buf: Vec<u8> = Vec::new();
             write_value_ref(&mut buf, &ValueRef::String("OK".into()))?;
@@ -425,8 +1701,6 @@ async fn handle_client(
         }
     }
 
-    client.close().await?;
-
     Ok(())
 }
 
@@ -440,7 +1714,19 @@ async fn run_server(my_shard: Rc<MyShard>) -> Result<()> {
         match server.accept().await {
             Ok(mut client) => {
                 spawn_local(enclose!((my_shard.clone() => my_shard) async move {
-                    if let Err(e) = handle_client(my_shard, &mut client).await {
+                    let result = match my_shard.tls.clone() {
+                        Some(tls) => {
+                            match tls::accept(&tls.client_acceptor, client).await
+                            {
+                                Ok(mut client) => {
+                                    handle_client(my_shard, &mut client).await
+                                }
+                                Err(e) => Err(e),
+                            }
+                        }
+                        None => handle_client(my_shard, &mut client).await,
+                    };
+                    if let Err(e) = result {
                         error!("Failed to handle client: {}", e);
                     }
                 }))
@@ -53,22 +53,29 @@ async fn run_failure_detector(my_shard: Rc<MyShard>) -> Result<()> {
                     .unwrap()
             ),
             &my_shard.args,
+            my_shard.tls.as_ref(),
         );
 
         if let Err(e) = connection.ping().await {
-            my_shard.clone().handle_dead_node(&node.name).await;
+            my_shard
+                .clone()
+                .handle_dead_node(&node.name, node.incarnation)
+                .await;
 
             info!(
                 "Notifying cluster that we failed to ping '{}': {}",
                 connection.address, e
             );
 
-            let gossip_event = GossipEvent::Dead(node.name);
+            let gossip_event = GossipEvent::Dead(node.name, node.incarnation);
 
             if let Err(e) = my_shard
                 .clone()
                 .broadcast_message_to_local_shards(&ShardMessage::Event(
-                    ShardEvent::Gossip(gossip_event.clone()),
+                    ShardEvent::Gossip(
+                        gossip_event.clone(),
+                        my_shard.clock.now(),
+                    ),
                 ))
                 .await
             {
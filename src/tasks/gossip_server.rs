@@ -1,48 +1,76 @@
-use std::{rc::Rc, time::Duration};
+use std::{
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
 use glommio::{
     enclose, executor, net::UdpSocket, spawn_local, spawn_local_into,
     timer::sleep, Latency, Shares, Task,
 };
 use log::{error, trace};
+use time::OffsetDateTime;
 
 use crate::{
-    error::Result,
-    gossip::deserialize_gossip_message,
+    error::{Error, Result},
+    gossip::{deserialize_gossip_message, GossipMessage},
     messages::{ShardEvent, ShardMessage},
+    metrics::increment,
     shards::MyShard,
 };
 
 const UDP_PACKET_BUFFER_SIZE: usize = 65536;
-const GOSSIP_REQUEST_EXPIRATION_TIME: Duration = Duration::from_secs(30);
+
+/// How long a message is remembered for deduplication.
+pub const GOSSIP_REQUEST_EXPIRATION_TIME: Duration = Duration::from_secs(30);
+
+/// Signed messages older than the deduplication window are rejected, as a
+/// replay of them would no longer be recognized.
+fn reject_stale(message: GossipMessage, signed: bool) -> Result<GossipMessage> {
+    let oldest = OffsetDateTime::now_utc() - GOSSIP_REQUEST_EXPIRATION_TIME;
+    if signed && message.timestamp < oldest {
+        return Err(Error::StaleGossipMessage);
+    }
+    Ok(message)
+}
+
+/// Forget the messages first seen at least `GOSSIP_REQUEST_EXPIRATION_TIME`
+/// before `now`, including the ones seen less than `gossip_max_seen_count`
+/// times.
+pub fn expire_gossip_requests(my_shard: &MyShard, now: Instant) {
+    my_shard
+        .gossip_requests
+        .borrow_mut()
+        .retain(|_, (_, first_seen_at)| {
+            now.saturating_duration_since(*first_seen_at)
+                < GOSSIP_REQUEST_EXPIRATION_TIME
+        });
+}
 
 async fn handle_gossip_packet(
     my_shard: Rc<MyShard>,
     packet_buf: &[u8],
 ) -> Result<()> {
-    let message = deserialize_gossip_message(packet_buf)?;
+    increment(&my_shard.metrics.gossip_packets_received, 1);
+
+    let secret = my_shard.args.gossip_secret.as_deref();
+    let message = match deserialize_gossip_message(packet_buf, secret)
+        .and_then(|message| reject_stale(message, secret.is_some()))
+    {
+        Ok(message) => message,
+        Err(e) => {
+            increment(&my_shard.metrics.rejected_gossip_packets, 1);
+            return Err(e);
+        }
+    };
 
     // Check whether we have seen this gossip event enough times.
     let seen_first_time = {
         let mut requests = my_shard.gossip_requests.borrow_mut();
-        let seen_count = requests
-            .entry((message.source.clone(), message.event.kind()))
-            .or_insert(0);
+        let (seen_count, _) = requests
+            .entry((message.source.clone(), message.sequence))
+            .or_insert((0, Instant::now()));
 
         if *seen_count >= my_shard.args.gossip_max_seen_count {
-            if *seen_count == my_shard.args.gossip_max_seen_count {
-                spawn_local(
-                    enclose!((my_shard.clone() => my_shard) async move {
-                        sleep(GOSSIP_REQUEST_EXPIRATION_TIME).await;
-                        my_shard
-                            .gossip_requests
-                            .borrow_mut()
-                            .remove(&(message.source, message.event.kind()));
-                    }),
-                )
-                .detach();
-                *seen_count += 1;
-            }
             return Ok(());
         }
 
@@ -52,9 +80,13 @@ async fn handle_gossip_packet(
 
     let continue_with_gossip = if seen_first_time {
         trace!("Gossip: {:?}", message.event);
+
+        // Gossip reaches every node, so the clocks of all nodes move past the
+        // clock of the most advanced one, even without writes between them.
+        my_shard.clock.update(message.timestamp);
         my_shard
             .broadcast_message_to_local_shards(&ShardMessage::Event(
-                ShardEvent::Gossip(message.event.clone()),
+                ShardEvent::Gossip(message.event.clone(), message.timestamp),
             ))
             .await?;
         my_shard.clone().handle_gossip_event(message.event).await?
@@ -76,6 +108,15 @@ async fn run_gossip_server(my_shard: Rc<MyShard>) -> Result<()> {
 
     let mut buf = vec![0; UDP_PACKET_BUFFER_SIZE];
 
+    // Cancelled once dropped, when the server stops.
+    let _expiration_task =
+        spawn_local(enclose!((my_shard.clone() => my_shard) async move {
+            loop {
+                sleep(GOSSIP_REQUEST_EXPIRATION_TIME).await;
+                expire_gossip_requests(&my_shard, Instant::now());
+            }
+        }));
+
     loop {
         match server.recv_from(&mut buf).await {
             Ok((n, _client_address)) => {
@@ -0,0 +1,121 @@
+use std::{rc::Rc, time::Duration};
+
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use glommio::{
+    enclose, executor, net::TcpListener, spawn_local, spawn_local_into,
+    Latency, Shares, Task,
+};
+use log::{error, trace};
+
+use crate::{
+    error::Result, metrics::render_metrics, shards::MyShard,
+    utils::timeout::timeout,
+};
+
+/// Requests with a larger header are dropped.
+const MAX_REQUEST_HEADER_SIZE: usize = 8192;
+
+/// Clients that don't send the whole header in time are dropped, so an idle
+/// connection doesn't hold a task forever.
+const READ_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+const METRICS_PATH: &str = "/metrics";
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Read until the end of the HTTP header, returns the request line.
+async fn read_request_line(
+    client: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<String>> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = client.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST_HEADER_SIZE {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    Ok(String::from_utf8_lossy(&request)
+        .lines()
+        .next()
+        .map(str::to_string))
+}
+
+async fn handle_metrics_client(
+    my_shard: Rc<MyShard>,
+    client: &mut (impl AsyncRead + AsyncWrite + Unpin),
+) -> Result<()> {
+    let request_line =
+        match timeout(READ_REQUEST_TIMEOUT, read_request_line(client)).await? {
+            Some(request_line) => request_line,
+            None => return Ok(()),
+        };
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(METRICS_PATH)) => {
+            ("200 OK", render_metrics(&my_shard.get_node_stats().await?))
+        }
+        _ => ("404 Not Found", String::new()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: {METRICS_CONTENT_TYPE}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    client.write_all(response.as_bytes()).await?;
+    client.close().await?;
+
+    Ok(())
+}
+
+async fn run_metrics_server(my_shard: Rc<MyShard>) -> Result<()> {
+    let address =
+        format!("{}:{}", my_shard.args.ip, my_shard.args.metrics_port);
+    let server = TcpListener::bind(address.as_str())?;
+    trace!("Listening for metrics requests on: {}", address);
+
+    loop {
+        match server.accept().await {
+            Ok(mut client) => {
+                spawn_local(
+                    enclose!((my_shard.clone() => my_shard) async move {
+                        let result =
+                            handle_metrics_client(my_shard, &mut client).await;
+                        if let Err(e) = result {
+                            error!("Failed to handle metrics client: {}", e);
+                        }
+                    }),
+                )
+                .detach();
+            }
+            Err(e) => {
+                error!("Failed to accept metrics client: {}", e);
+            }
+        }
+    }
+}
+
+pub fn spawn_metrics_server_task(my_shard: Rc<MyShard>) -> Task<Result<()>> {
+    let shares = my_shard.args.background_tasks_shares.into();
+    spawn_local_into(
+        async move {
+            let result = run_metrics_server(my_shard).await;
+            if let Err(e) = &result {
+                error!("Error starting metrics server: {}", e);
+            }
+            result
+        },
+        executor().create_task_queue(
+            Shares::Static(shares),
+            Latency::NotImportant,
+            "metrics-server",
+        ),
+    )
+    .unwrap()
+}
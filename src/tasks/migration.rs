@@ -1,16 +1,22 @@
-use std::{cmp::Ordering, net::Shutdown, rc::Rc, time::Duration};
+use std::{cmp::Ordering, rc::Rc, time::Duration};
 
 use async_channel::Sender;
-use glommio::{net::TcpStream, spawn_local, timer::sleep};
+use bincode::Options;
+use futures::AsyncWriteExt;
+use glommio::{spawn_local, timer::sleep};
 use log::error;
 
 use crate::{
     error::Result,
     messages::{ShardEvent, ShardMessage, ShardPacket},
+    metrics::{decrement, increment},
     notify_flow_event,
+    paxos::PaxosState,
     remote_shard_connection::send_message_to_stream,
     shards::{hash_bytes, MyShard, ShardConnection},
     storage_engine::{lsm_tree::LSMTree, Entry},
+    tls::RemoteShardStream,
+    utils::bincode::bincode_options,
 };
 
 #[cfg(feature = "flow-events")]
@@ -37,20 +43,28 @@ impl RangeAndAction {
 }
 
 enum Action {
-    Remote(TcpStream),
+    Remote(RemoteShardStream),
     Local(u16, Sender<ShardPacket>),
     Delete,
 }
 
 fn create_set_message(name: String, entry: Entry) -> ShardMessage {
+    let timestamp = entry.value.timestamp;
     ShardMessage::Event(ShardEvent::Set(
         name,
         entry.key,
-        entry.value.data,
-        entry.value.timestamp,
+        entry.value.into_data(),
+        timestamp,
     ))
 }
 
+fn in_ranges(key: &[u8], ranges: &[(u32, u32)]) -> Option<usize> {
+    let hash = hash_bytes(key).ok()?;
+    ranges
+        .iter()
+        .position(|(start, end)| between_cmp(hash, start, end))
+}
+
 fn between_cmp(hash: u32, start: &u32, end: &u32) -> bool {
     if end < start {
         hash.cmp(start) == Ordering::Less || hash.cmp(end) != Ordering::Less
@@ -60,6 +74,7 @@ fn between_cmp(hash: u32, start: &u32, end: &u32) -> bool {
 }
 
 async fn migrate_actions(
+    my_shard: &MyShard,
     collection_name: String,
     tree: Rc<LSMTree>,
     ranges_and_actions: &[RangeAndAction],
@@ -84,24 +99,11 @@ async fn migrate_actions(
     let ranges_clone = ranges.clone();
 
     let mut iter = tree.iter_filter(Box::new(move |key, _| {
-        hash_bytes(key)
-            .map(|hash| {
-                ranges_clone
-                    .iter()
-                    .any(|(start, end)| between_cmp(hash, start, end))
-            })
-            .unwrap_or(false)
+        in_ranges(key, &ranges_clone).is_some()
     }));
 
     while let Ok(Some(entry)) = iter.next().await {
-        let index = ranges
-            .iter()
-            .position(|(start, end)| {
-                hash_bytes(&entry.key)
-                    .map(|hash| between_cmp(hash, start, end))
-                    .unwrap_or(false)
-            })
-            .unwrap();
+        let index = in_ranges(&entry.key, &ranges).unwrap();
 
         let key = entry.key.clone();
         let msg = create_set_message(collection_name.clone(), entry);
@@ -109,19 +111,25 @@ async fn migrate_actions(
         match &mut actions[index] {
             Action::Remote(ref mut stream) => {
                 send_message_to_stream(stream, &msg).await?;
+                increment(&my_shard.metrics.migrated_entries, 1);
             }
             Action::Local(id, sender) => {
                 sender.send(ShardPacket::new(*id, msg)).await?;
+                increment(&my_shard.metrics.migrated_entries, 1);
             }
             Action::Delete => {
                 tree.clone().delete(key).await?;
+                increment(&my_shard.metrics.migration_deleted_entries, 1);
             }
         }
     }
 
+    migrate_paxos_state(my_shard, &collection_name, &ranges, &mut actions)
+        .await?;
+
     for action in actions {
-        if let Action::Remote(stream) = action {
-            if let Err(e) = stream.shutdown(Shutdown::Both).await {
+        if let Action::Remote(mut stream) = action {
+            if let Err(e) = stream.close().await {
                 error!("Error shutting down migration socket: {}", e);
             }
         }
@@ -130,6 +138,61 @@ async fn migrate_actions(
     Ok(())
 }
 
+/// Migrate the paxos state of the keys too, a new replica of a key must not
+/// forget the promises and accepted proposals of the replica it replaces.
+async fn migrate_paxos_state(
+    my_shard: &MyShard,
+    collection_name: &str,
+    ranges: &[(u32, u32)],
+    actions: &mut [Action],
+) -> Result<()> {
+    let paxos_tree = match my_shard.get_existing_paxos_tree().await? {
+        Some(tree) => tree,
+        None => return Ok(()),
+    };
+
+    let name = collection_name.to_string();
+    let ranges_clone = ranges.to_vec();
+    let mut iter = paxos_tree.iter_filter(Box::new(move |paxos_key, _| {
+        bincode_options()
+            .deserialize::<(String, Vec<u8>)>(paxos_key)
+            .map(|(collection, key)| {
+                collection == name && in_ranges(&key, &ranges_clone).is_some()
+            })
+            .unwrap_or(false)
+    }));
+
+    while let Some(entry) = iter.next().await? {
+        let (collection, key): (String, Vec<u8>) =
+            bincode_options().deserialize(&entry.key)?;
+        let data = match entry.value.into_data() {
+            Some(data) => data,
+            None => continue,
+        };
+        let index = in_ranges(&key, ranges).unwrap();
+
+        let state: PaxosState = bincode_options().deserialize(&data)?;
+        let msg =
+            ShardMessage::Event(ShardEvent::PaxosState(collection, key, state));
+
+        match &mut actions[index] {
+            Action::Remote(ref mut stream) => {
+                send_message_to_stream(stream, &msg).await?;
+            }
+            Action::Local(id, sender) => {
+                sender.send(ShardPacket::new(*id, msg)).await?;
+            }
+            Action::Delete => {
+                // No paxos requests of the key are sent to this shard
+                // anymore.
+                paxos_tree.clone().delete(entry.key).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
 pub fn spawn_migration_actions_tasks(
     my_shard: Rc<MyShard>,
     collections_to_ranges_and_actions: Vec<(String, Vec<RangeAndAction>)>,
@@ -144,23 +207,29 @@ pub fn spawn_migration_actions_tasks(
             .get(&collection_name)
             .map(|c| c.tree.clone())
         {
-            #[cfg(feature = "flow-events")]
-            let s = my_shard.clone();
+            let my_shard = my_shard.clone();
 
             spawn_local(async move {
+                increment(&my_shard.metrics.running_migrations, 1);
+
                 // Migration may happen before one of the new shards has fully
                 // initialized.
                 if let Some(duration) = sleep_duration {
                     sleep(duration).await;
                 }
 
-                let result =
-                    migrate_actions(collection_name, tree, &ranges_and_actions)
-                        .await;
+                let result = migrate_actions(
+                    &my_shard,
+                    collection_name,
+                    tree,
+                    &ranges_and_actions,
+                )
+                .await;
                 if let Err(e) = &result {
                     error!("Error migrating: {}", e);
                 }
-                notify_flow_event!(s, FlowEvent::DoneMigration);
+                decrement(&my_shard.metrics.running_migrations, 1);
+                notify_flow_event!(my_shard, FlowEvent::DoneMigration);
                 result
             })
             .detach();
@@ -1,8 +1,10 @@
+pub mod anti_entropy;
 pub mod compaction;
 pub mod db_server;
 pub mod failure_detector;
 pub mod gossip_server;
 pub mod local_shard_server;
+pub mod metrics_server;
 pub mod migration;
 pub mod remote_shard_server;
 pub mod stop_event_waiter;
@@ -14,6 +14,7 @@ use crate::{
         get_message_from_stream, send_message_to_stream,
     },
     shards::MyShard,
+    tls,
 };
 
 async fn handle_remote_shard_client(
@@ -65,8 +66,26 @@ async fn run_remote_shard_server(my_shard: Rc<MyShard>) -> Result<()> {
         match server.accept().await {
             Ok(mut client) => {
                 spawn_local(enclose!((my_shard.clone() => my_shard) async move {
-                    let result =
-                        handle_remote_shard_client(my_shard, &mut client).await;
+                    let result = match my_shard.tls.clone() {
+                        Some(tls) => {
+                            match tls::accept(&tls.remote_shard_acceptor, client)
+                                .await
+                            {
+                                Ok(mut client) => {
+                                    handle_remote_shard_client(
+                                        my_shard,
+                                        &mut client,
+                                    )
+                                    .await
+                                }
+                                Err(e) => Err(e),
+                            }
+                        }
+                        None => {
+                            handle_remote_shard_client(my_shard, &mut client)
+                                .await
+                        }
+                    };
                     if let Err(e) = result {
                         error!("Failed to handle remote shard client: {}", e);
                     }
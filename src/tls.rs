@@ -0,0 +1,209 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{AsyncRead, AsyncWrite};
+use futures_rustls::{client, server, TlsAcceptor, TlsConnector};
+use glommio::net::TcpStream;
+use rustls::{
+    server::AllowAnyAuthenticatedClient, Certificate, ClientConfig, PrivateKey,
+    RootCertStore, ServerConfig, ServerName,
+};
+use rustls_pemfile::Item;
+
+use crate::{
+    args::Args,
+    error::{Error, Result},
+    utils::timeout::timeout,
+};
+
+/// Handshakes that don't finish in time are dropped, so a client that
+/// connects and never sends a hello doesn't hold a task forever.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The TLS configuration of a shard, loaded once from the paths in `Args`.
+#[derive(Clone)]
+pub struct Tls {
+    /// Wraps connections accepted on the client (db server) listener.
+    pub client_acceptor: TlsAcceptor,
+
+    /// Wraps connections accepted on the remote shard listener, requires a
+    /// client certificate when mutual TLS is enabled.
+    pub remote_shard_acceptor: TlsAcceptor,
+
+    /// Wraps connections made to remote shards.
+    pub remote_shard_connector: TlsConnector,
+}
+
+impl Tls {
+    /// Returns None when no certificate was configured (TLS is disabled).
+    pub fn from_args(args: &Args) -> Result<Option<Self>> {
+        let (cert_path, key_path) = match (&args.tls_cert, &args.tls_key) {
+            (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+            (None, None) => return Ok(None),
+            _ => return Err(Error::TlsMissingCertOrKey),
+        };
+
+        let certs = load_certs(cert_path)?;
+        let key = load_private_key(key_path)?;
+
+        // Without a CA, the certificate is expected to be self-signed.
+        let roots =
+            load_root_store(args.tls_ca_cert.as_ref().unwrap_or(cert_path))?;
+
+        let client_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs.clone(), key.clone())?;
+
+        let remote_shard_builder = ServerConfig::builder().with_safe_defaults();
+        let remote_shard_config = if args.tls_mutual {
+            remote_shard_builder.with_client_cert_verifier(
+                AllowAnyAuthenticatedClient::new(roots.clone()).boxed(),
+            )
+        } else {
+            remote_shard_builder.with_no_client_auth()
+        }
+        .with_single_cert(certs.clone(), key.clone())?;
+
+        let connector_builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let connector_config = if args.tls_mutual {
+            connector_builder.with_client_auth_cert(certs, key)?
+        } else {
+            connector_builder.with_no_client_auth()
+        };
+
+        Ok(Some(Self {
+            client_acceptor: TlsAcceptor::from(Arc::new(client_config)),
+            remote_shard_acceptor: TlsAcceptor::from(Arc::new(
+                remote_shard_config,
+            )),
+            remote_shard_connector: TlsConnector::from(Arc::new(
+                connector_config,
+            )),
+        }))
+    }
+}
+
+/// Accept a TLS connection, failing when the handshake takes too long.
+pub async fn accept(
+    acceptor: &TlsAcceptor,
+    stream: TcpStream,
+) -> Result<server::TlsStream<TcpStream>> {
+    timeout(HANDSHAKE_TIMEOUT, async {
+        Ok(acceptor.accept(stream).await?)
+    })
+    .await
+}
+
+fn read_pem_items(path: &str) -> Result<Vec<Item>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    Ok(rustls_pemfile::read_all(&mut reader)?)
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>> {
+    let certs = read_pem_items(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    if certs.is_empty() {
+        return Err(Error::TlsNoCertificates(path.to_string()));
+    }
+
+    Ok(certs)
+}
+
+fn load_private_key(path: &str) -> Result<PrivateKey> {
+    read_pem_items(path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) => {
+                Some(PrivateKey(der))
+            }
+            _ => None,
+        })
+        .ok_or_else(|| Error::TlsNoPrivateKey(path.to_string()))
+}
+
+fn load_root_store(path: &str) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(&cert)?;
+    }
+    Ok(roots)
+}
+
+/// Parses the host part of an "host:port" address as the name to verify
+/// the remote certificate against.
+pub fn server_name_from_address(address: &str) -> Result<ServerName> {
+    let host = address
+        .rsplit_once(':')
+        .map_or(address, |(host, _)| host)
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    ServerName::try_from(host)
+        .map_err(|_| Error::TlsInvalidServerName(host.to_string()))
+}
+
+/// A connection to a remote shard, encrypted when TLS is enabled.
+pub enum RemoteShardStream {
+    Plain(TcpStream),
+    Tls(Box<client::TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for RemoteShardStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for RemoteShardStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_close(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_close(cx),
+        }
+    }
+}
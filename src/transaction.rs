@@ -0,0 +1,92 @@
+use crate::error::{Error, Result};
+
+/// A condition on the current value of a key, checked before any write of a
+/// transaction is applied.
+#[derive(Debug, Clone)]
+pub enum Condition {
+    Exists,
+    NotExists,
+
+    /// The key exists, and its encoded value is equal to this value.
+    Equals(Vec<u8>),
+}
+
+impl Condition {
+    pub fn parse(name: &str, expected: Option<Vec<u8>>) -> Result<Self> {
+        match (name, expected) {
+            ("exists", _) => Ok(Self::Exists),
+            ("not_exists", _) => Ok(Self::NotExists),
+            ("equals", Some(expected)) => Ok(Self::Equals(expected)),
+            ("equals", None) => {
+                Err(Error::MissingField("expected".to_string()))
+            }
+            _ => Err(Error::UnsupportedField(name.to_string())),
+        }
+    }
+
+    /// Whether the current value (None when the key doesn't exist) passes
+    /// the condition.
+    #[must_use]
+    pub fn matches(&self, value: Option<&[u8]>) -> bool {
+        match self {
+            Self::Exists => value.is_some(),
+            Self::NotExists => value.is_none(),
+            Self::Equals(expected) => value == Some(&expected[..]),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Write {
+    Set(Vec<u8>),
+    Delete,
+}
+
+impl Write {
+    /// The value to write to the collection, None deletes the key.
+    #[must_use]
+    pub fn into_value(self) -> Option<Vec<u8>> {
+        match self {
+            Self::Set(value) => Some(value),
+            Self::Delete => None,
+        }
+    }
+}
+
+/// A single operation of a transaction on a key.
+#[derive(Debug, Clone)]
+pub struct Operation {
+    pub key: Vec<u8>,
+
+    /// None only checks the condition.
+    pub write: Option<Write>,
+    pub condition: Option<Condition>,
+}
+
+impl Operation {
+    pub fn parse(
+        op: &str,
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+        condition: Option<Condition>,
+    ) -> Result<Self> {
+        let write = match (op, value) {
+            ("set", Some(value)) => Some(Write::Set(value)),
+            ("set", None) => {
+                return Err(Error::MissingField("value".to_string()))
+            }
+            ("delete", _) => Some(Write::Delete),
+            ("check", _) if condition.is_some() => None,
+            ("check", _) => {
+                return Err(Error::MissingField("condition".to_string()))
+            }
+            _ => return Err(Error::UnsupportedField(op.to_string())),
+        };
+
+        Ok(Self {
+            key,
+            write,
+            condition,
+        })
+    }
+}
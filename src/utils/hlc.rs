@@ -0,0 +1,80 @@
+//! A hybrid logical clock: timestamps follow the wall clock, but are always
+//! greater than every timestamp generated or observed before, so a write
+//! always wins over the writes its coordinator has seen, even when the clocks
+//! of the nodes are skewed.
+//!
+//! The logical counter is folded into the nanoseconds of the timestamp,
+//! keeping the encoding of `timestamp_nanos`, so timestamps generated by the
+//! clock are ordered correctly with timestamps of existing data.
+
+use std::{cell::Cell, time::Duration};
+
+use time::OffsetDateTime;
+
+/// Timestamps further than this into the future are not observed, so a node
+/// with a broken clock can't drag the clocks of the whole cluster with it.
+const MAX_CLOCK_DRIFT: Duration = Duration::from_secs(60);
+
+pub struct HybridLogicalClock {
+    last: Cell<OffsetDateTime>,
+}
+
+impl Default for HybridLogicalClock {
+    fn default() -> Self {
+        Self {
+            last: Cell::new(OffsetDateTime::UNIX_EPOCH),
+        }
+    }
+}
+
+impl HybridLogicalClock {
+    /// A timestamp greater than all timestamps generated or observed before.
+    #[must_use]
+    pub fn now(&self) -> OffsetDateTime {
+        let timestamp = OffsetDateTime::now_utc()
+            .max(self.last.get() + Duration::from_nanos(1));
+        self.last.set(timestamp);
+        timestamp
+    }
+
+    /// Observe a timestamp generated by another shard.
+    pub fn update(&self, timestamp: OffsetDateTime) {
+        if timestamp > self.last.get()
+            && timestamp <= OffsetDateTime::now_utc() + MAX_CLOCK_DRIFT
+        {
+            self.last.set(timestamp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn now_always_increases() {
+        let clock = HybridLogicalClock::default();
+        let mut last = clock.now();
+        for _ in 0..1000 {
+            let timestamp = clock.now();
+            assert!(timestamp > last);
+            last = timestamp;
+        }
+    }
+
+    #[test]
+    fn now_is_after_observed_timestamps() {
+        let clock = HybridLogicalClock::default();
+        let remote = OffsetDateTime::now_utc() + Duration::from_secs(10);
+        clock.update(remote);
+        assert!(clock.now() > remote);
+    }
+
+    #[test]
+    fn far_future_timestamps_are_ignored() {
+        let clock = HybridLogicalClock::default();
+        let remote = OffsetDateTime::now_utc() + MAX_CLOCK_DRIFT * 2;
+        clock.update(remote);
+        assert!(clock.now() < remote);
+    }
+}
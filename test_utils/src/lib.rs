@@ -6,7 +6,7 @@ use dbeel::{
     flow_events::FlowEvent,
     local_shard::LocalShardConnection,
     run_shard::{create_shard, run_shard},
-    shards::MyShard,
+    shards::{Incarnation, MyShard},
     utils::timeout::timeout,
 };
 
@@ -51,8 +51,12 @@ where
     let handle = builder.name("test").spawn(|| async move {
         let run_test = async {
             let id = 0;
-            let shard =
-                create_shard(args, id, vec![LocalShardConnection::new(id)]);
+            let shard = create_shard(
+                args,
+                id,
+                vec![LocalShardConnection::new(id)],
+                Incarnation::default(),
+            )?;
             let start_event_receiver =
                 shard.subscribe_to_flow_event(FlowEvent::StartTasks.into());
             let shard_run_handle =
@@ -97,12 +101,18 @@ where
                 .map(LocalShardConnection::new)
                 .collect::<Vec<_>>();
 
+            let incarnation = Incarnation::default();
             let mut shards = (0..number_of_shards)
                 .map(|id| {
-                    create_shard(args.clone(), id, local_connections.clone())
+                    create_shard(
+                        args.clone(),
+                        id,
+                        local_connections.clone(),
+                        incarnation.clone(),
+                    )
                 })
                 .rev()
-                .collect::<Vec<_>>();
+                .collect::<Result<Vec<_>>>()?;
 
             let start_event_receivers =
                 subscribe_to_flow_events(&shards, FlowEvent::StartTasks);
@@ -177,6 +187,7 @@ pub fn next_node_args(
     args.remote_shard_port += number_of_shards;
     args.port += number_of_shards;
     args.gossip_port += number_of_shards;
+    args.metrics_port += number_of_shards;
     args.name = name;
     args
 }
@@ -0,0 +1,268 @@
+use std::{collections::BTreeMap, path::Path, sync::Once};
+
+use dbeel::{
+    args::{parse_args_from, Args},
+    error::Result,
+    metrics::CollectionShardStats,
+    storage_engine::{
+        sstable_builder::SSTableBuilder, Entry, EntryKind, EntryValue,
+    },
+};
+use dbeel_client::DbeelClient;
+use rmpv::{encode::write_value, Value};
+use rstest::{fixture, rstest};
+use serial_test::serial;
+use test_utils::{install_logger, test_node};
+use time::OffsetDateTime;
+
+static ONCE: Once = Once::new();
+
+#[fixture]
+fn args() -> Args {
+    ONCE.call_once(|| {
+        install_logger();
+    });
+
+    // Remove the test directory if it exists.
+    let _ = std::fs::remove_dir_all("/tmp/test");
+    parse_args_from(["", "--dir", "/tmp/test"])
+}
+
+#[rstest]
+#[serial]
+fn node_status_and_shard_stats(args: Args) -> Result<()> {
+    test_node(2, args, |shard, _| async move {
+        let client = DbeelClient::from_seed_nodes(&[(
+            shard.args.ip.clone(),
+            shard.args.port,
+        )])
+        .await
+        .unwrap();
+        client.create_collection("test").await.unwrap();
+
+        let status = client.node_status().await.unwrap();
+        assert_eq!(status.name, shard.args.name);
+        assert!(status.nodes.is_empty());
+        assert_eq!(
+            status
+                .shards
+                .iter()
+                .map(|s| s.shard_name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["dbeel-0", "dbeel-1"]
+        );
+        for shard_status in &status.shards {
+            assert_eq!(shard_status.owned_ranges.len(), 1);
+            assert_eq!(shard_status.running_migrations, 0);
+        }
+
+        let stats = client.shard_stats().await.unwrap();
+        assert_eq!(stats.len(), 2);
+        assert!(stats.iter().all(|s| s.collections.contains_key("test")));
+    })?
+    .join()?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn flush_and_compact_collection(args: Args) -> Result<()> {
+    test_node(2, args, |shard, _| async move {
+        let client = DbeelClient::from_seed_nodes(&[(
+            shard.args.ip.clone(),
+            shard.args.port,
+        )])
+        .await
+        .unwrap();
+        let collection = client.create_collection("test").await.unwrap();
+
+        let total_sstables = |stats: &[CollectionShardStats]| {
+            stats.iter().map(|s| s.sstables.len()).sum::<usize>()
+        };
+
+        for round in 0..2 {
+            for i in 0..10 {
+                collection
+                    .set_from_str_key(
+                        format!("key{round}-{i}").as_str(),
+                        Value::Boolean(true),
+                    )
+                    .await
+                    .unwrap();
+            }
+
+            let stats = collection.stats().await.unwrap();
+            assert_eq!(stats.len(), 2);
+            assert_eq!(
+                stats.iter().map(|s| s.memtable_entries).sum::<u64>(),
+                10
+            );
+            assert!(stats
+                .iter()
+                .all(|s| s.memtable_entries == 0 || s.wal_bytes > 0));
+
+            collection.flush().await.unwrap();
+
+            let stats = collection.stats().await.unwrap();
+            assert!(stats.iter().all(|s| s.memtable_entries == 0));
+            assert!(total_sstables(&stats) >= 1);
+        }
+
+        collection.compact().await.unwrap();
+
+        let stats = collection.stats().await.unwrap();
+        assert!(stats.iter().all(|s| s.sstables.len() <= 1));
+        assert!(total_sstables(&stats) >= 1);
+
+        for round in 0..2 {
+            for i in 0..10 {
+                assert_eq!(
+                    collection
+                        .get_from_str_key(format!("key{round}-{i}").as_str())
+                        .await
+                        .unwrap(),
+                    Value::Boolean(true)
+                );
+            }
+        }
+    })?
+    .join()?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn list_collections(args: Args) -> Result<()> {
+    test_node(2, args, |shard, _| async move {
+        let client = DbeelClient::from_seed_nodes(&[(
+            shard.args.ip.clone(),
+            shard.args.port,
+        )])
+        .await
+        .unwrap();
+        client.create_collection("b").await.unwrap();
+        let collection = client.create_collection("a").await.unwrap();
+        for i in 0..10 {
+            collection
+                .set_from_str_key(
+                    format!("key{i}").as_str(),
+                    Value::Boolean(true),
+                )
+                .await
+                .unwrap();
+        }
+        collection.flush().await.unwrap();
+
+        let summaries = client.list_collections().await.unwrap();
+        assert_eq!(
+            summaries
+                .iter()
+                .map(|s| s.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["a", "b"]
+        );
+        assert_eq!(summaries[0].approximate_documents, 10);
+        assert!(summaries[0].disk_bytes > 0);
+        assert_eq!(summaries[1].approximate_documents, 0);
+        assert!(
+            summaries[0].metadata.created_at
+                >= summaries[1].metadata.created_at
+        );
+    })?
+    .join()?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn count_collection(args: Args) -> Result<()> {
+    test_node(2, args, |shard, _| async move {
+        let client = DbeelClient::from_seed_nodes(&[(
+            shard.args.ip.clone(),
+            shard.args.port,
+        )])
+        .await
+        .unwrap();
+        let collection = client.create_collection("test").await.unwrap();
+        assert_eq!(collection.count().await.unwrap(), 0);
+
+        for i in 0..100 {
+            collection
+                .set_from_str_key(
+                    format!("key{i}").as_str(),
+                    Value::Boolean(true),
+                )
+                .await
+                .unwrap();
+        }
+        collection.flush().await.unwrap();
+        for i in 0..10 {
+            collection
+                .delete_from_str_key(format!("key{i}").as_str())
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(collection.count().await.unwrap(), 90);
+    })?
+    .join()?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn ingest_sstables(args: Args) -> Result<()> {
+    test_node(1, args, |shard, _| async move {
+        let client = DbeelClient::from_seed_nodes(&[(
+            shard.args.ip.clone(),
+            shard.args.port,
+        )])
+        .await
+        .unwrap();
+        let collection = client.create_collection("test").await.unwrap();
+
+        let dir = "/tmp/test_ingest";
+        let _ = std::fs::remove_dir_all(dir);
+
+        let mut documents = BTreeMap::new();
+        for i in 0..10 {
+            let (mut key, mut value) = (Vec::new(), Vec::new());
+            write_value(&mut key, &Value::String(format!("key{i}").into()))
+                .unwrap();
+            write_value(&mut value, &Value::from(i)).unwrap();
+            documents.insert(key, value);
+        }
+
+        let mut builder = SSTableBuilder::create(
+            &Path::new(dir).join(&shard.shard_name),
+            0,
+            documents.len(),
+        )
+        .unwrap();
+        for (key, data) in documents {
+            let value = EntryValue {
+                data,
+                timestamp: OffsetDateTime::now_utc(),
+                kind: EntryKind::Put,
+            };
+            builder.add(&Entry { key, value }).await.unwrap();
+        }
+        builder.finish().await.unwrap();
+
+        assert_eq!(collection.ingest(dir).await.unwrap(), 1);
+        assert_eq!(
+            collection.get_from_str_key("key3").await.unwrap(),
+            Value::from(3)
+        );
+        assert_eq!(collection.count().await.unwrap(), 10);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    })?
+    .join()?;
+
+    Ok(())
+}
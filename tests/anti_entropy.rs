@@ -0,0 +1,161 @@
+use std::sync::Once;
+
+use dbeel::{
+    args::{parse_args_from, Args},
+    error::Result,
+    flow_events::FlowEvent,
+};
+use rstest::{fixture, rstest};
+use serial_test::serial;
+use test_utils::{install_logger, next_node_args, test_node};
+
+static ONCE: Once = Once::new();
+
+#[fixture]
+fn args() -> Args {
+    ONCE.call_once(|| {
+        install_logger();
+    });
+
+    // Remove the test directories if it exists.
+    let _ = std::fs::remove_dir_all("/tmp/test");
+    let _ = std::fs::remove_dir_all("/tmp/test1");
+    parse_args_from(["", "--dir", "/tmp/test", "--anti-entropy-interval", "50"])
+}
+
+#[rstest]
+#[serial]
+fn sync_missed_collection_creation(args: Args) -> Result<()> {
+    let (seed_sender, seed_receiver) = async_channel::bounded(1);
+    let (done_sender, done_receiver) = async_channel::bounded(1);
+
+    let first_handle =
+        test_node(1, args.clone(), move |shard, _| async move {
+            seed_sender
+                .send(vec![format!(
+                    "{}:{}",
+                    shard.args.ip,
+                    shard.args.remote_shard_port + shard.id
+                )])
+                .await
+                .unwrap();
+            while shard.nodes.borrow().is_empty() {
+                let receiver = shard
+                    .subscribe_to_flow_event(FlowEvent::AliveNodeGossip.into());
+                receiver.recv().await.unwrap();
+            }
+
+            // Create the collection without gossiping, as if the gossip
+            // message got lost.
+            shard
+                .create_collection("test".to_string(), 1)
+                .await
+                .unwrap();
+
+            done_receiver.recv().await.unwrap();
+        })?;
+
+    let mut second_args = next_node_args(args, "second".to_string(), 1);
+    second_args.dir = "/tmp/test1".to_string();
+    second_args.seed_nodes = seed_receiver.recv_blocking()?;
+
+    let second_handle =
+        test_node(1, second_args, move |shard, _| async move {
+            while !shard.collections.borrow().contains_key("test") {
+                let receiver = shard.subscribe_to_flow_event(
+                    FlowEvent::CollectionCreated.into(),
+                );
+                receiver.recv().await.unwrap();
+            }
+
+            assert_eq!(
+                shard.collections.borrow()["test"]
+                    .metadata
+                    .replication_factor,
+                1
+            );
+
+            done_sender.send(()).await.unwrap();
+        })?;
+
+    second_handle.join()?;
+    first_handle.join()?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn sync_missed_collection_drop(args: Args) -> Result<()> {
+    let (seed_sender, seed_receiver) = async_channel::bounded(1);
+    let (created_sender, created_receiver) = async_channel::bounded(1);
+    let (dropped_sender, dropped_receiver) = async_channel::bounded(1);
+    let (done_sender, done_receiver) = async_channel::bounded(1);
+
+    let first_handle =
+        test_node(1, args.clone(), move |shard, _| async move {
+            seed_sender
+                .send(vec![format!(
+                    "{}:{}",
+                    shard.args.ip,
+                    shard.args.remote_shard_port + shard.id
+                )])
+                .await
+                .unwrap();
+            shard
+                .create_collection("test".to_string(), 1)
+                .await
+                .unwrap();
+            created_receiver.recv().await.unwrap();
+
+            // Drop the collection without gossiping, as if the gossip
+            // message got lost.
+            shard
+                .drop_collection("test", shard.clock.now())
+                .await
+                .unwrap();
+
+            dropped_receiver.recv().await.unwrap();
+
+            // The second node doesn't create the collection again.
+            for _ in 0..2 {
+                let receiver = shard.subscribe_to_flow_event(
+                    FlowEvent::ClusterMetadataReconciled.into(),
+                );
+                receiver.recv().await.unwrap();
+            }
+            assert!(!shard.collections.borrow().contains_key("test"));
+
+            done_sender.send(()).await.unwrap();
+        })?;
+
+    let mut second_args = next_node_args(args, "second".to_string(), 1);
+    second_args.dir = "/tmp/test1".to_string();
+    second_args.seed_nodes = seed_receiver.recv_blocking()?;
+
+    let second_handle =
+        test_node(1, second_args, move |shard, _| async move {
+            while !shard.collections.borrow().contains_key("test") {
+                let receiver = shard.subscribe_to_flow_event(
+                    FlowEvent::CollectionCreated.into(),
+                );
+                receiver.recv().await.unwrap();
+            }
+            created_sender.send(()).await.unwrap();
+
+            while shard.collections.borrow().contains_key("test") {
+                let receiver = shard.subscribe_to_flow_event(
+                    FlowEvent::ClusterMetadataReconciled.into(),
+                );
+                receiver.recv().await.unwrap();
+            }
+
+            dropped_sender.send(()).await.unwrap();
+            done_receiver.recv().await.unwrap();
+        })?;
+
+    second_handle.join()?;
+    first_handle.join()?;
+
+    Ok(())
+}
@@ -0,0 +1,134 @@
+use std::sync::Once;
+
+use dbeel::{
+    args::{parse_args_from, Args},
+    auth::Permission,
+    error::{Error, Result},
+    tasks::db_server::ResponseError,
+};
+use dbeel_client::{self, DbeelClient};
+use rmpv::Value;
+use rstest::{fixture, rstest};
+use serial_test::serial;
+use test_utils::{install_logger, test_shard};
+
+static ONCE: Once = Once::new();
+
+fn response_equals_error(
+    response: dbeel_client::error::Error,
+    error: &Error,
+) -> bool {
+    if let dbeel_client::error::Error::SendRequestToCluster(errors) = response {
+        let re = ResponseError::new(error);
+        errors.iter().all(|e| {
+            if let dbeel_client::error::Error::ServerErr(name, message) = e {
+                name == &re.name && message == &re.message
+            } else {
+                panic!("Expected server error");
+            }
+        })
+    } else {
+        panic!("Expected cluster request error");
+    }
+}
+
+#[fixture]
+fn args() -> Args {
+    ONCE.call_once(|| {
+        install_logger();
+    });
+
+    // Remove the test directory if it exists.
+    let _ = std::fs::remove_dir_all("/tmp/test");
+    parse_args_from([
+        "",
+        "--dir",
+        "/tmp/test",
+        "--auth",
+        "--root-password",
+        "pw",
+    ])
+}
+
+#[rstest]
+#[serial]
+fn reject_unauthenticated(args: Args) -> Result<()> {
+    test_shard(args, |shard| async move {
+        let address = (shard.args.ip.clone(), shard.args.port);
+
+        assert!(response_equals_error(
+            DbeelClient::from_seed_nodes(std::slice::from_ref(&address))
+                .await
+                .unwrap_err(),
+            &Error::Unauthenticated
+        ));
+
+        assert!(response_equals_error(
+            DbeelClient::from_seed_nodes_with_credentials(
+                &[address],
+                Some(("root", "wrong"))
+            )
+            .await
+            .unwrap_err(),
+            &Error::InvalidCredentials
+        ));
+    })?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn collection_permissions(args: Args) -> Result<()> {
+    test_shard(args, |shard| async move {
+        let address = (shard.args.ip.clone(), shard.args.port);
+
+        let root = DbeelClient::from_seed_nodes_with_credentials(
+            std::slice::from_ref(&address),
+            Some(("root", "pw")),
+        )
+        .await
+        .unwrap();
+        let collection = root.create_collection("test").await.unwrap();
+        collection
+            .set_from_str_key("key", Value::Boolean(true))
+            .await
+            .unwrap();
+        root.create_user("reader", "reader_pw", &[("test", Permission::Read)])
+            .await
+            .unwrap();
+
+        let reader = DbeelClient::from_seed_nodes_with_credentials(
+            &[address],
+            Some(("reader", "reader_pw")),
+        )
+        .await
+        .unwrap();
+        let collection = reader.collection("test").await.unwrap();
+        assert_eq!(
+            collection.get_from_str_key("key").await.unwrap(),
+            Value::Boolean(true)
+        );
+        assert!(response_equals_error(
+            collection
+                .set_from_str_key("key", Value::Boolean(false))
+                .await
+                .unwrap_err(),
+            &Error::PermissionDenied("test".to_string())
+        ));
+        assert!(response_equals_error(
+            reader.create_collection("other").await.unwrap_err(),
+            &Error::PermissionDenied("other".to_string())
+        ));
+
+        root.grant("reader", "test", Permission::Write)
+            .await
+            .unwrap();
+        collection
+            .set_from_str_key("key", Value::Boolean(false))
+            .await
+            .unwrap();
+    })?;
+
+    Ok(())
+}
@@ -3,6 +3,7 @@ use std::{sync::Once, time::Duration};
 use dbeel::{
     args::{parse_args_from, Args},
     error::Result,
+    shards::{ConflictResolution, DEFAULT_GC_GRACE_SECONDS},
 };
 use dbeel_client::DbeelClient;
 use rstest::{fixture, rstest};
@@ -65,3 +66,69 @@ fn find_collections_after_rerun(args: Args) -> Result<()> {
 
     Ok(())
 }
+
+#[rstest]
+#[serial]
+fn find_altered_collection_after_rerun(args: Args) -> Result<()> {
+    test_shard(args.clone(), |shard| async move {
+        let client = DbeelClient::from_seed_nodes(&[(
+            shard.args.ip.clone(),
+            shard.args.port,
+        )])
+        .await
+        .unwrap();
+
+        let mut collection = client.create_collection("test").await.unwrap();
+        collection.set_replication_factor(3).await.unwrap();
+
+        let metadata = shard.get_collection("test").unwrap().metadata;
+        assert_eq!(metadata.replication_factor, 3);
+        assert_eq!(metadata.version, 1);
+    })?;
+
+    test_shard(args, |shard| async move {
+        let metadata = shard.get_collection("test").unwrap().metadata;
+        assert_eq!(metadata.replication_factor, 3);
+        assert_eq!(metadata.version, 1);
+    })?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn find_legacy_collection(args: Args) -> Result<()> {
+    // Saved before collections had options, only the replication factor.
+    std::fs::create_dir_all(format!("{}/test-0", args.dir))?;
+    std::fs::write(format!("{}/test.metadata", args.dir), 2u16.to_le_bytes())?;
+
+    test_shard(args, |shard| async move {
+        let metadata = shard.get_collection("test").unwrap().metadata;
+        assert_eq!(metadata.replication_factor, 2);
+        assert_eq!(metadata.version, 0);
+        assert_eq!(metadata.conflict_resolution, ConflictResolution::default());
+        assert_eq!(metadata.gc_grace_seconds, DEFAULT_GC_GRACE_SECONDS);
+    })
+}
+
+#[rstest]
+#[serial]
+fn find_collection_saved_before_gc_grace(args: Args) -> Result<()> {
+    // Saved with a version and conflict resolution, but without any of the
+    // fields added after them.
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&2u16.to_le_bytes());
+    bytes.extend_from_slice(&3u64.to_le_bytes());
+    bytes.extend_from_slice(&1u32.to_le_bytes());
+    std::fs::create_dir_all(format!("{}/test-0", args.dir))?;
+    std::fs::write(format!("{}/test.metadata", args.dir), bytes)?;
+
+    test_shard(args, |shard| async move {
+        let metadata = shard.get_collection("test").unwrap().metadata;
+        assert_eq!(metadata.replication_factor, 2);
+        assert_eq!(metadata.version, 3);
+        assert_eq!(metadata.conflict_resolution, ConflictResolution::Siblings);
+        assert_eq!(metadata.gc_grace_seconds, DEFAULT_GC_GRACE_SECONDS);
+        assert_eq!(metadata.truncated_at, None);
+    })
+}
@@ -3,13 +3,18 @@ use std::sync::Once;
 use dbeel::{
     args::{parse_args_from, Args},
     error::{Error, Result},
+    shards::{CollectionMetadata, ConflictResolution},
     tasks::db_server::ResponseError,
 };
-use dbeel_client::{self, DbeelClient};
-use rmpv::Value;
+use dbeel_client::{
+    self, ChangeCursor, Condition, DbeelClient, TransactionOperation,
+};
+use futures::{future::join_all, StreamExt};
+use rmpv::{encode::write_value, Value};
 use rstest::{fixture, rstest};
 use serial_test::serial;
 use test_utils::{install_logger, test_shard};
+use time::OffsetDateTime;
 
 const ASSERT_AMOUNT_OF_TIMES: usize = 3;
 
@@ -91,6 +96,65 @@ fn drop_collection(args: Args) -> Result<()> {
     Ok(())
 }
 
+#[rstest]
+#[serial]
+fn truncate_collection(args: Args) -> Result<()> {
+    test_shard(args, |shard| async move {
+        let client = DbeelClient::from_seed_nodes(&[(
+            shard.args.ip.clone(),
+            shard.args.port,
+        )])
+        .await
+        .unwrap();
+
+        let collection = client.create_collection("test").await.unwrap();
+        collection
+            .set_from_str_key("key", Value::F32(100.0))
+            .await
+            .unwrap();
+        collection.truncate().await.unwrap();
+
+        assert!(response_equals_error(
+            collection.get_from_str_key("key").await.unwrap_err(),
+            &Error::KeyNotFound,
+        ));
+
+        // A write from before the truncate that reaches the shard late (e.g.
+        // replicated by a replica that didn't truncate yet) is dropped.
+        let truncated = shard.get_collection("test").unwrap();
+        let truncated_at = truncated.metadata.truncated_at.unwrap();
+        let (mut key, mut value) = (Vec::new(), Vec::new());
+        write_value(&mut key, &Value::String("key".into())).unwrap();
+        write_value(&mut value, &Value::F32(150.0)).unwrap();
+        shard
+            .write_to_collection(
+                "test",
+                &truncated,
+                key,
+                Some(value),
+                truncated_at,
+            )
+            .await
+            .unwrap();
+        assert!(response_equals_error(
+            collection.get_from_str_key("key").await.unwrap_err(),
+            &Error::KeyNotFound,
+        ));
+
+        // The collection is still usable after being truncated.
+        collection
+            .set_from_str_key("key", Value::F32(200.0))
+            .await
+            .unwrap();
+        assert_eq!(
+            collection.get_from_str_key("key").await.unwrap(),
+            Value::F32(200.0)
+        );
+    })?;
+
+    Ok(())
+}
+
 #[rstest]
 #[serial]
 fn get_non_existing_key(args: Args) -> Result<()> {
@@ -212,6 +276,267 @@ fn delete_and_get_key(args: Args) -> Result<()> {
     Ok(())
 }
 
+#[rstest]
+#[serial]
+fn transaction(args: Args) -> Result<()> {
+    test_shard(args.clone(), |shard| async move {
+        let client = DbeelClient::from_seed_nodes(&[(
+            shard.args.ip.clone(),
+            shard.args.port,
+        )])
+        .await
+        .unwrap();
+
+        let collection = client.create_collection("test").await.unwrap();
+        collection
+            .set_from_str_key("a", Value::from(1))
+            .await
+            .unwrap();
+
+        collection
+            .transaction(vec![
+                TransactionOperation::check(
+                    "a".into(),
+                    Condition::Equals(Value::from(1)),
+                ),
+                TransactionOperation::set("b".into(), Value::from(2))
+                    .when(Condition::NotExists),
+                TransactionOperation::delete("a".into()),
+            ])
+            .await
+            .unwrap();
+
+        // "b" exists now, nothing is written.
+        let response = collection
+            .transaction(vec![
+                TransactionOperation::set("c".into(), Value::from(3)),
+                TransactionOperation::set("b".into(), Value::from(4))
+                    .when(Condition::NotExists),
+            ])
+            .await;
+        assert!(response_equals_error(
+            response.unwrap_err(),
+            &Error::TransactionConditionFailed(1)
+        ));
+    })?;
+
+    // The transaction is replayed from the WAL.
+    test_shard(args, |shard| async move {
+        let client = DbeelClient::from_seed_nodes(&[(
+            shard.args.ip.clone(),
+            shard.args.port,
+        )])
+        .await
+        .unwrap();
+
+        let collection = client.collection("test").await.unwrap();
+        assert_eq!(
+            collection.get_from_str_key("b").await.unwrap(),
+            Value::from(2)
+        );
+        for key in ["a", "c"] {
+            assert!(response_equals_error(
+                collection.get_from_str_key(key).await.unwrap_err(),
+                &Error::KeyNotFound
+            ));
+        }
+    })?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn compare_and_set(args: Args) -> Result<()> {
+    test_shard(args, |shard| async move {
+        let client = DbeelClient::from_seed_nodes(&[(
+            shard.args.ip.clone(),
+            shard.args.port,
+        )])
+        .await
+        .unwrap();
+
+        let collection = client.create_collection("test").await.unwrap();
+
+        collection
+            .compare_and_set(
+                TransactionOperation::set("counter".into(), Value::from(0))
+                    .when(Condition::NotExists),
+            )
+            .await
+            .unwrap();
+        assert!(response_equals_error(
+            collection
+                .compare_and_set(
+                    TransactionOperation::set("counter".into(), Value::from(1))
+                        .when(Condition::NotExists),
+                )
+                .await
+                .unwrap_err(),
+            &Error::ConditionFailed
+        ));
+
+        // Concurrent increments, none of them is lost.
+        join_all((0..5).map(|_| async {
+            loop {
+                let value = collection.get_from_str_key("counter").await;
+                let value = value.unwrap().as_u64().unwrap();
+                let result = collection
+                    .compare_and_set(
+                        TransactionOperation::set(
+                            "counter".into(),
+                            Value::from(value + 1),
+                        )
+                        .when(Condition::Equals(Value::from(value))),
+                    )
+                    .await;
+                match result {
+                    Ok(()) => break,
+                    Err(e) => {
+                        assert!(response_equals_error(
+                            e,
+                            &Error::ConditionFailed
+                        ));
+                    }
+                }
+            }
+        }))
+        .await;
+        assert_eq!(
+            collection.get_from_str_key("counter").await.unwrap(),
+            Value::from(5)
+        );
+
+        collection
+            .compare_and_set(
+                TransactionOperation::delete("counter".into())
+                    .when(Condition::Equals(Value::from(5))),
+            )
+            .await
+            .unwrap();
+        assert!(response_equals_error(
+            collection.get_from_str_key("counter").await.unwrap_err(),
+            &Error::KeyNotFound
+        ));
+    })?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn siblings(args: Args) -> Result<()> {
+    test_shard(args, |shard| async move {
+        let client = DbeelClient::from_seed_nodes(&[(
+            shard.args.ip.clone(),
+            shard.args.port,
+        )])
+        .await
+        .unwrap();
+
+        let collection = client
+            .create_collection_ex("test", 1, ConflictResolution::Siblings)
+            .await
+            .unwrap();
+
+        // Writes without a context are concurrent, both values are kept.
+        for value in [1, 2] {
+            collection
+                .set_from_str_key("key", Value::from(value))
+                .await
+                .unwrap();
+        }
+        let siblings = collection.get_siblings("key".into()).await.unwrap();
+        assert_eq!(siblings.values, vec![Value::from(1), Value::from(2)]);
+
+        // Writing with the context of the read resolves the siblings.
+        collection
+            .set_with_context("key".into(), Value::from(3), siblings.context)
+            .await
+            .unwrap();
+        let siblings = collection.get_siblings("key".into()).await.unwrap();
+        assert_eq!(siblings.values, vec![Value::from(3)]);
+
+        collection
+            .delete_with_context("key".into(), siblings.context)
+            .await
+            .unwrap();
+        assert!(response_equals_error(
+            collection.get_siblings("key".into()).await.unwrap_err(),
+            &Error::KeyNotFound
+        ));
+    })?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn crdts(args: Args) -> Result<()> {
+    test_shard(args, |shard| async move {
+        let client = DbeelClient::from_seed_nodes(&[(
+            shard.args.ip.clone(),
+            shard.args.port,
+        )])
+        .await
+        .unwrap();
+
+        let counters = client
+            .create_collection_ex("counters", 1, ConflictResolution::Counter)
+            .await
+            .unwrap();
+        join_all((0..5).map(|_| counters.increment("views".into(), 2))).await;
+        counters.increment("views".into(), -3).await.unwrap();
+        assert_eq!(counters.get_counter("views".into()).await.unwrap(), 7);
+        assert!(response_equals_error(
+            counters
+                .set("views".into(), Value::from(0))
+                .await
+                .unwrap_err(),
+            &Error::NotSupportedByConflictResolution(
+                "set".to_string(),
+                "counter"
+            )
+        ));
+
+        let sets = client
+            .create_collection_ex("sets", 1, ConflictResolution::Set)
+            .await
+            .unwrap();
+        for tag in ["a", "b", "c"] {
+            sets.add("tags".into(), tag.into()).await.unwrap();
+        }
+        sets.remove("tags".into(), "b".into()).await.unwrap();
+        assert_eq!(
+            sets.get_set("tags".into()).await.unwrap(),
+            vec![Value::from("a"), Value::from("c")]
+        );
+
+        let maps = client
+            .create_collection_ex("maps", 1, ConflictResolution::Map)
+            .await
+            .unwrap();
+        maps.put_field("user".into(), "name".into(), "dbeel".into())
+            .await
+            .unwrap();
+        maps.put_field("user".into(), "age".into(), Value::from(1))
+            .await
+            .unwrap();
+        maps.put_field("user".into(), "age".into(), Value::from(2))
+            .await
+            .unwrap();
+        maps.remove_field("user".into(), "name".into())
+            .await
+            .unwrap();
+        assert_eq!(
+            maps.get_map("user".into()).await.unwrap(),
+            vec![(Value::from("age"), Value::from(2))]
+        );
+    })?;
+
+    Ok(())
+}
+
 #[rstest]
 #[serial]
 fn multiple_collections(args: Args) -> Result<()> {
@@ -265,3 +590,301 @@ fn multiple_collections(args: Args) -> Result<()> {
 
     Ok(())
 }
+
+#[rstest]
+#[serial]
+fn scan_collection(args: Args) -> Result<()> {
+    test_shard(args, |shard| async move {
+        let client = DbeelClient::from_seed_nodes(&[(
+            shard.args.ip.clone(),
+            shard.args.port,
+        )])
+        .await
+        .unwrap();
+
+        let collection = client.create_collection("test").await.unwrap();
+
+        for i in 0..25 {
+            collection
+                .set(Value::from(i), Value::Boolean(false))
+                .await
+                .unwrap();
+        }
+        collection.flush().await.unwrap();
+
+        // Newer values in the memtable override the values in sstables.
+        for i in 0..25 {
+            collection
+                .set(Value::from(i), Value::Boolean(true))
+                .await
+                .unwrap();
+        }
+        collection.delete(Value::from(7)).await.unwrap();
+
+        let documents = collection
+            .scan(10)
+            .await
+            .unwrap()
+            .map(|document| document.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            documents,
+            (0..25)
+                .filter(|i| *i != 7)
+                .map(|i| (Value::from(i), Value::Boolean(true)))
+                .collect::<Vec<_>>()
+        );
+    })?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn dump_collection(args: Args) -> Result<()> {
+    test_shard(args, |shard| async move {
+        let client = DbeelClient::from_seed_nodes(&[(
+            shard.args.ip.clone(),
+            shard.args.port,
+        )])
+        .await
+        .unwrap();
+
+        let collection = client.create_collection("test").await.unwrap();
+
+        for i in 0..2500 {
+            collection
+                .set(Value::from(i), Value::Boolean(false))
+                .await
+                .unwrap();
+        }
+        collection.flush().await.unwrap();
+
+        // Every key is dumped once, with its newest value.
+        for i in 0..2500 {
+            collection
+                .set(Value::from(i), Value::Boolean(true))
+                .await
+                .unwrap();
+        }
+        collection.delete(Value::from(7)).await.unwrap();
+
+        let mut documents = collection
+            .dump()
+            .await
+            .unwrap()
+            .map(|document| document.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+        documents.sort_unstable_by_key(|(key, _)| key.as_u64());
+        assert_eq!(
+            documents,
+            (0..2500)
+                .filter(|i| *i != 7)
+                .map(|i| (Value::from(i), Value::Boolean(true)))
+                .collect::<Vec<_>>()
+        );
+    })?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn subscribe_to_changes(mut args: Args) -> Result<()> {
+    args.change_log_capacity = 3;
+
+    test_shard(args, |shard| async move {
+        let client = DbeelClient::from_seed_nodes(&[(
+            shard.args.ip.clone(),
+            shard.args.port,
+        )])
+        .await
+        .unwrap();
+
+        let collection = client.create_collection("test").await.unwrap();
+        collection
+            .set(Value::from(0), Value::Boolean(false))
+            .await
+            .unwrap();
+
+        // Only changes made after subscribing are streamed.
+        let mut cursor = ChangeCursor::default();
+        let mut changes = collection.subscribe(&cursor).await.unwrap();
+
+        collection
+            .set(Value::from(1), Value::Boolean(true))
+            .await
+            .unwrap();
+        collection.delete(Value::from(1)).await.unwrap();
+
+        let change = changes.next().await.unwrap().unwrap();
+        assert_eq!(change.key, Value::from(1));
+        assert_eq!(change.value, Some(Value::Boolean(true)));
+        cursor.advance(&change);
+
+        let change = changes.next().await.unwrap().unwrap();
+        assert_eq!(change.key, Value::from(1));
+        assert_eq!(change.value, None);
+        drop(changes);
+
+        // Resume from the delete.
+        let mut changes = collection.subscribe(&cursor).await.unwrap();
+        let change = changes.next().await.unwrap().unwrap();
+        assert_eq!(change.key, Value::from(1));
+        assert_eq!(change.value, None);
+        cursor.advance(&change);
+        drop(changes);
+
+        // The change log holds only the last 3 changes.
+        for i in 2..6 {
+            collection
+                .set(Value::from(i), Value::Boolean(true))
+                .await
+                .unwrap();
+        }
+        assert!(collection.subscribe(&cursor).await.is_err());
+    })?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn changes_after_restart(args: Args) -> Result<()> {
+    test_shard(args.clone(), |shard| async move {
+        let client = DbeelClient::from_seed_nodes(&[(
+            shard.args.ip.clone(),
+            shard.args.port,
+        )])
+        .await
+        .unwrap();
+
+        let collection = client.create_collection("test").await.unwrap();
+        for i in 0..3 {
+            collection
+                .set(Value::from(i), Value::Boolean(true))
+                .await
+                .unwrap();
+        }
+    })?;
+
+    test_shard(args, |shard| async move {
+        let changes = shard.get_collection("test").unwrap().changes;
+        let cursor = changes.next_sequence() - 3;
+        let keys = changes
+            .read(cursor, 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|change| change.key)
+            .collect::<Vec<_>>();
+
+        let expected_keys = (0..3)
+            .map(|i| {
+                let mut key = Vec::new();
+                write_value(&mut key, &Value::from(i)).unwrap();
+                key
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(keys, expected_keys);
+    })?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn push_changes_of_large_writes(mut args: Args) -> Result<()> {
+    args.change_log_capacity = 5000;
+
+    test_shard(args, |shard| async move {
+        shard
+            .create_collection_with_metadata(
+                "test".to_string(),
+                CollectionMetadata::new(1),
+            )
+            .await
+            .unwrap();
+        let changes = shard.get_collection("test").unwrap().changes;
+        let write = |start: u32, len: u32| {
+            (start..start + len)
+                .map(|i| (i.to_be_bytes().to_vec(), Some(Vec::new())))
+                .collect::<Vec<_>>()
+        };
+
+        // More than half of the tree's memtable, the second write drops all
+        // changes of the first.
+        let first_cursor = changes.next_sequence();
+        changes
+            .push(write(0, 5000), OffsetDateTime::now_utc())
+            .await
+            .unwrap();
+        changes
+            .push(write(5000, 5000), OffsetDateTime::now_utc())
+            .await
+            .unwrap();
+        assert!(changes.read(first_cursor, 1).await.is_err());
+
+        // More changes than the log holds, only the last ones are kept.
+        let cursor = changes.next_sequence();
+        changes
+            .push(write(10000, 6000), OffsetDateTime::now_utc())
+            .await
+            .unwrap();
+        assert!(changes.read(cursor + 999, 1).await.is_err());
+
+        let read = changes.read(cursor + 1000, 5000).await.unwrap();
+        assert_eq!(read.len(), 5000);
+        assert_eq!(read[0].key, 11000u32.to_be_bytes().to_vec());
+        assert_eq!(read[4999].key, 15999u32.to_be_bytes().to_vec());
+    })?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn watch_key_and_prefix(args: Args) -> Result<()> {
+    test_shard(args, |shard| async move {
+        let client = DbeelClient::from_seed_nodes(&[(
+            shard.args.ip.clone(),
+            shard.args.port,
+        )])
+        .await
+        .unwrap();
+
+        let collection = client.create_collection("test").await.unwrap();
+        let mut key_events = collection.watch(Value::from("a")).await.unwrap();
+        let mut prefix_events = collection.watch_prefix("user:").await.unwrap();
+
+        collection
+            .set_from_str_key("b", Value::from(0))
+            .await
+            .unwrap();
+        collection
+            .set_from_str_key("a", Value::from(1))
+            .await
+            .unwrap();
+        collection
+            .set_from_str_key("user:1", Value::from(2))
+            .await
+            .unwrap();
+        collection.delete_from_str_key("user:1").await.unwrap();
+
+        let event = key_events.next().await.unwrap().unwrap();
+        assert_eq!(event.key, Value::from("a"));
+        assert_eq!(event.value, Some(Value::from(1)));
+
+        let event = prefix_events.next().await.unwrap().unwrap();
+        assert_eq!(event.key, Value::from("user:1"));
+        assert_eq!(event.value, Some(Value::from(2)));
+
+        let event = prefix_events.next().await.unwrap().unwrap();
+        assert_eq!(event.key, Value::from("user:1"));
+        assert_eq!(event.value, None);
+    })?;
+
+    Ok(())
+}
@@ -0,0 +1,459 @@
+use std::{
+    sync::Once,
+    time::{Duration, Instant},
+};
+
+use dbeel::{
+    args::{parse_args_from, Args},
+    error::Result,
+    flow_events::FlowEvent,
+    gossip::{serialize_gossip_message, GossipEvent, GossipMessage},
+    messages::NodeMetadata,
+    shards::CollectionMetadata,
+    tasks::gossip_server::{
+        expire_gossip_requests, GOSSIP_REQUEST_EXPIRATION_TIME,
+    },
+};
+use glommio::net::UdpSocket;
+use rstest::{fixture, rstest};
+use serial_test::serial;
+use test_utils::{install_logger, next_node_args, test_node};
+use time::OffsetDateTime;
+
+static ONCE: Once = Once::new();
+
+#[fixture]
+fn args() -> Args {
+    ONCE.call_once(|| {
+        install_logger();
+    });
+
+    // Remove the test directories if it exists.
+    let _ = std::fs::remove_dir_all("/tmp/test");
+    let _ = std::fs::remove_dir_all("/tmp/test1");
+    parse_args_from(["", "--dir", "/tmp/test", "--gossip-secret", "secret"])
+}
+
+fn create_collection_message(name: &str, secret: Option<&str>) -> Vec<u8> {
+    create_collection_message_at(name, secret, OffsetDateTime::now_utc())
+}
+
+fn create_collection_message_at(
+    name: &str,
+    secret: Option<&str>,
+    timestamp: OffsetDateTime,
+) -> Vec<u8> {
+    let message = GossipMessage::new(
+        "attacker-0".to_string(),
+        name.len() as u64,
+        timestamp,
+        GossipEvent::CreateCollection(
+            name.to_string(),
+            CollectionMetadata::new(1),
+        ),
+    );
+    serialize_gossip_message(&message, secret).unwrap()
+}
+
+async fn send_gossip(
+    socket: &UdpSocket,
+    address: &str,
+    sequence: u64,
+    event: GossipEvent,
+) {
+    let message = GossipMessage::new(
+        "tester-0".to_string(),
+        sequence,
+        OffsetDateTime::now_utc(),
+        event,
+    );
+    socket
+        .send_to(
+            &serialize_gossip_message(&message, Some("secret")).unwrap(),
+            address,
+        )
+        .await
+        .unwrap();
+}
+
+fn create_collection_event(name: &str) -> GossipEvent {
+    GossipEvent::CreateCollection(name.to_string(), CollectionMetadata::new(1))
+}
+
+fn fake_node(incarnation: u64) -> NodeMetadata {
+    NodeMetadata {
+        name: "fake".to_string(),
+        ip: "127.0.0.1".to_string(),
+        remote_shard_base_port: 1,
+        ids: vec![0],
+        gossip_port: 1,
+        db_port: 1,
+        incarnation,
+    }
+}
+
+#[rstest]
+#[serial]
+fn reject_unsigned_gossip(args: Args) -> Result<()> {
+    test_node(1, args, |shard, _| async move {
+        let address = format!("{}:{}", shard.args.ip, shard.args.gossip_port);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let collection_created_receiver =
+            shard.subscribe_to_flow_event(FlowEvent::CollectionCreated.into());
+
+        socket
+            .send_to(&create_collection_message("unsigned", None), &address)
+            .await
+            .unwrap();
+        socket
+            .send_to(
+                &create_collection_message("forged", Some("wrong")),
+                &address,
+            )
+            .await
+            .unwrap();
+        socket
+            .send_to(
+                &create_collection_message("signed", Some("secret")),
+                &address,
+            )
+            .await
+            .unwrap();
+
+        // Packets are handled in order, so the previous packets were already
+        // handled once the signed one is.
+        collection_created_receiver.recv().await.unwrap();
+
+        let collections = shard.collections.borrow();
+        assert!(collections.contains_key("signed"));
+        assert!(!collections.contains_key("unsigned"));
+        assert!(!collections.contains_key("forged"));
+        assert_eq!(shard.metrics.rejected_gossip_packets.get(), 2);
+    })?
+    .join()?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn reject_replayed_gossip(args: Args) -> Result<()> {
+    test_node(1, args, |shard, _| async move {
+        let address = format!("{}:{}", shard.args.ip, shard.args.gossip_port);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let collection_created_receiver =
+            shard.subscribe_to_flow_event(FlowEvent::CollectionCreated.into());
+
+        // A validly signed message, captured and replayed after its sequence
+        // was forgotten.
+        socket
+            .send_to(
+                &create_collection_message_at(
+                    "replayed",
+                    Some("secret"),
+                    OffsetDateTime::now_utc() - Duration::from_secs(60),
+                ),
+                &address,
+            )
+            .await
+            .unwrap();
+        socket
+            .send_to(
+                &create_collection_message("fresh", Some("secret")),
+                &address,
+            )
+            .await
+            .unwrap();
+
+        collection_created_receiver.recv().await.unwrap();
+
+        let collections = shard.collections.borrow();
+        assert!(collections.contains_key("fresh"));
+        assert!(!collections.contains_key("replayed"));
+        assert_eq!(shard.metrics.rejected_gossip_packets.get(), 1);
+    })?
+    .join()?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn ignore_stale_membership_gossip(mut args: Args) -> Result<()> {
+    // Don't ping the fake node.
+    args.failure_detection_interval = 60000;
+
+    test_node(1, args, |shard, _| async move {
+        let address = format!("{}:{}", shard.args.ip, shard.args.gossip_port);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let collection_created_receiver =
+            shard.subscribe_to_flow_event(FlowEvent::CollectionCreated.into());
+        let alive_receiver =
+            shard.subscribe_to_flow_event(FlowEvent::AliveNodeGossip.into());
+
+        // An alive event of the incarnation that was seen dead is stale.
+        send_gossip(&socket, &address, 0, GossipEvent::Dead("fake".into(), 5))
+            .await;
+        send_gossip(&socket, &address, 1, GossipEvent::Alive(fake_node(5)))
+            .await;
+        send_gossip(&socket, &address, 2, create_collection_event("first"))
+            .await;
+        collection_created_receiver.recv().await.unwrap();
+
+        assert!(!shard.nodes.borrow().contains_key("fake"));
+        assert_eq!(shard.dead_nodes.borrow().get("fake").unwrap().0, 5);
+
+        send_gossip(&socket, &address, 3, GossipEvent::Alive(fake_node(6)))
+            .await;
+        alive_receiver.recv().await.unwrap();
+
+        assert_eq!(shard.nodes.borrow().get("fake").unwrap().incarnation, 6);
+        assert!(!shard.dead_nodes.borrow().contains_key("fake"));
+
+        // A dead event of an older incarnation is stale.
+        send_gossip(&socket, &address, 4, GossipEvent::Dead("fake".into(), 5))
+            .await;
+        send_gossip(&socket, &address, 5, create_collection_event("second"))
+            .await;
+        collection_created_receiver.recv().await.unwrap();
+
+        assert!(shard.nodes.borrow().contains_key("fake"));
+        assert!(!shard.dead_nodes.borrow().contains_key("fake"));
+    })?
+    .join()?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn rebuild_ring_of_node_restarted_with_other_shards(
+    mut args: Args,
+) -> Result<()> {
+    // Don't ping the fake node.
+    args.failure_detection_interval = 60000;
+
+    test_node(1, args, |shard, _| async move {
+        let address = format!("{}:{}", shard.args.ip, shard.args.gossip_port);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let collection_created_receiver =
+            shard.subscribe_to_flow_event(FlowEvent::CollectionCreated.into());
+        let alive_receiver =
+            shard.subscribe_to_flow_event(FlowEvent::AliveNodeGossip.into());
+
+        send_gossip(&socket, &address, 0, GossipEvent::Alive(fake_node(1)))
+            .await;
+        alive_receiver.recv().await.unwrap();
+        assert_eq!(shard.shards.borrow().len(), 2);
+
+        // Restarted with another shard before it was seen dead.
+        let restarted = NodeMetadata {
+            ids: vec![0, 1],
+            ..fake_node(2)
+        };
+        send_gossip(&socket, &address, 1, GossipEvent::Alive(restarted)).await;
+        alive_receiver.recv().await.unwrap();
+        assert_eq!(shard.shards.borrow().len(), 3);
+
+        // Refuted a suspicion of its death, nothing changed.
+        let refuted = NodeMetadata {
+            ids: vec![0, 1],
+            ..fake_node(3)
+        };
+        send_gossip(&socket, &address, 2, GossipEvent::Alive(refuted)).await;
+        send_gossip(&socket, &address, 3, create_collection_event("marker"))
+            .await;
+        collection_created_receiver.recv().await.unwrap();
+        assert_eq!(shard.shards.borrow().len(), 3);
+        assert_eq!(shard.nodes.borrow().get("fake").unwrap().incarnation, 3);
+    })?
+    .join()?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn refute_own_death_once_per_node(args: Args) -> Result<()> {
+    test_node(2, args, |node_shard, shards| async move {
+        let address =
+            format!("{}:{}", node_shard.args.ip, node_shard.args.gossip_port);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let name = node_shard.args.name.clone();
+
+        let collection_created_receiver = node_shard
+            .subscribe_to_flow_event(FlowEvent::CollectionCreated.into());
+
+        let incarnation = node_shard.incarnation.get();
+        send_gossip(
+            &socket,
+            &address,
+            0,
+            GossipEvent::Dead(name.clone(), incarnation),
+        )
+        .await;
+        send_gossip(&socket, &address, 1, create_collection_event("first"))
+            .await;
+        collection_created_receiver.recv().await.unwrap();
+
+        // All shards of the node share the refuted incarnation.
+        assert_eq!(node_shard.incarnation.get(), incarnation + 1);
+        for shard in &shards {
+            assert_eq!(shard.incarnation.get(), incarnation + 1);
+        }
+
+        // The death was already refuted.
+        send_gossip(&socket, &address, 2, GossipEvent::Dead(name, incarnation))
+            .await;
+        send_gossip(&socket, &address, 3, create_collection_event("second"))
+            .await;
+        collection_created_receiver.recv().await.unwrap();
+
+        assert_eq!(node_shard.incarnation.get(), incarnation + 1);
+    })?
+    .join()?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn deduplicate_gossip_by_sequence(args: Args) -> Result<()> {
+    test_node(1, args, |shard, _| async move {
+        let address = format!("{}:{}", shard.args.ip, shard.args.gossip_port);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let collection_created_receiver =
+            shard.subscribe_to_flow_event(FlowEvent::CollectionCreated.into());
+
+        send_gossip(&socket, &address, 0, create_collection_event("kept"))
+            .await;
+        collection_created_receiver.recv().await.unwrap();
+
+        // Same source and sequence, seen as a copy of the first message.
+        send_gossip(
+            &socket,
+            &address,
+            0,
+            GossipEvent::DropCollection(
+                "kept".to_string(),
+                OffsetDateTime::now_utc(),
+            ),
+        )
+        .await;
+        send_gossip(&socket, &address, 1, create_collection_event("marker"))
+            .await;
+        collection_created_receiver.recv().await.unwrap();
+
+        let collections = shard.collections.borrow();
+        assert!(collections.contains_key("kept"));
+        assert!(collections.contains_key("marker"));
+    })?
+    .join()?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn expire_gossip_requests_seen_once(args: Args) -> Result<()> {
+    test_node(1, args, |shard, _| async move {
+        let address = format!("{}:{}", shard.args.ip, shard.args.gossip_port);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let collection_created_receiver =
+            shard.subscribe_to_flow_event(FlowEvent::CollectionCreated.into());
+
+        // Seen once, less than gossip_max_seen_count times.
+        for i in 0..3 {
+            send_gossip(
+                &socket,
+                &address,
+                i,
+                create_collection_event(&format!("c{i}")),
+            )
+            .await;
+            collection_created_receiver.recv().await.unwrap();
+        }
+        assert_eq!(shard.gossip_requests.borrow().len(), 3);
+
+        expire_gossip_requests(&shard, Instant::now());
+        assert_eq!(shard.gossip_requests.borrow().len(), 3);
+
+        expire_gossip_requests(
+            &shard,
+            Instant::now() + GOSSIP_REQUEST_EXPIRATION_TIME,
+        );
+        assert!(shard.gossip_requests.borrow().is_empty());
+    })?
+    .join()?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn gossip_advances_clock_of_skewed_node(args: Args) -> Result<()> {
+    let (seed_sender, seed_receiver) = async_channel::bounded(1);
+    let (skewed_sender, skewed_receiver) = async_channel::bounded(1);
+    let (done_sender, done_receiver) = async_channel::bounded(1);
+
+    let first_handle =
+        test_node(1, args.clone(), move |shard, _| async move {
+            seed_sender
+                .send(vec![format!(
+                    "{}:{}",
+                    shard.args.ip,
+                    shard.args.remote_shard_port + shard.id
+                )])
+                .await
+                .unwrap();
+            while shard.nodes.borrow().is_empty() {
+                let receiver = shard
+                    .subscribe_to_flow_event(FlowEvent::AliveNodeGossip.into());
+                receiver.recv().await.unwrap();
+            }
+
+            // The clock of this node runs ahead of the other node's.
+            let skewed = OffsetDateTime::now_utc() + Duration::from_secs(10);
+            shard.clock.update(skewed);
+            skewed_sender.send(skewed).await.unwrap();
+
+            shard
+                .gossip(create_collection_event("skewed"))
+                .await
+                .unwrap();
+
+            done_receiver.recv().await.unwrap();
+        })?;
+
+    let mut second_args = next_node_args(args, "second".to_string(), 1);
+    second_args.dir = "/tmp/test1".to_string();
+    second_args.seed_nodes = seed_receiver.recv_blocking()?;
+
+    let second_handle =
+        test_node(1, second_args, move |shard, _| async move {
+            let skewed = skewed_receiver.recv().await.unwrap();
+            while !shard.collections.borrow().contains_key("skewed") {
+                let receiver = shard.subscribe_to_flow_event(
+                    FlowEvent::CollectionCreated.into(),
+                );
+                receiver.recv().await.unwrap();
+            }
+
+            // Without a single write between the nodes, timestamps of this
+            // node are after the timestamps of the skewed node.
+            assert!(shard.clock.now() > skewed);
+
+            done_sender.send(()).await.unwrap();
+        })?;
+
+    second_handle.join()?;
+    first_handle.join()?;
+
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use std::sync::Once;
+
+use dbeel::{
+    args::{parse_args_from, Args},
+    error::Result,
+};
+use dbeel_client::DbeelClient;
+use futures::{AsyncReadExt, AsyncWriteExt};
+use glommio::net::TcpStream;
+use rmpv::Value;
+use rstest::{fixture, rstest};
+use serial_test::serial;
+use test_utils::{install_logger, test_node};
+
+static ONCE: Once = Once::new();
+
+#[fixture]
+fn args() -> Args {
+    ONCE.call_once(|| {
+        install_logger();
+    });
+
+    // Remove the test directory if it exists.
+    let _ = std::fs::remove_dir_all("/tmp/test");
+    parse_args_from(["", "--dir", "/tmp/test"])
+}
+
+async fn http_get(address: &str, path: &str) -> String {
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream
+        .write_all(format!("GET {path} HTTP/1.1\r\n\r\n").as_bytes())
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[rstest]
+#[serial]
+fn metrics_of_all_shards(args: Args) -> Result<()> {
+    test_node(2, args, |shard, _| async move {
+        let client = DbeelClient::from_seed_nodes(&[(
+            shard.args.ip.clone(),
+            shard.args.port,
+        )])
+        .await
+        .unwrap();
+        let collection = client.create_collection("test").await.unwrap();
+        collection
+            .set_from_str_key("key", Value::Boolean(true))
+            .await
+            .unwrap();
+
+        let address =
+            format!("{}:{}", shard.args.ip, shard.args.metrics_port);
+
+        let response = http_get(&address, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        for shard_name in ["dbeel-0", "dbeel-1"] {
+            assert!(response.contains(&format!(
+                "dbeel_sstables{{shard=\"{shard_name}\",collection=\"test\"}} 0"
+            )));
+            assert!(response.contains(&format!(
+                "dbeel_gossip_packets_rejected_total{{shard=\"{shard_name}\"}} 0"
+            )));
+        }
+        assert!(response.contains("type=\"create_collection\""));
+        assert!(response.contains("type=\"set\""));
+        assert!(response.contains(
+            "dbeel_memtable_entries{shard=\"dbeel-0\",collection=\"test\"}"
+        ));
+
+        let response = http_get(&address, "/other").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+    })?
+    .join()?;
+
+    Ok(())
+}
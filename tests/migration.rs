@@ -4,7 +4,6 @@ use dbeel::{
     args::{parse_args_from, Args},
     error::Result,
     flow_events::FlowEvent,
-    storage_engine::TOMBSTONE,
 };
 use dbeel_client::{Consistency, DbeelClient};
 use event_listener::Event;
@@ -260,6 +259,24 @@ fn migration_on_death(args: Args) -> Result<()> {
                 Some((*LOWER_VALUE).clone())
             );
 
+            // Migrated items are not new changes, only the write to the key
+            // the shard was the primary of is in its change log.
+            let changes = shard.get_collection("test").unwrap().changes;
+            let (cursor, expected_keys) = if should_own_upper {
+                (changes.next_sequence() - 1, vec![(*UPPER_KEY).clone()])
+            } else {
+                (changes.next_sequence(), vec![])
+            };
+            let keys = changes
+                .read(cursor, 10)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|change| change.key)
+                .collect::<Vec<_>>();
+            assert_eq!(keys, expected_keys);
+            assert!(changes.read(cursor - 1, 10).await.is_err());
+
             done_sender.send(()).await.unwrap();
             done_receiver.recv().await.unwrap();
         })?);
@@ -453,14 +470,12 @@ fn migration_on_new_node(args: Args) -> Result<()> {
                 .unwrap(),
             Some((*UPPER_VALUE).clone())
         );
-        assert_eq!(
-            shard.collections.borrow()["test"]
-                .tree
-                .get(&LOWER_KEY)
-                .await
-                .unwrap(),
-            Some(TOMBSTONE)
-        );
+        assert!(shard.collections.borrow()["test"]
+            .tree
+            .get_entry(&LOWER_KEY)
+            .await
+            .unwrap()
+            .is_some_and(|value| value.is_delete()));
 
         b_done_event.notify(2);
         join_all(b_done_listeners).await;
@@ -509,3 +524,143 @@ fn migration_on_new_node(args: Args) -> Result<()> {
 
     Ok(())
 }
+
+#[rstest]
+#[serial]
+fn migration_on_replication_factor_change(args: Args) -> Result<()> {
+    // "a-0"    -> 2727548292
+    // "b-0"    -> 1121949192
+    // "key"    -> 1211368233
+    //
+    // b-0 -> key -> a-0.
+
+    let (seed_sender, seed_receiver) = async_channel::bounded(1);
+    let (collection_created_sender, collection_created_receiver) =
+        async_channel::bounded(1);
+    let (grown_sender, grown_receiver) = async_channel::bounded(1);
+    let (shrunk_sender, shrunk_receiver) = async_channel::bounded(1);
+    let (done_sender, done_receiver) = async_channel::bounded(1);
+
+    let mut a_args = args;
+    a_args.name = "a".to_string();
+
+    let a_handle = test_node(1, a_args.clone(), move |shard, _| async move {
+        seed_sender
+            .send(vec![format!(
+                "{}:{}",
+                shard.args.ip,
+                shard.args.remote_shard_port + shard.id
+            )])
+            .await
+            .unwrap();
+        while shard.nodes.borrow().is_empty() {
+            let receiver = shard
+                .subscribe_to_flow_event(FlowEvent::AliveNodeGossip.into());
+            receiver.recv().await.unwrap();
+        }
+
+        let mut client = DbeelClient::from_seed_nodes(&[(
+            shard.args.ip.clone(),
+            shard.args.port,
+        )])
+        .await
+        .unwrap();
+
+        client.set_read_timeout(Duration::from_secs(1));
+        client.set_write_timeout(Duration::from_secs(1));
+
+        assert!(client
+            .create_collection_with_replication("test", 0)
+            .await
+            .is_err());
+
+        let mut collection = client
+            .create_collection_with_replication("test", 1)
+            .await
+            .unwrap();
+        collection_created_receiver.recv().await.unwrap();
+
+        collection
+            .set_consistent(
+                Value::String("key".into()),
+                Value::F32(42.0),
+                Consistency::Fixed(1),
+            )
+            .await
+            .unwrap();
+
+        // The owner of the key sends it to the new replica.
+        collection.set_replication_factor(2).await.unwrap();
+        grown_receiver.recv().await.unwrap();
+
+        assert!(collection.set_replication_factor(0).await.is_err());
+
+        // The shard that is no longer a replica of the key deletes it.
+        collection.set_replication_factor(1).await.unwrap();
+        shrunk_receiver.recv().await.unwrap();
+
+        assert_eq!(
+            shard.collections.borrow()["test"]
+                .tree
+                .get(&LOWER_KEY)
+                .await
+                .unwrap(),
+            Some((*LOWER_VALUE).clone())
+        );
+
+        done_sender.send(()).await.unwrap();
+    })?;
+
+    let mut b_args = next_node_args(a_args, "b".to_string(), 1);
+    b_args.dir = "/tmp/test1".to_string();
+    b_args.seed_nodes = seed_receiver.recv_blocking()?;
+
+    let b_handle = test_node(1, b_args, move |shard, _| async move {
+        while !shard.collections.borrow().contains_key("test") {
+            let event = shard
+                .subscribe_to_flow_event(FlowEvent::CollectionCreated.into());
+            event.recv().await.unwrap();
+        }
+        collection_created_sender.send(()).await.unwrap();
+
+        loop {
+            let item_migrated = shard.subscribe_to_flow_event(
+                FlowEvent::ItemSetFromShardMessage.into(),
+            );
+            if shard.collections.borrow()["test"]
+                .tree
+                .get(&LOWER_KEY)
+                .await
+                .unwrap()
+                == Some((*LOWER_VALUE).clone())
+            {
+                break;
+            }
+            item_migrated.recv().await.unwrap();
+        }
+        grown_sender.send(()).await.unwrap();
+
+        loop {
+            let done_migration =
+                shard.subscribe_to_flow_event(FlowEvent::DoneMigration.into());
+            if shard.collections.borrow()["test"]
+                .tree
+                .get(&LOWER_KEY)
+                .await
+                .unwrap()
+                .is_none()
+            {
+                break;
+            }
+            done_migration.recv().await.unwrap();
+        }
+        shrunk_sender.send(()).await.unwrap();
+
+        done_receiver.recv().await.unwrap();
+    })?;
+
+    b_handle.join()?;
+    a_handle.join()?;
+
+    Ok(())
+}
@@ -18,6 +18,7 @@ static ONCE: Once = Once::new();
 fn create_metadata_from_args(
     args: Args,
     number_of_shards: u16,
+    incarnation: u64,
 ) -> NodeMetadata {
     NodeMetadata {
         name: args.name,
@@ -26,6 +27,7 @@ fn create_metadata_from_args(
         ids: (0..number_of_shards).collect::<Vec<_>>(),
         gossip_port: args.gossip_port,
         db_port: args.port,
+        incarnation,
     }
 }
 
@@ -87,15 +89,19 @@ fn node_discovery_and_shutdown_detect_(
             );
 
             seed_sender
-                .send(vec![format!(
-                    "{}:{}",
-                    node_shard.args.ip,
-                    node_shard.args.remote_shard_port + node_shard.id
-                )])
+                .send((
+                    vec![format!(
+                        "{}:{}",
+                        node_shard.args.ip,
+                        node_shard.args.remote_shard_port + node_shard.id
+                    )],
+                    node_shard.incarnation.get(),
+                ))
                 .await
                 .unwrap();
 
-            let second_args = second_up_receiver.recv().await.unwrap();
+            let (second_args, second_incarnation) =
+                second_up_receiver.recv().await.unwrap();
             wait_for_flow_events(all_shards_alive_node_gossip_events)
                 .await
                 .unwrap();
@@ -103,9 +109,14 @@ fn node_discovery_and_shutdown_detect_(
             let second_node = create_metadata_from_args(
                 second_args,
                 number_of_shards_second_node,
+                second_incarnation,
             );
 
             for shard in &all_shards {
+                assert_eq!(
+                    shard.incarnation.get(),
+                    node_shard.incarnation.get()
+                );
                 assert_eq!(shard.nodes.borrow().len(), 1);
                 assert_eq!(
                     shard.nodes.borrow().get(&second_node.name).unwrap(),
@@ -124,7 +135,7 @@ fn node_discovery_and_shutdown_detect_(
         },
     )?;
 
-    let seed_nodes = seed_receiver.recv_blocking()?;
+    let (seed_nodes, first_incarnation) = seed_receiver.recv_blocking()?;
 
     let mut second_args = next_node_args(
         args.clone(),
@@ -141,10 +152,16 @@ fn node_discovery_and_shutdown_detect_(
             let mut all_shards = other_shards.clone();
             all_shards.push(node_shard.clone());
 
-            second_up_sender.send(second_args).await.unwrap();
+            second_up_sender
+                .send((second_args, node_shard.incarnation.get()))
+                .await
+                .unwrap();
 
-            let first_node =
-                create_metadata_from_args(args, number_of_shards_first_node);
+            let first_node = create_metadata_from_args(
+                args,
+                number_of_shards_first_node,
+                first_incarnation,
+            );
 
             for shard in &all_shards {
                 assert_eq!(shard.nodes.borrow().len(), 1);
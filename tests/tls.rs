@@ -0,0 +1,120 @@
+use std::sync::Once;
+
+use dbeel::{
+    args::{parse_args_from, Args},
+    error::Result,
+};
+use dbeel_client::{tls_config_from_ca_cert, DbeelClient};
+use rcgen::generate_simple_self_signed;
+use rmpv::Value;
+use rstest::{fixture, rstest};
+use serial_test::serial;
+use test_utils::{install_logger, next_node_args, test_node, test_shard};
+
+const CERT_PATH: &str = "/tmp/test-tls/cert.pem";
+const KEY_PATH: &str = "/tmp/test-tls/key.pem";
+
+static ONCE: Once = Once::new();
+
+fn generate_self_signed_certificate() {
+    let cert = generate_simple_self_signed(vec![
+        "127.0.0.1".to_string(),
+        "localhost".to_string(),
+    ])
+    .unwrap();
+    std::fs::create_dir_all("/tmp/test-tls").unwrap();
+    std::fs::write(CERT_PATH, cert.serialize_pem().unwrap()).unwrap();
+    std::fs::write(KEY_PATH, cert.serialize_private_key_pem()).unwrap();
+}
+
+#[fixture]
+fn args() -> Args {
+    ONCE.call_once(|| {
+        install_logger();
+        generate_self_signed_certificate();
+    });
+
+    // Remove the test directories if it exists.
+    let _ = std::fs::remove_dir_all("/tmp/test");
+    let _ = std::fs::remove_dir_all("/tmp/test1");
+    parse_args_from([
+        "",
+        "--dir",
+        "/tmp/test",
+        "--tls-cert",
+        CERT_PATH,
+        "--tls-key",
+        KEY_PATH,
+    ])
+}
+
+#[rstest]
+#[serial]
+fn client_over_tls(args: Args) -> Result<()> {
+    test_shard(args, |shard| async move {
+        let address = (shard.args.ip.clone(), shard.args.port);
+
+        assert!(DbeelClient::from_seed_nodes(std::slice::from_ref(&address))
+            .await
+            .is_err());
+
+        let client = DbeelClient::from_seed_nodes_ex(
+            &[address],
+            None,
+            Some(tls_config_from_ca_cert(CERT_PATH).unwrap()),
+        )
+        .await
+        .unwrap();
+        let collection = client.create_collection("test").await.unwrap();
+        collection
+            .set_from_str_key("key", Value::Boolean(true))
+            .await
+            .unwrap();
+        assert_eq!(
+            collection.get_from_str_key("key").await.unwrap(),
+            Value::Boolean(true)
+        );
+    })?;
+
+    Ok(())
+}
+
+#[rstest]
+#[serial]
+fn node_discovery_over_mutual_tls(mut args: Args) -> Result<()> {
+    args.tls_mutual = true;
+
+    let (seed_sender, seed_receiver) = async_channel::bounded(1);
+    let (done_sender, done_receiver) = async_channel::bounded(1);
+
+    let first_handle =
+        test_node(1, args.clone(), move |shard, _| async move {
+            seed_sender
+                .send(vec![format!(
+                    "{}:{}",
+                    shard.args.ip,
+                    shard.args.remote_shard_port + shard.id
+                )])
+                .await
+                .unwrap();
+            done_receiver.recv().await.unwrap();
+        })?;
+
+    let first_name = args.name.clone();
+    let mut second_args = next_node_args(args, "second".to_string(), 1);
+    second_args.dir = "/tmp/test1".to_string();
+    second_args.seed_nodes = seed_receiver.recv_blocking()?;
+
+    let second_handle =
+        test_node(1, second_args, move |shard, _| async move {
+            // Discovery asks the seed node for its metadata over the remote
+            // shard port, which requires both nodes to trust each other.
+            assert!(shard.nodes.borrow().contains_key(&first_name));
+            done_sender.send(()).await.unwrap();
+        })?;
+
+    second_handle.join()?;
+    first_handle.join()?;
+
+    Ok(())
+}